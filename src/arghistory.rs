@@ -0,0 +1,110 @@
+// Arguments previously passed to each command, offered by Tab completion for
+// an argument that matches no file, so `ssh <Tab>` suggests hosts used before.
+// The REPL records the lines it runs, except those starting with a space, as
+// they were typed, so an argument such as `$TOKEN` is kept rather than the
+// secret it expands to. It keeps the store in `~/.myshell_args` as one `command<TAB>argument` line per
+// entry, oldest first.
+
+use std::collections::HashMap;
+
+/// The store, relative to `HOME`.
+pub const ARGS_FILE: &str = ".myshell_args";
+
+/// The most arguments remembered for one command; the oldest go first.
+pub const MAX_PER_COMMAND: usize = 50;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArgHistory {
+    /// Each command's arguments, most recent first.
+    commands: HashMap<String, Vec<String>>,
+}
+
+impl ArgHistory {
+    /// Reads a store written by `to_text`. Malformed lines are skipped.
+    pub fn load(text: &str) -> Self {
+        let mut history = ArgHistory::default();
+        for line in text.lines() {
+            if let Some((command, arg)) = line.split_once('\t') {
+                history.record(command, [arg]);
+            }
+        }
+        history
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort();
+        let mut text = String::new();
+        for (command, args) in commands {
+            for arg in args.iter().rev() {
+                text.push_str(&format!("{}\t{}\n", command, arg));
+            }
+        }
+        text
+    }
+
+    /// Records `args` as the latest used with `command`. Empty arguments and
+    /// those a line of the store could not hold are left out.
+    pub fn record<'a>(&mut self, command: &str, args: impl IntoIterator<Item = &'a str>) {
+        if command.is_empty() || command.contains(['\t', '\n']) {
+            return;
+        }
+        for arg in args {
+            if arg.is_empty() || arg.contains('\n') {
+                continue;
+            }
+            let used = self.commands.entry(command.to_string()).or_default();
+            used.retain(|a| a != arg);
+            used.insert(0, arg.to_string());
+            used.truncate(MAX_PER_COMMAND);
+        }
+    }
+
+    /// The arguments used with `command` that `matches` accepts, most recent
+    /// first.
+    pub fn suggestions(&self, command: &str, matches: impl Fn(&str) -> bool) -> Vec<String> {
+        self.commands
+            .get(command)
+            .map(|used| used.iter().filter(|arg| matches(arg)).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// The command word and arguments of a simple command as typed, before any
+/// expansion. Leading assignments, and redirections with their targets, are
+/// left out.
+pub fn typed_words(text: &str) -> Option<(&str, Vec<&str>)> {
+    let mut spans = crate::syntax::word_spans(text).into_iter().map(|span| &text[span]);
+    let mut words = Vec::new();
+    while let Some(word) = spans.next() {
+        if let Some(operator) = redirection_operator(word) {
+            // A bare operator takes the next word as its target.
+            if operator.len() == word.len() {
+                spans.next();
+            }
+        } else if !(words.is_empty() && is_assignment(word)) {
+            words.push(word);
+        }
+    }
+    let (command, args) = words.split_first()?;
+    Some((command, args.to_vec()))
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| crate::expand::is_name(name))
+}
+
+// The operator at the start of `word`, with any descriptor before it, if the
+// word is a redirection: `2>`, `<<-`, `&>`, `>&` and so on.
+fn redirection_operator(word: &str) -> Option<&str> {
+    let digits = word.len() - word.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let rest = &word[digits..];
+    if !(rest.starts_with(['<', '>']) || rest.starts_with("&>")) {
+        return None;
+    }
+    let mut end = word.len() - rest.trim_start_matches(['<', '>', '&', '|']).len();
+    if word[..end].ends_with("<<") && word[end..].starts_with('-') {
+        end += 1;
+    }
+    Some(&word[..end])
+}
@@ -0,0 +1,349 @@
+// Shell arithmetic: the integer expression language shared by `$(( ))`,
+// `(( ))` and `let`. Operators and precedence follow bash.
+
+use std::fmt;
+
+use crate::expand::Variables;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArithError {
+    pub expr: String,
+    pub message: String,
+}
+
+impl fmt::Display for ArithError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.expr.trim(), self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// Longest operators first so that e.g. `<<=` is not read as `<` `<=`.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&",
+    "^", "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENT_OPERATORS: &[&str] = &["=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|="];
+
+// Binary operators from loosest to tightest binding; `**` is handled
+// separately because it is right-associative.
+const BINARY_LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// A variable whose value refers to itself (`x=x`) must not recurse forever.
+const MAX_DEPTH: usize = 64;
+
+/// Evaluates `expr`, reading and assigning variables through `vars`.
+pub fn evaluate(expr: &str, vars: &dyn Variables) -> Result<i64, ArithError> {
+    evaluate_at_depth(expr, vars, 0)
+}
+
+fn evaluate_at_depth(expr: &str, vars: &dyn Variables, depth: usize) -> Result<i64, ArithError> {
+    let error = |message: &str| ArithError { expr: expr.to_string(), message: message.to_string() };
+    if depth > MAX_DEPTH {
+        return Err(error("expression recursion level exceeded"));
+    }
+    let tokens = tokenize(expr).map_err(|m| error(&m))?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { tokens, pos: 0, vars, depth };
+    let value = parser.comma(false).map_err(|m| error(&m))?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(tok) => Err(error(&format!("syntax error in expression (error token is \"{}\")", describe(tok)))),
+    }
+}
+
+fn describe(tok: &Tok) -> String {
+    match tok {
+        Tok::Num(n) => n.to_string(),
+        Tok::Ident(name) => name.clone(),
+        Tok::Op(op) => op.to_string(),
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Tok>, String> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '#') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Tok::Num(parse_number(&word)?));
+            } else if word.contains('#') {
+                return Err(format!("syntax error: invalid arithmetic operator (error token is \"{}\")", word));
+            } else {
+                tokens.push(Tok::Ident(word));
+            }
+        } else {
+            let rest: String = chars[i..].iter().take(3).collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("syntax error: invalid arithmetic operator (error token is \"{}\")", c))?;
+            tokens.push(Tok::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+// Integer literals: decimal, `0x` hex, leading-zero octal and `base#digits`.
+fn parse_number(word: &str) -> Result<i64, String> {
+    let invalid = || format!("value too great for base (error token is \"{}\")", word);
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        let base: u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) {
+            return Err(format!("invalid arithmetic base (error token is \"{}\")", word));
+        }
+        (base, digits)
+    } else if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, hex)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Tok>,
+    pos: usize,
+    vars: &'a dyn Variables,
+    depth: usize,
+}
+
+// Each level takes `skip`: when set, the operand is parsed but has no side
+// effects and cannot fail at runtime, which is how `&&`, `||` and `?:`
+// short-circuit.
+impl Parser<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Tok::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("syntax error: `{}' expected", op))
+        }
+    }
+
+    fn comma(&mut self, skip: bool) -> Result<i64, String> {
+        let mut value = self.assignment(skip)?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            value = self.assignment(skip)?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self, skip: bool) -> Result<i64, String> {
+        if let (Some(Tok::Ident(name)), Some(Tok::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1))
+            && ASSIGNMENT_OPERATORS.contains(op)
+        {
+            let (name, op) = (name.clone(), *op);
+            self.pos += 2;
+            let rhs = self.assignment(skip)?;
+            if skip {
+                return Ok(0);
+            }
+            let value = match op {
+                "=" => rhs,
+                _ => apply_binary(&op[..op.len() - 1], self.variable(&name)?, rhs)?,
+            };
+            self.vars.set_var(&name, &value.to_string());
+            return Ok(value);
+        }
+        self.ternary(skip)
+    }
+
+    fn ternary(&mut self, skip: bool) -> Result<i64, String> {
+        let condition = self.binary(0, skip)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let if_true = self.assignment(skip || condition == 0)?;
+        self.expect(":")?;
+        let if_false = self.assignment(skip || condition != 0)?;
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, level: usize, skip: bool) -> Result<i64, String> {
+        if level == BINARY_LEVELS.len() {
+            return self.power(skip);
+        }
+        let mut lhs = self.binary(level + 1, skip)?;
+        while let Some(op) = self.peek_op().filter(|op| BINARY_LEVELS[level].contains(op)) {
+            self.pos += 1;
+            lhs = match op {
+                "&&" => {
+                    let rhs = self.binary(level + 1, skip || lhs == 0)?;
+                    (lhs != 0 && rhs != 0) as i64
+                }
+                "||" => {
+                    let rhs = self.binary(level + 1, skip || lhs != 0)?;
+                    (lhs != 0 || rhs != 0) as i64
+                }
+                _ => {
+                    let rhs = self.binary(level + 1, skip)?;
+                    if skip { 0 } else { apply_binary(op, lhs, rhs)? }
+                }
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn power(&mut self, skip: bool) -> Result<i64, String> {
+        let base = self.unary(skip)?;
+        if self.peek_op() != Some("**") {
+            return Ok(base);
+        }
+        self.pos += 1;
+        let exponent = self.power(skip)?;
+        if skip { Ok(0) } else { apply_binary("**", base, exponent) }
+    }
+
+    fn unary(&mut self, skip: bool) -> Result<i64, String> {
+        match self.peek_op() {
+            Some(op @ ("!" | "~" | "-" | "+")) => {
+                self.pos += 1;
+                let value = self.unary(skip)?;
+                Ok(match op {
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    "-" => value.wrapping_neg(),
+                    _ => value,
+                })
+            }
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                let Some(Tok::Ident(name)) = self.tokens.get(self.pos).cloned() else {
+                    return Err(format!("syntax error: operand expected (error token is \"{}\")", op));
+                };
+                self.pos += 1;
+                if skip {
+                    return Ok(0);
+                }
+                let delta = if op == "++" { 1 } else { -1 };
+                let value = self.variable(&name)?.wrapping_add(delta);
+                self.vars.set_var(&name, &value.to_string());
+                Ok(value)
+            }
+            _ => self.primary(skip),
+        }
+    }
+
+    fn primary(&mut self, skip: bool) -> Result<i64, String> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match tok {
+            Some(Tok::Num(n)) => Ok(n),
+            Some(Tok::Ident(name)) => {
+                let value = if skip { 0 } else { self.variable(&name)? };
+                if let Some(op @ ("++" | "--")) = self.peek_op() {
+                    self.pos += 1;
+                    if !skip {
+                        let delta = if op == "++" { 1 } else { -1 };
+                        self.vars.set_var(&name, &value.wrapping_add(delta).to_string());
+                    }
+                }
+                Ok(value)
+            }
+            Some(Tok::Op("(")) => {
+                let value = self.comma(skip)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(tok) => Err(format!("syntax error: operand expected (error token is \"{}\")", describe(&tok))),
+            None => Err("syntax error: operand expected".to_string()),
+        }
+    }
+
+    // A variable's value is itself evaluated as an expression; unset or empty is 0.
+    fn variable(&self, name: &str) -> Result<i64, String> {
+        match self.vars.var(name) {
+            Some(value) if !value.trim().is_empty() => {
+                evaluate_at_depth(&value, self.vars, self.depth + 1).map_err(|e| e.message)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+fn apply_binary(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    Ok(match op {
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err("division by 0".to_string()),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" if rhs < 0 => return Err("exponent less than 0".to_string()),
+        "**" => lhs.wrapping_pow(rhs.min(u32::MAX as i64) as u32),
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "&" => lhs & rhs,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        _ => unreachable!("unknown arithmetic operator {}", op),
+    })
+}
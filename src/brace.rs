@@ -0,0 +1,238 @@
+// Brace expansion, which runs on the raw text of a command before any other
+// expansion. `pre{a,b}post` gives one word per comma-separated alternative;
+// `{X..Y}` and `{X..Y..STEP}` count between two integers or two letters.
+// Quoted and escaped braces, `${`, and anything that is not a valid brace
+// expression are left as written.
+
+use std::borrow::Cow;
+
+/// Expands the braces in each word of `line`, returning it with every word
+/// replaced by its expansions. Words are the raw text between unquoted
+/// blanks, so the results are parsed and expanded as usual afterwards. If
+/// `allow_assignments`, leading `NAME=value` words are not expanded, and
+/// redirection targets never are.
+pub fn expand_line(line: &str, allow_assignments: bool) -> Cow<'_, str> {
+    if !line.contains('{') {
+        return Cow::Borrowed(line);
+    }
+    let words = split_words(line);
+    let mut expanded = Vec::with_capacity(words.len());
+    let mut leading_assignments = allow_assignments;
+    let mut after_redirection = false;
+    for word in words {
+        leading_assignments = leading_assignments && is_assignment(word);
+        let redirection = has_unquoted(word, &['<', '>']);
+        if leading_assignments || redirection || after_redirection {
+            expanded.push(word.to_string());
+        } else {
+            expanded.extend(expand_word(word));
+        }
+        after_redirection = redirection && word.trim_start_matches(|c: char| c.is_ascii_digit()).trim_start_matches(['<', '>', '&']).is_empty();
+    }
+    Cow::Owned(expanded.join(" "))
+}
+
+/// The expansions of one raw word, in order. A word with no brace expression
+/// expands to itself.
+pub fn expand_word(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut from = 0;
+    while let Some((open, close)) = find_braces(&chars, from) {
+        let body: String = chars[open + 1..close].iter().collect();
+        let Some(items) = alternatives(&body).or_else(|| range(&body)) else {
+            from = open + 1;
+            continue;
+        };
+        let prefix: String = chars[..open].iter().collect();
+        let suffix: String = chars[close + 1..].iter().collect();
+        // Each result may hold further brace expressions, in an alternative
+        // or in the suffix.
+        return items.iter().flat_map(|item| expand_word(&format!("{}{}{}", prefix, item, suffix))).collect();
+    }
+    vec![word.to_string()]
+}
+
+// Splits a line into raw words on unquoted blanks, keeping quotes,
+// escapes, `$(...)`, `${...}` and backquotes inside the word.
+fn split_words(line: &str) -> Vec<&str> {
+    let offsets: Vec<usize> = line.char_indices().map(|(offset, _)| offset).chain([line.len()]).collect();
+    let chars: Vec<char> = line.chars().collect();
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            if let Some(s) = start.take() {
+                words.push(&line[offsets[s]..offsets[i]]);
+            }
+            i += 1;
+            continue;
+        }
+        start.get_or_insert(i);
+        i = skip_unit(&chars, i);
+    }
+    if let Some(s) = start {
+        words.push(&line[offsets[s]..]);
+    }
+    words
+}
+
+// Returns the index just past the character at `i`, or past the whole quoted
+// string, escape or `$` group it starts.
+fn skip_unit(chars: &[char], i: usize) -> usize {
+    let closing = |from: usize, quote: char| chars[from..].iter().position(|&c| c == quote).map_or(chars.len(), |j| from + j + 1);
+    match chars[i] {
+        '\\' => (i + 2).min(chars.len()),
+        '\'' => closing(i + 1, '\''),
+        '`' => closing(i + 1, '`'),
+        '"' => {
+            let mut j = i + 1;
+            while j < chars.len() && chars[j] != '"' {
+                j += if chars[j] == '\\' { 2 } else { 1 };
+            }
+            (j + 1).min(chars.len())
+        }
+        '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+            let (opener, closer) = if chars[i + 1] == '(' { ('(', ')') } else { ('{', '}') };
+            let mut depth = 0;
+            let mut j = i + 1;
+            while j < chars.len() {
+                if chars[j] == opener {
+                    depth += 1;
+                } else if chars[j] == closer {
+                    depth -= 1;
+                    if depth == 0 {
+                        return j + 1;
+                    }
+                } else if matches!(chars[j], '\'' | '"' | '\\' | '`') {
+                    j = skip_unit(chars, j);
+                    continue;
+                }
+                j += 1;
+            }
+            chars.len()
+        }
+        _ => i + 1,
+    }
+}
+
+// Finds the first unquoted `{` at or after `from` that has a matching `}`.
+fn find_braces(chars: &[char], from: usize) -> Option<(usize, usize)> {
+    let mut i = from;
+    while i < chars.len() {
+        if chars[i] == '{' && let Some(close) = matching_brace(chars, i) {
+            return Some((i, close));
+        }
+        i = skip_unit(chars, i);
+    }
+    None
+}
+
+fn matching_brace(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut j = open;
+    while j < chars.len() {
+        match chars[j] {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(j);
+                }
+            }
+            _ => {
+                j = skip_unit(chars, j);
+                continue;
+            }
+        }
+        j += 1;
+    }
+    None
+}
+
+// Splits `a,b,c` on its top-level commas. `None` without a comma.
+fn alternatives(body: &str) -> Option<Vec<String>> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(chars[start..i].iter().collect());
+                start = i + 1;
+            }
+            _ => {
+                i = skip_unit(&chars, i);
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if items.is_empty() {
+        return None;
+    }
+    items.push(chars[start..].iter().collect());
+    Some(items)
+}
+
+// Expands `X..Y` or `X..Y..STEP`. The sign of the step is ignored: the range
+// always runs from X towards Y. Numbers are zero-padded to the wider endpoint
+// if either was written with a leading zero.
+fn range(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None,
+    };
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |text: &str| text.trim_start_matches('-').len() > 1 && text.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        return Some(steps(first, last, step).map(|n| format!("{:0width$}", n, width = width)).collect());
+    }
+    let letter = |text: &str| {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c as i64),
+            _ => None,
+        }
+    };
+    let (first, last) = (letter(start)?, letter(end)?);
+    Some(steps(first, last, step).map(|code| quote_char(code as u8 as char)).collect())
+}
+
+fn steps(first: i64, last: i64, step: u64) -> impl Iterator<Item = i64> {
+    let count = (first.abs_diff(last) / step).saturating_add(1).min(i64::MAX as u64);
+    let step = if last < first { -(step as i64) } else { step as i64 };
+    (0..count as i64).map(move |k| first + k * step)
+}
+
+// A letter range such as `{Z..a}` passes through punctuation, which must not
+// be read as syntax when the word is parsed.
+fn quote_char(c: char) -> String {
+    match c {
+        c if c.is_ascii_alphanumeric() || c == '_' => c.to_string(),
+        '\'' => "\\'".to_string(),
+        c => format!("'{}'", c),
+    }
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| crate::expand::is_name(name))
+}
+
+fn has_unquoted(word: &str, targets: &[char]) -> bool {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if targets.contains(&chars[i]) {
+            return true;
+        }
+        i = skip_unit(&chars, i);
+    }
+    false
+}
@@ -0,0 +1,1215 @@
+// Builtin commands. Each returns its exit status; output goes through
+// `CommandOutput` so that redirections apply uniformly.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rustyline::config::EditMode;
+
+use crate::arith;
+use crate::cond::CondExpr;
+use crate::direnv;
+use crate::expand::{self, Variables};
+use crate::history;
+use crate::limits::{self, Limit, Which};
+use crate::paths;
+use crate::printf;
+use crate::{sanitize_for_display, Argument, Command, CommandOutput, HashedCommand, Redirection, Shell};
+
+pub struct ExitCommand;
+impl Command for ExitCommand {
+    fn name(&self) -> &str { "exit" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let code = match args.first() {
+            None => shell.last_status(),
+            Some(arg) => match arg.value.parse::<i64>() {
+                Ok(n) => (n & 0xff) as i32,
+                Err(_) => {
+                    shell.write_error(&format!("exit: {}: numeric argument required\n", sanitize_for_display(&arg.value)));
+                    2
+                }
+            },
+        };
+        shell.request_exit(code);
+        code
+    }
+}
+
+pub struct BreakCommand;
+impl Command for BreakCommand {
+    fn name(&self) -> &str { "break" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let levels = match args.first() {
+            None => 1,
+            Some(arg) => match arg.value.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                Ok(_) => {
+                    shell.write_error(&format!("break: {}: loop count out of range\n", sanitize_for_display(&arg.value)));
+                    return 1;
+                }
+                Err(_) => {
+                    shell.write_error(&format!("break: {}: numeric argument required\n", sanitize_for_display(&arg.value)));
+                    return 1;
+                }
+            },
+        };
+        if !shell.request_break(levels) {
+            shell.write_error("break: only meaningful in a `select' loop\n");
+        }
+        0
+    }
+}
+
+pub struct EchoCommand;
+impl Command for EchoCommand {
+    fn name(&self) -> &str { "echo" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // Leading words made only of `n`, `e` and `E` after a dash are
+        // options, as in bash: `-n` drops the newline, `-e` decodes escapes
+        // and `-E` stops decoding them. Anything else starts the text.
+        let mut newline = true;
+        let mut escapes = false;
+        let mut words = args.iter().map(|a| a.value.as_str()).peekable();
+        while let Some(flags) = words.peek().and_then(|word| word.strip_prefix('-')) {
+            if flags.is_empty() || !flags.chars().all(|c| matches!(c, 'n' | 'e' | 'E')) {
+                break;
+            }
+            for flag in flags.chars() {
+                match flag {
+                    'n' => newline = false,
+                    'e' => escapes = true,
+                    _ => escapes = false,
+                }
+            }
+            words.next();
+        }
+        let words: Vec<&str> = words.collect();
+        CommandOutput::write(shell, &render_echo(&words, escapes, newline), "", redirection);
+        0
+    }
+}
+
+/// What `echo` prints for `words`: the words joined by spaces, with escapes
+/// decoded if `escapes`, and a newline if `newline`. A `\c` escape ends the
+/// output there, newline included.
+pub fn render_echo(words: &[&str], escapes: bool, newline: bool) -> String {
+    let mut output = String::new();
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            output.push(' ');
+        }
+        if !escapes {
+            output.push_str(word);
+            continue;
+        }
+        let (decoded, more) = printf::echo_escapes(word);
+        output.push_str(&decoded);
+        if !more {
+            return output;
+        }
+    }
+    if newline {
+        output.push('\n');
+    }
+    output
+}
+
+pub struct PrintfCommand;
+impl Command for PrintfCommand {
+    fn name(&self) -> &str { "printf" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let Some((format, rest)) = args.split_first() else {
+            shell.write_error("printf: usage: printf format [arguments]\n");
+            return 2;
+        };
+        let rest: Vec<String> = rest.iter().map(|a| a.value.clone()).collect();
+        let formatted = printf::format_printf(&format.value, &rest);
+        let stderr: String = formatted
+            .errors
+            .iter()
+            .map(|arg| format!("printf: {}: invalid number\n", sanitize_for_display(arg)))
+            .collect();
+        CommandOutput::write(shell, &formatted.output, &stderr, redirection);
+        if formatted.errors.is_empty() { 0 } else { 1 }
+    }
+}
+
+pub struct TypeCommand;
+impl Command for TypeCommand {
+    fn name(&self) -> &str { "type" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // `-t` prints just the kind of each name: alias, function, builtin or
+        // file. `--json` prints a JSON array with an object for each name,
+        // giving its kind (`null` if not found) and, for a file, its path.
+        let format = args.first().map(|a| a.value.as_str()).filter(|flag| matches!(*flag, "-t" | "--json"));
+        let names = if format.is_some() { &args[1..] } else { args };
+        // Every name is answered from one snapshot of PATH.
+        shell.check_path_dirs();
+        shell.path_index.borrow_mut().refresh();
+        let mut stdout = String::new();
+        let mut objects = Vec::new();
+        let mut status = 0;
+        for arg in names {
+            let name = &arg.value;
+            let shown = sanitize_for_display(name);
+            let alias = shell.aliases.borrow().get(name).cloned();
+            let found = if let Some(value) = alias {
+                Some(("alias", format!("{} is aliased to `{}'", shown, sanitize_for_display(&value)), None))
+            } else if shell.function(name).is_some() {
+                Some(("function", format!("{} is a function", shown), None))
+            } else if shell.is_builtin(name) {
+                Some(("builtin", format!("{} is a shell builtin", shown), None))
+            } else if let Some(path) = shell.hashed(name) {
+                let description = format!("{} is hashed ({})", shown, sanitize_for_display(&path.display().to_string()));
+                Some(("file", description, Some(path)))
+            } else {
+                shell.find_indexed_executable(name).map(|path| {
+                    ("file", format!("{} is {}", shown, sanitize_for_display(&path.display().to_string())), Some(path))
+                })
+            };
+            if found.is_none() {
+                status = 1;
+            }
+            match (format, found) {
+                (Some("--json"), found) => {
+                    let mut object = format!("{{\"name\":{}", json_string(name));
+                    match &found {
+                        Some((kind, _, path)) => {
+                            object.push_str(&format!(",\"kind\":{}", json_string(kind)));
+                            if let Some(path) = path {
+                                object.push_str(&format!(",\"path\":{}", json_string(&path.display().to_string())));
+                            }
+                        }
+                        None => object.push_str(",\"kind\":null"),
+                    }
+                    object.push('}');
+                    objects.push(object);
+                }
+                (Some(_), Some((kind, _, _))) => {
+                    stdout.push_str(kind);
+                    stdout.push('\n');
+                }
+                (None, Some((_, description, _))) => {
+                    stdout.push_str(&description);
+                    stdout.push('\n');
+                }
+                // `type -t` prints nothing for an unknown name.
+                (Some(_), None) => {}
+                (None, None) => stdout.push_str(&format!("{}: not found\n", shown)),
+            }
+        }
+        if format == Some("--json") {
+            stdout = format!("[{}]\n", objects.join(","));
+        }
+        CommandOutput::write(shell, &stdout, "", redirection);
+        status
+    }
+}
+
+// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `:`, `true` and `false`, which do nothing but return a status. Being
+/// builtins, they cost no process, which matters in loops and scripts.
+pub struct StatusCommand {
+    name: &'static str,
+    status: i32,
+}
+
+impl StatusCommand {
+    pub fn new(name: &'static str, status: i32) -> Self {
+        StatusCommand { name, status }
+    }
+}
+
+impl Command for StatusCommand {
+    fn name(&self) -> &str { self.name }
+    fn execute(&self, _args: &[Argument], _redirection: Option<&dyn Redirection>, _shell: &Shell) -> i32 {
+        self.status
+    }
+}
+
+pub struct PwdCommand;
+impl Command for PwdCommand {
+    fn name(&self) -> &str { "pwd" }
+    fn execute(&self, _args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        CommandOutput::write(shell, &(shell.cwd().display().to_string() + "\n"), "", redirection);
+        0
+    }
+}
+
+pub struct CdCommand;
+impl Command for CdCommand {
+    fn name(&self) -> &str { "cd" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        if args.len() > 1 {
+            shell.write_error("cd: too many arguments\n");
+            return 1;
+        }
+        let previous = args.first().is_some_and(|a| a.value == "-");
+        let target_dir = if args.is_empty() || args[0].value == "~" {
+            shell.var("HOME").unwrap_or_default()
+        } else if previous {
+            match shell.var("OLDPWD") {
+                Some(dir) => dir,
+                None => {
+                    shell.write_error("cd: OLDPWD not set\n");
+                    return 1;
+                }
+            }
+        } else {
+            args[0].value.clone()
+        };
+        let cwd = shell.cwd();
+        let cdpath = shell.var("CDPATH");
+        let resolved = resolve_cd_target(&target_dir, &cwd, cdpath.as_deref());
+        let Some((resolved, dir)) = resolved.and_then(|r| paths::normalize_dir(&r).map(|dir| (r, dir))) else {
+            shell.write_error(&format!("cd: {}: No such file or directory\n", sanitize_for_display(&target_dir)));
+            return 1;
+        };
+        shell.set_var("OLDPWD", &cwd.display().to_string());
+        shell.set_cwd(dir.clone());
+        shell.update_dir_env();
+        if cd_prints_dir(previous, &resolved, &cwd, &target_dir) {
+            CommandOutput::write(shell, &format!("{}\n", dir.display()), "", redirection);
+        }
+        0
+    }
+}
+
+/// Whether `cd` prints the directory it changed to, as bash does when the
+/// argument alone does not say where it went: after `cd -`, and when a
+/// CDPATH entry other than the current directory supplied the match.
+pub fn cd_prints_dir(previous: bool, resolved: &Path, cwd: &Path, target: &str) -> bool {
+    previous || resolved != cwd.join(target)
+}
+
+/// Directories `cd` searches for `target`, in order. Absolute targets, ones
+/// starting with `.` or `..`, and on Windows drive-qualified ones resolve only
+/// against the current directory;
+/// anything else tries each CDPATH entry (an empty entry meaning the current
+/// directory) before falling back to the current directory.
+pub fn cd_search_roots(target: &str, cwd: &Path, cdpath: Option<&str>) -> Vec<PathBuf> {
+    let explicit = paths::is_explicit(target);
+    let mut roots = Vec::new();
+    if let Some(cdpath) = cdpath.filter(|_| !explicit) {
+        let splitter = if cfg!(windows) { ';' } else { ':' };
+        for entry in cdpath.split(splitter) {
+            let root = if entry.is_empty() { cwd.to_path_buf() } else { cwd.join(entry) };
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+    }
+    if !roots.iter().any(|r| r == cwd) {
+        roots.push(cwd.to_path_buf());
+    }
+    roots
+}
+
+pub fn resolve_cd_target(target: &str, cwd: &Path, cdpath: Option<&str>) -> Option<PathBuf> {
+    cd_search_roots(target, cwd, cdpath)
+        .into_iter()
+        .map(|root| root.join(target))
+        .find(|dir| dir.is_dir())
+}
+
+pub struct EnableCommand;
+impl Command for EnableCommand {
+    fn name(&self) -> &str { "enable" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let mut all = false;
+        let mut disable = false;
+        let mut names = Vec::new();
+        for arg in args {
+            match arg.value.as_str() {
+                "-a" => all = true,
+                "-n" => disable = true,
+                "-an" | "-na" => {
+                    all = true;
+                    disable = true;
+                }
+                _ => names.push(arg.value.as_str()),
+            }
+        }
+
+        if names.is_empty() {
+            // `enable` lists enabled builtins, `enable -n` disabled ones and
+            // `enable -a` all of them, each in a form that can be re-entered.
+            let mut stdout = String::new();
+            for builtin in &shell.builtins {
+                let enabled = builtin.enabled.get();
+                if all || enabled != disable {
+                    let flag = if enabled { "" } else { "-n " };
+                    stdout.push_str(&format!("enable {}{}\n", flag, builtin.command.name()));
+                }
+            }
+            CommandOutput::write(shell, &stdout, "", redirection);
+            return 0;
+        }
+
+        let mut status = 0;
+        for name in names {
+            match shell.builtins.iter().find(|b| b.command.name() == name) {
+                Some(builtin) => {
+                    builtin.enabled.set(!disable);
+                    shell.update_command_index();
+                }
+                None => {
+                    shell.write_error(&format!("enable: {}: not a shell builtin\n", sanitize_for_display(name)));
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+}
+
+pub struct AliasCommand;
+impl Command for AliasCommand {
+    fn name(&self) -> &str { "alias" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let names: Vec<&str> = args.iter().map(|a| a.value.as_str()).filter(|&a| a != "-p").collect();
+        let mut stdout = String::new();
+        let mut status = 0;
+        if names.is_empty() {
+            for (name, value) in shell.aliases.borrow().iter() {
+                stdout.push_str(&format!("alias {}={}\n", name, single_quote(value)));
+            }
+        }
+        for arg in names {
+            match arg.split_once('=') {
+                Some((name, value)) => {
+                    shell.aliases.borrow_mut().insert(name.to_string(), value.to_string());
+                    shell.update_command_index();
+                }
+                None => match shell.aliases.borrow().get(arg) {
+                    Some(value) => stdout.push_str(&format!("alias {}={}\n", arg, single_quote(value))),
+                    None => {
+                        shell.write_error(&format!("alias: {}: not found\n", sanitize_for_display(arg)));
+                        status = 1;
+                    }
+                },
+            }
+        }
+        CommandOutput::write(shell, &stdout, "", redirection);
+        status
+    }
+}
+
+pub struct UnaliasCommand;
+impl Command for UnaliasCommand {
+    fn name(&self) -> &str { "unalias" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // As in bash, names given alongside `-a` are ignored.
+        if args.iter().any(|a| a.value == "-a") {
+            shell.aliases.borrow_mut().clear();
+            shell.update_command_index();
+            return 0;
+        }
+        if args.is_empty() {
+            shell.write_error("unalias: usage: unalias [-a] name [name ...]\n");
+            return 2;
+        }
+        let mut status = 0;
+        for arg in args {
+            if shell.aliases.borrow_mut().remove(&arg.value).is_none() {
+                shell.write_error(&format!("unalias: {}: not found\n", sanitize_for_display(&arg.value)));
+                status = 1;
+            }
+        }
+        shell.update_command_index();
+        status
+    }
+}
+
+/// Quotes `value` so the shell reads it back unchanged.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Double-quotes `value` so the shell reads it back unchanged.
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The `declare` command that recreates a variable, as `declare -p` prints it.
+fn declaration(name: &str, value: &str, exported: bool) -> String {
+    format!("declare {} {}={}\n", if exported { "-x" } else { "--" }, name, double_quote(value))
+}
+
+pub struct DeclareCommand;
+impl DeclareCommand {
+    const USAGE: &'static str = "declare: usage: declare [-px] [name[=value] ...]\n";
+}
+
+impl Command for DeclareCommand {
+    fn name(&self) -> &str { "declare" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let (mut print, mut export) = (false, false);
+        let mut names = args;
+        while let Some(flags) = names.first().map(|a| a.value.as_str()).filter(|a| a.starts_with('-') && a.len() > 1) {
+            names = &names[1..];
+            if flags == "--" {
+                break;
+            }
+            for flag in flags[1..].chars() {
+                match flag {
+                    'p' => print = true,
+                    'x' => export = true,
+                    _ => {
+                        shell.write_error(&format!("declare: -{}: invalid option\n{}", sanitize_for_display(&flag.to_string()), Self::USAGE));
+                        return 2;
+                    }
+                }
+            }
+        }
+
+        if print || names.is_empty() {
+            let mut status = 0;
+            let vars = shell.vars.borrow();
+            let exported = shell.exported.borrow();
+            let mut stdout = String::new();
+            if names.is_empty() {
+                // Names the environment allows but the shell cannot assign
+                // are left out, so the output can be sourced.
+                let mut all: Vec<_> = vars.iter().filter(|(name, _)| expand::is_name(name) && (!export || exported.contains(*name))).collect();
+                all.sort();
+                for (name, value) in all {
+                    stdout.push_str(&declaration(name, value, exported.contains(name)));
+                }
+            }
+            for arg in names {
+                match vars.get(&arg.value) {
+                    Some(value) => stdout.push_str(&declaration(&arg.value, value, exported.contains(&arg.value))),
+                    None => {
+                        shell.write_error(&format!("declare: {}: not found\n", sanitize_for_display(&arg.value)));
+                        status = 1;
+                    }
+                }
+            }
+            CommandOutput::write(shell, &stdout, "", redirection);
+            return status;
+        }
+
+        assign("declare", names, export, shell)
+    }
+}
+
+// Sets each `NAME=value` in `args` and, if `export`, marks each NAME
+// exported, whether or not it is set yet. Used by `declare` and `export`,
+// named by `command` in errors.
+fn assign(command: &str, args: &[Argument], export: bool, shell: &Shell) -> i32 {
+    let mut status = 0;
+    for arg in args {
+        let (name, value) = match arg.value.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.value.as_str(), None),
+        };
+        if !expand::is_name(name) {
+            shell.write_error(&format!("{}: `{}': not a valid identifier\n", command, sanitize_for_display(&arg.value)));
+            status = 1;
+            continue;
+        }
+        if let Some(value) = value {
+            shell.set_var(name, value);
+        }
+        if export {
+            shell.exported.borrow_mut().insert(name.to_string());
+        }
+    }
+    status
+}
+
+pub struct ExportCommand;
+impl Command for ExportCommand {
+    fn name(&self) -> &str { "export" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // Plain `export` lists `NAME=value`; `-p` lists `declare -x` lines
+        // that can be sourced back.
+        let print = args.first().is_some_and(|a| a.value == "-p");
+        if print || args.is_empty() {
+            let exported = shell.exported_vars();
+            let exported = exported.iter().filter(|(name, _)| expand::is_name(name));
+            let stdout: String = if print {
+                exported.map(|(name, value)| declaration(name, value, true)).collect()
+            } else {
+                exported.map(|(name, value)| format!("{}={}\n", name, value)).collect()
+            };
+            CommandOutput::write(shell, &stdout, "", redirection);
+            return 0;
+        }
+        let names = if args[0].value == "--" { &args[1..] } else { args };
+        assign("export", names, true, shell)
+    }
+}
+
+pub struct UnsetCommand;
+impl Command for UnsetCommand {
+    fn name(&self) -> &str { "unset" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // Names that are not set are skipped without complaint.
+        for arg in args {
+            shell.vars.borrow_mut().remove(&arg.value);
+            shell.exported.borrow_mut().remove(&arg.value);
+        }
+        0
+    }
+}
+
+pub struct SetCommand;
+impl Command for SetCommand {
+    fn name(&self) -> &str { "set" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let vi = shell.edit_settings.borrow().editing_mode == EditMode::Vi;
+        let options = [
+            ("autocd", shell.autocd.get()),
+            ("complete-options", shell.edit_settings.borrow().complete_options),
+            ("emacs", !vi),
+            ("lithist", shell.lithist.get()),
+            ("times", shell.time_commands.get()),
+            ("vi", vi),
+        ];
+        match args {
+            [flag] if flag.value == "-o" => {
+                let on_off = |on: bool| if on { "on" } else { "off" };
+                let stdout: String = options.iter().map(|(name, on)| format!("{:<15}\t{}\n", name, on_off(*on))).collect();
+                CommandOutput::write(shell, &stdout, "", redirection);
+                0
+            }
+            [flag] if flag.value == "+o" => {
+                let sign = |on: bool| if on { '-' } else { '+' };
+                let stdout: String = options.iter().map(|(name, on)| format!("set {}o {}\n", sign(*on), name)).collect();
+                CommandOutput::write(shell, &stdout, "", redirection);
+                0
+            }
+            [flag, option] if flag.value == "-o" || flag.value == "+o" => {
+                let enable = flag.value == "-o";
+                // With only two editing modes, turning one off selects the other.
+                let mode = match (option.value.as_str(), enable) {
+                    ("vi", true) | ("emacs", false) => EditMode::Vi,
+                    ("emacs", true) | ("vi", false) => EditMode::Emacs,
+                    ("times", _) => {
+                        shell.time_commands.set(enable);
+                        return 0;
+                    }
+                    ("autocd", _) => {
+                        shell.autocd.set(enable);
+                        return 0;
+                    }
+                    ("lithist", _) => {
+                        shell.lithist.set(enable);
+                        return 0;
+                    }
+                    ("complete-options", _) => {
+                        shell.edit_settings.borrow_mut().complete_options = enable;
+                        return 0;
+                    }
+                    _ => {
+                        shell.write_error(&format!("set: {}: invalid option name\n", sanitize_for_display(&option.value)));
+                        return 1;
+                    }
+                };
+                shell.edit_settings.borrow_mut().editing_mode = mode;
+                0
+            }
+            _ => {
+                shell.write_error("set: usage: set [-o option] [+o option]\n");
+                2
+            }
+        }
+    }
+}
+
+pub struct HelpCommand;
+impl Command for HelpCommand {
+    fn name(&self) -> &str { "help" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // Without a pattern, every builtin's synopsis; with one, the synopsis
+        // and description of each builtin whose name starts with it.
+        if args.is_empty() {
+            let stdout: String = shell.builtins.iter().map(|builtin| format!("{}\n", builtin.synopsis)).collect();
+            CommandOutput::write(shell, &stdout, "", redirection);
+            return 0;
+        }
+        let mut stdout = String::new();
+        let mut status = 0;
+        for arg in args {
+            let matching: Vec<_> = shell.builtins.iter().filter(|builtin| builtin.command.name().starts_with(&arg.value)).collect();
+            if matching.is_empty() {
+                shell.write_error(&format!("help: no help topics match `{}'\n", sanitize_for_display(&arg.value)));
+                status = 1;
+            }
+            for builtin in matching {
+                stdout.push_str(&format!("{}: {}\n    {}\n", builtin.command.name(), builtin.synopsis, builtin.description));
+            }
+        }
+        CommandOutput::write(shell, &stdout, "", redirection);
+        status
+    }
+}
+
+pub struct BindCommand;
+impl Command for BindCommand {
+    fn name(&self) -> &str { "bind" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // `bind -v` lists the editing settings; any other argument is an
+        // inputrc line such as `set editing-mode vi`.
+        let mut status = 0;
+        for arg in args {
+            if arg.value == "-v" {
+                let stdout = shell.edit_settings.borrow().describe();
+                CommandOutput::write(shell, &stdout, "", redirection);
+            } else if arg.value.starts_with('-') {
+                shell.write_error(&format!("bind: {}: invalid option\n", sanitize_for_display(&arg.value)));
+                return 2;
+            } else if let Err(e) = shell.edit_settings.borrow_mut().apply_line(&arg.value) {
+                shell.write_error(&format!("bind: {}\n", sanitize_for_display(&e)));
+                status = 1;
+            }
+        }
+        status
+    }
+}
+
+pub struct HashCommand;
+impl Command for HashCommand {
+    fn name(&self) -> &str { "hash" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        if args.is_empty() {
+            let hashed = shell.hashed.borrow();
+            let stdout = if hashed.is_empty() {
+                "hash: hash table empty\n".to_string()
+            } else {
+                let mut listing = "hits\tcommand\n".to_string();
+                for entry in hashed.values() {
+                    listing.push_str(&format!("{:>4}\t{}\n", entry.hits, sanitize_for_display(&entry.path.display().to_string())));
+                }
+                listing
+            };
+            drop(hashed);
+            CommandOutput::write(shell, &stdout, "", redirection);
+            return 0;
+        }
+        let mut status = 0;
+        for arg in args {
+            if arg.value == "-r" {
+                shell.hashed.borrow_mut().clear();
+                shell.path_index.borrow_mut().recheck();
+                shell.check_path_dirs();
+                continue;
+            }
+            // Hashing a name ahead of time does not count as a use.
+            match shell.find_executable_in_path(&arg.value) {
+                Some(path) => {
+                    shell.hashed.borrow_mut().insert(arg.value.clone(), HashedCommand { path, hits: 0 });
+                }
+                None => {
+                    shell.write_error(&format!("hash: {}: not found\n", sanitize_for_display(&arg.value)));
+                    status = 1;
+                }
+            }
+        }
+        status
+    }
+}
+
+/// `ulimit [-SHa] [-cdfnstuv] [LIMIT]`. Without an option it reports the
+/// file size limit; without `-S` or `-H`, setting changes both values and
+/// showing reports the soft one.
+pub struct UlimitCommand;
+
+impl UlimitCommand {
+    const USAGE: &'static str = "ulimit: usage: ulimit [-SHa] [-cdfnstuv] [limit]\n";
+
+    fn show(limit: &Limit, which: Which, labelled: bool) -> Result<String, String> {
+        let (soft, hard) = limits::get(limit).map_err(|e| format!("{}: cannot get limit: {}", limit.description, e))?;
+        let value = match if which == Which::Hard { hard } else { soft } {
+            Some(value) => value.to_string(),
+            None => "unlimited".to_string(),
+        };
+        if !labelled {
+            return Ok(format!("{}\n", value));
+        }
+        let option = if limit.unit.is_empty() {
+            format!("(-{})", limit.option)
+        } else {
+            format!("({}, -{})", limit.unit, limit.option)
+        };
+        Ok(format!("{:<24}{:>12} {}\n", limit.description, option, value))
+    }
+}
+
+impl Command for UlimitCommand {
+    fn name(&self) -> &str { "ulimit" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let mut which = Which::Both;
+        let mut all = false;
+        let mut selected: Vec<&Limit> = Vec::new();
+        let mut value = None;
+        for arg in args {
+            match arg.value.strip_prefix('-').filter(|flags| !flags.is_empty() && value.is_none()) {
+                Some(flags) => {
+                    for flag in flags.chars() {
+                        match flag {
+                            'S' => which = Which::Soft,
+                            'H' => which = Which::Hard,
+                            'a' => all = true,
+                            _ => match limits::find(flag) {
+                                Some(limit) => selected.push(limit),
+                                None => {
+                                    shell.write_error(&format!("ulimit: -{}: invalid option\n{}", sanitize_for_display(&flag.to_string()), Self::USAGE));
+                                    return 2;
+                                }
+                            },
+                        }
+                    }
+                }
+                None if value.is_none() => value = Some(arg.value.as_str()),
+                None => {
+                    shell.write_error(&format!("ulimit: {}: too many arguments\n", sanitize_for_display(&arg.value)));
+                    return 2;
+                }
+            }
+        }
+        if all {
+            selected = limits::LIMITS.iter().collect();
+        } else if selected.is_empty() {
+            selected.extend(limits::find('f'));
+        }
+
+        if let Some(value) = value {
+            let parsed = match value {
+                "unlimited" => None,
+                _ => match value.parse::<u64>() {
+                    Ok(n) => Some(n),
+                    Err(_) => {
+                        shell.write_error(&format!("ulimit: {}: invalid number\n", sanitize_for_display(value)));
+                        return 1;
+                    }
+                },
+            };
+            let mut status = 0;
+            for limit in selected {
+                if let Err(e) = limits::set(limit, which, parsed) {
+                    shell.write_error(&format!("ulimit: {}: cannot modify limit: {}\n", limit.description, e));
+                    status = 1;
+                }
+            }
+            return status;
+        }
+
+        let labelled = selected.len() > 1;
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        for limit in selected {
+            match Self::show(limit, which, labelled) {
+                Ok(line) => stdout.push_str(&line),
+                Err(e) => stderr.push_str(&format!("ulimit: {}\n", e)),
+            }
+        }
+        CommandOutput::write(shell, &stdout, &stderr, redirection);
+        if stderr.is_empty() { 0 } else { 1 }
+    }
+}
+
+/// `source` and its POSIX spelling `.`.
+pub struct SourceCommand {
+    name: &'static str,
+}
+
+impl SourceCommand {
+    pub fn new(name: &'static str) -> Self {
+        SourceCommand { name }
+    }
+}
+
+impl Command for SourceCommand {
+    fn name(&self) -> &str { self.name }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let Some(file) = args.first() else {
+            shell.write_error(&format!("{}: filename argument required\n", self.name));
+            return 2;
+        };
+        let saved_args = (args.len() > 1).then(|| shell.positional.replace(args[1..].iter().map(|a| a.value.clone()).collect()));
+        let sourced = shell.source_file(Path::new(&file.value));
+        if let Some(saved_args) = saved_args {
+            shell.positional.replace(saved_args);
+        }
+        match sourced {
+            Ok(status) => status,
+            Err(e) => {
+                shell.write_error(&format!("{}: {}: {}\n", self.name, sanitize_for_display(&file.value), e));
+                1
+            }
+        }
+    }
+}
+
+pub struct EnvAllowCommand;
+impl Command for EnvAllowCommand {
+    fn name(&self) -> &str { "envallow" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // `envallow [FILE]` approves a `.myshellenv` (by default the one in
+        // the working directory) as it is now; `envallow -d [FILE]` revokes it.
+        let deny = args.first().is_some_and(|a| a.value == "-d");
+        let target = args.get(usize::from(deny)).map_or(direnv::ENV_FILE, |a| a.value.as_str());
+        let requested = shell.cwd().join(target);
+        let file = requested
+            .parent()
+            .and_then(paths::normalize_dir)
+            .zip(requested.file_name())
+            .map(|(dir, name)| dir.join(name))
+            .filter(|file| file.is_file());
+        let Some(file) = file else {
+            shell.write_error(&format!("envallow: {}: No such file or directory\n", sanitize_for_display(target)));
+            return 1;
+        };
+        let Some(list) = direnv::allow_list_path(shell) else {
+            shell.write_error("envallow: HOME is not set\n");
+            return 1;
+        };
+        let result = if deny { direnv::deny(&list, &file) } else { direnv::allow(&list, &file) };
+        if let Err(e) = result {
+            shell.write_error(&format!("envallow: {}: {}\n", sanitize_for_display(&list.display().to_string()), e));
+            return 1;
+        }
+        // A file for the current directory takes effect at once.
+        if file.parent() == Some(shell.cwd().as_path()) {
+            if shell.dir_env_dir() == Some(shell.cwd()) {
+                shell.unload_dir_env();
+            }
+            if !deny {
+                shell.update_dir_env();
+            }
+        }
+        0
+    }
+}
+
+/// `history --porcelain` is a stable interface for scripts: one line per
+/// entry, `index<TAB>epoch<TAB>command`, with no padding and no time
+/// formatting. Backslashes, tabs and newlines in the command are written
+/// `\\`, `\t` and `\n`, so an entry never spans lines.
+pub struct HistoryCommand;
+impl Command for HistoryCommand {
+    fn name(&self) -> &str { "history" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        match args {
+            [] | [_] if args.first().is_none_or(|count| !count.value.starts_with('-')) => {
+                // `history N` lists the last N entries, numbered as in the
+                // full list.
+                let history = shell.history.borrow();
+                let entries = history.entries();
+                let skip = match args.first() {
+                    Some(count) => match count.value.parse::<usize>() {
+                        Ok(count) => entries.len().saturating_sub(count),
+                        Err(_) => {
+                            shell.write_error(&format!("history: {}: numeric argument required\n", sanitize_for_display(&count.value)));
+                            return 1;
+                        }
+                    },
+                    None => 0,
+                };
+                // `HISTTIMEFORMAT` puts each entry's time before it.
+                let time_format = shell.var("HISTTIMEFORMAT");
+                let stdout: String = entries
+                    .iter()
+                    .enumerate()
+                    .skip(skip)
+                    .map(|(i, entry)| {
+                        let time = time_format.as_deref().map(|format| history::format_time(entry.time, format)).unwrap_or_default();
+                        format!("{:>5}  {}{}\n", i + 1, time, entry.line)
+                    })
+                    .collect();
+                CommandOutput::write(shell, &stdout, "", redirection);
+                0
+            }
+            [flag] if flag.value == "--porcelain" => {
+                let stdout: String = shell
+                    .history
+                    .borrow()
+                    .entries()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| format!("{}\t{}\t{}\n", i + 1, entry.time, porcelain_escape(&entry.line)))
+                    .collect();
+                CommandOutput::write(shell, &stdout, "", redirection);
+                0
+            }
+            [flag, file @ ..] if flag.value == "-r" && file.len() <= 1 => {
+                let path = match file {
+                    [file] => shell.cwd().join(&file.value),
+                    _ => match shell.history_file() {
+                        Some(path) => path,
+                        None => return 0,
+                    },
+                };
+                match std::fs::read(&path) {
+                    Ok(bytes) => {
+                        shell.history.borrow_mut().read(&bytes);
+                        0
+                    }
+                    Err(e) => {
+                        shell.write_error(&format!("history: {}: {}\n", sanitize_for_display(&path.display().to_string()), e));
+                        1
+                    }
+                }
+            }
+            [flag, spec] if flag.value == "-d" => match shell.history.borrow_mut().delete(&spec.value) {
+                Ok(()) => 0,
+                Err(e) => {
+                    shell.write_error(&format!("history: {}\n", sanitize_for_display(&e)));
+                    1
+                }
+            },
+            [flag] if flag.value == "-d" => {
+                shell.write_error("history: -d: option requires an argument\n");
+                2
+            }
+            _ => {
+                shell.write_error("history: usage: history [n] [--porcelain] [-d offset] [-r [filename]]\n");
+                2
+            }
+        }
+    }
+}
+
+// Escapes what would break a tab-separated line.
+fn porcelain_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+pub struct CompgenCommand;
+impl CompgenCommand {
+    const USAGE: &'static str = "compgen: usage: compgen [-bcdf] [word]\n";
+}
+impl Command for CompgenCommand {
+    fn name(&self) -> &str { "compgen" }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let (action, word) = match args {
+            [action] => (action.value.as_str(), ""),
+            [action, word] => (action.value.as_str(), word.value.as_str()),
+            _ => {
+                shell.write_error(Self::USAGE);
+                return 2;
+            }
+        };
+        shell.check_path_dirs();
+        let settings = *shell.edit_settings.borrow();
+        let index = shell.command_index.lock().unwrap();
+        let candidates = match action {
+            "-b" => index.builtins().iter().filter(|name| settings.completes(name, word)).cloned().collect(),
+            "-c" => crate::complete::command_completions(word, index.names(), index.path_dirs(), &settings),
+            "-f" => crate::complete::path_completions(word, &shell.cwd(), false),
+            "-d" => crate::complete::path_completions(word, &shell.cwd(), true),
+            _ => {
+                shell.write_error(&format!("compgen: {}: invalid option\n", sanitize_for_display(action)));
+                shell.write_error(Self::USAGE);
+                return 2;
+            }
+        };
+        let stdout: String = candidates.iter().map(|name| format!("{}\n", name)).collect();
+        CommandOutput::write(shell, &stdout, "", redirection);
+        if candidates.is_empty() { 1 } else { 0 }
+    }
+}
+
+/// `read [-rs] [-n nchars] [-t timeout] [name ...]`. Without `-r`, a
+/// backslash escapes the character after it. `-n` stops after that many
+/// characters, or at a newline, without waiting for Enter at a terminal; `-s`
+/// does not echo what is typed there. `-t` gives up after that many seconds,
+/// which may be fractional, leaving the variables empty.
+pub struct ReadCommand;
+
+impl ReadCommand {
+    const USAGE: &'static str = "read: usage: read [-rs] [-n nchars] [-t timeout] [name ...]\n";
+    /// The status after a timeout, as if killed by SIGALRM.
+    const TIMED_OUT: i32 = 128 + 14;
+}
+
+impl Command for ReadCommand {
+    fn name(&self) -> &str { "read" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        let (mut raw, mut silent, mut count, mut timeout) = (false, false, None, None);
+        let mut rest = args;
+        while let Some(flags) = rest.first().map(|a| a.value.as_str()).filter(|a| a.starts_with('-') && a.len() > 1) {
+            rest = &rest[1..];
+            for (i, flag) in flags[1..].char_indices() {
+                match flag {
+                    'r' => raw = true,
+                    's' => silent = true,
+                    'n' | 't' => {
+                        // The value is the rest of the word, or the next word.
+                        let value = match &flags[i + 2..] {
+                            "" => rest.first().map(|a| a.value.as_str()),
+                            attached => Some(attached),
+                        };
+                        let Some(value) = value else {
+                            shell.write_error(&format!("read: -{}: option requires an argument\n{}", flag, Self::USAGE));
+                            return 2;
+                        };
+                        if flags[i + 2..].is_empty() {
+                            rest = &rest[1..];
+                        }
+                        if flag == 'n' {
+                            let Ok(n) = value.parse::<usize>() else {
+                                shell.write_error(&format!("read: {}: invalid number\n", sanitize_for_display(value)));
+                                return 1;
+                            };
+                            count = Some(n);
+                        } else {
+                            let Some(seconds) = value.parse::<f64>().ok().and_then(|s| Duration::try_from_secs_f64(s).ok()) else {
+                                shell.write_error(&format!("read: {}: invalid timeout specification\n", sanitize_for_display(value)));
+                                return 1;
+                            };
+                            timeout = Some(seconds);
+                        }
+                        break;
+                    }
+                    _ => {
+                        shell.write_error(&format!("read: -{}: invalid option\n{}", sanitize_for_display(&flag.to_string()), Self::USAGE));
+                        return 2;
+                    }
+                }
+            }
+        }
+        let names: Vec<&str> = rest.iter().map(|a| a.value.as_str()).collect();
+        if let Some(bad) = names.iter().find(|name| !expand::is_name(name)) {
+            shell.write_error(&format!("read: `{}': not a valid identifier\n", sanitize_for_display(bad)));
+            return 1;
+        }
+        let (line, complete) = {
+            let _mode = (count.is_some() || silent).then(|| shell.terminal_input_mode(count.is_none(), !silent));
+            // A timeout too long to represent is no timeout.
+            match (timeout.and_then(|timeout| Instant::now().checked_add(timeout)), count) {
+                (Some(deadline), count) => match shell.read_chars_before(count, deadline) {
+                    Ok(read) => read,
+                    Err(e) if e.kind() != std::io::ErrorKind::TimedOut => (String::new(), false),
+                    Err(_) => {
+                        for name in if names.is_empty() { &["REPLY"][..] } else { &names[..] } {
+                            shell.set_var(name, "");
+                        }
+                        return Self::TIMED_OUT;
+                    }
+                },
+                (None, Some(count)) => shell.read_chars(count),
+                (None, None) => match shell.read_line() {
+                    Some(line) => {
+                        let complete = line.ends_with('\n');
+                        (line, complete)
+                    }
+                    None => (String::new(), false),
+                },
+            }
+        };
+        let text = line.trim_end_matches(['\n', '\r']);
+        let text = if raw { text.to_string() } else { unescape(text) };
+
+        // The line is split on whitespace, the last name taking the rest.
+        if names.is_empty() {
+            shell.set_var("REPLY", &text);
+        } else {
+            let mut rest = text.trim();
+            for (i, name) in names.iter().enumerate() {
+                if i + 1 == names.len() {
+                    shell.set_var(name, rest);
+                    break;
+                }
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                shell.set_var(name, &rest[..end]);
+                rest = rest[end..].trim_start();
+            }
+        }
+        // Input that ends early still sets the variables, but fails.
+        if complete { 0 } else { 1 }
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub struct LetCommand;
+impl Command for LetCommand {
+    fn name(&self) -> &str { "let" }
+    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        if args.is_empty() {
+            shell.write_error("let: expression expected\n");
+            return 1;
+        }
+        let exprs: Vec<&str> = args.iter().map(|a| a.value.as_str()).collect();
+        evaluate_arithmetic_command("let", &exprs, shell)
+    }
+}
+
+/// Runs `let`/`(( ))`: evaluates each expression in turn, returning 0 if the
+/// last one is non-zero and 1 if it is zero or evaluation fails.
+pub fn evaluate_arithmetic_command(context: &str, exprs: &[&str], shell: &Shell) -> i32 {
+    let mut last = 0;
+    for expr in exprs {
+        match arith::evaluate(expr, shell) {
+            Ok(value) => last = value,
+            Err(e) => {
+                shell.write_error(&format!("{}: {}\n", context, sanitize_for_display(&e.to_string())));
+                return 1;
+            }
+        }
+    }
+    if last != 0 { 0 } else { 1 }
+}
+
+/// Runs an `(( expr ))` command given the text between the parentheses.
+pub fn run_arithmetic_command(expr: &str, shell: &Shell) -> i32 {
+    match expand::expand_string(expr, shell) {
+        Ok(expr) => evaluate_arithmetic_command("((", &[expr.as_str()], shell),
+        Err(e) => {
+            shell.write_error(&format!("{}\n", e));
+            1
+        }
+    }
+}
+
+/// Runs a `[[ expr ]]` command given the text between the brackets. Returns 0
+/// if the expression is true, 1 if false and 2 on a syntax or expansion error.
+pub fn run_conditional_command(body: &str, shell: &Shell) -> i32 {
+    match CondExpr::parse(body).and_then(|expr| expr.evaluate(shell, &shell.cwd())) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            shell.write_error(&format!("[[: {}\n", sanitize_for_display(&e.to_string())));
+            2
+        }
+    }
+}
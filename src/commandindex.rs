@@ -0,0 +1,80 @@
+// The names that count as commands for completion and `compgen -c`: the
+// enabled builtins, the functions and aliases defined, and the executables
+// in the PATH directories. The shell updates the index wherever one of those
+// changes and the completer reads it through a shared handle, so candidates
+// are never stale. Each change bumps a generation counter, which lets a
+// reader tell that what it showed earlier is out of date.
+
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandIndex {
+    builtins: Vec<String>,
+    functions: Vec<String>,
+    aliases: Vec<String>,
+    path_dirs: Vec<PathBuf>,
+    generation: u64,
+}
+
+impl CommandIndex {
+    pub fn new(builtins: Vec<String>, path_dirs: Vec<PathBuf>) -> Self {
+        CommandIndex { builtins, path_dirs, ..Default::default() }
+    }
+
+    /// Counts the changes made, starting from zero.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn builtins(&self) -> &[String] {
+        &self.builtins
+    }
+
+    pub fn path_dirs(&self) -> &[PathBuf] {
+        &self.path_dirs
+    }
+
+    pub fn set_path_dirs(&mut self, dirs: Vec<PathBuf>) {
+        Self::update(&mut self.path_dirs, dirs, &mut self.generation);
+    }
+
+    pub fn set_builtins(&mut self, names: Vec<String>) {
+        Self::update(&mut self.builtins, names, &mut self.generation);
+    }
+
+    pub fn set_functions(&mut self, names: Vec<String>) {
+        Self::update(&mut self.functions, names, &mut self.generation);
+    }
+
+    pub fn set_aliases(&mut self, names: Vec<String>) {
+        Self::update(&mut self.aliases, names, &mut self.generation);
+    }
+
+    fn update<T: PartialEq>(list: &mut Vec<T>, new: Vec<T>, generation: &mut u64) {
+        if *list != new {
+            *list = new;
+            *generation += 1;
+        }
+    }
+
+    /// Every builtin, alias and function name, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.builtins.iter().chain(&self.aliases).chain(&self.functions)
+    }
+
+    /// What kind of command `name` is, as completion listings describe it, or
+    /// `None` for an executable on PATH. Aliases shadow functions, and
+    /// functions builtins, as when the command runs.
+    pub fn kind(&self, name: &str) -> Option<&'static str> {
+        let has = |names: &[String]| names.iter().any(|n| n == name);
+        if has(&self.aliases) {
+            Some("alias")
+        } else if has(&self.functions) {
+            Some("function")
+        } else if has(&self.builtins) {
+            Some("builtin")
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,380 @@
+// The completion engine behind Tab, `--complete` and `Shell::complete`. It
+// takes a line and a cursor position and returns where the word being
+// completed starts and the candidates for it, each with the text that
+// replaces the word and how a listing shows it. Nothing here knows about
+// rustyline; the line editor's completer and Tab handler only adapt what the
+// engine returns.
+
+use std::collections::HashSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::arghistory::ArgHistory;
+use crate::builtins::cd_search_roots;
+use crate::commandindex::CommandIndex;
+use crate::helpopts::OptionCache;
+use crate::inputrc::EditSettings;
+use crate::paths;
+
+/// One way to complete the word.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// What replaces the word, with a trailing space when the word is done.
+    pub text: String,
+    /// `builtin`, `function` or `alias` for a command the shell defines,
+    /// `None` otherwise.
+    pub kind: Option<&'static str>,
+    /// How a listing shows the candidate.
+    pub display: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompletionResult {
+    /// Where in the line the word being completed starts.
+    pub start: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+impl CompletionResult {
+    /// The candidates' text without trailing spaces, as a listing names them.
+    pub fn names(&self) -> Vec<&str> {
+        self.candidates.iter().map(|candidate| candidate.text.trim_end()).collect()
+    }
+}
+
+/// The state completion reads, behind handles the shell and the line editor
+/// share.
+#[derive(Clone, Default)]
+pub struct Engine {
+    /// The shell's own index, which it keeps current as commands change.
+    pub index: Arc<Mutex<CommandIndex>>,
+    /// Copies the REPL refreshes before each prompt.
+    pub settings: Arc<Mutex<EditSettings>>,
+    pub arg_history: Arc<Mutex<ArgHistory>>,
+    /// The shell's working directory and `CDPATH`, which need not be the
+    /// process's.
+    pub cwd: Arc<Mutex<PathBuf>>,
+    pub cdpath: Arc<Mutex<Option<String>>>,
+    /// The options read from `--help`.
+    pub options: Arc<Mutex<OptionCache>>,
+}
+
+impl Engine {
+    /// Completes in the shell's working directory and with its `CDPATH`.
+    pub fn complete(&self, line: &str, pos: usize) -> CompletionResult {
+        let cwd = self.cwd.lock().unwrap().clone();
+        let cdpath = self.cdpath.lock().unwrap().clone();
+        self.complete_in(line, pos, &cwd, cdpath.as_deref())
+    }
+
+    /// The candidates for the word before `pos` in `line`, with relative
+    /// paths taken from `cwd`. The rules particular to `cd` and `kill` come
+    /// first, then long options, then the arguments used before with the
+    /// command, and otherwise command names. The command is found past any
+    /// prefix such as `sudo` or `env NAME=value`.
+    pub fn complete_in(&self, line: &str, pos: usize, cwd: &Path, cdpath: Option<&str>) -> CompletionResult {
+        let settings = *self.settings.lock().unwrap();
+        let index = self.index.lock().unwrap();
+        let special = command_argument_completions(line, pos, cwd, cdpath)
+            .or_else(|| long_option_completions(line, pos, &settings, &index, &mut self.options.lock().unwrap(), cwd));
+        if let Some((start, candidates)) = special {
+            return CompletionResult { start, candidates };
+        }
+
+        let start = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let word = &line[start..pos];
+        let (names, commands) = match used_argument_completions(line, pos, cwd, &self.arg_history.lock().unwrap(), &settings) {
+            Some(used) => (used, false),
+            None => (command_completions(word, index.names(), index.path_dirs(), &settings), true),
+        };
+        // A word that cannot grow any further is finished with a space.
+        let add_space = names.len() == 1 || find_longest_common_prefix(&names) == word;
+        let candidates = names
+            .into_iter()
+            .map(|name| {
+                let kind = if commands { index.kind(&name) } else { None };
+                Candidate {
+                    display: describe_candidate(&name, kind, &settings),
+                    text: if add_space { format!("{} ", name) } else { name },
+                    kind,
+                }
+            })
+            .collect();
+        CompletionResult { start, candidates }
+    }
+}
+
+/// Completes a `cd` argument against the same roots `cd` searches, so every
+/// candidate is a directory `cd` would enter. Each candidate displays its full
+/// path and replaces the word with the name `cd` resolves; a name present under
+/// several roots is offered once, for the root `cd` would pick. On Windows,
+/// names match case-insensitively and keep the separator style typed.
+pub fn cd_completions(word: &str, cwd: &Path, cdpath: Option<&str>) -> Vec<Candidate> {
+    let (dir_part, prefix) = paths::split_dir_prefix(word);
+    let separator = paths::preferred_separator(word);
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for root in cd_search_roots(word, cwd, cdpath) {
+        let dir = root.join(dir_part);
+        let Ok(entries) = std::fs::read_dir(&dir) else { continue; };
+        let mut names: Vec<String> = entries
+            .flatten()
+            .filter(|e| e.path().is_dir())
+            .filter_map(|e| e.file_name().to_str().map(str::to_string))
+            .filter(|name| paths::name_has_prefix(name, prefix) && (prefix.starts_with('.') || !name.starts_with('.')))
+            .collect();
+        names.sort();
+        for name in names {
+            let replacement = format!("{}{}{}", dir_part, name, separator);
+            if seen.insert(replacement.clone()) {
+                candidates.push(Candidate {
+                    display: format!("{}{}", dir.join(&name).display(), separator),
+                    text: replacement,
+                    kind: None,
+                });
+            }
+        }
+    }
+    candidates
+}
+
+/// The files under `cwd` whose paths start with `word`, or only the
+/// directories if `dirs_only`, sorted and written as `word` would continue.
+/// Hidden names are left out unless `word` names them with a leading dot.
+pub fn path_completions(word: &str, cwd: &Path, dirs_only: bool) -> Vec<String> {
+    let (dir_part, prefix) = paths::split_dir_prefix(word);
+    let Ok(entries) = std::fs::read_dir(cwd.join(dir_part)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|e| !dirs_only || e.path().is_dir())
+        .filter_map(|e| e.file_name().to_str().map(str::to_string))
+        .filter(|name| paths::name_has_prefix(name, prefix) && (prefix.starts_with('.') || !name.starts_with('.')))
+        .collect();
+    names.sort();
+    names.into_iter().map(|name| format!("{}{}", dir_part, name)).collect()
+}
+
+/// Signal names as `kill -NAME` takes them.
+const SIGNAL_NAMES: &[&str] = &[
+    "ABRT", "ALRM", "BUS", "CHLD", "CONT", "FPE", "HUP", "ILL", "INT", "KILL", "PIPE", "QUIT", "SEGV", "STOP", "TERM", "TRAP",
+    "TSTP", "TTIN", "TTOU", "USR1", "USR2", "WINCH",
+];
+
+/// Completions particular to the command being typed: directories for a
+/// `cd` argument and signal names for a `kill` option. `None` leaves the
+/// word to the general rules.
+pub fn command_argument_completions(line: &str, pos: usize, cwd: &Path, cdpath: Option<&str>) -> Option<(usize, Vec<Candidate>)> {
+    if let Some(word) = cd_argument(line, pos) {
+        return Some((pos - word.len(), cd_completions(word, cwd, cdpath)));
+    }
+    let word = kill_signal_argument(line, pos)?;
+    Some((pos - word.len(), signal_completions(word)))
+}
+
+/// Completes a word starting with `-` from the `--help` output of the
+/// external command it is an argument of, when `set -o complete-options` is
+/// on. `None` leaves the word to the general rules, as when the command is a
+/// builtin, function or alias, or offered no matching options.
+pub fn long_option_completions(
+    line: &str,
+    pos: usize,
+    settings: &EditSettings,
+    index: &CommandIndex,
+    cache: &mut OptionCache,
+    cwd: &Path,
+) -> Option<(usize, Vec<Candidate>)> {
+    let start = line[..pos].rfind(' ').map(|i| i + 1)?;
+    let word = &line[start..pos];
+    let command = *command_words(line, start).first()?;
+    if !settings.complete_options || !word.starts_with('-') || index.kind(command).is_some() {
+        return None;
+    }
+    let path = if command.contains(paths::is_separator) {
+        cwd.join(command)
+    } else {
+        find_executable_in(index.path_dirs(), command)?
+    };
+    let candidates: Vec<Candidate> = cache
+        .options(&path)
+        .iter()
+        .filter(|option| option.starts_with(word))
+        .map(|option| Candidate { text: format!("{} ", option), kind: None, display: option.clone() })
+        .collect();
+    (!candidates.is_empty()).then_some((start, candidates))
+}
+
+/// The first executable named `name` in `dirs`.
+pub fn find_executable_in(dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+    dirs.iter()
+        .map(|dir| dir.join(name))
+        .find(|path| std::fs::metadata(path).is_ok_and(|metadata| crate::pathindex::is_executable(&metadata)))
+}
+
+/// Completes `-NAME` for each signal whose name starts with what follows the
+/// dash, in any case.
+pub fn signal_completions(word: &str) -> Vec<Candidate> {
+    let prefix = word.trim_start_matches('-').to_ascii_uppercase();
+    SIGNAL_NAMES
+        .iter()
+        .filter(|name| name.starts_with(&prefix))
+        .map(|name| Candidate { text: format!("-{} ", name), kind: None, display: format!("-{}", name) })
+        .collect()
+}
+
+/// Returns the word under the cursor when it is an option of `kill`.
+fn kill_signal_argument(line: &str, pos: usize) -> Option<&str> {
+    let start = line[..pos].rfind(' ').map(|i| i + 1)?;
+    let word = &line[start..pos];
+    (command_words(line, start).first() == Some(&"kill") && word.starts_with('-')).then_some(word)
+}
+
+/// Returns the word under the cursor when it is the argument of `cd`.
+fn cd_argument(line: &str, pos: usize) -> Option<&str> {
+    let start = line[..pos].rfind(' ').map(|i| i + 1)?;
+    (command_words(line, start) == ["cd"]).then(|| &line[start..pos])
+}
+
+/// Commands that run the command named after them. Completion looks past
+/// them, their options and any `NAME=value` words to the command itself.
+const COMMAND_PREFIXES: &[&str] = &["sudo", "env", "command", "builtin", "time", "nohup", "nice", "xargs"];
+
+// Whether `option` of the prefix command takes the next word as its value.
+fn takes_value(prefix: &str, option: &str) -> bool {
+    let options: &[&str] = match prefix {
+        "sudo" => &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U", "-T"],
+        "env" => &["-u", "-C", "-S"],
+        "nice" => &["-n"],
+        "xargs" => &["-a", "-d", "-E", "-I", "-L", "-n", "-P", "-s"],
+        "time" => &["-f", "-o"],
+        _ => &[],
+    };
+    options.contains(&option)
+}
+
+/// The words before `start`, from the command the word there is an argument
+/// of. Leading assignments and prefix commands such as `sudo -u user` are
+/// left out, so the list is empty when the word is itself a command name.
+pub fn command_words(line: &str, start: usize) -> Vec<&str> {
+    let words: Vec<&str> = line[..start].split_whitespace().collect();
+    let mut i = 0;
+    while let Some(&word) = words.get(i) {
+        i += 1;
+        if word.split_once('=').is_some_and(|(name, _)| crate::expand::is_name(name)) {
+            continue;
+        }
+        if !COMMAND_PREFIXES.contains(&word) {
+            return words[i - 1..].to_vec();
+        }
+        while let Some(option) = words.get(i).filter(|option| option.starts_with('-')) {
+            i += 1;
+            if *option == "--" {
+                break;
+            }
+            if takes_value(word, option) {
+                i += 1;
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// Completes an argument from those used before with the same command, most
+/// recent first. Returns `None` for the command word itself, for a partial
+/// word that begins the name of an existing file, and when nothing was used
+/// before, so other completions apply. An empty word is not taken to name
+/// every file, so `ssh <Tab>` offers the hosts used before.
+pub fn used_argument_completions(line: &str, pos: usize, cwd: &Path, history: &ArgHistory, settings: &EditSettings) -> Option<Vec<String>> {
+    let start = line[..pos].rfind(' ').map(|i| i + 1)?;
+    let command = *command_words(line, start).first()?;
+    let word = &line[start..pos];
+    if !word.is_empty() && names_file(word, cwd) {
+        return None;
+    }
+    let used = history.suggestions(command, |arg| settings.completes(arg, word));
+    (!used.is_empty()).then_some(used)
+}
+
+// Whether some file's path starts with `word`.
+fn names_file(word: &str, cwd: &Path) -> bool {
+    let (dir, name) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let Ok(entries) = std::fs::read_dir(cwd.join(dir)) else {
+        return false;
+    };
+    entries.flatten().any(|entry| entry.file_name().to_str().is_some_and(|n| n.starts_with(name)))
+}
+
+/// Puts command candidates in the order both the candidate list and the
+/// double-Tab listing show them: builtins, functions and aliases first,
+/// alphabetically, then executables, alphabetically ignoring case with ties
+/// broken by case. An executable sharing a name with a builtin is listed once,
+/// as the builtin.
+pub fn order_candidates(mut shell_names: Vec<String>, mut executables: Vec<String>) -> Vec<String> {
+    shell_names.sort();
+    shell_names.dedup();
+    executables.retain(|name| shell_names.binary_search(name).is_err());
+    executables.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b)));
+    executables.dedup();
+    shell_names.append(&mut executables);
+    shell_names
+}
+
+/// The commands that complete `word`: the shell's own names (builtins,
+/// functions and aliases) that match, then the executables in `path_dirs`,
+/// ordered by `order_candidates`. Tab completion and `compgen -c` both use it.
+pub fn command_completions<'a>(
+    word: &str,
+    shell_names: impl IntoIterator<Item = &'a String>,
+    path_dirs: &[PathBuf],
+    settings: &EditSettings,
+) -> Vec<String> {
+    let shell_matches = shell_names.into_iter().filter(|name| settings.completes(name, word)).cloned().collect();
+    let mut executables = Vec::new();
+    for dir in path_dirs {
+        let Ok(entries) = std::fs::read_dir(dir) else { continue; };
+        for entry in entries.flatten() {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else { continue; };
+            if settings.completes(&name, word) && std::fs::metadata(entry.path()).is_ok_and(|metadata| crate::pathindex::is_executable(&metadata)) {
+                executables.push(name);
+            }
+        }
+    }
+    order_candidates(shell_matches, executables)
+}
+
+/// How a candidate appears in a completion listing: its name, followed by
+/// its kind unless it has none or `completion-descriptions` is off.
+pub fn describe_candidate(name: &str, kind: Option<&str>, settings: &EditSettings) -> String {
+    match kind {
+        Some(kind) if settings.completion_descriptions => format!("{}  ({})", name, kind),
+        _ => name.to_string(),
+    }
+}
+
+/// The longest prefix every match shares.
+pub fn find_longest_common_prefix(matches: &[String]) -> String {
+    if matches.is_empty() {
+        return String::new();
+    }
+    let mut prefix = matches[0].clone();
+    if env::var("DEBUG").is_ok() {
+        crate::stdio::eprint(&format!("[DEBUG] Initial prefix: '{}'\n", prefix));
+    }
+    for m in &matches[1..] {
+        let mut i = 0;
+        let max = std::cmp::min(prefix.len(), m.len());
+        while i < max && prefix.as_bytes()[i] == m.as_bytes()[i] {
+            i += 1;
+        }
+        prefix.truncate(i);
+        if env::var("DEBUG").is_ok() {
+            crate::stdio::eprint(&format!("[DEBUG] Truncated prefix after comparing with '{}': '{}'\n", m, prefix));
+        }
+    }
+    prefix
+}
@@ -0,0 +1,298 @@
+// `[[ ... ]]` conditional expressions: string and glob comparisons, integer
+// comparisons, file tests, and `!`/`&&`/`||` with grouping. Operands are
+// expanded without word splitting, so `[[ $x == a* ]]` works unquoted.
+
+use std::fmt;
+#[cfg(target_family = "unix")]
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::arith;
+use crate::expand::{self, ExpansionError, Variables};
+use crate::glob;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CondError(pub String);
+
+impl fmt::Display for CondError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<ExpansionError> for CondError {
+    fn from(e: ExpansionError) -> Self {
+        CondError(e.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    /// A word as written, quotes included; expanded only when evaluated.
+    Word(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &["&&", "||", "!", "(", ")"];
+
+const UNARY_OPERATORS: &[&str] = &["-n", "-z", "-e", "-a", "-f", "-d", "-s", "-r", "-w", "-x", "-L", "-h"];
+
+const BINARY_OPERATORS: &[&str] = &["==", "=", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CondExpr {
+    /// A lone word: true if it expands to a non-empty string.
+    Word(String),
+    Unary { op: String, operand: String },
+    Binary { op: String, lhs: String, rhs: String },
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+impl CondExpr {
+    /// Parses the text between `[[` and `]]`.
+    pub fn parse(body: &str) -> Result<Self, CondError> {
+        let tokens = tokenize(body)?;
+        if tokens.is_empty() {
+            return Err(CondError("syntax error: expression expected".to_string()));
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(tok) => Err(CondError(format!("syntax error near `{}'", tok.text()))),
+        }
+    }
+
+    /// Evaluates the expression, resolving relative file operands against `cwd`.
+    /// `&&` and `||` short-circuit, so the right side is not expanded unless needed.
+    pub fn evaluate(&self, vars: &dyn Variables, cwd: &Path) -> Result<bool, CondError> {
+        match self {
+            CondExpr::Word(word) => Ok(!expand::expand_string(word, vars)?.is_empty()),
+            CondExpr::Unary { op, operand } => {
+                let operand = expand::expand_string(operand, vars)?;
+                Ok(match op.as_str() {
+                    "-n" => !operand.is_empty(),
+                    "-z" => operand.is_empty(),
+                    _ => file_test(op, &cwd.join(operand)),
+                })
+            }
+            CondExpr::Binary { op, lhs, rhs } => {
+                let lhs = expand::expand_string(lhs, vars)?;
+                match op.as_str() {
+                    "==" | "=" => Ok(glob::compile(&expand::expand_pattern(rhs, vars)?).matches(&lhs)),
+                    "!=" => Ok(!glob::compile(&expand::expand_pattern(rhs, vars)?).matches(&lhs)),
+                    "<" => Ok(lhs < expand::expand_string(rhs, vars)?),
+                    ">" => Ok(lhs > expand::expand_string(rhs, vars)?),
+                    _ => {
+                        let rhs = expand::expand_string(rhs, vars)?;
+                        let integer = |text: &str| arith::evaluate(text, vars).map_err(|e| CondError(e.to_string()));
+                        let (lhs, rhs) = (integer(&lhs)?, integer(&rhs)?);
+                        Ok(match op.as_str() {
+                            "-eq" => lhs == rhs,
+                            "-ne" => lhs != rhs,
+                            "-lt" => lhs < rhs,
+                            "-le" => lhs <= rhs,
+                            "-gt" => lhs > rhs,
+                            _ => lhs >= rhs,
+                        })
+                    }
+                }
+            }
+            CondExpr::Not(inner) => Ok(!inner.evaluate(vars, cwd)?),
+            CondExpr::And(lhs, rhs) => Ok(lhs.evaluate(vars, cwd)? && rhs.evaluate(vars, cwd)?),
+            CondExpr::Or(lhs, rhs) => Ok(lhs.evaluate(vars, cwd)? || rhs.evaluate(vars, cwd)?),
+        }
+    }
+}
+
+fn file_test(op: &str, path: &Path) -> bool {
+    if matches!(op, "-L" | "-h") {
+        return path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink());
+    }
+    let Ok(metadata) = path.metadata() else {
+        return false;
+    };
+    match op {
+        "-f" => metadata.is_file(),
+        "-d" => metadata.is_dir(),
+        "-s" => metadata.len() > 0,
+        "-r" | "-w" | "-x" => has_permission(op, path, &metadata),
+        _ => true,
+    }
+}
+
+/// Asks the system whether this process may access `path`, so the answer
+/// accounts for its user, groups and any access control lists.
+#[cfg(target_family = "unix")]
+fn has_permission(op: &str, path: &Path, _metadata: &std::fs::Metadata) -> bool {
+    let mode = match op {
+        "-r" => libc::R_OK,
+        "-w" => libc::W_OK,
+        _ => libc::X_OK,
+    };
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn has_permission(op: &str, _path: &Path, metadata: &std::fs::Metadata) -> bool {
+    op != "-w" || !metadata.permissions().readonly()
+}
+
+impl Tok {
+    fn text(&self) -> &str {
+        match self {
+            Tok::Word(word) => word,
+            Tok::Op(op) => op,
+        }
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self, Tok::Op(o) if *o == op)
+    }
+}
+
+// Splits the body into words and the logical operators. Quoted text and
+// `$(...)`/`${...}` stay inside their word, so operators there are literal.
+fn tokenize(body: &str) -> Result<Vec<Tok>, CondError> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(Tok::Word(std::mem::take(&mut word)));
+            }
+            i += 1;
+            continue;
+        }
+        if let Some(op) = OPERATORS.iter().find(|op| chars[i..].starts_with(&op.chars().collect::<Vec<_>>())) {
+            // `!` is only an operator as a whole word, so `!=` and `a!b` are words.
+            let standalone = *op != "!" || (word.is_empty() && chars.get(i + 1).is_none_or(|c| c.is_whitespace()));
+            if standalone {
+                if !word.is_empty() {
+                    tokens.push(Tok::Word(std::mem::take(&mut word)));
+                }
+                tokens.push(Tok::Op(op));
+                i += op.len();
+                continue;
+            }
+        }
+        match c {
+            '\'' | '"' => {
+                let end = (i + 1..chars.len())
+                    .find(|&j| chars[j] == c && (c == '\'' || chars[j - 1] != '\\'))
+                    .ok_or_else(|| CondError(format!("unexpected EOF while looking for matching `{}'", c)))?;
+                word.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                word.extend(&chars[i..i + 2]);
+                i += 2;
+            }
+            '$' => match expand::Expansion::scan(&chars, i) {
+                Some((_, next)) => {
+                    word.extend(&chars[i..next]);
+                    i = next;
+                }
+                None => {
+                    word.push(c);
+                    i += 1;
+                }
+            },
+            _ => {
+                word.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(Tok::Word(word));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Tok>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.peek().is_some_and(|t| t.is_op(op));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn word(&mut self) -> Result<String, CondError> {
+        match self.tokens.get(self.pos) {
+            Some(Tok::Word(word)) => {
+                self.pos += 1;
+                Ok(word.clone())
+            }
+            Some(tok) => Err(CondError(format!("syntax error near `{}'", tok.text()))),
+            None => Err(CondError("syntax error: unexpected end of expression".to_string())),
+        }
+    }
+
+    fn or(&mut self) -> Result<CondExpr, CondError> {
+        let mut lhs = self.and()?;
+        while self.eat("||") {
+            lhs = CondExpr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<CondExpr, CondError> {
+        let mut lhs = self.not()?;
+        while self.eat("&&") {
+            lhs = CondExpr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<CondExpr, CondError> {
+        if self.eat("!") {
+            return Ok(CondExpr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<CondExpr, CondError> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(CondError("syntax error: expected `)'".to_string()));
+            }
+            return Ok(expr);
+        }
+        let first = self.word()?;
+        // A binary operator takes precedence, so `[[ -n == -n ]]` compares strings.
+        if let Some(Tok::Word(op)) = self.peek()
+            && BINARY_OPERATORS.contains(&op.as_str())
+            && self.tokens.get(self.pos + 1).is_some()
+        {
+            let op = op.clone();
+            self.pos += 1;
+            let rhs = self.word()?;
+            return Ok(CondExpr::Binary { op, lhs: first, rhs });
+        }
+        if UNARY_OPERATORS.contains(&first.as_str()) && matches!(self.peek(), Some(Tok::Word(_))) {
+            let operand = self.word()?;
+            return Ok(CondExpr::Unary { op: first, operand });
+        }
+        Ok(CondExpr::Word(first))
+    }
+}
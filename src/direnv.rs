@@ -0,0 +1,96 @@
+// Per-directory environments. Entering a directory with a `.myshellenv` file
+// sources it, provided `envallow` approved that exact content; the variables
+// it changed are put back on leaving the directory. Approvals live in
+// `$XDG_CONFIG_HOME/myshell/envallow` (or `~/.config/myshell/envallow`), one
+// `hash<TAB>path` line per file, the hash being the SHA-256 of its contents.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::expand::Variables;
+
+/// The file sourced on entering a directory.
+pub const ENV_FILE: &str = ".myshellenv";
+
+/// A sourced env file and how to undo it.
+#[derive(Clone)]
+pub struct LoadedEnv {
+    pub dir: PathBuf,
+    /// Each variable the file changed, with its value beforehand.
+    pub previous: Vec<(String, Option<String>)>,
+}
+
+/// Whether an env file may be sourced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    Allowed,
+    /// Never allowed, or allowed and then revoked.
+    NotAllowed,
+    /// Allowed, but edited since.
+    Changed,
+}
+
+/// The SHA-256 of the file's bytes, in hex. A file edited to run something
+/// else cannot be made to keep an approved hash.
+pub fn content_hash(contents: &[u8]) -> String {
+    Sha256::digest(contents).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The allow-list file, or `None` without `HOME` or `XDG_CONFIG_HOME`.
+pub fn allow_list_path(vars: &dyn Variables) -> Option<PathBuf> {
+    let config = match vars.var("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(vars.var("HOME")?).join(".config"),
+    };
+    Some(config.join("myshell").join("envallow"))
+}
+
+fn read_allow_list(list: &Path) -> HashMap<PathBuf, String> {
+    let text = fs::read_to_string(list).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let (hash, path) = line.split_once('\t')?;
+            Some((PathBuf::from(path), hash.to_string()))
+        })
+        .collect()
+}
+
+fn write_allow_list(list: &Path, entries: &HashMap<PathBuf, String>) -> io::Result<()> {
+    if let Some(parent) = list.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut lines: Vec<String> = entries.iter().map(|(path, hash)| format!("{}\t{}\n", hash, path.display())).collect();
+    lines.sort();
+    fs::write(list, lines.concat())
+}
+
+/// Records the current contents of `file` as allowed.
+pub fn allow(list: &Path, file: &Path) -> io::Result<()> {
+    let hash = content_hash(&fs::read(file)?);
+    let mut entries = read_allow_list(list);
+    entries.insert(file.to_path_buf(), hash);
+    write_allow_list(list, &entries)
+}
+
+/// Removes `file` from the allow list.
+pub fn deny(list: &Path, file: &Path) -> io::Result<()> {
+    let mut entries = read_allow_list(list);
+    if entries.remove(file).is_some() {
+        write_allow_list(list, &entries)?;
+    }
+    Ok(())
+}
+
+/// Whether `contents`, read from `file`, may be sourced. The caller runs
+/// the very bytes it checked, so the file cannot be swapped in between.
+pub fn approval(list: &Path, file: &Path, contents: &[u8]) -> Approval {
+    match read_allow_list(list).remove(file) {
+        None => Approval::NotAllowed,
+        Some(allowed) if content_hash(contents) == allowed => Approval::Allowed,
+        Some(_) => Approval::Changed,
+    }
+}
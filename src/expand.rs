@@ -0,0 +1,585 @@
+// `$` expansions: parameters (`$NAME`, `${NAME}` and the operators of
+// `${NAME...}`: defaults, lengths, substrings, prefix and suffix removal and
+// the `${NAME/pattern/replacement}` substitution family), arithmetic `$(( ))`
+// and command substitution `$( )`. An operator not among them is a bad
+// substitution, not an empty value.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+
+use crate::arith;
+use crate::glob::{self, Pattern};
+
+/// Variable store that expansions read from and arithmetic assigns to.
+pub trait Variables {
+    fn var(&self, name: &str) -> Option<String>;
+    fn set_var(&self, name: &str, value: &str);
+
+    /// The output of `script` for a `$( )` substitution. Only a shell can
+    /// run commands; other stores refuse.
+    fn command_output(&self, _script: &str) -> Result<String, ExpansionError> {
+        Err(ExpansionError("command substitution is not available here".to_string()))
+    }
+}
+
+/// Looks variables up in the process environment. Assignments are discarded,
+/// since the environment is only a read-only fallback for parsing.
+pub struct Environment;
+
+impl Variables for Environment {
+    fn var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+
+    fn set_var(&self, _name: &str, _value: &str) {}
+}
+
+impl Variables for RefCell<HashMap<String, String>> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.borrow().get(name).cloned()
+    }
+
+    fn set_var(&self, name: &str, value: &str) {
+        self.borrow_mut().insert(name.to_string(), value.to_string());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpansionError(pub String);
+
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expansion {
+    Parameter(ParamExpansion),
+    /// The raw text between `$((` and `))`.
+    Arithmetic(String),
+    /// The raw script between `$(` and `)`.
+    Command(String),
+}
+
+impl Expansion {
+    /// Scans a `$` expansion beginning at `chars[start]` (which must be `$`).
+    /// Returns the expansion and the index just past it, or `None` if the `$`
+    /// does not start an expansion and should be taken literally.
+    pub fn scan(chars: &[char], start: usize) -> Option<(Self, usize)> {
+        if chars.get(start + 1) == Some(&'(') && chars.get(start + 2) == Some(&'(') {
+            let end = find_arithmetic_end(chars, start + 3)?;
+            let expr: String = chars[start + 3..end].iter().collect();
+            return Some((Expansion::Arithmetic(expr), end + 2));
+        }
+        if chars.get(start + 1) == Some(&'(') {
+            let end = find_command_end(chars, start + 2)?;
+            let script: String = chars[start + 2..end].iter().collect();
+            return Some((Expansion::Command(script), end + 1));
+        }
+        ParamExpansion::scan(chars, start).map(|(param, next)| (Expansion::Parameter(param), next))
+    }
+
+    pub fn expand(&self, vars: &dyn Variables) -> Result<String, ExpansionError> {
+        match self {
+            Expansion::Parameter(param) => param.expand(vars),
+            Expansion::Arithmetic(expr) => {
+                let expr = expand_string(expr, vars)?;
+                arith::evaluate(&expr, vars)
+                    .map(|value| value.to_string())
+                    .map_err(|e| ExpansionError(e.to_string()))
+            }
+            Expansion::Command(script) => vars.command_output(script),
+        }
+    }
+}
+
+/// Where a substitution pattern must match: anywhere, or anchored to one end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anchor {
+    None,
+    Start,
+    End,
+}
+
+/// What the `${NAME-word}` family does when NAME is unset or, with a colon,
+/// empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOp {
+    /// `-`: the word instead.
+    Default,
+    /// `=`: the word, assigned to NAME as well.
+    Assign,
+    /// `?`: fail, with the word as the message.
+    Error,
+    /// `+`: nothing; otherwise the word.
+    Alternative,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamOp {
+    Plain,
+    /// `${#NAME}`: the length of the value in characters.
+    Length,
+    /// `${NAME-word}`, `${NAME:-word}`, `${NAME=word}` and the rest. With
+    /// `null`, an empty value counts as unset. The word is raw text,
+    /// expanded only if it is used.
+    Test { op: TestOp, null: bool, word: String },
+    /// `${NAME#pat}`, `${NAME##pat}`, `${NAME%pat}` and `${NAME%%pat}`: the
+    /// value less the shortest, or longest, match at its start or end.
+    Remove { anchor: Anchor, longest: bool, pattern: String },
+    /// `${NAME:offset}` and `${NAME:offset:length}`, as raw arithmetic. A
+    /// negative offset counts back from the end, and a negative length
+    /// leaves that many characters off the end.
+    Substring { offset: String, length: Option<String> },
+    /// `${NAME/pat/repl}`, `${NAME//pat/repl}`, `${NAME/#pat/repl}`, `${NAME/%pat/repl}`.
+    /// Pattern and replacement are kept as raw text and expanded on use.
+    Substitute { anchor: Anchor, global: bool, pattern: String, replacement: String },
+    /// Anything else; expanding it fails, quoting the whole of `${...}`.
+    Bad(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamExpansion {
+    pub name: String,
+    pub op: ParamOp,
+}
+
+impl ParamExpansion {
+    fn scan(chars: &[char], start: usize) -> Option<(Self, usize)> {
+        let mut i = start + 1;
+        match chars.get(i)? {
+            '{' => {
+                let end = find_closing_brace(chars, i + 1)?;
+                let body: String = chars[i + 1..end].iter().collect();
+                Some((Self::parse_braced(&body), end + 1))
+            }
+            // Positional and special parameters are a single character.
+            c if c.is_ascii_digit() || is_special(*c) => {
+                Some((ParamExpansion { name: c.to_string(), op: ParamOp::Plain }, i + 1))
+            }
+            c if is_name_start(*c) => {
+                while i < chars.len() && is_name_char(chars[i]) {
+                    i += 1;
+                }
+                let name: String = chars[start + 1..i].iter().collect();
+                Some((ParamExpansion { name, op: ParamOp::Plain }, i))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses the text between `${` and `}`.
+    pub fn parse_braced(body: &str) -> Self {
+        // `${#}` is the number of positional parameters, `${#NAME}` a length.
+        if let Some(name) = body.strip_prefix('#')
+            && !name.is_empty()
+            && param_name_len(name) == name.len()
+        {
+            return ParamExpansion { name: name.to_string(), op: ParamOp::Length };
+        }
+        let (name, rest) = body.split_at(param_name_len(body));
+        let op = match Self::parse_op(rest) {
+            Some(op) if !name.is_empty() => op,
+            _ => ParamOp::Bad(body.to_string()),
+        };
+        ParamExpansion { name: name.to_string(), op }
+    }
+
+    // Parses what follows the name in `${...}`, or `None` if it is no
+    // operator.
+    fn parse_op(rest: &str) -> Option<ParamOp> {
+        let (null, after) = match rest.strip_prefix(':') {
+            Some(after) => (true, after),
+            None => (false, rest),
+        };
+        let test = match after.chars().next() {
+            Some('-') => Some(TestOp::Default),
+            Some('=') => Some(TestOp::Assign),
+            Some('?') => Some(TestOp::Error),
+            Some('+') => Some(TestOp::Alternative),
+            _ => None,
+        };
+        if let Some(op) = test {
+            return Some(ParamOp::Test { op, null, word: after[1..].to_string() });
+        }
+        if null {
+            if after.is_empty() {
+                return None;
+            }
+            let (offset, length) = match after.split_once(':') {
+                Some((offset, length)) => (offset, Some(length.to_string())),
+                None => (after, None),
+            };
+            return Some(ParamOp::Substring { offset: offset.to_string(), length });
+        }
+        for (prefix, anchor, longest) in [("##", Anchor::Start, true), ("#", Anchor::Start, false), ("%%", Anchor::End, true), ("%", Anchor::End, false)] {
+            if let Some(pattern) = rest.strip_prefix(prefix) {
+                return Some(ParamOp::Remove { anchor, longest, pattern: pattern.to_string() });
+            }
+        }
+        Some(match rest.strip_prefix('/') {
+            Some(spec) => {
+                let (anchor, global, spec) = if let Some(s) = spec.strip_prefix('/') {
+                    (Anchor::None, true, s)
+                } else if let Some(s) = spec.strip_prefix('#') {
+                    (Anchor::Start, false, s)
+                } else if let Some(s) = spec.strip_prefix('%') {
+                    (Anchor::End, false, s)
+                } else {
+                    (Anchor::None, false, spec)
+                };
+                let (pattern, replacement) = split_unescaped_slash(spec);
+                ParamOp::Substitute {
+                    anchor,
+                    global,
+                    pattern: pattern.to_string(),
+                    replacement: replacement.unwrap_or("").to_string(),
+                }
+            }
+            None if rest.is_empty() => ParamOp::Plain,
+            None => return None,
+        })
+    }
+
+    pub fn expand(&self, vars: &dyn Variables) -> Result<String, ExpansionError> {
+        let value = vars.var(&self.name);
+        match &self.op {
+            ParamOp::Plain => Ok(value.unwrap_or_default()),
+            ParamOp::Length => Ok(value.unwrap_or_default().chars().count().to_string()),
+            ParamOp::Test { op, null, word } => {
+                let set = value.as_ref().is_some_and(|value| !(*null && value.is_empty()));
+                match (op, set) {
+                    (TestOp::Alternative, true) => expand_string(word, vars),
+                    (TestOp::Alternative, false) => Ok(String::new()),
+                    (_, true) => Ok(value.unwrap_or_default()),
+                    (TestOp::Default, false) => expand_string(word, vars),
+                    (TestOp::Assign, false) => {
+                        if !is_name(&self.name) {
+                            return Err(ExpansionError(format!("${}: cannot assign in this way", self.name)));
+                        }
+                        let word = expand_string(word, vars)?;
+                        vars.set_var(&self.name, &word);
+                        Ok(word)
+                    }
+                    (TestOp::Error, false) => {
+                        let message = match (word.is_empty(), null) {
+                            (true, true) => "parameter null or not set".to_string(),
+                            (true, false) => "parameter not set".to_string(),
+                            (false, _) => expand_string(word, vars)?,
+                        };
+                        Err(ExpansionError(format!("{}: {}", self.name, message)))
+                    }
+                }
+            }
+            ParamOp::Remove { anchor, longest, pattern } => {
+                let value = value.unwrap_or_default();
+                let pattern = glob::compile(&expand_text(pattern, vars, true)?);
+                let kept = match (anchor, longest) {
+                    (Anchor::End, true) => pattern.longest_suffix_match(&value).map(|start| &value[..start]),
+                    (Anchor::End, false) => pattern.shortest_suffix_match(&value).map(|start| &value[..start]),
+                    (_, true) => pattern.longest_match_at(&value, 0).map(|end| &value[end..]),
+                    (_, false) => pattern.shortest_prefix_match(&value).map(|end| &value[end..]),
+                };
+                Ok(kept.unwrap_or(&value).to_string())
+            }
+            ParamOp::Substring { offset, length } => {
+                let chars: Vec<char> = value.unwrap_or_default().chars().collect();
+                let len = chars.len() as i64;
+                let number = |text: &str| {
+                    arith::evaluate(&expand_string(text, vars)?, vars).map_err(|e| ExpansionError(e.to_string()))
+                };
+                let offset = number(offset)?;
+                let start = if offset < 0 { len + offset } else { offset };
+                if !(0..=len).contains(&start) {
+                    return Ok(String::new());
+                }
+                let end = match length {
+                    Some(length) => match number(length)? {
+                        count if count < 0 && len + count < start => {
+                            return Err(ExpansionError(format!("{}: substring expression < 0", length.trim())));
+                        }
+                        count if count < 0 => len + count,
+                        count => start.saturating_add(count).min(len),
+                    },
+                    None => len,
+                };
+                Ok(chars[start as usize..end as usize].iter().collect())
+            }
+            ParamOp::Substitute { anchor, global, pattern, replacement } => {
+                let value = value.unwrap_or_default();
+                let pattern = glob::compile(&expand_text(pattern, vars, true)?);
+                let replacement = expand_text(replacement, vars, false)?;
+                Ok(substitute(&value, &pattern, &replacement, anchor, *global))
+            }
+            ParamOp::Bad(body) => Err(ExpansionError(format!("${{{}}}: bad substitution", body))),
+        }
+    }
+}
+
+// The length of the parameter name `text` starts with: one character for a
+// special parameter, the digits of a positional one, or a variable name.
+fn param_name_len(text: &str) -> usize {
+    match text.chars().next() {
+        Some(c) if is_special(c) => 1,
+        Some(c) if c.is_ascii_digit() => text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len()),
+        Some(c) if is_name_start(c) => text.find(|c: char| !is_name_char(c)).unwrap_or(text.len()),
+        _ => 0,
+    }
+}
+
+/// Replaces matches of `pattern` in `value`. Matches are always the longest
+/// possible at their starting position; the unanchored forms never replace an
+/// empty match.
+pub fn substitute(value: &str, pattern: &Pattern, replacement: &str, anchor: &Anchor, global: bool) -> String {
+    match anchor {
+        Anchor::Start => match pattern.longest_match_at(value, 0) {
+            Some(end) => format!("{}{}", replacement, &value[end..]),
+            None => value.to_string(),
+        },
+        Anchor::End => match pattern.longest_suffix_match(value) {
+            Some(start) => format!("{}{}", &value[..start], replacement),
+            None => value.to_string(),
+        },
+        Anchor::None => {
+            let mut result = String::with_capacity(value.len());
+            let mut pos = 0;
+            while pos < value.len() {
+                match pattern.longest_match_at(value, pos).filter(|&end| end > pos) {
+                    Some(end) => {
+                        result.push_str(replacement);
+                        pos = end;
+                        if !global {
+                            break;
+                        }
+                    }
+                    None => {
+                        let c = value[pos..].chars().next().unwrap();
+                        result.push(c);
+                        pos += c.len_utf8();
+                    }
+                }
+            }
+            result.push_str(&value[pos..]);
+            result
+        }
+    }
+}
+
+/// Expands `raw` as a single string: quotes are removed and expansions
+/// substituted, with no splitting into words.
+pub fn expand_string(raw: &str, vars: &dyn Variables) -> Result<String, ExpansionError> {
+    expand_text(raw, vars, false)
+}
+
+/// Expands the body of a here-document. Only `$` expansions are
+/// substituted, and quotes are ordinary characters; a backslash escapes `$`,
+/// a backquote or another backslash, and joins a line to the next.
+pub fn expand_here_doc(body: &str, vars: &dyn Variables) -> Result<String, ExpansionError> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '$' && let Some((expansion, next)) = Expansion::scan(&chars, i) {
+            out.push_str(&expansion.expand(vars)?);
+            i = next;
+            continue;
+        }
+        if c == '\\' && let Some(&escaped @ ('$' | '`' | '\\' | '\n')) = chars.get(i + 1) {
+            if escaped != '\n' {
+                out.push(escaped);
+            }
+            i += 2;
+            continue;
+        }
+        out.push(c);
+        i += 1;
+    }
+    Ok(out)
+}
+
+/// Expands `raw` for use as a glob pattern: quoted characters are escaped so
+/// they match literally, while unquoted wildcards keep their meaning.
+pub fn expand_pattern(raw: &str, vars: &dyn Variables) -> Result<String, ExpansionError> {
+    expand_text(raw, vars, true)
+}
+
+/// Expands a word embedded in a `${...}` operator: quotes are removed and
+/// nested expansions substituted. In pattern context, quoted characters are
+/// escaped so they match literally rather than as wildcards.
+fn expand_text(raw: &str, vars: &dyn Variables, as_pattern: bool) -> Result<String, ExpansionError> {
+    let chars: Vec<char> = raw.chars().collect();
+    let literal = |out: &mut String, c: char| {
+        if as_pattern {
+            out.push_str(&glob::escape(&c.to_string()));
+        } else {
+            out.push(c);
+        }
+    };
+    let mut out = String::new();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if in_single_quote {
+            if c == '\'' {
+                in_single_quote = false;
+            } else {
+                literal(&mut out, c);
+            }
+        } else if c == '$' && let Some((expansion, next)) = Expansion::scan(&chars, i) {
+            let value = expansion.expand(vars)?;
+            if in_double_quote {
+                value.chars().for_each(|c| literal(&mut out, c));
+            } else {
+                out.push_str(&value);
+            }
+            i = next;
+            continue;
+        } else if c == '"' {
+            in_double_quote = !in_double_quote;
+        } else if c == '\'' && !in_double_quote {
+            in_single_quote = true;
+        } else if c == '\\'
+            && i + 1 < chars.len()
+            && (!in_double_quote || matches!(chars[i + 1], '$' | '`' | '"' | '\\'))
+        {
+            i += 1;
+            literal(&mut out, chars[i]);
+        } else if in_double_quote {
+            literal(&mut out, c);
+        } else {
+            out.push(c);
+        }
+        i += 1;
+    }
+    Ok(out)
+}
+
+// Splits `pattern/replacement` on the first slash not escaped or quoted.
+fn split_unescaped_slash(spec: &str) -> (&str, Option<&str>) {
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut escaped = false;
+    for (i, c) in spec.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if in_single_quote {
+            in_single_quote = c != '\'';
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_double_quote = !in_double_quote;
+        } else if c == '\'' && !in_double_quote {
+            in_single_quote = true;
+        } else if c == '/' && !in_double_quote {
+            return (&spec[..i], Some(&spec[i + 1..]));
+        }
+    }
+    (spec, None)
+}
+
+// Finds the `}` closing a `${`, skipping nested expansions and quoted text.
+fn find_closing_brace(chars: &[char], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    while i < chars.len() {
+        let c = chars[i];
+        if in_single_quote {
+            in_single_quote = c != '\'';
+        } else if c == '\\' {
+            i += 1;
+        } else if c == '"' {
+            in_double_quote = !in_double_quote;
+        } else if c == '\'' && !in_double_quote {
+            in_single_quote = true;
+        } else if c == '$' && chars.get(i + 1) == Some(&'{') {
+            depth += 1;
+            i += 1;
+        } else if c == '}' {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+        i += 1;
+    }
+    None
+}
+
+// Finds the `))` closing a `$((`, allowing balanced parentheses inside.
+fn find_arithmetic_end(chars: &[char], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '(' => depth += 1,
+            ')' if depth == 0 => return (chars.get(i + 1) == Some(&')')).then_some(i),
+            ')' => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Finds the `)` closing a `$(`, skipping quoted text, balanced parentheses
+// and nested substitutions.
+fn find_command_end(chars: &[char], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_double_quote = false;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '\'' if !in_double_quote => i += chars[i + 1..].iter().position(|&c| c == '\'')? + 1,
+            '"' => in_double_quote = !in_double_quote,
+            '$' if chars.get(i + 1) == Some(&'(') => i = find_command_end(chars, i + 2)?,
+            '(' if !in_double_quote => depth += 1,
+            ')' if !in_double_quote => {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Whether `text` is a valid variable name.
+pub fn is_name(text: &str) -> bool {
+    text.starts_with(is_name_start) && text.chars().all(is_name_char)
+}
+
+/// The value of the tilde prefix `~prefix`: `~` is `$HOME`, `~+` is `$PWD`
+/// and `~-` is `$OLDPWD`. Returns `None` for other prefixes, or when the
+/// variable is unset, in which case the text is kept as written.
+pub fn tilde(prefix: &str, vars: &dyn Variables) -> Option<String> {
+    let name = match prefix {
+        "" => "HOME",
+        "+" => "PWD",
+        "-" => "OLDPWD",
+        _ => return None,
+    };
+    vars.var(name)
+}
+
+// The special parameters: `$?`, the last status, and the positional
+// parameters' `$#`, `$@` and `$*`.
+fn is_special(c: char) -> bool {
+    matches!(c, '?' | '#' | '@' | '*')
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
@@ -0,0 +1,164 @@
+// Temporarily pointing one of the shell's own descriptors somewhere else.
+// Builtins write through the shell's streams, so a redirection normally
+// needs no real descriptor at all; these are for code that writes to the
+// process's stdout or stderr directly. The descriptor's previous target is
+// kept as a duplicate and put back when the guard is dropped, including when
+// the code in between panics. On Windows it is the process's standard handle
+// for descriptor 0, 1 or 2 that is swapped instead. Also here: waiting a
+// limited time for a descriptor to have input, for `read -t`.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, RawHandle};
+
+/// Descriptor `fd` pointed at another file until this is dropped.
+#[cfg(unix)]
+pub struct Redirected {
+    fd: RawFd,
+    saved: OwnedFd,
+}
+
+#[cfg(unix)]
+impl Redirected {
+    /// Points `fd` at `target`, keeping what it pointed at before.
+    pub fn new(fd: RawFd, target: &File) -> io::Result<Self> {
+        flush(fd);
+        // The copy is close-on-exec, so commands started meanwhile do not
+        // inherit it.
+        // SAFETY: `fcntl` and `dup2` only act on descriptors; the copy is
+        // owned by the guard from here on.
+        let saved = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if saved < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = unsafe { OwnedFd::from_raw_fd(saved) };
+        if unsafe { libc::dup2(target.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Redirected { fd, saved })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Redirected {
+    fn drop(&mut self) {
+        flush(self.fd);
+        // SAFETY: as in `new`.
+        unsafe { libc::dup2(self.saved.as_raw_fd(), self.fd) };
+    }
+}
+
+#[cfg(windows)]
+const STD_INPUT_HANDLE: u32 = -10i32 as u32;
+#[cfg(windows)]
+const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+#[cfg(windows)]
+const STD_ERROR_HANDLE: u32 = -12i32 as u32;
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    fn GetStdHandle(which: u32) -> RawHandle;
+    fn SetStdHandle(which: u32, handle: RawHandle) -> i32;
+}
+
+/// The standard handle for descriptor `fd` pointed at another file until
+/// this is dropped.
+#[cfg(windows)]
+pub struct Redirected {
+    fd: i32,
+    which: u32,
+    saved: RawHandle,
+    // Held so the handle stays open for as long as it is the standard one.
+    _target: File,
+}
+
+#[cfg(windows)]
+impl Redirected {
+    /// Points the standard handle for `fd` at `target`, keeping what it
+    /// pointed at before. Only descriptors 0, 1 and 2 have one.
+    pub fn new(fd: i32, target: &File) -> io::Result<Self> {
+        let which = match fd {
+            0 => STD_INPUT_HANDLE,
+            1 => STD_OUTPUT_HANDLE,
+            2 => STD_ERROR_HANDLE,
+            _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "no standard handle for this descriptor")),
+        };
+        let target = target.try_clone()?;
+        flush(fd);
+        // SAFETY: these only read and replace the process's standard handle;
+        // the one put in place is owned by the guard until it is put back.
+        let saved = unsafe { GetStdHandle(which) };
+        if saved as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { SetStdHandle(which, target.as_raw_handle()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Redirected { fd, which, saved, _target: target })
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Redirected {
+    fn drop(&mut self) {
+        flush(self.fd);
+        // SAFETY: as in `new`.
+        unsafe { SetStdHandle(self.which, self.saved) };
+    }
+}
+
+// Writes out what Rust has buffered for the descriptor, so it goes where
+// the descriptor pointed when it was written.
+fn flush(fd: i32) {
+    match fd {
+        1 => drop(io::stdout().flush()),
+        2 => drop(io::stderr().flush()),
+        _ => {}
+    }
+}
+
+/// Runs `f` with descriptor `fd` pointed at `target`, then points it back.
+pub fn with_redirected<T>(fd: i32, target: &File, f: impl FnOnce() -> T) -> io::Result<T> {
+    let _guard = Redirected::new(fd, target)?;
+    Ok(f())
+}
+
+/// Runs `f` with the process's stdout pointed at `target`.
+pub fn with_redirected_stdout<T>(target: &File, f: impl FnOnce() -> T) -> io::Result<T> {
+    with_redirected(1, target, f)
+}
+
+/// Waits up to `timeout` for `fd` to have input, or end of input, to read.
+#[cfg(unix)]
+pub fn wait_readable(fd: i32, timeout: Duration) -> bool {
+    let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // Rounded up, so a wait never ends before the time is up.
+    let millis = timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+    // SAFETY: `poll` reads and writes only the one pollfd given.
+    unsafe { libc::poll(&mut poll, 1, millis) > 0 }
+}
+
+/// Without `poll`, input is always taken to be ready, and reads block.
+#[cfg(not(unix))]
+pub fn wait_readable(_fd: i32, _timeout: Duration) -> bool {
+    true
+}
+
+/// Reads one byte straight from `fd`, past any buffering, so that waiting
+/// on the descriptor tells whether the next read would block.
+#[cfg(unix)]
+pub fn read_byte(fd: i32) -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    // SAFETY: reads at most one byte into `byte`.
+    match unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) } {
+        1 => Ok(Some(byte)),
+        0 => Ok(None),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,256 @@
+// Glob-style pattern matching (`*`, `?`, `[...]`) shared by every feature
+// that matches text against a shell pattern.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Named(String),
+}
+
+impl ClassItem {
+    fn contains(&self, c: char) -> bool {
+        match self {
+            ClassItem::Char(x) => *x == c,
+            ClassItem::Range(lo, hi) => *lo <= c && c <= *hi,
+            ClassItem::Named(name) => match name.as_str() {
+                "alpha" => c.is_alphabetic(),
+                "digit" => c.is_ascii_digit(),
+                "alnum" => c.is_alphanumeric(),
+                "upper" => c.is_uppercase(),
+                "lower" => c.is_lowercase(),
+                "space" => c.is_whitespace(),
+                "blank" => c == ' ' || c == '\t',
+                "punct" => c.is_ascii_punctuation(),
+                "xdigit" => c.is_ascii_hexdigit(),
+                "cntrl" => c.is_control(),
+                "print" => !c.is_control(),
+                "graph" => !c.is_control() && !c.is_whitespace(),
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(char),
+    AnyChar,
+    AnyString,
+    Class { negated: bool, items: Vec<ClassItem> },
+}
+
+impl Token {
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            Token::Literal(x) => *x == c,
+            Token::AnyChar => true,
+            Token::AnyString => true,
+            Token::Class { negated, items } => items.iter().any(|i| i.contains(c)) != *negated,
+        }
+    }
+}
+
+/// A compiled glob pattern. A backslash makes the following character literal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if i + 1 < chars.len() => {
+                    tokens.push(Token::Literal(chars[i + 1]));
+                    i += 2;
+                    continue;
+                }
+                '*' => {
+                    if tokens.last() != Some(&Token::AnyString) {
+                        tokens.push(Token::AnyString);
+                    }
+                }
+                '?' => tokens.push(Token::AnyChar),
+                '[' => {
+                    if let Some((token, next)) = Self::parse_class(&chars, i) {
+                        tokens.push(token);
+                        i = next;
+                        continue;
+                    }
+                    tokens.push(Token::Literal('['));
+                }
+                c => tokens.push(Token::Literal(c)),
+            }
+            i += 1;
+        }
+        Pattern { tokens }
+    }
+
+    // Parses a bracket expression starting at `chars[start] == '['`, returning
+    // the token and the index just past the closing `]`. An unterminated
+    // bracket is not a class and is matched literally by the caller.
+    fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+        let mut i = start + 1;
+        let negated = matches!(chars.get(i), Some('!') | Some('^'));
+        if negated {
+            i += 1;
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == ']' && !first {
+                return Some((Token::Class { negated, items }, i + 1));
+            }
+            first = false;
+            if c == '[' && chars.get(i + 1) == Some(&':') {
+                let rest: String = chars[i + 2..].iter().collect();
+                if let Some(end) = rest.find(":]") {
+                    items.push(ClassItem::Named(rest[..end].to_string()));
+                    i += 2 + rest[..end].chars().count() + 2;
+                    continue;
+                }
+            }
+            let c = if c == '\\' && i + 1 < chars.len() {
+                i += 1;
+                chars[i]
+            } else {
+                c
+            };
+            if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&h| h != ']') {
+                items.push(ClassItem::Range(c, chars[i + 2]));
+                i += 3;
+            } else {
+                items.push(ClassItem::Char(c));
+                i += 1;
+            }
+        }
+        None
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.matches_chars(&text)
+    }
+
+    fn matches_chars(&self, text: &[char]) -> bool {
+        let (mut p, mut t) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::AnyString) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                }
+                Some(token) if token.matches_char(text[t]) => {
+                    p += 1;
+                    t += 1;
+                }
+                _ => match backtrack {
+                    Some((star_p, star_t)) => {
+                        p = star_p + 1;
+                        t = star_t + 1;
+                        backtrack = Some((star_p, star_t + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[p..].iter().all(|t| *t == Token::AnyString)
+    }
+
+    /// Finds the longest match starting exactly at byte offset `start` of `text`,
+    /// returning the byte offset where it ends.
+    pub fn longest_match_at(&self, text: &str, start: usize) -> Option<usize> {
+        let ends: Vec<usize> = text[start..]
+            .char_indices()
+            .map(|(i, _)| start + i)
+            .chain(std::iter::once(text.len()))
+            .collect();
+        ends.into_iter().rev().find(|&end| self.matches(&text[start..end]))
+    }
+
+    /// Finds the shortest match starting at the beginning of `text`,
+    /// returning the byte offset where it ends.
+    pub fn shortest_prefix_match(&self, text: &str) -> Option<usize> {
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .find(|&end| self.matches(&text[..end]))
+    }
+
+    /// Finds the shortest match that ends exactly at the end of `text`,
+    /// returning the byte offset where it starts.
+    pub fn shortest_suffix_match(&self, text: &str) -> Option<usize> {
+        let starts: Vec<usize> = text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).collect();
+        starts.into_iter().rev().find(|&start| self.matches(&text[start..]))
+    }
+
+    /// Finds the longest match that ends exactly at the end of `text`,
+    /// returning the byte offset where it starts.
+    pub fn longest_suffix_match(&self, text: &str) -> Option<usize> {
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .find(|&start| self.matches(&text[start..]))
+    }
+}
+
+// Patterns are pure functions of their text, so cached entries never go
+// stale; the bound only limits memory.
+pub(crate) const CACHE_CAPACITY: usize = 128;
+
+#[derive(Default)]
+struct PatternCache {
+    entries: HashMap<String, (Rc<Pattern>, u64)>,
+    clock: u64,
+}
+
+impl PatternCache {
+    fn get(&mut self, text: &str) -> Rc<Pattern> {
+        self.clock += 1;
+        if let Some((pattern, last_used)) = self.entries.get_mut(text) {
+            *last_used = self.clock;
+            return pattern.clone();
+        }
+        if self.entries.len() >= CACHE_CAPACITY
+            && let Some(oldest) = self.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(k, _)| k.clone())
+        {
+            self.entries.remove(&oldest);
+        }
+        let pattern = Rc::new(Pattern::new(text));
+        self.entries.insert(text.to_string(), (pattern.clone(), self.clock));
+        pattern
+    }
+}
+
+thread_local! {
+    static CACHE: RefCell<PatternCache> = RefCell::new(PatternCache::default());
+}
+
+/// Compiles `pattern`, reusing a recent compilation of the same text. Loops
+/// that test many words against a few patterns (`case`, `[[ == ]]`) hit the
+/// cache after the first iteration.
+pub fn compile(pattern: &str) -> Rc<Pattern> {
+    CACHE.with(|cache| cache.borrow_mut().get(pattern))
+}
+
+/// Backslash-escapes glob metacharacters so `text` matches only itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,121 @@
+// Long options for Tab completion, read from what an executable prints for
+// `--help`. This is off unless `set -o complete-options`, since it runs the
+// command being typed. Each executable is asked once, with a time limit and
+// a cap on the output read, and its options kept until its modification time
+// changes. A command that fails, hangs or prints no options offers none.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+/// How long `--help` may take to finish printing.
+pub const HELP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most bytes of `--help` output read.
+pub const HELP_LIMIT: usize = 64 * 1024;
+
+/// The distinct `--name` options mentioned in `text`, sorted. An option
+/// begins a word or follows punctuation such as `[` or `,`, and its name is
+/// lowercase letters, digits and dashes, so `--color=WHEN` gives `--color`.
+pub fn scrape(text: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    for (i, _) in text.match_indices("--") {
+        if text[..i].chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            continue;
+        }
+        let name: String = text[i + 2..]
+            .chars()
+            .take_while(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')
+            .collect();
+        let name = name.trim_end_matches('-');
+        // Rules such as `------` are not options.
+        if !name.is_empty() && !name.starts_with('-') {
+            options.push(format!("--{}", name));
+        }
+    }
+    options.sort();
+    options.dedup();
+    options
+}
+
+/// Runs `path --help` and returns what it wrote to stdout, up to `limit`
+/// bytes, or `None` if it could not be started or had not finished within
+/// `timeout`, in which case it is killed.
+pub fn run_help(path: &Path, timeout: Duration, limit: usize) -> Option<String> {
+    let mut child = Command::new(path)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let stdout = child.stdout.take()?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.take(limit as u64).read_to_end(&mut output);
+        let _ = sender.send(output);
+    });
+    let output = receiver.recv_timeout(timeout);
+    let _ = child.kill();
+    let _ = child.wait();
+    output.ok().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// How the cache reaches executables; tests substitute one that counts runs.
+pub trait HelpReader: Send {
+    /// What `path --help` printed, if it ran in time.
+    fn help(&self, path: &Path) -> Option<String>;
+    /// When `path` last changed, or `None` if that cannot be told, in which
+    /// case it is asked again every time.
+    fn modified(&self, path: &Path) -> Option<SystemTime>;
+}
+
+pub struct CommandReader;
+
+impl HelpReader for CommandReader {
+    fn help(&self, path: &Path) -> Option<String> {
+        run_help(path, HELP_TIMEOUT, HELP_LIMIT)
+    }
+
+    fn modified(&self, path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+}
+
+struct Entry {
+    modified: Option<SystemTime>,
+    options: Vec<String>,
+}
+
+pub struct OptionCache {
+    reader: Box<dyn HelpReader>,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Default for OptionCache {
+    fn default() -> Self {
+        Self::with_reader(Box::new(CommandReader))
+    }
+}
+
+impl OptionCache {
+    pub fn with_reader(reader: Box<dyn HelpReader>) -> Self {
+        OptionCache { reader, entries: HashMap::new() }
+    }
+
+    /// The long options of the executable at `path`, asking it only if it
+    /// is new or has changed since it was last asked.
+    pub fn options(&mut self, path: &Path) -> &[String] {
+        let modified = self.reader.modified(path);
+        let fresh = self.entries.get(path).is_some_and(|entry| modified.is_some() && entry.modified == modified);
+        if !fresh {
+            let options = self.reader.help(path).map(|text| scrape(&text)).unwrap_or_default();
+            self.entries.insert(path.to_path_buf(), Entry { modified, options });
+        }
+        &self.entries[path].options
+    }
+}
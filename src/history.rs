@@ -0,0 +1,383 @@
+// The shell's command history. The REPL records each line here and mirrors
+// the list into rustyline for Up-arrow recall; the `history` builtin lists
+// and edits it. A command typed over several lines is one entry. Between
+// sessions the list is kept in `HISTFILE`, by default `~/.myshell_history`,
+// in bash's format: each
+// entry preceded by a `#<seconds since the epoch>` line giving when it was
+// recorded, so the files of either shell can be read by the other.
+// `HISTFILE_FORMAT` picks how multi-line entries are written; a file is read
+// the same way whatever it says, entry by entry, so one written partly by
+// bash and partly by this shell loads whole. Entries that are not UTF-8
+// are shown with replacement characters but written back as they were read.
+// Saving adds the session's new entries to the file as it is then, so what
+// other sessions wrote, and what the session left out for `HISTSIZE`, stays.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The saved history, relative to `HOME`.
+pub const HISTORY_FILE: &str = ".myshell_history";
+
+/// Follows the timestamp of an entry written in the native format. Bash
+/// reads the line as a timestamp still, and keeps it when it rewrites the
+/// file.
+const NATIVE_MARK: &str = " native";
+
+/// How entries are written to the history file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    /// Each entry as recorded, newlines and all, as bash writes with
+    /// `lithist`. Used unless `HISTFILE_FORMAT` names another.
+    Multiline,
+    /// `bash`: each entry on one line, its lines joined as `join_lines`
+    /// does, so that bash without `lithist` reads it as it was.
+    Bash,
+    /// `native`: each entry on one line with its newlines and backslashes
+    /// escaped, after a marked timestamp. Nothing is lost, and no line of a
+    /// command can be taken for a timestamp.
+    Native,
+}
+
+impl FileFormat {
+    /// The format `HISTFILE_FORMAT` asks for.
+    pub fn from_var(value: Option<&str>) -> Self {
+        match value {
+            Some("bash") => FileFormat::Bash,
+            Some("native") => FileFormat::Native,
+            _ => FileFormat::Multiline,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub line: String,
+    /// When the entry was recorded, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The entry's bytes, when they are not UTF-8 and `line` has
+    /// replacement characters instead.
+    pub raw: Option<Vec<u8>>,
+    /// Whether the history file has the entry, it having been read from
+    /// there or saved.
+    pub in_file: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<Entry>,
+    /// The most entries kept, if there is a limit; the oldest go first.
+    max: Option<usize>,
+    /// Set when entries were removed, so the editor's copy must be rebuilt
+    /// rather than appended to.
+    rewritten: bool,
+}
+
+impl History {
+    /// Reads a history file, as written by `to_bytes` in any format or by bash.
+    pub fn load(bytes: &[u8]) -> Self {
+        let mut history = History::default();
+        history.read(bytes);
+        history.rewritten = false;
+        history
+    }
+
+    /// Appends the entries of a history file. After a timestamp line, every
+    /// line up to the next one belongs to a single entry, unless the
+    /// timestamp is marked native, when the one line after it is an escaped
+    /// entry. Lines with no timestamp before them are an entry each,
+    /// recorded now.
+    pub fn read(&mut self, bytes: &[u8]) {
+        let mut timed: Option<(Vec<u8>, u64)> = None;
+        let mut lines = lines(bytes).peekable();
+        while let Some(line) = lines.next() {
+            if let Some((time, native)) = timestamp(line) {
+                if let Some((entry, time)) = timed.take() {
+                    self.push_bytes(entry, time);
+                }
+                if !native {
+                    timed = Some((Vec::new(), time));
+                } else if let Some(escaped) = lines.next_if(|next| timestamp(next).is_none()) {
+                    self.push_bytes(unescape(escaped), time);
+                }
+            } else if let Some((entry, _)) = &mut timed {
+                if !entry.is_empty() {
+                    entry.push(b'\n');
+                }
+                entry.extend_from_slice(line);
+            } else {
+                self.push_bytes(line.to_vec(), now());
+            }
+        }
+        if let Some((entry, time)) = timed {
+            self.push_bytes(entry, time);
+        }
+        self.rewritten = true;
+    }
+
+    /// What saving to a history file that now holds `on_disk` writes: the
+    /// entries there, then those this history has that it does not, keeping
+    /// the last `limit` of them.
+    pub fn file_bytes(&self, on_disk: &[u8], format: FileFormat, limit: Option<usize>) -> Vec<u8> {
+        let mut file = History::load(on_disk);
+        file.entries.extend(self.entries.iter().filter(|entry| !entry.in_file).cloned());
+        file.set_max(limit);
+        file.to_bytes(format)
+    }
+
+    /// Notes that the history file has every entry, once it is saved.
+    pub fn mark_saved(&mut self) {
+        for entry in &mut self.entries {
+            entry.in_file = true;
+        }
+    }
+
+    /// The history file's contents. An entry that was not UTF-8 is written
+    /// as it was read, in any format but `native`, which only escapes it.
+    pub fn to_bytes(&self, format: FileFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for entry in &self.entries {
+            let line = match (&entry.raw, format) {
+                (Some(raw), FileFormat::Native) => escape(raw),
+                (Some(raw), _) => raw.clone(),
+                (None, FileFormat::Multiline) => entry.line.clone().into_bytes(),
+                (None, FileFormat::Bash) => join_lines(&entry.line).into_bytes(),
+                (None, FileFormat::Native) => escape(entry.line.as_bytes()),
+            };
+            let mark = if format == FileFormat::Native { NATIVE_MARK } else { "" };
+            bytes.extend_from_slice(format!("#{}{}\n", entry.time, mark).as_bytes());
+            bytes.extend_from_slice(&line);
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Records `line` as run now, ignoring blank ones.
+    pub fn push(&mut self, line: &str) {
+        self.push_at(line, now());
+    }
+
+    fn push_at(&mut self, line: &str, time: u64) {
+        self.push_entry(Entry { line: line.to_string(), time, raw: None, in_file: false });
+    }
+
+    // Records an entry read from a file, keeping its bytes if they are not
+    // UTF-8.
+    fn push_bytes(&mut self, bytes: Vec<u8>, time: u64) {
+        let entry = match String::from_utf8(bytes) {
+            Ok(line) => Entry { line, time, raw: None, in_file: true },
+            Err(e) => Entry { line: String::from_utf8_lossy(e.as_bytes()).into_owned(), time, raw: Some(e.into_bytes()), in_file: true },
+        };
+        self.push_entry(entry);
+    }
+
+    fn push_entry(&mut self, entry: Entry) {
+        if entry.line.trim().is_empty() {
+            return;
+        }
+        self.entries.push(entry);
+        self.trim();
+    }
+
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if let Some(max) = self.max
+            && self.entries.len() > max
+        {
+            let excess = self.entries.len() - max;
+            self.entries.drain(..excess);
+            self.rewritten = true;
+        }
+    }
+
+    /// Deletes the entries named by `spec`, as given to `history -d`: a
+    /// 1-based position, or `start-end` for an inclusive range. Negative
+    /// positions count back from the end, so `-1` is the latest entry.
+    pub fn delete(&mut self, spec: &str) -> Result<(), String> {
+        let out_of_range = || format!("{}: history position out of range", spec);
+        let range = (1..spec.len())
+            .filter(|&i| spec.as_bytes()[i] == b'-')
+            .find_map(|i| Some((spec[..i].parse::<i64>().ok()?, spec[i + 1..].parse::<i64>().ok()?)));
+        let (start, end) = match range {
+            Some((start, end)) => (self.index(start), self.index(end)),
+            None => {
+                let position = spec.parse::<i64>().map_err(|_| out_of_range())?;
+                (self.index(position), self.index(position))
+            }
+        };
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => {
+                self.entries.drain(start..=end);
+                self.rewritten = true;
+                Ok(())
+            }
+            _ => Err(out_of_range()),
+        }
+    }
+
+    // The 0-based index of a `history -d` position.
+    fn index(&self, position: i64) -> Option<usize> {
+        let len = self.entries.len() as i64;
+        let index = if position < 0 { len + position } else { position - 1 };
+        (0..len).contains(&index).then_some(index as usize)
+    }
+
+    /// Whether entries were removed since the last call.
+    pub fn take_rewritten(&mut self) -> bool {
+        std::mem::take(&mut self.rewritten)
+    }
+}
+
+// The lines of a file, without their line endings, as `str::lines` splits
+// them.
+fn lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    bytes.split(|&b| b == b'\n').filter(move |_| !bytes.is_empty()).map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+// The time of a `#<seconds>` line, and whether it is marked native.
+fn timestamp(line: &[u8]) -> Option<(u64, bool)> {
+    let stamp = std::str::from_utf8(line.strip_prefix(b"#")?).ok()?;
+    let (digits, native) = match stamp.strip_suffix(NATIVE_MARK) {
+        Some(digits) => (digits, true),
+        None => (stamp, false),
+    };
+    Some((digits.parse().ok()?, native))
+}
+
+fn escape(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    for &b in line {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+// Undoes `escape`. A backslash before anything else is kept as it is.
+fn unescape(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        match (b, bytes.peek()) {
+            (b'\\', Some(b'n')) => {
+                out.push(b'\n');
+                bytes.next();
+            }
+            (b'\\', Some(b'\\')) => {
+                out.push(b'\\');
+                bytes.next();
+            }
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+/// The limit a variable such as `HISTSIZE` sets: `Some(None)` for none, as
+/// a negative number asks, and `None` if it is unset or not a number.
+pub fn size_limit(value: Option<&str>) -> Option<Option<usize>> {
+    let size: i64 = value?.parse().ok()?;
+    Some(usize::try_from(size).ok())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Formats `time` in the local time zone with a `strftime` format, as
+/// `HISTTIMEFORMAT` gives it.
+#[cfg(unix)]
+pub fn format_time(time: u64, format: &str) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let time = time as libc::time_t;
+    // SAFETY: an all-zero `tm` is valid, and `localtime_r` only writes it.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let mut buffer = vec![0u8; format.as_bytes().len() * 8 + 64];
+    // SAFETY: `strftime` writes at most `buffer.len()` bytes into `buffer`
+    // and returns how many, reading only the format and `tm`.
+    let len = unsafe { libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), format.as_ptr(), &tm) };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// Without `strftime`, the time is shown as seconds since the epoch.
+#[cfg(not(unix))]
+pub fn format_time(time: u64, _format: &str) -> String {
+    format!("{} ", time)
+}
+
+/// Joins the lines of a command typed over several lines into one, as
+/// bash's `cmdhist` does: with `; `, or with a space after a word such as
+/// `do` or `{` that `;` may not follow. Newlines inside quotes are kept, as
+/// is the newline ending a comment, which would otherwise swallow the rest.
+/// A command with a here-document is kept as it is, its body being lines.
+pub fn join_lines(command: &str) -> String {
+    if crate::syntax::has_here_docs(command) {
+        return command.to_string();
+    }
+    let mut joined = String::with_capacity(command.len());
+    let mut quote = None;
+    let mut indent = false;
+    let mut comment = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if indent && quote.is_none() && c.is_whitespace() {
+            continue;
+        }
+        indent = false;
+        if comment {
+            joined.push(c);
+            comment = c != '\n';
+            indent = !comment;
+            continue;
+        }
+        match (quote, c) {
+            (None, '#') if joined.is_empty() || joined.ends_with(|c: char| c.is_whitespace() || ";|&()".contains(c)) => {
+                comment = true;
+                joined.push(c);
+            }
+            (None, '\n') => {
+                let before = joined.trim_end();
+                let last_word = before.rsplit(char::is_whitespace).next().unwrap_or("");
+                let separator = if before.is_empty() {
+                    ""
+                } else if before.ends_with([';', '|', '&', '(', '{']) || matches!(last_word, "do" | "then" | "else" | "in") {
+                    " "
+                } else {
+                    "; "
+                };
+                joined.truncate(before.len());
+                joined.push_str(separator);
+                indent = true;
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                joined.push(c);
+            }
+            (Some(open), _) if c == open => {
+                quote = None;
+                joined.push(c);
+            }
+            (None | Some('"'), '\\') => {
+                joined.push(c);
+                joined.extend(chars.next());
+            }
+            _ => joined.push(c),
+        }
+    }
+    joined
+}
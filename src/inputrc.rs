@@ -0,0 +1,155 @@
+// Line-editing settings, read from `~/.myshell_inputrc` at startup and changed
+// at run time by `set -o` and `bind`. The file takes readline's `set name
+// value` lines for the handful of variables the shell understands. Before
+// any of that, the editing mode follows the user's editor.
+
+use rustyline::config::{BellStyle, Configurer, EditMode};
+
+/// The settings file, relative to `HOME`.
+pub const INPUTRC_FILE: &str = ".myshell_inputrc";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditSettings {
+    pub editing_mode: EditMode,
+    pub completion_ignore_case: bool,
+    pub bell_style: BellStyle,
+    /// How many entries the history keeps unless `HISTSIZE` says; 500, as
+    /// in bash.
+    pub history_size: usize,
+    /// List every candidate on the first Tab, instead of ringing the bell
+    /// and waiting for a second.
+    pub show_all_if_ambiguous: bool,
+    /// Follow builtin, function and alias candidates in completion listings
+    /// with their kind, as in `cd  (builtin)`. Off suits plain terminals.
+    pub completion_descriptions: bool,
+    /// `set -o complete-options`: complete the `--long` options of external
+    /// commands from their `--help` output. Not an inputrc variable.
+    pub complete_options: bool,
+}
+
+impl Default for EditSettings {
+    fn default() -> Self {
+        EditSettings {
+            editing_mode: EditMode::Emacs,
+            completion_ignore_case: false,
+            bell_style: BellStyle::Audible,
+            history_size: 500,
+            show_all_if_ambiguous: false,
+            completion_descriptions: true,
+            complete_options: false,
+        }
+    }
+}
+
+impl EditSettings {
+    /// Starts in vi mode if `editor`, the command in `VISUAL` or `EDITOR`,
+    /// runs a vi such as `vim` or `/usr/bin/nvim`, as zsh does. Any other
+    /// editor leaves the mode as it is.
+    pub fn follow_editor(&mut self, editor: &str) {
+        let program = editor.split_whitespace().next().unwrap_or_default();
+        let name = program.rsplit(['/', '\\']).next().unwrap_or_default();
+        if name.contains("vi") {
+            self.editing_mode = EditMode::Vi;
+        }
+    }
+
+    /// Applies the `set` lines of an inputrc file, returning a warning for
+    /// each line that could not be used. Blank lines and `#` comments are
+    /// skipped.
+    pub fn load(&mut self, text: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.apply_line(line) {
+                warnings.push(format!("{}: line {}: {}", INPUTRC_FILE, number + 1, e));
+            }
+        }
+        warnings
+    }
+
+    /// Applies one `set name value` line.
+    pub fn apply_line(&mut self, line: &str) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("set") {
+            return Err(format!("{}: expected `set name value'", line));
+        }
+        match (words.next(), words.next()) {
+            (Some(name), Some(value)) => self.set(name, value),
+            _ => Err(format!("{}: expected `set name value'", line)),
+        }
+    }
+
+    /// Sets the variable `name` from its inputrc spelling.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("{}: invalid value for {}", value, name);
+        let on_off = |value: &str| match value.to_ascii_lowercase().as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(invalid()),
+        };
+        match name {
+            "editing-mode" => {
+                self.editing_mode = match value {
+                    "emacs" => EditMode::Emacs,
+                    "vi" => EditMode::Vi,
+                    _ => return Err(invalid()),
+                }
+            }
+            "completion-ignore-case" => self.completion_ignore_case = on_off(value)?,
+            "bell-style" => {
+                self.bell_style = match value {
+                    "none" => BellStyle::None,
+                    "audible" => BellStyle::Audible,
+                    "visible" => BellStyle::Visible,
+                    _ => return Err(invalid()),
+                }
+            }
+            "history-size" => self.history_size = value.parse().map_err(|_| invalid())?,
+            "show-all-if-ambiguous" => self.show_all_if_ambiguous = on_off(value)?,
+            "completion-descriptions" => self.completion_descriptions = on_off(value)?,
+            _ => return Err(format!("{}: unknown variable name", name)),
+        }
+        Ok(())
+    }
+
+    /// The settings as `set` lines, in the form `bind -v` prints.
+    pub fn describe(&self) -> String {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let mode = if self.editing_mode == EditMode::Vi { "vi" } else { "emacs" };
+        let bell = match self.bell_style {
+            BellStyle::None => "none",
+            BellStyle::Audible => "audible",
+            BellStyle::Visible => "visible",
+        };
+        format!(
+            "set bell-style {}\nset completion-descriptions {}\nset completion-ignore-case {}\nset editing-mode {}\nset history-size {}\nset show-all-if-ambiguous {}\n",
+            bell,
+            on_off(self.completion_descriptions),
+            on_off(self.completion_ignore_case),
+            mode,
+            self.history_size,
+            on_off(self.show_all_if_ambiguous),
+        )
+    }
+
+    /// Configures the line editor to match. Duplicates are kept so that the
+    /// editor's history lines up entry for entry with the shell's.
+    pub fn apply_to(&self, editor: &mut impl Configurer) -> rustyline::Result<()> {
+        editor.set_edit_mode(self.editing_mode);
+        editor.set_bell_style(self.bell_style);
+        editor.set_history_ignore_dups(false)?;
+        editor.set_max_history_size(self.history_size)
+    }
+
+    /// Whether the candidate `name` completes `prefix`.
+    pub fn completes(&self, name: &str, prefix: &str) -> bool {
+        if self.completion_ignore_case {
+            name.to_lowercase().starts_with(&prefix.to_lowercase())
+        } else {
+            name.starts_with(prefix)
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Resource limits, read and changed by the `ulimit` builtin. A limit set in
+// the shell is inherited by every command it spawns afterwards. Values are
+// in the limit's own unit (512-byte blocks, kilobytes, seconds or a count);
+// `None` means unlimited.
+
+use std::io;
+
+/// A limit `ulimit` knows, by its option letter.
+pub struct Limit {
+    pub option: char,
+    pub description: &'static str,
+    /// Shown before the option in `ulimit -a`, as in `(blocks, -f)`.
+    pub unit: &'static str,
+    /// Bytes per unit, or 1 for limits that are not sizes.
+    factor: u64,
+}
+
+/// The limits in `ulimit -a` order.
+pub const LIMITS: &[Limit] = &[
+    Limit { option: 'c', description: "core file size", unit: "blocks", factor: 512 },
+    Limit { option: 'd', description: "data seg size", unit: "kbytes", factor: 1024 },
+    Limit { option: 'f', description: "file size", unit: "blocks", factor: 512 },
+    Limit { option: 'n', description: "open files", unit: "", factor: 1 },
+    Limit { option: 's', description: "stack size", unit: "kbytes", factor: 1024 },
+    Limit { option: 't', description: "cpu time", unit: "seconds", factor: 1 },
+    Limit { option: 'u', description: "max user processes", unit: "", factor: 1 },
+    Limit { option: 'v', description: "virtual memory", unit: "kbytes", factor: 1024 },
+];
+
+pub fn find(option: char) -> Option<&'static Limit> {
+    LIMITS.iter().find(|limit| limit.option == option)
+}
+
+/// Which of a limit's two values to change. The soft limit is the one
+/// enforced; the hard limit caps it, and can only be raised by root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Which {
+    Soft,
+    Hard,
+    Both,
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+
+    use super::{Limit, Which};
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    type Resource = libc::c_int;
+
+    fn resource(limit: &Limit) -> Resource {
+        match limit.option {
+            'c' => libc::RLIMIT_CORE,
+            'd' => libc::RLIMIT_DATA,
+            'f' => libc::RLIMIT_FSIZE,
+            'n' => libc::RLIMIT_NOFILE,
+            's' => libc::RLIMIT_STACK,
+            't' => libc::RLIMIT_CPU,
+            'u' => libc::RLIMIT_NPROC,
+            'v' => libc::RLIMIT_AS,
+            option => unreachable!("no resource for -{}", option),
+        }
+    }
+
+    fn to_units(value: libc::rlim_t, limit: &Limit) -> Option<u64> {
+        (value != libc::RLIM_INFINITY).then(|| value / limit.factor)
+    }
+
+    fn getrlimit(limit: &Limit) -> io::Result<libc::rlimit> {
+        let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: `getrlimit` only writes the struct it is given.
+        if unsafe { libc::getrlimit(resource(limit), &mut current) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(current)
+    }
+
+    pub fn get(limit: &Limit) -> io::Result<(Option<u64>, Option<u64>)> {
+        let current = getrlimit(limit)?;
+        Ok((to_units(current.rlim_cur, limit), to_units(current.rlim_max, limit)))
+    }
+
+    pub fn set(limit: &Limit, which: Which, value: Option<u64>) -> io::Result<()> {
+        let raw = match value {
+            Some(value) => value
+                .checked_mul(limit.factor)
+                .filter(|&bytes| bytes != libc::RLIM_INFINITY)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "limit out of range"))?,
+            None => libc::RLIM_INFINITY,
+        };
+        let mut current = getrlimit(limit)?;
+        if which != Which::Hard {
+            current.rlim_cur = raw;
+        }
+        if which != Which::Soft {
+            current.rlim_max = raw;
+        }
+        // SAFETY: `current` is a fully initialised `rlimit`.
+        if unsafe { libc::setrlimit(resource(limit), &current) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+
+    use super::{Limit, Which};
+
+    pub fn get(_limit: &Limit) -> io::Result<(Option<u64>, Option<u64>)> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn set(_limit: &Limit, _which: Which, _value: Option<u64>) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// The soft and hard values of `limit`.
+pub fn get(limit: &Limit) -> io::Result<(Option<u64>, Option<u64>)> {
+    imp::get(limit)
+}
+
+/// Changes `limit`, keeping whichever value `which` leaves alone.
+pub fn set(limit: &Limit, which: Which, value: Option<u64>) -> io::Result<()> {
+    imp::set(limit, which, value)
+}
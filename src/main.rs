@@ -1,20 +1,65 @@
+use std::borrow::Cow;
 #[allow(unused_imports)]
 use std::env;
 
+mod arghistory;
+mod arith;
+mod brace;
+mod builtins;
+mod commandindex;
+mod complete;
+mod cond;
+mod direnv;
+mod expand;
+mod fd;
+mod glob;
+mod helpopts;
+mod history;
+mod inputrc;
+mod limits;
+mod options;
+mod pathindex;
+mod pipe;
+mod paths;
+mod printf;
+mod stdio;
+mod syntax;
+mod terminal;
+mod theme;
+#[cfg(test)]
+mod test_support;
 #[cfg(test)]
 mod tests;
 
-use std::io::Write;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::Stdio;
+use std::rc::Rc;
 #[cfg(target_family = "unix")]
 use std::os::unix::fs::PermissionsExt;
 use std::sync::{Arc, Mutex};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
+use std::time::Instant;
+
+use builtins::{AliasCommand, BindCommand, BreakCommand, CdCommand, CompgenCommand, DeclareCommand, EnvAllowCommand, ExportCommand, HashCommand, HelpCommand, HistoryCommand, PrintfCommand, ReadCommand, EchoCommand, EnableCommand, ExitCommand, LetCommand, PwdCommand, SetCommand, SourceCommand, StatusCommand, TypeCommand, UlimitCommand, UnaliasCommand, UnsetCommand};
+use arghistory::ArgHistory;
+use commandindex::CommandIndex;
+use complete::CompletionResult;
+use expand::{Environment, Expansion, ExpansionError, Variables};
+use inputrc::EditSettings;
+use options::{Mode, StartupFile};
+use syntax::{CaseArm, HereDoc, Node, Stage};
+use theme::Theme;
 
 use rustyline::completion::{Completer, Pair};
+use rustyline::config::{BellStyle, CompletionType, Configurer};
 use rustyline::error::ReadlineError;
-use rustyline::{Context, Editor, Result, EventHandler, ConditionalEventHandler, Event, EventContext, RepeatCount, Cmd, KeyCode, KeyEvent, Modifiers};
-use rustyline_derive::{Helper, Highlighter, Hinter, Validator};
+use rustyline::highlight::{CmdKind, Highlighter};
+use rustyline::{Context, Editor, Result, EventHandler, ConditionalEventHandler, Event, EventContext, RepeatCount, Cmd, KeyCode, KeyEvent, Modifiers, Movement};
+use rustyline_derive::{Helper, Hinter, Validator};
 
 // --- Domain Objects ---
 
@@ -34,8 +79,65 @@ impl Argument {
 pub trait Redirection: std::fmt::Debug {
     fn target(&self) -> &str;
     fn mode_name(&self) -> &str; // e.g. "1>", "2>>"
-    fn apply(&self, cmd: &mut std::process::Command) -> std::io::Result<()>;
-    fn print(&self, stdout: &str, stderr: &str) -> std::io::Result<()>;
+    /// The descriptor being redirected: 1 for stdout, 2 for stderr, or any
+    /// other.
+    fn fd(&self) -> i32;
+    /// Opens the target file, resolving a relative path against `cwd`.
+    fn open(&self, cwd: &Path) -> std::io::Result<File>;
+    /// Points the redirected descriptor at the target, on top of whatever
+    /// earlier redirections on the line did to `streams`.
+    fn apply(&self, streams: &mut Streams, cwd: &Path) -> std::result::Result<(), RedirectError> {
+        let file = self.open(cwd).map_err(|error| RedirectError { target: self.target().to_string(), error })?;
+        streams.set(self.fd(), Output::File(Rc::new(file)));
+        Ok(())
+    }
+}
+
+/// A redirection target that could not be opened.
+#[derive(Debug)]
+pub struct RedirectError {
+    pub target: String,
+    pub error: std::io::Error,
+}
+
+/// Where a command's stdout and stderr go once its redirections are applied.
+#[derive(Clone, Debug)]
+pub struct Streams {
+    pub stdout: Output,
+    pub stderr: Output,
+    /// Other descriptors opened by redirections such as `3>file`, which only
+    /// external commands see.
+    pub others: Vec<(i32, Output)>,
+}
+
+impl Streams {
+    /// Where `fd` points, or `None` if it is not open.
+    pub fn get(&self, fd: i32) -> Option<&Output> {
+        match fd {
+            1 => Some(&self.stdout),
+            2 => Some(&self.stderr),
+            _ => self.others.iter().find(|(other, _)| *other == fd).map(|(_, output)| output),
+        }
+    }
+
+    pub fn set(&mut self, fd: i32, output: Output) {
+        match fd {
+            1 => self.stdout = output,
+            2 => self.stderr = output,
+            _ => match self.others.iter_mut().find(|(other, _)| *other == fd) {
+                Some(slot) => slot.1 = output,
+                None => self.others.push((fd, output)),
+            },
+        }
+    }
+
+    /// Closes `fd`, as `N>&-` does.
+    pub fn close(&mut self, fd: i32) {
+        match fd {
+            1 | 2 => self.set(fd, Output::Closed),
+            _ => self.others.retain(|(other, _)| *other != fd),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -51,15 +153,9 @@ impl StdoutRedirect {
 impl Redirection for StdoutRedirect {
     fn target(&self) -> &str { &self.target }
     fn mode_name(&self) -> &str { Self::OPERATOR }
-    fn apply(&self, cmd: &mut std::process::Command) -> std::io::Result<()> {
-        let file = File::create(&self.target)?;
-        cmd.stdout(file);
-        Ok(())
-    }
-    fn print(&self, stdout: &str, stderr: &str) -> std::io::Result<()> {
-        let mut file = File::create(&self.target)?;
-        eprint!("{}", stderr);
-        write!(file, "{}", stdout)
+    fn fd(&self) -> i32 { 1 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        File::create(cwd.join(&self.target))
     }
 }
 
@@ -75,15 +171,9 @@ impl StderrRedirect {
 impl Redirection for StderrRedirect {
     fn target(&self) -> &str { &self.target }
     fn mode_name(&self) -> &str { Self::OPERATOR }
-    fn apply(&self, cmd: &mut std::process::Command) -> std::io::Result<()> {
-        let file = File::create(&self.target)?;
-        cmd.stderr(file);
-        Ok(())
-    }
-    fn print(&self, stdout: &str, stderr: &str) -> std::io::Result<()> {
-        let mut file = File::create(&self.target)?;
-        print!("{}", stdout);
-        write!(file, "{}", stderr)
+    fn fd(&self) -> i32 { 2 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        File::create(cwd.join(&self.target))
     }
 }
 
@@ -100,15 +190,9 @@ impl StdoutAppendRedirect {
 impl Redirection for StdoutAppendRedirect {
     fn target(&self) -> &str { &self.target }
     fn mode_name(&self) -> &str { Self::OPERATOR }
-    fn apply(&self, cmd: &mut std::process::Command) -> std::io::Result<()> {
-        let file = OpenOptions::new().create(true).write(true).append(true).open(&self.target)?;
-        cmd.stdout(file);
-        Ok(())
-    }
-    fn print(&self, stdout: &str, stderr: &str) -> std::io::Result<()> {
-        let mut file = OpenOptions::new().create(true).write(true).append(true).open(&self.target)?;
-        eprint!("{}", stderr);
-        write!(file, "{}", stdout)
+    fn fd(&self) -> i32 { 1 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(cwd.join(&self.target))
     }
 }
 
@@ -124,101 +208,533 @@ impl StderrAppendRedirect {
 impl Redirection for StderrAppendRedirect {
     fn target(&self) -> &str { &self.target }
     fn mode_name(&self) -> &str { Self::OPERATOR }
-    fn apply(&self, cmd: &mut std::process::Command) -> std::io::Result<()> {
-        let file = OpenOptions::new().create(true).write(true).append(true).open(&self.target)?;
-        cmd.stderr(file);
+    fn fd(&self) -> i32 { 2 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(cwd.join(&self.target))
+    }
+}
+
+/// `N> file` or `N>> file` for a descriptor other than stdout and stderr.
+#[derive(Debug)]
+pub struct FdRedirect {
+    pub fd: i32,
+    pub target: String,
+    pub append: bool,
+}
+
+impl Redirection for FdRedirect {
+    fn target(&self) -> &str { &self.target }
+    fn mode_name(&self) -> &str { if self.append { ">>" } else { ">" } }
+    fn fd(&self) -> i32 { self.fd }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).write(true).append(self.append).truncate(!self.append).open(cwd.join(&self.target))
+    }
+}
+
+/// `&> file`: stdout and stderr both go to one truncated file.
+#[derive(Debug)]
+pub struct BothRedirect {
+    pub target: String,
+}
+
+impl BothRedirect {
+    pub const OPERATOR: &'static str = "&>";
+}
+
+impl Redirection for BothRedirect {
+    fn target(&self) -> &str { &self.target }
+    fn mode_name(&self) -> &str { Self::OPERATOR }
+    fn fd(&self) -> i32 { 1 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        File::create(cwd.join(&self.target))
+    }
+    fn apply(&self, streams: &mut Streams, cwd: &Path) -> std::result::Result<(), RedirectError> {
+        let file = self.open(cwd).map_err(|error| RedirectError { target: self.target.clone(), error })?;
+        let file = Output::File(Rc::new(file));
+        streams.stdout = file.clone();
+        streams.stderr = file;
+        Ok(())
+    }
+}
+
+/// `&>> file`: stdout and stderr are both appended to one file.
+#[derive(Debug)]
+pub struct BothAppendRedirect {
+    pub target: String,
+}
+
+impl BothAppendRedirect {
+    pub const OPERATOR: &'static str = "&>>";
+}
+
+impl Redirection for BothAppendRedirect {
+    fn target(&self) -> &str { &self.target }
+    fn mode_name(&self) -> &str { Self::OPERATOR }
+    fn fd(&self) -> i32 { 1 }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(cwd.join(&self.target))
+    }
+    fn apply(&self, streams: &mut Streams, cwd: &Path) -> std::result::Result<(), RedirectError> {
+        let file = self.open(cwd).map_err(|error| RedirectError { target: self.target.clone(), error })?;
+        let file = Output::File(Rc::new(file));
+        streams.stdout = file.clone();
+        streams.stderr = file;
+        Ok(())
+    }
+}
+
+/// `N>&M`: descriptor `fd` becomes a copy of whatever `target` points at
+/// when the redirection is reached, so `> f 2>&1` sends both to `f` while
+/// `2>&1 > f` leaves stderr where stdout was.
+#[derive(Debug)]
+pub struct DupRedirect {
+    pub fd: i32,
+    /// The number of the descriptor copied.
+    pub target: String,
+}
+
+impl Redirection for DupRedirect {
+    fn target(&self) -> &str { &self.target }
+    fn mode_name(&self) -> &str {
+        match self.fd {
+            1 => "1>&",
+            2 => "2>&",
+            _ => ">&",
+        }
+    }
+    fn fd(&self) -> i32 { self.fd }
+    fn open(&self, _cwd: &Path) -> std::io::Result<File> {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"))
+    }
+    fn apply(&self, streams: &mut Streams, _cwd: &Path) -> std::result::Result<(), RedirectError> {
+        let Some(source) = self.target.parse().ok().and_then(|fd| streams.get(fd)).cloned() else {
+            let error = std::io::Error::other("Bad file descriptor");
+            return Err(RedirectError { target: self.target.clone(), error });
+        };
+        streams.set(self.fd, source);
         Ok(())
     }
-    fn print(&self, stdout: &str, stderr: &str) -> std::io::Result<()> {
-        let mut file = OpenOptions::new().create(true).write(true).append(true).open(&self.target)?;
-        print!("{}", stdout);
-        write!(file, "{}", stderr)
+}
+
+/// `N>&-`: closes descriptor `fd`.
+#[derive(Debug)]
+pub struct CloseRedirect {
+    pub fd: i32,
+}
+
+impl Redirection for CloseRedirect {
+    fn target(&self) -> &str { "-" }
+    fn mode_name(&self) -> &str { ">&" }
+    fn fd(&self) -> i32 { self.fd }
+    fn open(&self, _cwd: &Path) -> std::io::Result<File> {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"))
+    }
+    fn apply(&self, streams: &mut Streams, _cwd: &Path) -> std::result::Result<(), RedirectError> {
+        streams.close(self.fd);
+        Ok(())
     }
 }
 
+/// Several redirections on one command, applied left to right.
 #[derive(Debug)]
+pub struct RedirectionList {
+    pub redirections: Vec<Box<dyn Redirection>>,
+}
+
+impl RedirectionList {
+    fn last(&self) -> &dyn Redirection {
+        self.redirections.last().expect("a redirection list is never empty").as_ref()
+    }
+}
+
+impl Redirection for RedirectionList {
+    fn target(&self) -> &str { self.last().target() }
+    fn mode_name(&self) -> &str { self.last().mode_name() }
+    fn fd(&self) -> i32 { self.last().fd() }
+    fn open(&self, cwd: &Path) -> std::io::Result<File> {
+        self.last().open(cwd)
+    }
+    fn apply(&self, streams: &mut Streams, cwd: &Path) -> std::result::Result<(), RedirectError> {
+        self.redirections.iter().try_for_each(|r| r.apply(streams, cwd))
+    }
+}
+
+/// A `NAME=value` word, already expanded.
+pub type Assignment = (String, String);
+
+#[derive(Debug, Default)]
 pub struct CommandLine {
+    /// `NAME=value` words preceding the command. With no command they set
+    /// shell variables; otherwise they apply only to that command.
+    pub assignments: Vec<Assignment>,
     pub command: String,
     pub args: Vec<Argument>,
     pub redirection: Option<Box<dyn Redirection>>,
+    /// What the command reads as its stdin, from `< file`, `<<< word` or a
+    /// here-document.
+    pub input: Option<InputSource>,
+    /// Inputs for other descriptors, from `3< file` or `3<<< word`. Only
+    /// external commands see them.
+    pub fd_inputs: Vec<(i32, InputSource)>,
+}
+
+/// Where an input redirection reads from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputSource {
+    /// `< file`.
+    File(String),
+    /// `<<< word`: the expanded word, read with a newline after it.
+    HereString(String),
+    /// `<<WORD`: the here-document's body, expanded unless WORD was quoted.
+    HereDoc(String),
+}
+
+impl InputSource {
+    /// Opens the source for reading, resolving a file against `cwd`.
+    fn open(&self, cwd: &Path) -> std::io::Result<Input> {
+        match self {
+            InputSource::File(target) => {
+                let file = File::open(cwd.join(target))?;
+                Ok(Input::File(Rc::new(RefCell::new(BufReader::new(file)))))
+            }
+            InputSource::HereString(text) => Ok(Input::Buffer(Rc::new(RefCell::new(format!("{}\n", text).into_bytes())))),
+            InputSource::HereDoc(body) => Ok(Input::Buffer(Rc::new(RefCell::new(body.clone().into_bytes())))),
+        }
+    }
+
+    /// Names the source in an error message.
+    fn name(&self) -> &str {
+        match self {
+            InputSource::File(target) => target,
+            InputSource::HereString(_) => "here-string",
+            InputSource::HereDoc(_) => "here-document",
+        }
+    }
+}
+
+/// Whether a backslash before `next` escapes it. Inside double quotes only
+/// `$`, `` ` ``, `"`, `\` and newline can be escaped. On Windows, where `\`
+/// also separates path components, an unquoted backslash escapes only a
+/// character the shell would otherwise treat specially, so `C:\Users\me`
+/// stays a path.
+fn escapes(next: char, in_double_quote: bool) -> bool {
+    if in_double_quote {
+        matches!(next, '$' | '`' | '"' | '\\' | '\n')
+    } else if cfg!(windows) {
+        next.is_whitespace() || "|&;<>()$`\\\"'*?[]{}#~=".contains(next)
+    } else {
+        true
+    }
 }
 
 impl CommandLine {
+    /// Parses `input`, expanding variables from the process environment. A line
+    /// whose expansion fails parses as an empty command.
     pub fn parse(input: &str) -> Self {
-        let input = input.trim();
-        let (command, rest) = input.split_once(' ').unwrap_or((input, ""));
-
-        let handlers: [(&str, fn(String) -> Box<dyn Redirection>); 6] = [
-            (StdoutAppendRedirect::OPERATOR, |t| Box::new(StdoutAppendRedirect { target: t })),
-            (StderrAppendRedirect::OPERATOR, |t| Box::new(StderrAppendRedirect { target: t })),
-            (StdoutAppendRedirect::DEFAULT_OPERATOR, |t| Box::new(StdoutAppendRedirect { target: t })),
-            (StdoutRedirect::OPERATOR, |t| Box::new(StdoutRedirect { target: t })),
-            (StderrRedirect::OPERATOR, |t| Box::new(StderrRedirect { target: t })),
-            (StdoutRedirect::DEFAULT_OPERATOR, |t| Box::new(StdoutRedirect { target: t })),
-        ];
+        Self::parse_with(input, &Environment).unwrap_or_default()
+    }
 
-        let (parsing_args_str, redirection) = handlers.into_iter()
-            .find_map(|(op, constructor)| {
-                rest.split_once(op).map(|(a, f)| {
-                    let target = f.trim().trim_matches(|c| c == '\'' || c == '"').to_string();
-                    (a, Some(constructor(target)))
-                })
-            })
-            .unwrap_or((rest, None));
-
-        let args = Self::parse_args_string(parsing_args_str);
-        
-        CommandLine {
-            command: command.to_string(),
-            args,
+    pub fn parse_with(input: &str, vars: &dyn Variables) -> std::result::Result<Self, ExpansionError> {
+        Self::parse_with_here_docs(input, &[], vars)
+    }
+
+    /// Parses `input` with the bodies of its here-documents, which go to its
+    /// `<<` redirections in order. A `<<` without a body reads nothing.
+    pub fn parse_with_here_docs(input: &str, here_docs: &[HereDoc], vars: &dyn Variables) -> std::result::Result<Self, ExpansionError> {
+        let mut words = Self::parse_words(input.trim(), true, here_docs, vars)?;
+        let command = if words.args.is_empty() { String::new() } else { words.args.remove(0).value };
+        let redirection = words.redirection();
+
+        Ok(CommandLine {
+            assignments: words.assignments,
+            command,
+            args: words.args,
             redirection,
-        }
+            input: words.input,
+            fd_inputs: words.fd_inputs,
+        })
     }
 
-    fn parse_args_string(args: &str) -> Vec<Argument> {
-        let mut result = Vec::new();
+    /// Expands a list of words, such as the words of `select`, without
+    /// treating any of them as assignments.
+    pub fn expand_words(raw: &str, vars: &dyn Variables) -> std::result::Result<Vec<String>, ExpansionError> {
+        let words = Self::parse_words(raw, false, &[], vars)?;
+        Ok(words.args.into_iter().map(|a| a.value).collect())
+    }
+
+    // Splits `args` into words, expanding as it goes. Redirections may appear
+    // anywhere and are collected separately, to be applied in the order
+    // written. If `allow_assignments`, leading `NAME=value` words are
+    // assignments rather than arguments. Each `<<` takes the next of
+    // `here_docs`.
+    fn parse_words(
+        args: &str,
+        allow_assignments: bool,
+        here_docs: &[HereDoc],
+        vars: &dyn Variables,
+    ) -> std::result::Result<Words, ExpansionError> {
+        let args = brace::expand_line(args, allow_assignments);
+        let mut here_docs = here_docs.iter();
+        let chars: Vec<char> = args.chars().collect();
+        let mut words = Words::default();
         let mut current_arg = String::new();
         let mut in_single_quote = false;
         let mut in_double_quote = false;
+        // Whether the current word has had anything quoted or expanded, which
+        // stops it being an assignment or starting with a tilde prefix.
+        let mut word_quoted = false;
+        // What the current word will become once finished.
+        let mut kind = WordKind::Argument;
+        // Whether a `~` here would begin a tilde prefix.
+        let mut tilde_allowed = true;
 
-        for c in args.chars() {
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let tilde_here = std::mem::replace(&mut tilde_allowed, false);
+            // An operator right after another is part of its target's text,
+            // as in `> >`; once the target has begun, it ends the target.
+            let awaiting_target =
+                matches!(kind, WordKind::RedirectTarget { .. } | WordKind::InputTarget { .. }) && current_arg.is_empty() && !word_quoted;
             if in_single_quote {
                 if c == '\'' {
                     in_single_quote = false;
                 } else {
                     current_arg.push(c);
                 }
+            } else if c == '$' && let Some((expansion, next)) = Expansion::scan(&chars, i) {
+                let value = expansion.expand(vars)?;
+                word_quoted = true;
+                if in_double_quote || kind != WordKind::Argument {
+                    current_arg.push_str(&value);
+                } else {
+                    // Unquoted expansions are split into separate arguments on whitespace.
+                    for c in value.chars() {
+                        if !c.is_whitespace() {
+                            current_arg.push(c);
+                        } else if !current_arg.is_empty() {
+                            words.finish(&mut current_arg, &mut kind);
+                        }
+                    }
+                }
+                i = next;
+                continue;
+            } else if c == '\\' && i + 1 < chars.len() && escapes(chars[i + 1], in_double_quote) {
+                // An escaped character is taken literally, so `\>` is not a
+                // redirection; an escaped newline joins the lines.
+                i += 1;
+                if chars[i] != '\n' {
+                    current_arg.push(chars[i]);
+                }
+                word_quoted = true;
             } else if in_double_quote {
                 if c == '"' {
                     in_double_quote = false;
-                } else if c == '\\' {
-                    current_arg.push(c);
                 } else {
                     current_arg.push(c);
                 }
+            } else if c == '~'
+                && tilde_here
+                && let Some((value, next)) = Self::tilde_prefix(&chars, i, matches!(kind, WordKind::Assignment(_)), vars)
+            {
+                current_arg.push_str(&value);
+                i = next;
+                continue;
+            } else if c == '\'' {
+                in_single_quote = true;
+                word_quoted = true;
+            } else if c == '"' {
+                in_double_quote = true;
+                word_quoted = true;
+            } else if c.is_whitespace() {
+                if !current_arg.is_empty() || kind != WordKind::Argument {
+                    words.finish(&mut current_arg, &mut kind);
+                }
+                word_quoted = false;
+                tilde_allowed = true;
+            } else if c == '<' && !awaiting_target {
+                // Digits right before the operator name the descriptor.
+                let explicit_fd = !word_quoted
+                    && kind == WordKind::Argument
+                    && !current_arg.is_empty()
+                    && current_arg.chars().all(|c| c.is_ascii_digit());
+                let fd = if explicit_fd { current_arg.parse().unwrap_or(i32::MAX) } else { 0 };
+                if explicit_fd {
+                    current_arg.clear();
+                } else if !current_arg.is_empty() || kind != WordKind::Argument {
+                    words.finish(&mut current_arg, &mut kind);
+                }
+                let source = if chars.get(i + 1) != Some(&'<') {
+                    i += 1;
+                    InputKind::File
+                } else if chars.get(i + 2) == Some(&'<') {
+                    i += 3;
+                    InputKind::HereString
+                } else {
+                    // The word after `<<` or `<<-` is the delimiter, which the
+                    // parser has already used to find the body.
+                    i += if chars.get(i + 2) == Some(&'-') { 3 } else { 2 };
+                    let body = match here_docs.next() {
+                        Some(doc) if doc.expand => expand::expand_here_doc(&doc.body, vars)?,
+                        Some(doc) => doc.body.clone(),
+                        None => String::new(),
+                    };
+                    InputKind::HereDoc(body)
+                };
+                while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+                    i += 1;
+                }
+                kind = WordKind::InputTarget { fd, source };
+                word_quoted = false;
+                tilde_allowed = true;
+                continue;
+            } else if (c == '>' || (c == '&' && chars.get(i + 1) == Some(&'>'))) && !awaiting_target {
+                // Digits right before the operator name the descriptor; `&>`
+                // names both stdout and stderr.
+                let explicit_fd = c == '>'
+                    && !word_quoted
+                    && kind == WordKind::Argument
+                    && !current_arg.is_empty()
+                    && current_arg.chars().all(|c| c.is_ascii_digit());
+                let fd = if c == '&' {
+                    i += 1;
+                    None
+                } else if explicit_fd {
+                    Some(current_arg.parse().unwrap_or(i32::MAX))
+                } else {
+                    Some(1)
+                };
+                if explicit_fd {
+                    current_arg.clear();
+                } else if !current_arg.is_empty() || kind != WordKind::Argument {
+                    words.finish(&mut current_arg, &mut kind);
+                }
+                let append = chars.get(i + 1) == Some(&'>');
+                i += if append { 2 } else { 1 };
+                // `>&2` copies a descriptor rather than naming a file.
+                let dup = fd.is_some() && !append && chars.get(i) == Some(&'&');
+                if dup {
+                    i += 1;
+                }
+                while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+                    i += 1;
+                }
+                kind = WordKind::RedirectTarget { fd, append, dup };
+                word_quoted = false;
+                tilde_allowed = true;
+                continue;
+            } else if c == '='
+                && allow_assignments
+                && !word_quoted
+                && kind == WordKind::Argument
+                && words.args.is_empty()
+                && expand::is_name(&current_arg)
+            {
+                kind = WordKind::Assignment(std::mem::take(&mut current_arg));
+                tilde_allowed = true;
             } else {
-                if c == '\'' {
-                    in_single_quote = true;
-                } else if c == '"' {
-                    in_double_quote = true;
-                } else if c.is_whitespace() {
-                     if !current_arg.is_empty() {
-                         result.push(Argument::new(current_arg.clone()));
-                         current_arg.clear();
-                     }
-                } else if c == '\\' { 
-                     current_arg.push(c);
+                current_arg.push(c);
+                tilde_allowed = c == ':' && matches!(kind, WordKind::Assignment(_));
+            }
+            i += 1;
+        }
+
+        if !current_arg.is_empty() || kind != WordKind::Argument {
+            words.finish(&mut current_arg, &mut kind);
+        }
+
+        Ok(words)
+    }
+
+    // Expands the tilde prefix starting at `chars[start] == '~'`, returning its
+    // value and the index just past it. The prefix runs to the next `/` (or
+    // `:` inside an assignment); prefixes that are quoted, unknown or refer to
+    // an unset variable are left as written.
+    fn tilde_prefix(chars: &[char], start: usize, in_assignment: bool, vars: &dyn Variables) -> Option<(String, usize)> {
+        let end = (start + 1..chars.len())
+            .find(|&j| chars[j] == '/' || chars[j].is_whitespace() || (in_assignment && chars[j] == ':'))
+            .unwrap_or(chars.len());
+        let prefix: String = chars[start + 1..end].iter().collect();
+        expand::tilde(&prefix, vars).map(|value| (value, end))
+    }
+}
+
+// What a word being parsed will become.
+#[derive(Debug, PartialEq, Eq)]
+enum WordKind {
+    Argument,
+    Assignment(String),
+    /// `fd` is `None` for `&>`, which redirects both stdout and stderr.
+    RedirectTarget { fd: Option<i32>, append: bool, dup: bool },
+    InputTarget { fd: i32, source: InputKind },
+}
+
+// Which input redirection a target follows.
+#[derive(Debug, PartialEq, Eq)]
+enum InputKind {
+    File,
+    HereString,
+    /// The body of the here-document, ready to read.
+    HereDoc(String),
+}
+
+#[derive(Default)]
+struct Words {
+    assignments: Vec<Assignment>,
+    args: Vec<Argument>,
+    redirections: Vec<Box<dyn Redirection>>,
+    input: Option<InputSource>,
+    fd_inputs: Vec<(i32, InputSource)>,
+}
+
+impl Words {
+    // Files the finished word `text` according to `kind`, resetting both.
+    fn finish(&mut self, text: &mut String, kind: &mut WordKind) {
+        let text = std::mem::take(text);
+        match std::mem::replace(kind, WordKind::Argument) {
+            WordKind::Argument => self.args.push(Argument::new(text)),
+            WordKind::Assignment(name) => self.assignments.push((name, text)),
+            WordKind::RedirectTarget { fd, append, dup } => {
+                let redirection: Box<dyn Redirection> = match (fd, append) {
+                    // `>&word` with a word that is not a descriptor means `&>word`.
+                    (Some(fd), _) if dup && !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) => {
+                        Box::new(DupRedirect { fd, target: text })
+                    }
+                    (Some(fd), _) if dup && text == "-" => Box::new(CloseRedirect { fd }),
+                    (Some(_), _) if dup => Box::new(BothRedirect { target: text }),
+                    (None, false) => Box::new(BothRedirect { target: text }),
+                    (None, true) => Box::new(BothAppendRedirect { target: text }),
+                    (Some(1), false) => Box::new(StdoutRedirect { target: text }),
+                    (Some(1), true) => Box::new(StdoutAppendRedirect { target: text }),
+                    (Some(2), false) => Box::new(StderrRedirect { target: text }),
+                    (Some(2), true) => Box::new(StderrAppendRedirect { target: text }),
+                    (Some(fd), append) => Box::new(FdRedirect { fd, target: text, append }),
+                };
+                self.redirections.push(redirection);
+            }
+            WordKind::InputTarget { fd, source } => {
+                let source = match source {
+                    InputKind::File => InputSource::File(text),
+                    InputKind::HereString => InputSource::HereString(text),
+                    InputKind::HereDoc(body) => InputSource::HereDoc(body),
+                };
+                if fd == 0 {
+                    self.input = Some(source);
                 } else {
-                    current_arg.push(c);
+                    self.fd_inputs.retain(|(earlier, _)| *earlier != fd);
+                    self.fd_inputs.push((fd, source));
                 }
             }
         }
-        
-        if !current_arg.is_empty() {
-            result.push(Argument::new(current_arg));
+    }
+
+    // The redirections as one, wrapped in a list only when there are several.
+    fn redirection(&mut self) -> Option<Box<dyn Redirection>> {
+        match self.redirections.len() {
+            0 => None,
+            1 => self.redirections.pop(),
+            _ => Some(Box::new(RedirectionList { redirections: std::mem::take(&mut self.redirections) })),
         }
-        
-        result
     }
 }
 
@@ -226,315 +742,2070 @@ impl CommandLine {
 
 pub trait Command {
     fn name(&self) -> &str;
-    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> bool;
+    /// Runs the command and returns its exit status.
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32;
 }
 
-pub struct ExitCommand;
-impl Command for ExitCommand {
-    fn name(&self) -> &str { "exit" }
-    fn execute(&self, _args: &[Argument], _redirection: Option<&dyn Redirection>, _shell: &Shell) -> bool {
-        false
-    }
+pub struct ExternalCommand {
+    name: String,
+    env: Vec<Assignment>,
+    /// Whether the command is a stage of a pipeline other than the last,
+    /// which is left running while the next stage starts.
+    in_pipeline: bool,
 }
 
-pub struct EchoCommand;
-impl Command for EchoCommand {
-    fn name(&self) -> &str { "echo" }
-    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, _shell: &Shell) -> bool {
-        let output = args.iter().map(|a| a.value.as_str()).collect::<Vec<&str>>().join(" ") + "\n";
-        CommandOutput::write(&output, "", redirection);
-        true
-    }
-}
+impl Command for ExternalCommand {
+    fn name(&self) -> &str { &self.name }
+    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> i32 {
+        // With `set -o autocd`, a directory named on its own is entered.
+        if shell.autocd.get() && args.is_empty() && shell.cwd().join(&self.name).is_dir() {
+            return CdCommand.execute(&[Argument::new(self.name.clone())], redirection, shell);
+        }
+        let Some(full_path) = shell.hash_command(&self.name) else {
+            if !shell.in_not_found_handler.get()
+                && let Some(handler) = shell.function("command_not_found_handle")
+            {
+                let mut handler_args = vec![Argument::new(self.name.clone())];
+                handler_args.extend_from_slice(args);
+                shell.in_not_found_handler.set(true);
+                let status = shell.call_function(&handler, &handler_args, redirection);
+                shell.in_not_found_handler.set(false);
+                return status;
+            }
+            shell.write_error(&format!("{}: command not found\n", sanitize_for_display(&self.name)));
+            return 127;
+        };
+        // Spawning a directory fails with a bare OS error, so it is caught
+        // here and reported the way bash does.
+        if full_path.is_dir() {
+            shell.write_error(&format!("myshell: {}: Is a directory\n", sanitize_for_display(&self.name)));
+            return 126;
+        }
+        let mut cmd = std::process::Command::new(&full_path);
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.arg0(&self.name);
+        }
+        cmd.args(args.iter().map(|a| &a.value));
+        // The environment is the shell's exported variables, so those unset
+        // since the shell started are not passed on.
+        cmd.env_clear();
+        cmd.envs(shell.exported_vars());
+        cmd.envs(self.env.iter().cloned());
+        if shell.sourcing_env.get() {
+            cmd.env(options::ENV_GUARD, "1");
+        }
+        cmd.current_dir(shell.cwd());
+        cmd.stdin(shell.stdin.borrow().stdio());
+        let Some(streams) = shell.redirected_streams(redirection) else {
+            return 1;
+        };
+        cmd.stdout(streams.stdout.stdio());
+        cmd.stderr(streams.stderr.stdio());
+        let attached = attach_fd_outputs(&mut cmd, &streams.others).and_then(|()| attach_fd_inputs(&mut cmd, &shell.fd_inputs.borrow()));
+        let here_strings = match attached {
+            Ok(here_strings) => here_strings,
+            Err(e) => {
+                shell.write_error(&format!("{}: {}\n", sanitize_for_display(&self.name), e));
+                return 1;
+            }
+        };
 
-pub struct TypeCommand;
-impl Command for TypeCommand {
-    fn name(&self) -> &str { "type" }
-    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> bool {
-        let mut stdout = String::new();
-        for arg in args {
-            let name = &arg.value;
-            if shell.is_builtin(name) {
-                stdout.push_str(&format!("{} is a shell builtin\n", name));
-            } else if let Some(path) = shell.find_executable_in_path(name) {
-                stdout.push_str(&format!("{} is {}\n", name, path.display()));
-            } else {
-                stdout.push_str(&format!("{}: not found\n", name));
+        let spawned = cmd.spawn().and_then(|mut child| {
+            // Buffered input is handed to the child whole; a thread writes it
+            // so a child that also fills its output pipe cannot deadlock.
+            if let (Some(mut pipe), Input::Buffer(buffer)) = (child.stdin.take(), &*shell.stdin.borrow()) {
+                let data = buffer.borrow_mut().split_off(0);
+                std::thread::spawn(move || pipe.write_all(&data));
+            }
+            for (mut pipe, data) in here_strings {
+                std::thread::spawn(move || pipe.write_all(&data));
+            }
+            let stderr = child.stderr.take().map(|mut pipe| {
+                std::thread::spawn(move || {
+                    let mut bytes = Vec::new();
+                    let _ = pipe.read_to_end(&mut bytes);
+                    bytes
+                })
+            });
+            if self.in_pipeline && child.stdout.is_none() {
+                let stderr = stderr.map(|reader| (reader, streams.stderr.clone()));
+                shell.pipe_children.borrow_mut().push(PipeChild::Command { child, stderr });
+                return Ok(None);
+            }
+            // Piped stdout is copied as it arrives rather than collected, so
+            // a capture can stop keeping it while the child runs on.
+            if let Some(mut pipe) = child.stdout.take() {
+                let mut chunk = vec![0; 64 * 1024];
+                loop {
+                    match pipe.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(n) => streams.stdout.write_bytes(&chunk[..n]),
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(_) => break,
+                    }
+                }
+            }
+            if let Some(reader) = stderr {
+                streams.stderr.write_bytes(&reader.join().unwrap_or_default());
+            }
+            child.wait().map(Some)
+        });
+        match spawned {
+            Ok(Some(status)) => exit_code(status),
+            // The pipeline waits for it.
+            Ok(None) => 0,
+            Err(e) => {
+                shell.write_error(&format!("myshell: {}: {}\n", sanitize_for_display(&self.name), io_error_text(&e)));
+                126
             }
         }
-        CommandOutput::write(&stdout, "", redirection);
-        true
     }
 }
 
-pub struct PwdCommand;
-impl Command for PwdCommand {
-    fn name(&self) -> &str { "pwd" }
-    fn execute(&self, _args: &[Argument], redirection: Option<&dyn Redirection>, _shell: &Shell) -> bool {
-        match env::current_dir() {
-            Ok(path) => CommandOutput::write(&(path.display().to_string() + "\n"), "", redirection),
-            Err(e) => CommandOutput::write("", &format!("pwd: error retrieving current directory: {}\n", e), redirection),
+/// A pipeline stage left running: an external command, with the thread
+/// collecting its stderr when that is copied to the shell's stderr rather
+/// than inherited, or a copy of the shell running a compound command or
+/// function.
+enum PipeChild {
+    Command { child: std::process::Child, stderr: Option<(std::thread::JoinHandle<Vec<u8>>, Output)> },
+    #[cfg(unix)]
+    Forked(libc::pid_t),
+}
+
+impl PipeChild {
+    fn wait(self) {
+        match self {
+            PipeChild::Command { mut child, stderr } => {
+                if let Some((reader, output)) = stderr {
+                    output.write_bytes(&reader.join().unwrap_or_default());
+                }
+                let _ = child.wait();
+            }
+            #[cfg(unix)]
+            PipeChild::Forked(pid) => {
+                let mut status = 0;
+                while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {}
+            }
         }
-        true
     }
 }
 
-pub struct CdCommand;
-impl Command for CdCommand {
-    fn name(&self) -> &str { "cd" }
-    fn execute(&self, args: &[Argument], _redirection: Option<&dyn Redirection>, _shell: &Shell) -> bool {
-        if args.len() > 1 {
-            eprint!("cd: too many arguments\n");
-        } else {
-            let target_dir = if args.is_empty() || args[0].value == "~" {
-                env::var("HOME").unwrap_or_else(|_| String::new())
-            } else {
-                args[0].value.clone()
-            };
-            if let Err(_) = env::set_current_dir(&target_dir) {
-                eprint!("cd: {}: No such file or directory\n", target_dir);
+// Connects each of `inputs` to its descriptor in the child. A here-string
+// is read through a pipe; the returned writers still have to be fed their
+// text once the child is running.
+#[cfg(unix)]
+fn attach_fd_inputs(cmd: &mut std::process::Command, inputs: &[(i32, Input)]) -> std::io::Result<Vec<(std::io::PipeWriter, Vec<u8>)>> {
+    let mut sources: Vec<(i32, std::os::fd::OwnedFd)> = Vec::new();
+    let mut here_strings = Vec::new();
+    for (fd, input) in inputs {
+        let source = match input {
+            Input::File(reader) => reader.borrow().get_ref().try_clone()?.into(),
+            Input::Buffer(buffer) => {
+                let (reader, writer) = std::io::pipe()?;
+                here_strings.push((writer, buffer.borrow().clone()));
+                reader.into()
             }
-        }
-        true
+            Input::Inherit => continue,
+        };
+        sources.push((*fd, source));
     }
+    attach_fds(cmd, sources);
+    Ok(here_strings)
 }
 
-pub struct ExternalCommand {
-    name: String,
+// Connects each of `outputs`, from redirections such as `3>file`, to its
+// descriptor in the child. One held in memory cannot be handed over, so
+// that descriptor stays closed.
+#[cfg(unix)]
+fn attach_fd_outputs(cmd: &mut std::process::Command, outputs: &[(i32, Output)]) -> std::io::Result<()> {
+    use std::os::fd::AsFd;
+
+    let mut sources = Vec::new();
+    for (fd, output) in outputs {
+        let source = match output {
+            Output::Inherit(1) => std::io::stdout().as_fd().try_clone_to_owned()?,
+            Output::Inherit(_) => std::io::stderr().as_fd().try_clone_to_owned()?,
+            Output::File(file) => file.try_clone()?.into(),
+            Output::Pipe(writer) => writer.file().try_clone()?.into(),
+            Output::Buffer(_) | Output::Capture(_) | Output::Closed => continue,
+        };
+        sources.push((*fd, source));
+    }
+    attach_fds(cmd, sources);
+    Ok(())
 }
 
-impl Command for ExternalCommand {
-    fn name(&self) -> &str { &self.name }
-    fn execute(&self, args: &[Argument], redirection: Option<&dyn Redirection>, shell: &Shell) -> bool {
-        if let Some(full_path) = shell.find_executable_in_path(&self.name) {
-            let executable = full_path.file_name().unwrap();
-            let mut cmd = std::process::Command::new(executable);
-            cmd.args(args.iter().map(|a| &a.value));
+// Makes each descriptor number in `sources` a copy of its file in the child.
+#[cfg(unix)]
+fn attach_fds(cmd: &mut std::process::Command, sources: Vec<(i32, std::os::fd::OwnedFd)>) {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
 
-            if let Some(r) = redirection {
-                if let Err(_) = r.apply(&mut cmd) {
-                    println!("{}: cannot open file for output redirection", r.target());
-                    return true;
+    if sources.is_empty() {
+        return;
+    }
+    // SAFETY: between fork and exec the closure only calls `dup2` and
+    // `fcntl`, which are async-signal-safe, and allocates nothing.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (fd, source) in &sources {
+                let source = source.as_raw_fd();
+                // A descriptor duplicated onto itself keeps its
+                // close-on-exec flag, so clear that instead.
+                let result = if source == *fd { libc::fcntl(source, libc::F_SETFD, 0) } else { libc::dup2(source, *fd) };
+                if result < 0 {
+                    return Err(std::io::Error::last_os_error());
                 }
             }
+            Ok(())
+        });
+    }
+}
 
-            match cmd.status() {
-                Ok(_) => {}, 
-                Err(e) => println!("{}: failed to execute: {}", self.name, e),
-            }
-        } else {
-            eprint!("{}: command not found\n", self.name); 
+#[cfg(not(unix))]
+fn attach_fd_inputs(_cmd: &mut std::process::Command, inputs: &[(i32, Input)]) -> std::io::Result<Vec<(std::io::PipeWriter, Vec<u8>)>> {
+    if inputs.is_empty() {
+        Ok(Vec::new())
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "cannot redirect descriptors other than stdin"))
+    }
+}
+
+#[cfg(not(unix))]
+fn attach_fd_outputs(_cmd: &mut std::process::Command, outputs: &[(i32, Output)]) -> std::io::Result<()> {
+    if outputs.is_empty() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "cannot redirect descriptors other than stdout and stderr"))
+    }
+}
+
+// A child killed by a signal reports 128 + the signal number, as in sh.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
         }
-        true
     }
+    status.code().unwrap_or(1)
 }
 
-// Helper for output handling
-struct CommandOutput;
-impl CommandOutput {
-    fn write(stdout: &str, stderr: &str, redirection: Option<&dyn Redirection>) {
-        if let Some(r) = redirection {
-            if let Err(_) = r.print(stdout, stderr) {
-                println!("{}: cannot open file for output redirection", r.target());
+/// The description of `e` as a shell prints it, such as `No such file or
+/// directory`, without the `(os error 2)` Rust appends.
+pub fn io_error_text(e: &std::io::Error) -> String {
+    let text = e.to_string();
+    match text.find(" (os error ") {
+        Some(end) => text[..end].to_string(),
+        None => text,
+    }
+}
+
+/// `text` with control characters made visible, for diagnostics that quote
+/// names the user or a filename supplied: C0 controls and DEL in caret
+/// notation (`^[`, `^J`, `^?`) and C1 controls as `\xNN`. Only messages are
+/// sanitized; commands still receive the raw text.
+pub fn sanitize_for_display(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c as u32 {
+            code @ 0..=0x1f => {
+                out.push('^');
+                out.push(char::from(code as u8 + b'@'));
             }
-        } else {
-            print!("{}", stdout);
-            eprint!("{}", stderr);
+            0x7f => out.push_str("^?"),
+            code @ 0x80..=0x9f => out.push_str(&format!("\\x{:02x}", code)),
+            _ => out.push(c),
         }
     }
+    out
 }
 
-// --- Shell ---
+/// Writes `contents` to `path`, creating it readable by its owner only, as
+/// files of what was typed should be.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
 
-pub struct Shell {
-    pub builtins: Vec<Box<dyn Command>>,
-    pub path_dirs: Vec<PathBuf>,
+/// A duration in the form `time` reports it, such as `0m1.250s`.
+pub fn format_elapsed(elapsed: std::time::Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let minutes = (secs / 60.0).floor();
+    format!("{}m{:.3}s", minutes, secs - minutes * 60.0)
 }
 
-impl Shell {
-    pub fn new() -> Self {
-        let path_env = env::var("PATH").unwrap_or_default();
-        let splitter = if cfg!(windows) { ';' } else { ':' };
-        let path_dirs: Vec<PathBuf> = path_env
-            .split(splitter)
-            .filter_map(|p| {
-                let path = PathBuf::from(p);
-                if path.is_dir() { Some(path) } else { None }
-            })
-            .collect();
-
-        let builtins: Vec<Box<dyn Command>> = vec![
-            Box::new(ExitCommand), 
-            Box::new(EchoCommand), 
-            Box::new(TypeCommand), 
-            Box::new(PwdCommand), 
-            Box::new(CdCommand)
-        ];
+/// Where the shell sends one of its output streams.
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// The shell process's own stdout (1) or stderr (2).
+    Inherit(i32),
+    /// Collected in memory, e.g. by the test harness.
+    Buffer(Rc<RefCell<Vec<u8>>>),
+    /// A redirection target, while a function's output is redirected.
+    File(Rc<File>),
+    /// The output of a command substitution.
+    Capture(Rc<RefCell<Capture>>),
+    /// The pipe to the next stage of a pipeline.
+    Pipe(Rc<pipe::PipeWriter>),
+    /// Closed by `>&-`. Writes are dropped, and a child process is given
+    /// the null device.
+    Closed,
+}
 
-        Shell {
-            builtins,
-            path_dirs,
+/// What a command substitution has captured. Past `limit` bytes, output is
+/// dropped, but still accepted so the command writing it can finish.
+#[derive(Debug)]
+pub struct Capture {
+    bytes: Vec<u8>,
+    limit: usize,
+    truncated: bool,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Self {
+        Capture { bytes: Vec::new(), limit, truncated: false }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let room = self.limit.saturating_sub(self.bytes.len());
+        if bytes.len() > room {
+            self.truncated = true;
         }
+        self.bytes.extend_from_slice(&bytes[..bytes.len().min(room)]);
     }
-    
-    pub fn with_settings(path_dirs: Vec<PathBuf>) -> Self {
-        Shell { builtins: vec![], path_dirs }
+}
+
+impl Output {
+    /// How a child process should be connected to this stream. Buffered
+    /// streams are piped and the child's output copied in once it exits.
+    fn stdio(&self) -> Stdio {
+        match self {
+            Output::Inherit(1) => Stdio::from(std::io::stdout()),
+            Output::Inherit(_) => Stdio::from(std::io::stderr()),
+            Output::Buffer(_) | Output::Capture(_) => Stdio::piped(),
+            Output::Closed => Stdio::null(),
+            Output::File(file) => file.try_clone().map_or_else(|_| Stdio::null(), Stdio::from),
+            Output::Pipe(writer) => writer.file().try_clone().map_or_else(|_| Stdio::null(), Stdio::from),
+        }
     }
 
-    pub fn is_builtin(&self, name: &str) -> bool {
-        self.builtins.iter().any(|c| c.name() == name)
+    /// Whether this is the shell's own stdout or stderr, and that is a
+    /// terminal.
+    fn is_terminal(&self) -> bool {
+        match self {
+            Output::Inherit(1) => std::io::stdout().is_terminal(),
+            Output::Inherit(_) => std::io::stderr().is_terminal(),
+            _ => false,
+        }
     }
 
-    pub fn find_executable_in_path(&self, executable: &str) -> Option<PathBuf> {
-        for path_dir in &self.path_dirs {
-            let full_path = path_dir.join(executable);
-            if let Ok(_metadata) = std::fs::metadata(&full_path) {
-                #[cfg(target_family = "unix")]
-                if _metadata.permissions().mode() & 0o111 != 0 {
-                    return Some(full_path);
-                }
-                #[cfg(target_family = "windows")]
-                return Some(full_path);
+    fn write_bytes(&self, bytes: &[u8]) {
+        match self {
+            Output::Inherit(fd) => stdio::write(*fd, bytes),
+            Output::Buffer(buffer) => buffer.borrow_mut().extend_from_slice(bytes),
+            Output::File(file) => {
+                let _ = (&**file).write_all(bytes);
             }
+            Output::Capture(capture) => capture.borrow_mut().push(bytes),
+            Output::Pipe(writer) => writer.write(bytes),
+            Output::Closed => {}
         }
-        None
     }
+}
 
-    pub fn execute(&self, cmd_line: CommandLine) -> bool {
-        if cmd_line.command.is_empty() { return true; }
-        
-        if let Some(cmd) = self.builtins.iter().find(|c| c.name() == cmd_line.command) {
-            return cmd.execute(&cmd_line.args, cmd_line.redirection.as_deref(), self);
+/// Where the shell reads its standard input from.
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// The shell process's own stdin.
+    Inherit,
+    /// Bytes not yet consumed, e.g. supplied by the test harness.
+    Buffer(Rc<RefCell<Vec<u8>>>),
+    /// A file opened by `< file`.
+    File(Rc<RefCell<BufReader<File>>>),
+}
+
+impl Input {
+    fn stdio(&self) -> Stdio {
+        match self {
+            Input::Inherit => Stdio::inherit(),
+            Input::Buffer(_) => Stdio::piped(),
+            Input::File(reader) => reader.borrow().get_ref().try_clone().map_or_else(|_| Stdio::null(), Stdio::from),
         }
-        
-        let ext_cmd = ExternalCommand { name: cmd_line.command.clone() };
-        ext_cmd.execute(&cmd_line.args, cmd_line.redirection.as_deref(), self)
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        let helper = MyHelper {
-            commands: self.builtins.iter().map(|c| c.name().to_string()).collect(),
-            path_dirs: self.path_dirs.clone(),
-        };
-
-        let tab_state = Arc::new(Mutex::new(TabState {
-            consecutive_tabs: 0,
-            last_line: String::new(),
-            last_pos: 0,
-        }));
+    /// Reads up to `count` characters, stopping at a newline, which is
+    /// consumed but not kept, and says whether it got them, or the newline,
+    /// before the end of input.
+    fn read_chars(&self, count: usize) -> (String, bool) {
+        Self::collect_chars(Some(count), || Ok(self.read_byte())).unwrap_or_default()
+    }
 
-        let tab_handler = MyTabHandler {
-            state: tab_state,
-            commands: self.builtins.iter().map(|c| c.name().to_string()).collect(),
-            path_dirs: self.path_dirs.clone(),
-        };
+    /// Reads as `read_chars` does, or a whole line without its newline if
+    /// `count` is `None`, failing with `TimedOut` if input stops arriving
+    /// before `deadline`.
+    fn read_chars_before(&self, count: Option<usize>, deadline: Instant) -> std::io::Result<(String, bool)> {
+        Self::collect_chars(count, || self.read_byte_before(deadline))
+    }
 
-        let mut rl = Editor::new()?;
+    fn collect_chars(count: Option<usize>, mut next: impl FnMut() -> std::io::Result<Option<u8>>) -> std::io::Result<(String, bool)> {
+        let mut bytes = Vec::new();
+        let mut chars = 0;
+        while count.is_none_or(|count| chars < count) {
+            let Some(first) = next()? else {
+                return Ok((String::from_utf8_lossy(&bytes).into_owned(), false));
+            };
+            if first == b'\n' {
+                return Ok((String::from_utf8_lossy(&bytes).into_owned(), true));
+            }
+            bytes.push(first);
+            chars += 1;
+            // The rest of a multibyte character belongs to the same count.
+            let len = match first {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            for _ in 1..len {
+                match next()? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok((String::from_utf8_lossy(&bytes).into_owned(), false)),
+                }
+            }
+        }
+        Ok((String::from_utf8_lossy(&bytes).into_owned(), true))
+    }
+
+    // The shell's own stdin is read a byte at a time straight from the
+    // descriptor, since what the standard library has buffered would not
+    // wake a wait on it.
+    fn read_byte_before(&self, deadline: Instant) -> std::io::Result<Option<u8>> {
+        let timed_out = || std::io::Error::from(std::io::ErrorKind::TimedOut);
+        let remaining = || deadline.saturating_duration_since(Instant::now());
+        match self {
+            Input::Inherit => {
+                if !fd::wait_readable(0, remaining()) {
+                    return Err(timed_out());
+                }
+                #[cfg(unix)]
+                return fd::read_byte(0);
+                #[cfg(not(unix))]
+                return Ok(self.read_byte());
+            }
+            Input::Buffer(_) => Ok(self.read_byte()),
+            Input::File(reader) => {
+                #[cfg(unix)]
+                {
+                    use std::os::fd::AsRawFd;
+                    let reader = reader.borrow();
+                    if reader.buffer().is_empty() && !fd::wait_readable(reader.get_ref().as_raw_fd(), remaining()) {
+                        return Err(timed_out());
+                    }
+                }
+                #[cfg(not(unix))]
+                let _ = reader;
+                Ok(self.read_byte())
+            }
+        }
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        let mut byte = [0];
+        let read = match self {
+            Input::Inherit => std::io::stdin().lock().read(&mut byte),
+            Input::Buffer(buffer) => {
+                let mut buffer = buffer.borrow_mut();
+                return (!buffer.is_empty()).then(|| buffer.remove(0));
+            }
+            Input::File(reader) => reader.borrow_mut().read(&mut byte),
+        };
+        matches!(read, Ok(1)).then_some(byte[0])
+    }
+
+    /// Reads one line including its newline, or `None` at end of input.
+    fn read_line(&self) -> Option<String> {
+        match self {
+            Input::Inherit => {
+                let mut line = String::new();
+                match std::io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                }
+            }
+            Input::Buffer(buffer) => {
+                let mut buffer = buffer.borrow_mut();
+                if buffer.is_empty() {
+                    return None;
+                }
+                let end = buffer.iter().position(|&b| b == b'\n').map_or(buffer.len(), |i| i + 1);
+                let line: Vec<u8> = buffer.drain(..end).collect();
+                Some(String::from_utf8_lossy(&line).into_owned())
+            }
+            Input::File(reader) => {
+                let mut line = Vec::new();
+                match reader.borrow_mut().read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(String::from_utf8_lossy(&line).into_owned()),
+                }
+            }
+        }
+    }
+}
+
+// Helper for output handling
+pub(crate) struct CommandOutput;
+impl CommandOutput {
+    pub(crate) fn write(shell: &Shell, stdout: &str, stderr: &str, redirection: Option<&dyn Redirection>) {
+        let Some(streams) = shell.redirected_streams(redirection) else {
+            return;
+        };
+        streams.stdout.write_bytes(stdout.as_bytes());
+        // What a builtin writes to stderr this way is always an error.
+        if !stderr.is_empty() {
+            let theme = shell.theme(&streams.stderr);
+            streams.stderr.write_bytes(Theme::paint_line(&theme.error, stderr).as_bytes());
+        }
+    }
+}
+
+// --- Shell ---
+
+/// A registered builtin. Disabled builtins (see `enable -n`) are skipped when
+/// resolving commands, so an executable of the same name runs instead.
+pub struct Builtin {
+    pub command: Box<dyn Command>,
+    /// How it is called, as `help` shows it.
+    pub synopsis: &'static str,
+    /// What it does, in a sentence.
+    pub description: &'static str,
+    pub enabled: Cell<bool>,
+}
+
+impl Builtin {
+    pub fn new(command: Box<dyn Command>, synopsis: &'static str, description: &'static str) -> Self {
+        Builtin { command, synopsis, description, enabled: Cell::new(true) }
+    }
+}
+
+pub struct Shell {
+    pub builtins: Vec<Builtin>,
+    /// Shell variables, seeded from the process environment.
+    pub vars: RefCell<HashMap<String, String>>,
+    /// The names of the variables passed to the commands the shell runs; at
+    /// first, those of the process environment.
+    pub exported: RefCell<HashSet<String>>,
+    /// `$1`, `$2`, ... for the function currently running.
+    positional: RefCell<Vec<String>>,
+    functions: RefCell<HashMap<String, Rc<Node>>>,
+    /// Line-editing settings, applied to the editor before each prompt.
+    pub edit_settings: RefCell<EditSettings>,
+    /// Where external commands were found, remembered so PATH is searched
+    /// once per command name, as with bash's `hash`.
+    hashed: RefCell<BTreeMap<String, HashedCommand>>,
+    /// The executables on PATH, for commands that look up many names.
+    pub path_index: RefCell<pathindex::PathIndex>,
+    /// The value of PATH the directories in the indexes came from; when the
+    /// variable no longer has it, they are read from it again.
+    path_value: RefCell<Option<String>>,
+    /// What completion offers as commands, shared with the completer.
+    pub command_index: Arc<Mutex<CommandIndex>>,
+    /// The long options completion has read from `--help`.
+    option_cache: Arc<Mutex<helpopts::OptionCache>>,
+    pub history: RefCell<history::History>,
+    /// Arguments typed with each command, for completion.
+    pub arg_history: RefCell<ArgHistory>,
+    /// Set while the REPL runs a line whose arguments should be remembered.
+    recording_args: Cell<bool>,
+    /// `set -o times`: report how long each command took.
+    pub time_commands: Cell<bool>,
+    /// `set -o autocd`: a directory named as a command is entered with `cd`.
+    pub autocd: Cell<bool>,
+    /// `set -o lithist`: history keeps the newlines of a multi-line command.
+    pub lithist: Cell<bool>,
+    /// Set while the `ENV` file is sourced; see `options::ENV_GUARD`.
+    sourcing_env: Cell<bool>,
+    /// Set for the REPL; see `is_interactive`.
+    pub interactive: Cell<bool>,
+    /// The file being sourced, named in diagnostics, and `$LINENO`: the
+    /// line of the command running, within that file or the input line.
+    script: RefCell<Option<String>>,
+    lineno: Cell<usize>,
+    /// The `.myshellenv` currently sourced, if any.
+    dir_env: RefCell<Option<direnv::LoadedEnv>>,
+    /// Alias definitions, kept sorted for `alias` listings.
+    aliases: RefCell<BTreeMap<String, String>>,
+    /// Set while `command_not_found_handle` runs, so a missing command inside
+    /// the handler reports normally instead of recursing.
+    in_not_found_handler: Cell<bool>,
+    /// How many enclosing loops are running, and how many of them a `break`
+    /// is still unwinding.
+    loop_depth: Cell<usize>,
+    breaking: Cell<usize>,
+    /// How many command substitutions are running inside one another, and
+    /// the status of the last one to finish on the current command line.
+    subst_depth: Cell<usize>,
+    subst_status: Cell<Option<i32>>,
+    /// Set while a simple command runs as a pipeline stage other than the
+    /// last, and the external commands and shell copies such stages
+    /// started, still running.
+    pipe_stage: Cell<bool>,
+    pipe_children: RefCell<Vec<PipeChild>>,
+    /// The reading ends of pipes whose stage has yet to start, which a copy
+    /// of the shell closes so that it is not a reader of its own output.
+    pipe_readers: RefCell<Vec<Input>>,
+    pub stdin: RefCell<Input>,
+    /// What other descriptors read, while a command with `3< file` runs.
+    pub fd_inputs: RefCell<Vec<(i32, Input)>>,
+    pub stdout: RefCell<Output>,
+    pub stderr: RefCell<Output>,
+    cwd: RefCell<PathBuf>,
+    last_status: Cell<i32>,
+    exit_code: Cell<Option<i32>>,
+}
+
+// What a subshell works on a copy of; see `Shell::subshell_state`.
+struct SubshellState {
+    vars: HashMap<String, String>,
+    exported: HashSet<String>,
+    positional: Vec<String>,
+    functions: HashMap<String, Rc<Node>>,
+    aliases: BTreeMap<String, String>,
+    enabled: Vec<bool>,
+    cwd: PathBuf,
+    dir_env: Option<direnv::LoadedEnv>,
+    edit_settings: EditSettings,
+    options: [bool; 3],
+    loop_depth: usize,
+    breaking: usize,
+}
+
+/// An entry in the command hash table.
+pub struct HashedCommand {
+    pub path: PathBuf,
+    /// How many times the entry has been used to run the command.
+    pub hits: usize,
+}
+
+impl Variables for Shell {
+    fn var(&self, name: &str) -> Option<String> {
+        let positional = self.positional.borrow();
+        match name {
+            "?" => Some(self.last_status().to_string()),
+            "#" => Some(positional.len().to_string()),
+            "@" | "*" => Some(positional.join(" ")),
+            "LINENO" => Some(self.lineno.get().to_string()),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                let index: usize = name.parse().ok()?;
+                positional.get(index.checked_sub(1)?).cloned()
+            }
+            _ => self.vars.var(name),
+        }
+    }
+
+    fn set_var(&self, name: &str, value: &str) {
+        self.vars.set_var(name, value);
+    }
+
+    fn command_output(&self, script: &str) -> std::result::Result<String, ExpansionError> {
+        self.substitute_command(script)
+    }
+}
+
+/// The most bytes a command substitution keeps unless `MYSHELL_SUBST_MAX`
+/// says otherwise.
+pub const DEFAULT_SUBST_MAX: usize = 64 * 1024 * 1024;
+
+/// How deeply command substitutions may nest, so one that runs itself again,
+/// as in `f() { echo $(f); }`, fails instead of exhausting the stack.
+const MAX_SUBST_DEPTH: usize = 64;
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        let builtins = vec![
+            Builtin::new(Box::new(ExitCommand), "exit [n]", "Exit the shell with status N, or that of the last command."),
+            Builtin::new(Box::new(EchoCommand), "echo [-neE] [arg ...]", "Write the arguments to standard output."),
+            Builtin::new(Box::new(TypeCommand), "type [-t | --json] name [name ...]", "Tell how each name would be run."),
+            Builtin::new(Box::new(PwdCommand), "pwd", "Print the current working directory."),
+            Builtin::new(Box::new(CdCommand), "cd [dir]", "Change the working directory, to HOME by default."),
+            Builtin::new(Box::new(LetCommand), "let expression [expression ...]", "Evaluate arithmetic expressions."),
+            Builtin::new(Box::new(EnableCommand), "enable [-a] [-n] [name ...]", "Enable and disable builtins, or list them."),
+            Builtin::new(Box::new(AliasCommand), "alias [-p] [name[=value] ...]", "Define aliases, or show them."),
+            Builtin::new(Box::new(UnaliasCommand), "unalias [-a] name [name ...]", "Remove alias definitions."),
+            Builtin::new(Box::new(BreakCommand), "break [n]", "Leave N enclosing select loops."),
+            Builtin::new(Box::new(SetCommand), "set [-o option] [+o option]", "Turn shell options on or off, or list them."),
+            Builtin::new(Box::new(BindCommand), "bind [-v] [line ...]", "Change line-editing settings, or list them."),
+            Builtin::new(Box::new(HashCommand), "hash [-r] [name ...]", "Remember where commands are found, or list them."),
+            Builtin::new(Box::new(SourceCommand::new("source")), "source filename [arguments]", "Run the commands in a file in this shell."),
+            Builtin::new(Box::new(SourceCommand::new(".")), ". filename [arguments]", "Run the commands in a file in this shell."),
+            Builtin::new(Box::new(StatusCommand::new(":", 0)), ": [arguments]", "Do nothing, successfully."),
+            Builtin::new(Box::new(StatusCommand::new("true", 0)), "true", "Return a successful status."),
+            Builtin::new(Box::new(StatusCommand::new("false", 1)), "false", "Return an unsuccessful status."),
+            Builtin::new(Box::new(EnvAllowCommand), "envallow [-d] [file]", "Approve a .myshellenv file, or revoke it with -d."),
+            Builtin::new(Box::new(HistoryCommand), "history [n] [--porcelain] [-d offset] [-r [filename]]", "Show or change the command history."),
+            Builtin::new(Box::new(ReadCommand), "read [-rs] [-n nchars] [-t timeout] [name ...]", "Read a line of input into variables."),
+            Builtin::new(Box::new(PrintfCommand), "printf format [arguments]", "Print the arguments as the format says."),
+            Builtin::new(Box::new(UlimitCommand), "ulimit [-SHa] [-cdfnstuv] [limit]", "Show or change resource limits."),
+            Builtin::new(Box::new(CompgenCommand), "compgen [-bcdf] [word]", "List the completions of a word."),
+            Builtin::new(Box::new(DeclareCommand), "declare [-px] [name[=value] ...]", "Set variables and their attributes, or show them."),
+            Builtin::new(Box::new(ExportCommand), "export [-p] [name[=value] ...]", "Pass variables to the commands the shell runs, or list them."),
+            Builtin::new(Box::new(UnsetCommand), "unset name [name ...]", "Remove variables, so they are neither set nor exported."),
+            Builtin::new(Box::new(HelpCommand), "help [pattern ...]", "Describe the builtins whose names start with a pattern."),
+        ];
+
+        Self::with_builtins(builtins)
+    }
+    
+    /// A shell without builtins that looks for commands in `path_dirs`
+    /// until PATH is assigned.
+    pub fn with_settings(path_dirs: Vec<PathBuf>) -> Self {
+        let shell = Self::with_builtins(vec![]);
+        shell.set_path_dirs(path_dirs);
+        shell
+    }
+
+    fn with_builtins(builtins: Vec<Builtin>) -> Self {
+        let path_value = env::var("PATH").ok();
+        let path_dirs = path_value.as_deref().map(pathindex::split_path).unwrap_or_default();
+        let shell = Shell {
+            builtins,
+            path_index: RefCell::new(pathindex::PathIndex::new(path_dirs.clone())),
+            path_value: RefCell::new(path_value),
+            command_index: Arc::new(Mutex::new(CommandIndex::new(Vec::new(), path_dirs))),
+            option_cache: Default::default(),
+            vars: RefCell::new(env::vars().collect()),
+            exported: RefCell::new(env::vars().map(|(name, _)| name).collect()),
+            positional: RefCell::new(Vec::new()),
+            functions: RefCell::new(HashMap::new()),
+            edit_settings: RefCell::new(EditSettings::default()),
+            hashed: RefCell::new(BTreeMap::new()),
+            history: RefCell::new(history::History::default()),
+            arg_history: RefCell::new(ArgHistory::default()),
+            recording_args: Cell::new(false),
+            time_commands: Cell::new(false),
+            autocd: Cell::new(false),
+            lithist: Cell::new(false),
+            sourcing_env: Cell::new(false),
+            interactive: Cell::new(false),
+            script: RefCell::new(None),
+            lineno: Cell::new(0),
+            dir_env: RefCell::new(None),
+            aliases: RefCell::new(BTreeMap::new()),
+            in_not_found_handler: Cell::new(false),
+            loop_depth: Cell::new(0),
+            breaking: Cell::new(0),
+            subst_depth: Cell::new(0),
+            subst_status: Cell::new(None),
+            pipe_stage: Cell::new(false),
+            pipe_children: RefCell::new(Vec::new()),
+            pipe_readers: RefCell::new(Vec::new()),
+            stdin: RefCell::new(Input::Inherit),
+            fd_inputs: RefCell::new(Vec::new()),
+            stdout: RefCell::new(Output::Inherit(1)),
+            stderr: RefCell::new(Output::Inherit(2)),
+            cwd: RefCell::new(env::current_dir().unwrap_or_default()),
+            last_status: Cell::new(0),
+            exit_code: Cell::new(None),
+        };
+        shell.set_cwd(shell.cwd());
+        shell.update_command_index();
+        shell
+    }
+
+    /// The shell's working directory. It is tracked separately from the
+    /// process's so that several shells can coexist in one process.
+    pub fn cwd(&self) -> PathBuf {
+        self.cwd.borrow().clone()
+    }
+
+    /// Changes the working directory, keeping `PWD` in step.
+    pub fn set_cwd(&self, dir: PathBuf) {
+        self.set_var("PWD", &dir.display().to_string());
+        *self.cwd.borrow_mut() = dir;
+    }
+
+    /// Reads a line from the shell's stdin, or `None` at end of input.
+    pub fn read_line(&self) -> Option<String> {
+        self.stdin.borrow().read_line()
+    }
+
+    /// Reads up to `count` characters of a line from the shell's stdin, and
+    /// whether it got them, or the end of the line, before input ended.
+    pub fn read_chars(&self, count: usize) -> (String, bool) {
+        self.stdin.borrow().read_chars(count)
+    }
+
+    /// Reads up to `count` characters, or a line, from the shell's stdin,
+    /// giving up with `TimedOut` at `deadline`.
+    pub fn read_chars_before(&self, count: Option<usize>, deadline: Instant) -> std::io::Result<(String, bool)> {
+        self.stdin.borrow().read_chars_before(count, deadline)
+    }
+
+    /// Switches the terminal's input mode, as `terminal::InputMode::set`
+    /// does, if the shell's stdin is the terminal.
+    pub fn terminal_input_mode(&self, canonical: bool, echo: bool) -> Option<terminal::InputMode> {
+        match *self.stdin.borrow() {
+            Input::Inherit => terminal::InputMode::set(0, canonical, echo),
+            _ => None,
+        }
+    }
+
+    pub fn write_stdout(&self, text: &str) {
+        self.stdout.borrow().write_bytes(text.as_bytes());
+    }
+
+    pub fn write_stderr(&self, text: &str) {
+        self.stderr.borrow().write_bytes(text.as_bytes());
+    }
+
+    /// Writes an error message to stderr, in the theme's error color.
+    pub fn write_error(&self, text: &str) {
+        let theme = self.theme(&self.stderr.borrow());
+        self.write_stderr(&Theme::paint_line(&theme.error, text));
+    }
+
+    /// The colors for writing to `output`: those of `MYSHELL_THEME`, unless
+    /// `NO_COLOR` is set or `output` is not a terminal.
+    pub fn theme(&self, output: &Output) -> Theme {
+        Theme::select(self.var("MYSHELL_THEME").as_deref(), self.var("NO_COLOR").as_deref(), output.is_terminal())
+    }
+
+    fn streams(&self) -> Streams {
+        Streams { stdout: self.stdout.borrow().clone(), stderr: self.stderr.borrow().clone(), others: Vec::new() }
+    }
+
+    /// Where a command's output goes: the shell's own streams with
+    /// `redirection` applied. If a target cannot be opened, says so and
+    /// returns `None`; the command should not run.
+    pub(crate) fn redirected_streams(&self, redirection: Option<&dyn Redirection>) -> Option<Streams> {
+        let mut streams = self.streams();
+        if let Some(r) = redirection
+            && let Err(e) = r.apply(&mut streams, &self.cwd())
+        {
+            self.write_error(&format!("myshell: {}: {}\n", sanitize_for_display(&e.target), io_error_text(&e.error)));
+            return None;
+        }
+        Some(streams)
+    }
+
+    /// Exit status of the most recently executed command.
+    pub fn last_status(&self) -> i32 {
+        self.last_status.get()
+    }
+
+    /// Leaves the `levels` innermost loops once the current command finishes.
+    /// Returns false when no loop is running.
+    pub fn request_break(&self, levels: usize) -> bool {
+        let depth = self.loop_depth.get();
+        if depth == 0 {
+            return false;
+        }
+        self.breaking.set(levels.min(depth));
+        true
+    }
+
+    /// Asks the shell to stop after the current command, exiting with `code`.
+    pub fn request_exit(&self, code: i32) {
+        self.exit_code.set(Some(code));
+    }
+
+    /// Whether the shell reads commands typed at a prompt, as opposed to a
+    /// script, `-c` string or piped input.
+    pub fn is_interactive(&self) -> bool {
+        self.interactive.get()
+    }
+
+    // Returns `status` for an error that ends a non-interactive shell, such
+    // as a syntax or expansion error, ending the shell with it unless
+    // interactive, where only the command fails.
+    fn fail(&self, status: i32) -> i32 {
+        if !self.is_interactive() {
+            self.request_exit(status);
+        }
+        status
+    }
+
+    /// The status the shell process exits with.
+    pub fn exit_status(&self) -> i32 {
+        self.exit_code.get().unwrap_or_else(|| self.last_status())
+    }
+
+    /// The enabled builtin called `name`, if any.
+    pub fn builtin(&self, name: &str) -> Option<&dyn Command> {
+        self.builtins
+            .iter()
+            .find(|b| b.enabled.get() && b.command.name() == name)
+            .map(|b| b.command.as_ref())
+    }
+
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtin(name).is_some()
+    }
+
+    /// Replaces the command word of `text` with its alias, if it has one. Only
+    /// a plain word is looked up: quoting or escaping any part of it, as in
+    /// `\ll` or `'ll'`, runs the command itself. The new command word is
+    /// expanded in turn, so `ll` can chain into an `ls` alias, but no alias is
+    /// expanded twice, so `alias ls='ls -F'` runs `ls` itself. An alias whose
+    /// value ends in a blank, like `alias sudo='sudo '`, has the word after it
+    /// looked up too.
+    pub fn expand_alias<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut line = Cow::Borrowed(text);
+        let mut expanded = HashSet::new();
+        // Where the word to look up begins, and where the words after values
+        // ending in a blank begin, the latest last.
+        let mut pos = 0;
+        let mut after_blank: Vec<usize> = Vec::new();
+        loop {
+            let start = line.len() - line[pos..].trim_start().len();
+            let end = line[start..]
+                .find(|c: char| c.is_whitespace() || ";&|<>()".contains(c))
+                .map_or(line.len(), |i| start + i);
+            let word = &line[start..end];
+            let plain = !word.is_empty() && !word.contains(['\'', '"', '\\', '$', '`']) && !expanded.contains(word);
+            let value = if plain { self.aliases.borrow().get(word).cloned() } else { None };
+            let Some(value) = value else {
+                match after_blank.pop() {
+                    Some(next) => {
+                        pos = next;
+                        continue;
+                    }
+                    None => return line,
+                }
+            };
+            expanded.insert(word.to_string());
+            // Those words all follow this one, so move with the text.
+            for next in &mut after_blank {
+                *next = *next - (end - start) + value.len();
+            }
+            if value.ends_with(char::is_whitespace) {
+                after_blank.push(start + value.len());
+            }
+            line = Cow::Owned(format!("{}{}{}", &line[..start], value, &line[end..]));
+            pos = start;
+        }
+    }
+
+    /// Finds `name` for running it, preferring the hash table and recording
+    /// the result there. An entry whose file has gone is looked up afresh.
+    /// Names containing a separator are paths from the working directory,
+    /// and are not hashed.
+    pub fn hash_command(&self, name: &str) -> Option<PathBuf> {
+        if name.contains(paths::is_separator) {
+            let path = self.cwd().join(name);
+            return path.exists().then_some(path);
+        }
+        self.update_path();
+        let mut hashed = self.hashed.borrow_mut();
+        if let Some(entry) = hashed.get_mut(name)
+            && entry.path.is_file()
+        {
+            entry.hits += 1;
+            return Some(entry.path.clone());
+        }
+        let path = self.find_executable_in_path(name)?;
+        hashed.insert(name.to_string(), HashedCommand { path: path.clone(), hits: 1 });
+        Some(path)
+    }
+
+    /// The hashed location of `name`, if it has one.
+    pub fn hashed(&self, name: &str) -> Option<PathBuf> {
+        self.hashed.borrow().get(name).map(|entry| entry.path.clone())
+    }
+
+    /// Warns about the PATH directories that cannot be read, unless they
+    /// have been checked already, and leaves them out of completion.
+    pub fn check_path_dirs(&self) {
+        self.update_path();
+        let Some(failures) = self.path_index.borrow_mut().check() else {
+            return;
+        };
+        for (dir, e) in failures {
+            let reason = io_error_text(&e);
+            self.write_error(&format!("myshell: warning: cannot read PATH directory {}: {}\n", sanitize_for_display(&dir.display().to_string()), reason));
+        }
+        self.command_index.lock().unwrap().set_path_dirs(self.path_index.borrow().readable_dirs());
+    }
+
+    /// Takes the directories from PATH again if it has been assigned since
+    /// they were last taken, forgetting where commands were found.
+    fn update_path(&self) {
+        let value = self.vars.var("PATH");
+        if *self.path_value.borrow() == value {
+            return;
+        }
+        self.set_path_dirs(value.as_deref().map(pathindex::split_path).unwrap_or_default());
+        *self.path_value.borrow_mut() = value;
+    }
+
+    fn set_path_dirs(&self, dirs: Vec<PathBuf>) {
+        self.path_index.borrow_mut().set_dirs(dirs);
+        self.hashed.borrow_mut().clear();
+        self.command_index.lock().unwrap().set_path_dirs(self.path_index.borrow().readable_dirs());
+    }
+
+    pub fn find_executable_in_path(&self, executable: &str) -> Option<PathBuf> {
+        self.check_path_dirs();
+        let path_dirs = self.path_index.borrow().readable_dirs();
+        for path_dir in &path_dirs {
+            let full_path = path_dir.join(executable);
+            if let Ok(_metadata) = std::fs::metadata(&full_path) {
+                #[cfg(target_family = "unix")]
+                if _metadata.permissions().mode() & 0o111 != 0 {
+                    return Some(full_path);
+                }
+                #[cfg(target_family = "windows")]
+                return Some(full_path);
+            }
+        }
+        None
+    }
+
+    /// Finds `executable` in the PATH index as of its last refresh, checking
+    /// the filesystem only if the index does not have it.
+    pub fn find_indexed_executable(&self, executable: &str) -> Option<PathBuf> {
+        self.update_path();
+        let indexed = self.path_index.borrow().find(executable);
+        indexed.or_else(|| self.find_executable_in_path(executable))
+    }
+
+    pub fn execute(&self, mut cmd_line: CommandLine) -> i32 {
+        // Inputs are opened first; if one fails nothing runs.
+        if cmd_line.input.is_some() || !cmd_line.fd_inputs.is_empty() {
+            let sources = cmd_line.input.take().map(|source| (0, source)).into_iter().chain(std::mem::take(&mut cmd_line.fd_inputs));
+            let mut opened = Vec::new();
+            for (fd, source) in sources {
+                match source.open(&self.cwd()) {
+                    Ok(input) => opened.push((fd, input)),
+                    Err(e) => {
+                        self.write_error(&format!("myshell: {}: {}\n", sanitize_for_display(source.name()), io_error_text(&e)));
+                        self.last_status.set(1);
+                        return 1;
+                    }
+                }
+            }
+            if cmd_line.command.is_empty() && cmd_line.assignments.is_empty() && cmd_line.redirection.is_none() {
+                self.last_status.set(0);
+                return 0;
+            }
+            let stdin = opened.iter().position(|(fd, _)| *fd == 0).map(|i| opened.remove(i).1);
+            let saved_stdin = stdin.map(|input| self.stdin.replace(input));
+            let saved_fd_inputs = (!opened.is_empty()).then(|| self.fd_inputs.replace(opened));
+            let status = self.execute(cmd_line);
+            if let Some(saved) = saved_stdin {
+                *self.stdin.borrow_mut() = saved;
+            }
+            if let Some(saved) = saved_fd_inputs {
+                *self.fd_inputs.borrow_mut() = saved;
+            }
+            return status;
+        }
+
+        if cmd_line.command.is_empty() {
+            // A bare redirection just creates or truncates its target.
+            if let Some(r) = &cmd_line.redirection {
+                let status = match r.apply(&mut self.streams(), &self.cwd()) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        self.write_error(&format!("myshell: {}: {}\n", sanitize_for_display(&e.target), io_error_text(&e.error)));
+                        1
+                    }
+                };
+                self.last_status.set(status);
+                return status;
+            }
+            // With no command, the status is that of the last command
+            // substitution, if there was one.
+            let substituted = self.subst_status.take();
+            if cmd_line.assignments.is_empty() && substituted.is_none() {
+                return self.last_status();
+            }
+            for (name, value) in &cmd_line.assignments {
+                self.set_var(name, value);
+            }
+            let status = substituted.unwrap_or(0);
+            self.last_status.set(status);
+            return status;
+        }
+
+        let in_pipeline = self.pipe_stage.take();
+        let redirection = cmd_line.redirection.as_deref();
+        let status = if let Some(body) = self.function(&cmd_line.command) {
+            let call = || self.with_assignments(&cmd_line.assignments, || self.call_function(&body, &cmd_line.args, redirection));
+            if in_pipeline { self.run_forked(call) } else { call() }
+        } else if let Some(cmd) = self.builtin(&cmd_line.command) {
+            self.with_assignments(&cmd_line.assignments, || self.run_builtin(cmd, &cmd_line.args, redirection))
+        } else {
+            let ext_cmd = ExternalCommand { name: cmd_line.command.clone(), env: cmd_line.assignments, in_pipeline };
+            ext_cmd.execute(&cmd_line.args, redirection, self)
+        };
+        // `$_` holds the last argument of the previous command, as expanded.
+        let last_word = cmd_line.args.last().map_or(&cmd_line.command, |arg| &arg.value);
+        self.set_var("_", last_word);
+        self.last_status.set(status);
+        status
+    }
+
+    // Runs `f` with `assignments` applied to the shell's variables, restoring
+    // the previous values afterwards.
+    fn with_assignments<T>(&self, assignments: &[Assignment], f: impl FnOnce() -> T) -> T {
+        let saved: Vec<_> = assignments.iter().map(|(name, _)| (name, self.var(name))).collect();
+        for (name, value) in assignments {
+            self.set_var(name, value);
+        }
+        let result = f();
+        for (name, value) in saved.into_iter().rev() {
+            match value {
+                Some(value) => self.set_var(name, &value),
+                None => {
+                    self.vars.borrow_mut().remove(name);
+                }
+            }
+        }
+        result
+    }
+
+    /// The body of the function `name`, if one is defined.
+    pub fn function(&self, name: &str) -> Option<Rc<Node>> {
+        self.functions.borrow().get(name).cloned()
+    }
+
+    // Runs a builtin on the shell's streams with `redirection` applied, so
+    // what it writes directly, such as its error messages, follows `2>&1`
+    // and the like just as its output does. When that sends the shell's real
+    // stdout to a file, the process's stdout follows too, for anything that
+    // writes there without going through the streams.
+    fn run_builtin(&self, cmd: &dyn Command, args: &[Argument], redirection: Option<&dyn Redirection>) -> i32 {
+        let Some(streams) = self.redirected_streams(redirection) else {
+            return 1;
+        };
+        let direct = match (&*self.stdout.borrow(), &streams.stdout) {
+            (Output::Inherit(1), Output::File(file)) => Some(file.clone()),
+            _ => None,
+        };
+        let saved_stdout = self.stdout.replace(streams.stdout);
+        let saved_stderr = self.stderr.replace(streams.stderr);
+        let status = match direct {
+            Some(file) => fd::with_redirected_stdout(&file, || cmd.execute(args, None, self)).unwrap_or_else(|e| {
+                self.write_error(&format!("myshell: {}: {}\n", cmd.name(), io_error_text(&e)));
+                1
+            }),
+            None => cmd.execute(args, None, self),
+        };
+        self.stdout.replace(saved_stdout);
+        self.stderr.replace(saved_stderr);
+        status
+    }
+
+    /// Runs a function body with `args` as its positional parameters. A
+    /// redirection applies to everything the body writes to that stream.
+    pub fn call_function(&self, body: &Node, args: &[Argument], redirection: Option<&dyn Redirection>) -> i32 {
+        let Some(streams) = self.redirected_streams(redirection) else {
+            return 1;
+        };
+        let saved_stdout = self.stdout.replace(streams.stdout);
+        let saved_stderr = self.stderr.replace(streams.stderr);
+        let args = args.iter().map(|a| a.value.clone()).collect();
+        let saved_args = self.positional.replace(args);
+
+        let status = self.run_node(body);
+
+        self.positional.replace(saved_args);
+        self.stdout.replace(saved_stdout);
+        self.stderr.replace(saved_stderr);
+        status
+    }
+
+    /// Parses and runs one line of input, returning its exit status.
+    pub fn run_line(&self, line: &str) -> i32 {
+        self.run_line_from(line, 1).unwrap_or_else(|status| status)
+    }
+
+    // Runs `text` as `run_line` does, counting its lines from `first_line`.
+    // A syntax error is reported, and its status is the error.
+    fn run_line_from(&self, text: &str, first_line: usize) -> std::result::Result<i32, i32> {
+        match syntax::parse_script_from(text, first_line) {
+            // A blank line leaves `$?` as it was.
+            Ok(nodes) if nodes.is_empty() => Ok(self.last_status()),
+            Ok(nodes) => Ok(self.run_nodes(&nodes)),
+            Err((e, line)) => {
+                self.lineno.set(line);
+                self.write_error(&format!("{}{}\n", self.location(), e));
+                self.last_status.set(2);
+                Err(self.fail(2))
+            }
+        }
+    }
+
+    // Whether to report each command's duration, from `set -o times` or
+    // `SHELL_TIME_ALL=1`.
+    fn timing_commands(&self) -> bool {
+        self.time_commands.get() || self.var("SHELL_TIME_ALL").as_deref() == Some("1")
+    }
+
+    /// Runs a line typed at the prompt, remembering the arguments of each
+    /// command in it unless the line starts with a space.
+    pub fn run_typed_line(&self, line: &str) -> i32 {
+        self.recording_args.set(!line.starts_with(' '));
+        let status = self.run_line(line);
+        self.recording_args.set(false);
+        status
+    }
+
+    fn run_nodes(&self, nodes: &[Node]) -> i32 {
+        let mut status = 0;
+        for node in nodes {
+            if self.exit_code.get().is_some() || self.breaking.get() > 0 {
+                break;
+            }
+            status = self.run_node(node);
+            if stdio::is_closed(1) && !self.is_interactive() {
+                self.request_exit(stdio::BROKEN_PIPE_STATUS);
+            }
+        }
+        status
+    }
+
+    fn run_node(&self, node: &Node) -> i32 {
+        let status = match node {
+            Node::Simple { text, line, here_docs } => {
+                self.lineno.set(*line);
+                self.run_simple(text, here_docs)
+            }
+            Node::Arithmetic(expr) => builtins::run_arithmetic_command(expr, self),
+            Node::Conditional(expr) => builtins::run_conditional_command(expr, self),
+            Node::Select { name, words, body } => self.run_select(name, words, body),
+            Node::Case { word, arms } => self.run_case(word, arms),
+            Node::Group(body) => self.run_nodes(body),
+            Node::Not(inner) => i32::from(self.run_node(inner) == 0),
+            Node::Pipeline(stages) => self.run_pipeline(stages),
+            Node::And(first, second) | Node::Or(first, second) => {
+                let status = self.run_node(first);
+                // The second command runs if the first's status calls for
+                // it, and the first did not end the shell or a loop.
+                let wanted = matches!(node, Node::And(..)) == (status == 0);
+                if wanted && self.exit_code.get().is_none() && self.breaking.get() == 0 {
+                    self.run_node(second)
+                } else {
+                    status
+                }
+            }
+            Node::Function { name, body } => {
+                self.functions.borrow_mut().insert(name.clone(), body.clone());
+                self.update_command_index();
+                0
+            }
+        };
+        self.last_status.set(status);
+        status
+    }
+
+    // Runs the stages of a pipeline in order, each reading what the one
+    // before wrote. A stage other than the last runs on while the later
+    // stages start, and is waited for at the end: an external command as a
+    // process of its own, anything else in a copy of the shell. The status
+    // is that of the last stage.
+    fn run_pipeline(&self, stages: &[Stage]) -> i32 {
+        let saved_stdin = self.stdin.borrow().clone();
+        let saved_stdout = self.stdout.borrow().clone();
+        let saved_stderr = self.stderr.borrow().clone();
+        let saved_children = self.pipe_children.take();
+        let mut status = 0;
+        for (i, stage) in stages.iter().enumerate() {
+            let last = i + 1 == stages.len();
+            if !last {
+                let (reader, writer) = match pipe::pipe().and_then(|(reader, writer)| Ok((reader, pipe::PipeWriter::new(writer)?))) {
+                    Ok(ends) => ends,
+                    Err(e) => {
+                        self.write_error(&format!("pipe: {}\n", e));
+                        status = 1;
+                        break;
+                    }
+                };
+                let output = Output::Pipe(Rc::new(writer));
+                if stage.pipe_stderr {
+                    *self.stderr.borrow_mut() = output.clone();
+                }
+                *self.stdout.borrow_mut() = output;
+                self.pipe_readers.borrow_mut().push(Input::File(Rc::new(RefCell::new(BufReader::new(reader)))));
+            }
+            status = match &stage.command {
+                Node::Simple { .. } => {
+                    self.pipe_stage.set(!last);
+                    let status = self.run_node(&stage.command);
+                    self.pipe_stage.set(false);
+                    status
+                }
+                command if !last => self.run_forked(|| self.run_node(command)),
+                command => self.run_node(command),
+            };
+            // Once the shell lets go of the writing end, the next stage sees
+            // the end of its input when the commands writing to it finish.
+            *self.stdout.borrow_mut() = saved_stdout.clone();
+            *self.stderr.borrow_mut() = saved_stderr.clone();
+            if !last && let Some(input) = self.pipe_readers.borrow_mut().pop() {
+                *self.stdin.borrow_mut() = input;
+            }
+        }
+        *self.stdin.borrow_mut() = saved_stdin;
+        for child in self.pipe_children.replace(saved_children) {
+            child.wait();
+        }
+        status
+    }
+
+    // Runs a pipeline stage in a copy of the shell made with fork(2), so it
+    // writes while the next stage reads, and leaves it for the pipeline to
+    // wait for. What the copy changes, as in a subshell, stays in the copy.
+    #[cfg(unix)]
+    fn run_forked(&self, run: impl FnOnce() -> i32) -> i32 {
+        // Output queued for a pipe goes out before the copy writes to it.
+        for output in [&self.stdout, &self.stderr] {
+            if let Output::Pipe(writer) = &*output.borrow() {
+                writer.file();
+            }
+        }
+        match unsafe { libc::fork() } {
+            -1 => run(),
+            0 => {
+                // The threads behind pipe writers are not copied, so the
+                // copy writes to its pipes directly, and like any process
+                // in a pipeline dies once the reader has gone.
+                for output in [&self.stdout, &self.stderr] {
+                    let direct = match &*output.borrow() {
+                        Output::Pipe(writer) => writer.file_copy().ok(),
+                        _ => None,
+                    };
+                    if let Some(file) = direct {
+                        *output.borrow_mut() = Output::File(Rc::new(file));
+                    }
+                }
+                self.pipe_readers.borrow_mut().clear();
+                unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+                let status = run();
+                unsafe { libc::_exit(self.exit_code.get().unwrap_or(status)) }
+            }
+            pid => {
+                self.pipe_children.borrow_mut().push(PipeChild::Forked(pid));
+                0
+            }
+        }
+    }
+
+    // Without fork(2), the stage runs to the end before the next starts.
+    #[cfg(not(unix))]
+    fn run_forked(&self, run: impl FnOnce() -> i32) -> i32 {
+        run()
+    }
+
+    fn run_simple(&self, text: &str, here_docs: &[HereDoc]) -> i32 {
+        self.subst_status.set(None);
+        if self.recording_args.get()
+            && let Some((command, args)) = arghistory::typed_words(text)
+        {
+            self.arg_history.borrow_mut().record(command, args);
+        }
+        match CommandLine::parse_with_here_docs(&self.expand_alias(text), here_docs, self) {
+            Ok(cmd_line) if self.timing_commands() => {
+                let start = std::time::Instant::now();
+                let status = self.execute(cmd_line);
+                self.write_stderr(&format!("real\t{}\n", format_elapsed(start.elapsed())));
+                status
+            }
+            Ok(cmd_line) => self.execute(cmd_line),
+            Err(e) => {
+                self.write_error(&format!("{}{}\n", self.location(), e));
+                self.fail(1)
+            }
+        }
+    }
+
+    // Where a diagnostic comes from while a file is being sourced, as
+    // `file: line N: `; empty for interactive input.
+    fn location(&self) -> String {
+        match &*self.script.borrow() {
+            Some(name) => format!("{}: line {}: ", sanitize_for_display(name), self.lineno.get()),
+            None => String::new(),
+        }
+    }
+
+    // Runs the body of the first arm with a pattern matching `word`. The status
+    // is that of the body, or 0 if no arm matches.
+    fn run_case(&self, word: &str, arms: &[CaseArm]) -> i32 {
+        let result = expand::expand_string(word, self).and_then(|value| {
+            for arm in arms {
+                for pattern in &arm.patterns {
+                    if glob::compile(&expand::expand_pattern(pattern, self)?).matches(&value) {
+                        return Ok(Some(&arm.body));
+                    }
+                }
+            }
+            Ok(None)
+        });
+        match result {
+            Ok(Some(body)) => self.run_nodes(body),
+            Ok(None) => 0,
+            Err(e) => {
+                self.write_error(&format!("{}\n", e));
+                self.fail(1)
+            }
+        }
+    }
+
+    // Shows a numbered menu of `words` on stderr and reads choices from stdin
+    // until end of input, binding `name` to the chosen word (empty if the
+    // reply is not a valid number) and `REPLY` to the reply itself. An empty
+    // reply shows the menu again.
+    fn run_select(&self, name: &str, words: &[String], body: &[Node]) -> i32 {
+        let words = match CommandLine::expand_words(&words.join(" "), self) {
+            Ok(words) => words,
+            Err(e) => {
+                self.write_error(&format!("{}\n", e));
+                return self.fail(1);
+            }
+        };
+        if words.is_empty() {
+            return 0;
+        }
+        let mut status = 0;
+        let mut show_menu = true;
+        self.loop_depth.set(self.loop_depth.get() + 1);
+        while self.exit_code.get().is_none() {
+            if show_menu {
+                let menu: String = words.iter().enumerate().map(|(i, word)| format!("{}) {}\n", i + 1, word)).collect();
+                self.write_stderr(&menu);
+            }
+            self.write_stderr(&self.var("PS3").unwrap_or_else(|| "#? ".to_string()));
+            let Some(line) = self.read_line() else {
+                self.write_stderr("\n");
+                break;
+            };
+            let reply = line.trim_end_matches(['\n', '\r']);
+            self.set_var("REPLY", reply);
+            show_menu = reply.trim().is_empty();
+            if show_menu {
+                continue;
+            }
+            let choice = reply.trim().parse::<usize>().ok().and_then(|n| words.get(n.checked_sub(1)?));
+            self.set_var(name, choice.map_or("", String::as_str));
+            status = self.run_nodes(body);
+            if self.breaking.get() > 0 {
+                self.breaking.set(self.breaking.get() - 1);
+                break;
+            }
+        }
+        self.loop_depth.set(self.loop_depth.get() - 1);
+        status
+    }
+
+    /// Runs the commands in `path`, resolved against the working directory,
+    /// returning the status of the last one.
+    pub fn source_file(&self, path: &Path) -> std::io::Result<i32> {
+        let text = std::fs::read_to_string(self.cwd().join(path))?;
+        Ok(self.source_text(path, &text))
+    }
+
+    // Runs `text`, read from `path`, as `source_file` does.
+    fn source_text(&self, path: &Path, text: &str) -> i32 {
+        let saved = (self.script.replace(Some(path.display().to_string())), self.lineno.get());
+        let status = self.run_commands(text);
+        *self.script.borrow_mut() = saved.0;
+        self.lineno.set(saved.1);
+        status
+    }
+
+    // Runs a script's `text` one complete command at a time, as it would be
+    // read, so a syntax error stops the script only after the commands before
+    // it have run.
+    fn run_commands(&self, text: &str) -> i32 {
+        let mut lines = text.split('\n');
+        let mut status = self.last_status();
+        let mut line_number = 1;
+        while let Some(line) = lines.next() {
+            let command = syntax::read_continuation(line.to_string(), || lines.next().map(str::to_string));
+            status = match self.run_line_from(&command, line_number) {
+                Ok(status) => status,
+                Err(status) => return status,
+            };
+            line_number += command.matches('\n').count() + 1;
+            if self.exit_code.get().is_some() || self.breaking.get() > 0 {
+                break;
+            }
+        }
+        status
+    }
+
+    /// Runs `script` for a `$( )` substitution and returns its stdout with
+    /// trailing newlines removed. Output past `$MYSHELL_SUBST_MAX` bytes is
+    /// dropped with a warning, and the substitution's status is then 1.
+    /// It runs as a subshell: `exit` inside it ends only the substitution,
+    /// and what it changes, from variables to the working directory, is
+    /// undone when it finishes.
+    fn substitute_command(&self, script: &str) -> std::result::Result<String, ExpansionError> {
+        if self.subst_depth.get() >= MAX_SUBST_DEPTH {
+            return Err(ExpansionError("command substitution: nested too deeply".to_string()));
+        }
+        let limit = self.var("MYSHELL_SUBST_MAX").and_then(|max| max.parse().ok()).unwrap_or(DEFAULT_SUBST_MAX);
+        let capture = Rc::new(RefCell::new(Capture::new(limit)));
+        let saved_stdout = self.stdout.replace(Output::Capture(capture.clone()));
+        let saved_exit = self.exit_code.take();
+        let saved_stage = self.pipe_stage.take();
+        let saved_state = self.subshell_state();
+        self.subst_depth.set(self.subst_depth.get() + 1);
+
+        let mut status = self.run_line(script);
+
+        self.subst_depth.set(self.subst_depth.get() - 1);
+        self.restore_subshell_state(saved_state);
+        self.pipe_stage.set(saved_stage);
+        self.exit_code.set(saved_exit);
+        *self.stdout.borrow_mut() = saved_stdout;
+        let Capture { mut bytes, truncated, .. } = capture.replace(Capture::new(0));
+        if truncated {
+            self.write_error(&format!("command substitution: output truncated to {} bytes\n", limit));
+            status = 1;
+        }
+        self.subst_status.set(Some(status));
+        let end = bytes.iter().rposition(|&b| b != b'\n').map_or(0, |i| i + 1);
+        bytes.truncate(end);
+        Ok(String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()))
+    }
+
+    // Copies what a subshell may change, to be put back when it ends.
+    fn subshell_state(&self) -> SubshellState {
+        SubshellState {
+            vars: self.vars.borrow().clone(),
+            exported: self.exported.borrow().clone(),
+            positional: self.positional.borrow().clone(),
+            functions: self.functions.borrow().clone(),
+            aliases: self.aliases.borrow().clone(),
+            enabled: self.builtins.iter().map(|b| b.enabled.get()).collect(),
+            cwd: self.cwd(),
+            dir_env: self.dir_env.borrow().clone(),
+            edit_settings: *self.edit_settings.borrow(),
+            options: [self.time_commands.get(), self.autocd.get(), self.lithist.get()],
+            loop_depth: self.loop_depth.replace(0),
+            breaking: self.breaking.get(),
+        }
+    }
+
+    fn restore_subshell_state(&self, state: SubshellState) {
+        *self.vars.borrow_mut() = state.vars;
+        *self.exported.borrow_mut() = state.exported;
+        *self.positional.borrow_mut() = state.positional;
+        *self.functions.borrow_mut() = state.functions;
+        *self.aliases.borrow_mut() = state.aliases;
+        for (builtin, enabled) in self.builtins.iter().zip(state.enabled) {
+            builtin.enabled.set(enabled);
+        }
+        *self.cwd.borrow_mut() = state.cwd;
+        *self.dir_env.borrow_mut() = state.dir_env;
+        *self.edit_settings.borrow_mut() = state.edit_settings;
+        let [time_commands, autocd, lithist] = state.options;
+        self.time_commands.set(time_commands);
+        self.autocd.set(autocd);
+        self.lithist.set(lithist);
+        self.loop_depth.set(state.loop_depth);
+        self.breaking.set(state.breaking);
+        self.update_command_index();
+    }
+
+    /// Completes a line typed at the prompt, as `syntax::read_continuation`
+    /// does, reading each further line with `read_line` after the prompt in
+    /// `PS2` (by default `> `).
+    pub fn read_continuation(&self, line: String, mut read_line: impl FnMut(&str) -> Option<String>) -> String {
+        let prompt = self.var("PS2").unwrap_or_else(|| "> ".to_string());
+        syntax::read_continuation(line, || read_line(&prompt))
+    }
+
+    /// Runs commands read from stdin, without a prompt, until end of input
+    /// or `exit`. Used when input is piped in rather than typed, and by an
+    /// interactive shell that has no line editor, which still keeps history.
+    pub fn run_stdin(&self) {
+        let next_line = || self.read_line().map(|line| line.trim_end_matches('\n').to_string());
+        while let Some(line) = next_line() {
+            let line = syntax::read_continuation(line, next_line);
+            if self.is_interactive() {
+                self.run_typed_line(&line);
+            } else {
+                self.run_line(&line);
+            }
+            if self.exit_code.get().is_some() {
+                break;
+            }
+            if self.is_interactive() {
+                self.record_history(&line);
+            }
+        }
+    }
+
+    /// Runs a script named on the command line with `args` as its positional
+    /// parameters. A script that cannot be read exits with status 127.
+    pub fn run_script(&self, path: &str, args: Vec<String>) {
+        self.positional.replace(args);
+        if let Err(e) = self.source_file(Path::new(path)) {
+            self.write_error(&format!("{}: {}\n", sanitize_for_display(path), e));
+            self.request_exit(127);
+        }
+    }
+
+    /// Brings the directory environment in line with the working directory:
+    /// a loaded `.myshellenv` is undone once the shell leaves its directory
+    /// or enters one with its own, and an allowed file in the new directory
+    /// is sourced. Unallowed or edited files only print a hint.
+    pub fn update_dir_env(&self) {
+        let cwd = self.cwd();
+        let file = cwd.join(direnv::ENV_FILE);
+        let has_file = file.is_file();
+        let (loaded_here, stale) = match &*self.dir_env.borrow() {
+            Some(env) => (env.dir == cwd, env.dir != cwd && (has_file || !cwd.starts_with(&env.dir))),
+            None => (false, false),
+        };
+        if stale {
+            self.unload_dir_env();
+        }
+        if !has_file || loaded_here {
+            return;
+        }
+        let Some(list) = direnv::allow_list_path(self) else {
+            return;
+        };
+        let shown = sanitize_for_display(&file.display().to_string());
+        let contents = match std::fs::read(&file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                self.write_error(&format!("{}: {}\n", shown, e));
+                return;
+            }
+        };
+        match direnv::approval(&list, &file, &contents) {
+            direnv::Approval::Allowed => {
+                let text = match String::from_utf8(contents) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        self.write_error(&format!("{}: {}\n", shown, e));
+                        return;
+                    }
+                };
+                let before = self.vars.borrow().clone();
+                self.source_text(&file, &text);
+                let after = self.vars.borrow();
+                let previous = before
+                    .keys()
+                    .chain(after.keys())
+                    .filter(|name| name.as_str() != "_" && before.get(*name) != after.get(*name))
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .map(|name| (name.clone(), before.get(name).cloned()))
+                    .collect();
+                *self.dir_env.borrow_mut() = Some(direnv::LoadedEnv { dir: cwd, previous });
+            }
+            direnv::Approval::NotAllowed => {
+                self.write_error(&format!("{}: not allowed; run `envallow' to load it\n", shown));
+            }
+            direnv::Approval::Changed => {
+                self.write_error(&format!("{}: changed since it was allowed; run `envallow' to load it\n", shown));
+            }
+        }
+    }
+
+    /// Puts back the variables the loaded `.myshellenv` changed.
+    pub fn unload_dir_env(&self) {
+        let Some(env) = self.dir_env.take() else {
+            return;
+        };
+        let mut vars = self.vars.borrow_mut();
+        for (name, value) in env.previous {
+            match value {
+                Some(value) => vars.insert(name, value),
+                None => vars.remove(&name),
+            };
+        }
+    }
+
+    /// The directory whose `.myshellenv` is loaded, if any.
+    pub fn dir_env_dir(&self) -> Option<PathBuf> {
+        self.dir_env.borrow().as_ref().map(|env| env.dir.clone())
+    }
+
+    /// Sources the startup files `options` calls for in `mode`. Missing
+    /// profiles are skipped silently.
+    pub fn load_startup_files(&self, options: &options::Options, mode: Mode) {
+        for file in options.startup_files(mode) {
+            let path = match file {
+                StartupFile::SystemProfile => PathBuf::from(options::SYSTEM_PROFILE),
+                StartupFile::Profile => match expand::tilde("", self) {
+                    Some(home) => Path::new(&home).join(options::PROFILE_FILE),
+                    None => continue,
+                },
+                StartupFile::Rc => {
+                    self.load_rc(options);
+                    continue;
+                }
+                StartupFile::Env => {
+                    self.load_env_file();
+                    continue;
+                }
+            };
+            let _ = self.source_file(&path);
+            if self.exit_code.get().is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Sources `~/.myshell_logout` when a login shell exits. The shell's
+    /// exit status is kept.
+    pub fn run_logout(&self, options: &options::Options) {
+        if !options.login {
+            return;
+        }
+        let Some(home) = expand::tilde("", self) else {
+            return;
+        };
+        let status = self.exit_status();
+        // `exit` has already been requested; let the file's commands run.
+        self.exit_code.set(None);
+        let _ = self.source_file(&Path::new(&home).join(options::LOGOUT_FILE));
+        self.exit_code.set(Some(status));
+    }
+
+    /// Sources the rc file chosen by `options`. A missing default rc file is
+    /// not an error; a missing `--rcfile` is reported.
+    pub fn load_rc(&self, options: &options::Options) {
+        let Some(path) = options.rc_path(self) else {
+            return;
+        };
+        if let Err(e) = self.source_file(&path)
+            && options.rcfile.is_some()
+        {
+            self.write_error(&format!("{}: {}\n", sanitize_for_display(&path.display().to_string()), e));
+        }
+    }
+
+    /// Sources the file named by `$ENV`, after parameter expansion, unless
+    /// this shell was started while another was sourcing it.
+    pub fn load_env_file(&self) {
+        if self.var(options::ENV_GUARD).is_some() {
+            return;
+        }
+        let Some(path) = self.var("ENV").filter(|value| !value.is_empty()).and_then(|value| expand::expand_string(&value, self).ok()) else {
+            return;
+        };
+        self.sourcing_env.set(true);
+        if let Err(e) = self.source_file(Path::new(&path)) {
+            self.write_error(&format!("{}: {}\n", sanitize_for_display(&path), e));
+        }
+        self.sourcing_env.set(false);
+    }
+
+    /// Picks the editing mode from `VISUAL`, or else `EDITOR`. This comes
+    /// before the startup files, so that their `set -o` and the inputrc
+    /// file have the last word.
+    pub fn follow_editor(&self) {
+        let set = |name| self.var(name).filter(|value| !value.is_empty());
+        if let Some(editor) = set("VISUAL").or_else(|| set("EDITOR")) {
+            self.edit_settings.borrow_mut().follow_editor(&editor);
+        }
+    }
+
+    /// Reads line-editing settings from `~/.myshell_inputrc`, if present,
+    /// warning about lines that cannot be used.
+    pub fn load_inputrc(&self) {
+        let Some(home) = self.var("HOME") else {
+            return;
+        };
+        let Ok(text) = std::fs::read_to_string(Path::new(&home).join(inputrc::INPUTRC_FILE)) else {
+            return;
+        };
+        for warning in self.edit_settings.borrow_mut().load(&text) {
+            self.write_error(&format!("{}\n", sanitize_for_display(&warning)));
+        }
+    }
+
+    /// Records a line typed at the prompt in the history. A command that
+    /// spans lines becomes one entry, joined with `; ` unless `set -o
+    /// lithist` keeps its newlines.
+    pub fn record_history(&self, line: &str) {
+        let entry = if self.lithist.get() { Cow::Borrowed(line) } else { Cow::Owned(history::join_lines(line)) };
+        let mut history = self.history.borrow_mut();
+        history.set_max(self.history_size());
+        history.push(&entry);
+    }
+
+    /// How many entries the history keeps: `HISTSIZE`, or else the inputrc
+    /// `history-size`. `None` means no limit, as a negative `HISTSIZE` asks.
+    pub fn history_size(&self) -> Option<usize> {
+        history::size_limit(self.var("HISTSIZE").as_deref()).unwrap_or(Some(self.edit_settings.borrow().history_size))
+    }
+
+    /// How many entries the history file keeps: `HISTFILESIZE`, or else as
+    /// many as the history.
+    pub fn history_file_size(&self) -> Option<usize> {
+        history::size_limit(self.var("HISTFILESIZE").as_deref()).unwrap_or_else(|| self.history_size())
+    }
+
+    /// Where the history is saved between sessions: `HISTFILE`, or
+    /// `~/.myshell_history` if that is unset. An empty `HISTFILE` means the
+    /// history is not saved.
+    pub fn history_file(&self) -> Option<PathBuf> {
+        match self.var("HISTFILE") {
+            Some(file) => (!file.is_empty()).then(|| self.cwd().join(file)),
+            None => self.var("HOME").map(|home| Path::new(&home).join(history::HISTORY_FILE)),
+        }
+    }
+
+    /// Reads the history saved by an earlier session, keeping the last
+    /// `history_size` entries. Entries that are not UTF-8 load too, and are
+    /// saved again byte for byte.
+    pub fn load_history(&self) {
+        if let Some(bytes) = self.history_file().and_then(|path| std::fs::read(path).ok()) {
+            let mut history = history::History::load(&bytes);
+            history.set_max(self.history_size());
+            *self.history.borrow_mut() = history;
+        }
+    }
+
+    /// Adds the entries recorded since the history was loaded to
+    /// `history_file`, in the format `HISTFILE_FORMAT` names, and cuts the
+    /// file to `history_file_size` entries. A file that cannot be read is
+    /// left alone.
+    pub fn save_history(&self) {
+        let Some(path) = self.history_file() else {
+            return;
+        };
+        let shown = sanitize_for_display(&path.display().to_string());
+        let on_disk = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                self.write_error(&format!("{}: {}\n", shown, e));
+                return;
+            }
+        };
+        let format = history::FileFormat::from_var(self.var("HISTFILE_FORMAT").as_deref());
+        let bytes = self.history.borrow().file_bytes(&on_disk, format, self.history_file_size());
+        match write_private_file(&path, &bytes) {
+            Ok(()) => self.history.borrow_mut().mark_saved(),
+            Err(e) => self.write_error(&format!("{}: {}\n", shown, e)),
+        }
+    }
+
+    /// What Tab would offer for the word before `pos` in `line`, given the
+    /// shell's commands, settings, remembered arguments and directory.
+    pub fn complete(&self, line: &str, pos: usize) -> CompletionResult {
+        self.check_path_dirs();
+        self.completion_engine().complete(line, pos)
+    }
+
+    /// The exported variables that are set, with their values, by name.
+    pub fn exported_vars(&self) -> Vec<(String, String)> {
+        let vars = self.vars.borrow();
+        let mut exported: Vec<_> =
+            self.exported.borrow().iter().filter_map(|name| vars.get(name).map(|value| (name.clone(), value.clone()))).collect();
+        exported.sort();
+        exported
+    }
+
+    /// A completion engine over the shell's command index and option cache,
+    /// with its own copies of the editing settings, remembered arguments,
+    /// working directory and `CDPATH`.
+    pub fn completion_engine(&self) -> complete::Engine {
+        complete::Engine {
+            index: self.command_index.clone(),
+            settings: Arc::new(Mutex::new(*self.edit_settings.borrow())),
+            arg_history: Arc::new(Mutex::new(self.arg_history.borrow().clone())),
+            cwd: Arc::new(Mutex::new(self.cwd())),
+            cdpath: Arc::new(Mutex::new(self.var("CDPATH"))),
+            options: self.option_cache.clone(),
+        }
+    }
+
+    /// A completer over `completion_engine`, with its own copy of the theme.
+    pub fn completer(&self) -> MyHelper {
+        MyHelper { engine: self.completion_engine(), theme: Arc::new(Mutex::new(self.theme(&self.stdout.borrow()))) }
+    }
+
+    /// Brings the command index in line with the builtins enabled and the
+    /// functions and aliases defined. Called wherever one of those changes.
+    pub fn update_command_index(&self) {
+        let mut index = self.command_index.lock().unwrap();
+        index.set_builtins(self.builtins.iter().filter(|b| b.enabled.get()).map(|b| b.command.name().to_string()).collect());
+        index.set_functions(self.functions.borrow().keys().cloned().collect());
+        index.set_aliases(self.aliases.borrow().keys().cloned().collect());
+    }
+
+    /// Reads the arguments remembered for completion from `~/.myshell_args`.
+    pub fn load_arg_history(&self) {
+        let Some(home) = self.var("HOME") else {
+            return;
+        };
+        if let Ok(text) = std::fs::read_to_string(Path::new(&home).join(arghistory::ARGS_FILE)) {
+            *self.arg_history.borrow_mut() = ArgHistory::load(&text);
+        }
+    }
+
+    /// Writes the remembered arguments back to `~/.myshell_args`.
+    pub fn save_arg_history(&self) {
+        let Some(home) = self.var("HOME") else {
+            return;
+        };
+        let path = Path::new(&home).join(arghistory::ARGS_FILE);
+        if let Err(e) = write_private_file(&path, self.arg_history.borrow().to_text().as_bytes()) {
+            self.write_error(&format!("{}: {}\n", sanitize_for_display(&path.display().to_string()), e));
+        }
+    }
+
+    // Keeps the history and the editor's copy of it to `history_size`.
+    fn limit_history(&self, editor: &mut impl Configurer) -> Result<()> {
+        let size = self.history_size();
+        self.history.borrow_mut().set_max(size);
+        editor.set_max_history_size(size.unwrap_or(usize::MAX))
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        self.load_inputrc();
+        self.load_history();
+        self.load_arg_history();
+        self.check_path_dirs();
+        let helper = self.completer();
+        let settings = helper.engine.settings.clone();
+        let arg_history = helper.engine.arg_history.clone();
+        let cwd = helper.engine.cwd.clone();
+        let cdpath = helper.engine.cdpath.clone();
+        let theme = helper.theme.clone();
+        let tab_handler = helper.tab_handler();
+
+        // Without a terminal to edit on, as with `-i` and piped input, or if
+        // the editor cannot start, lines are read as they come.
+        let editor = if std::io::stdin().is_terminal() { Some(Editor::new()) } else { None };
+        let mut rl = match editor {
+            Some(Ok(rl)) => rl,
+            editor => {
+                if let Some(Err(e)) = editor {
+                    self.write_error(&format!("myshell: line editing unavailable: {}\n", e));
+                }
+                self.run_stdin();
+                self.save_history();
+                self.save_arg_history();
+                return Ok(());
+            }
+        };
+
+        // Commands that leave the terminal raw or without echo, or die before
+        // restoring it, do not affect the next prompt; dropping the guard
+        // restores the terminal on exit as well.
+        let terminal = terminal::TerminalGuard::capture();
+        if let Some(guard) = &terminal {
+            guard.restore_on_signal();
+        }
+
+        self.limit_history(&mut rl)?;
+        for entry in self.history.borrow().entries() {
+            rl.add_history_entry(entry.line.as_str())?;
+        }
         rl.set_helper(Some(helper));
         rl.bind_sequence(KeyEvent(KeyCode::Tab, Modifiers::NONE), EventHandler::Conditional(Box::new(tab_handler)));
+        rl.bind_sequence(KeyEvent::alt('d'), EventHandler::Conditional(Box::new(KillArgument { forward: true })));
+        rl.bind_sequence(KeyEvent(KeyCode::Backspace, Modifiers::ALT), EventHandler::Conditional(Box::new(KillArgument { forward: false })));
 
         loop {
+            if let Some(guard) = &terminal {
+                guard.restore();
+            }
+            // Completion runs inside rustyline, so it sees the settings,
+            // remembered arguments, directory and CDPATH through copies
+            // refreshed before each prompt, and commands through the shared
+            // index.
+            *settings.lock().unwrap() = *self.edit_settings.borrow();
+            *cwd.lock().unwrap() = self.cwd();
+            *cdpath.lock().unwrap() = self.var("CDPATH");
+            self.check_path_dirs();
+            *arg_history.lock().unwrap() = self.arg_history.borrow().clone();
+            *theme.lock().unwrap() = self.theme(&self.stdout.borrow());
+            self.edit_settings.borrow().apply_to(&mut rl)?;
+            self.limit_history(&mut rl)?;
             let readline = rl.readline("$ ");
             match readline {
                 Ok(line) => {
-                    let cmd_line = CommandLine::parse(&line);
-                    if !self.execute(cmd_line) {
+                    let line = self.read_continuation(line, |prompt| rl.readline(prompt).ok());
+                    self.run_typed_line(&line);
+                    if self.exit_code.get().is_some() {
                         break;
                     }
-                    rl.add_history_entry(line.as_str())?;
+                    // The editor's history follows the shell's, which
+                    // `history -d` may have edited.
+                    self.record_history(&line);
+                    let mut history = self.history.borrow_mut();
+                    if history.take_rewritten() {
+                        rl.clear_history()?;
+                        for entry in history.entries() {
+                            rl.add_history_entry(entry.line.as_str())?;
+                        }
+                    } else if !line.trim().is_empty()
+                        && let Some(entry) = history.entries().last()
+                    {
+                        rl.add_history_entry(entry.line.as_str())?;
+                    }
                 }
                 Err(ReadlineError::Interrupted) => {
-                    println!("Ctrl-C");
+                    stdio::print("Ctrl-C\n");
                     break;
                 }
                 Err(ReadlineError::Eof) => {
-                    println!("Ctrl-D");
+                    stdio::print("Ctrl-D\n");
                     break;
                 }
                 Err(err) => {
-                    println!("Error: {:?}", err);
+                    stdio::print(&format!("Error: {:?}\n", err));
                     break;
                 }
             }
         }
+        self.save_history();
+        self.save_arg_history();
         Ok(())
     }
 }
 
-pub fn find_longest_common_prefix(matches: &[String]) -> String {
-    if matches.is_empty() {
-        return String::new();
-    }
-    let mut prefix = matches[0].clone();
-    if std::env::var("DEBUG").is_ok() {
-        eprintln!("[DEBUG] Initial prefix: '{}'", prefix);
-    }
-    for m in &matches[1..] {
-        let mut i = 0;
-        let max = std::cmp::min(prefix.len(), m.len());
-        while i < max && prefix.as_bytes()[i] == m.as_bytes()[i] {
-            i += 1;
-        }
-        prefix.truncate(i);
-        if std::env::var("DEBUG").is_ok() {
-            eprintln!("[DEBUG] Truncated prefix after comparing with '{}': '{}'", m, prefix);
+/// A candidate as `describe_candidate` shows it, with its kind in the
+/// theme's hint color.
+pub fn highlight_description<'a>(display: &'a str, theme: &Theme) -> Cow<'a, str> {
+    match display.rfind("  (") {
+        Some(i) if display.ends_with(')') && !theme.hint.is_empty() => {
+            Cow::Owned(format!("{}  {}", &display[..i], Theme::paint(&theme.hint, &display[i + 2..])))
         }
+        _ => Cow::Borrowed(display),
     }
-    prefix
 }
 
-#[derive(Helper, Highlighter, Hinter, Validator)]
+// The line editor's completer: an adapter over the completion engine.
+#[derive(Helper, Hinter, Validator)]
 pub struct MyHelper {
-    pub commands: Vec<String>,
-    pub path_dirs: Vec<std::path::PathBuf>,
+    pub engine: complete::Engine,
+    pub theme: Arc<Mutex<Theme>>,
 }
 
 impl MyHelper {
-    pub fn get_all_suggestions(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
-        let (start, word_to_complete) = {
-            let split_idx = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
-            (split_idx, &line[split_idx..pos])
-        };
-
-        let mut all_matches: Vec<String> = self
-            .commands
-            .iter()
-            .filter(|cmd| cmd.starts_with(word_to_complete))
-            .map(|cmd| format!("{} ", cmd))
-            .collect();
-
-        let mut executable_matches = self.get_executable_suggestions(word_to_complete);
-        all_matches.append(&mut executable_matches);
-
-        all_matches.sort();
-        all_matches.dedup();
-
-        (start, all_matches)
-    }
-
-    fn get_executable_suggestions(&self, word_to_complete: &str) -> Vec<String> {
-        let mut suggestions = Vec::new();
-        for path_dir in &self.path_dirs {
-            let Ok(entries) = std::fs::read_dir(path_dir) else { continue; };
-            for entry in entries.flatten() {
-                let file_name = entry.file_name();
-                let Some(name_str) = file_name.to_str() else { continue; };
-                if !name_str.starts_with(word_to_complete) { continue; }
-                let full_path = path_dir.join(name_str);
-                let Ok(metadata) = std::fs::metadata(&full_path) else { continue; };
-                let is_executable = if cfg!(target_family = "unix") {
-                    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
-                } else {
-                    metadata.is_file()
-                };
-                if is_executable {
-                    suggestions.push(format!("{} ", name_str));
-                }
-            }
+    /// The Tab key handler, sharing this completer's state.
+    fn tab_handler(&self) -> MyTabHandler {
+        MyTabHandler {
+            state: Arc::new(Mutex::new(TabState { consecutive_tabs: 0, last_line: String::new(), last_pos: 0, generation: 0 })),
+            engine: self.engine.clone(),
+            theme: self.theme.clone(),
         }
-        suggestions.sort();
-        suggestions.dedup();
-        suggestions
     }
 }
 
@@ -547,29 +2818,56 @@ impl Completer for MyHelper {
         pos: usize,
         _ctx: &Context<'_>,
     ) -> Result<(usize, Vec<Pair>)> {
-        let (start, matches) = self.get_all_suggestions(line, pos);
-    
-        let word_to_complete = &line[start..pos];
-        let trimmed_matches: Vec<String> = matches.iter().map(|s| s.trim_end().to_string()).collect();
-        let common_prefix = find_longest_common_prefix(&trimmed_matches);
-        let add_space = matches.len() == 1 || common_prefix == word_to_complete;
-    
-        let pairs = matches
-            .into_iter()
-            .map(|cmd| {
-                let replacement = if add_space {
-                    format!("{} ", cmd.trim_end())
-                } else {
-                    cmd.trim_end().to_string()
-                };
-                Pair {
-                    display: cmd.clone(),
-                    replacement,
-                }
-            })
-            .collect();
-        
-        Ok((start, pairs))
+        let result = self.engine.complete(line, pos);
+        let pairs = result.candidates.into_iter().map(|candidate| Pair { display: candidate.display, replacement: candidate.text }).collect();
+        Ok((result.start, pairs))
+    }
+}
+
+// Colors the prompt, the command word by what it names, and the kinds beside
+// completion candidates, as the theme says.
+impl Highlighter for MyHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let theme = self.theme.lock().unwrap();
+        let Some(span) = syntax::word_spans(line).into_iter().next() else {
+            return Cow::Borrowed(line);
+        };
+        let word = &line[span.clone()];
+        let index = self.engine.index.lock().unwrap();
+        let color = if index.kind(word).is_some() {
+            &theme.builtin
+        } else if !word.contains(paths::is_separator) && complete::find_executable_in(index.path_dirs(), word).is_some() {
+            &theme.executable
+        } else {
+            return Cow::Borrowed(line);
+        };
+        if color.is_empty() {
+            return Cow::Borrowed(line);
+        }
+        Cow::Owned(format!("{}{}{}", &line[..span.start], Theme::paint(color, word), &line[span.end..]))
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(&'s self, prompt: &'p str, _default: bool) -> Cow<'b, str> {
+        match &self.theme.lock().unwrap().prompt {
+            color if color.is_empty() => Cow::Borrowed(prompt),
+            color => Cow::Owned(Theme::paint(color, prompt)),
+        }
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        match &self.theme.lock().unwrap().hint {
+            color if color.is_empty() => Cow::Borrowed(hint),
+            color => Cow::Owned(Theme::paint(color, hint)),
+        }
+    }
+
+    fn highlight_candidate<'c>(&self, candidate: &'c str, _completion: CompletionType) -> Cow<'c, str> {
+        highlight_description(candidate, &self.theme.lock().unwrap())
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        let theme = self.theme.lock().unwrap();
+        kind != CmdKind::MoveCursor && !(theme.builtin.is_empty() && theme.executable.is_empty())
     }
 }
 
@@ -577,53 +2875,31 @@ struct TabState {
     consecutive_tabs: usize,
     last_line: String,
     last_pos: usize,
+    /// The command index generation the last Tab saw.
+    generation: u64,
 }
 
+// The Tab key: completes a word with one candidate or a longer common prefix
+// at once, and otherwise lists the candidates on the second press.
 struct MyTabHandler {
     state: Arc<Mutex<TabState>>,
-    commands: Vec<String>,
-    path_dirs: Vec<std::path::PathBuf>,
+    engine: complete::Engine,
+    theme: Arc<Mutex<Theme>>,
 }
 
 impl MyTabHandler {
-    fn get_suggestions(&self, line: &str, pos: usize) -> Vec<String> {
-        let (_, word_to_complete) = {
-            let split_idx = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
-            (split_idx, &line[split_idx..pos])
-        };
+    /// The candidates without trailing spaces, and how the double-Tab
+    /// listing shows each.
+    fn listing(&self, line: &str, pos: usize) -> (Vec<String>, Vec<String>) {
+        let result = self.engine.complete(line, pos);
+        result.candidates.into_iter().map(|candidate| (candidate.text.trim_end().to_string(), candidate.display)).unzip()
+    }
 
-        let mut all_matches: Vec<String> = self
-            .commands
-            .iter()
-            .filter(|cmd| cmd.starts_with(word_to_complete))
-            .map(|cmd| cmd.to_string())
-            .collect();
-
-        for path_dir in &self.path_dirs {
-            if let Ok(entries) = std::fs::read_dir(path_dir) {
-                for entry in entries.flatten() {
-                    let file_name = entry.file_name();
-                    if let Some(name_str) = file_name.to_str() {
-                        if name_str.starts_with(word_to_complete) {
-                            let full_path = path_dir.join(name_str);
-                            if let Ok(metadata) = std::fs::metadata(&full_path) {
-                                #[cfg(target_family = "unix")]
-                                if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
-                                    all_matches.push(name_str.to_string());
-                                }
-                                #[cfg(target_family = "windows")]
-                                if metadata.is_file() {
-                                    all_matches.push(name_str.to_string());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    // Rings the terminal bell unless `bell-style` silences it.
+    fn ring_bell(&self) {
+        if self.engine.settings.lock().unwrap().bell_style == BellStyle::Audible {
+            stdio::print("\x07");
         }
-        all_matches.sort();
-        all_matches.dedup();
-        all_matches
     }
 }
 
@@ -631,7 +2907,7 @@ impl ConditionalEventHandler for MyTabHandler {
     fn handle(&self, _event: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
         let current_line = ctx.line().to_string();
         let current_pos = ctx.pos();
-        let matches = self.get_suggestions(&current_line, current_pos);
+        let (matches, listing) = self.listing(&current_line, current_pos);
 
         if matches.len() == 1 {
             return Some(Cmd::Complete);
@@ -639,47 +2915,132 @@ impl ConditionalEventHandler for MyTabHandler {
 
         let mut state = self.state.lock().unwrap();
 
-        if current_line != state.last_line || current_pos != state.last_pos {
+        // A listing shown before the commands changed is out of date.
+        let generation = self.engine.index.lock().unwrap().generation();
+        if current_line != state.last_line || current_pos != state.last_pos || generation != state.generation {
              state.consecutive_tabs = 0;
              state.last_line = current_line.clone();
              state.last_pos = current_pos;
+             state.generation = generation;
         }
 
         if matches.is_empty() {
-             print!("\x07");
-             std::io::stdout().flush().unwrap();
+             self.ring_bell();
              return Some(Cmd::Noop);
         }
 
         state.consecutive_tabs += 1;
 
-        if state.consecutive_tabs == 1 {
-            let prefix = find_longest_common_prefix(&matches);
-            let start = current_line[..current_pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
-            let word_len = current_pos - start;
-            if prefix.len() > word_len {
-                state.consecutive_tabs = 0;
-                state.last_line = current_line.clone();
-                state.last_pos = current_pos;
-                return Some(Cmd::Complete);
-            } else {
-                print!("\x07");
-                std::io::stdout().flush().unwrap();
-                Some(Cmd::Noop)
-            }
+        let prefix = complete::find_longest_common_prefix(&matches);
+        let start = current_line[..current_pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let word_len = current_pos - start;
+        if state.consecutive_tabs == 1 && prefix.len() > word_len {
+            state.consecutive_tabs = 0;
+            state.last_line = current_line.clone();
+            state.last_pos = current_pos;
+            Some(Cmd::Complete)
+        } else if state.consecutive_tabs == 1 && !self.engine.settings.lock().unwrap().show_all_if_ambiguous {
+            self.ring_bell();
+            Some(Cmd::Noop)
         } else {
-             print!("\n");
-             let joined = matches.join("  ");
-             print!("{}", joined);
-             print!("\n");
-             print!("$ {}", current_line);
-             std::io::stdout().flush().unwrap();
+             let theme = self.theme.lock().unwrap();
+             let listing: Vec<_> = listing.iter().map(|entry| highlight_description(entry, &theme)).collect();
+             stdio::print(&format!("\n{}\n$ {}", listing.join("  "), current_line));
              Some(Cmd::Noop)
         }
     }
 }
 
+/// Where Alt-D stops killing: the end of the `count`th shell word after
+/// `pos`, a word that `pos` is inside counting as the first. Quoted strings
+/// are one word, as the shell parses them.
+pub fn kill_argument_forward(line: &str, pos: usize, count: usize) -> usize {
+    syntax::word_spans(line).into_iter().map(|span| span.end).filter(|&end| end > pos).nth(count.max(1) - 1).unwrap_or(line.len())
+}
+
+/// Where Alt-Backspace stops killing: the start of the `count`th shell word
+/// before `pos`.
+pub fn kill_argument_backward(line: &str, pos: usize, count: usize) -> usize {
+    syntax::word_spans(line).into_iter().map(|span| span.start).filter(|&start| start < pos).rev().nth(count.max(1) - 1).unwrap_or(0)
+}
+
+// Kills whole shell words rather than rustyline's alphanumeric ones. The
+// text goes on the kill ring, where Ctrl-Y yanks it and Alt-Y rotates.
+struct KillArgument {
+    forward: bool,
+}
+
+impl ConditionalEventHandler for KillArgument {
+    fn handle(&self, _event: &Event, count: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        let (line, pos) = (ctx.line(), ctx.pos());
+        let movement = if self.forward {
+            Movement::ForwardChar(line[pos..kill_argument_forward(line, pos, count)].chars().count())
+        } else {
+            Movement::BackwardChar(line[kill_argument_backward(line, pos, count)..pos].chars().count())
+        };
+        Some(Cmd::Kill(movement))
+    }
+}
+
 fn main() -> Result<()> {
+    let mut options = match options::Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            stdio::eprint(&format!("{}\n", e));
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        stdio::print(options::USAGE);
+        return Ok(());
+    }
+    if options.version {
+        stdio::print(&format!("myshell {}\n", env!("CARGO_PKG_VERSION")));
+        return Ok(());
+    }
+    // `--complete` offers what Tab would, but from the builtins and PATH
+    // alone, without reading any startup file. It fails if there is no
+    // candidate.
+    if let Some((line, pos)) = &options.complete {
+        let names = Shell::new().complete(line, *pos).names().iter().map(|name| format!("{}\n", name)).collect::<Vec<_>>();
+        stdio::print(&names.concat());
+        std::process::exit(i32::from(names.is_empty()));
+    }
+    // A program name such as `-myshell`, as `login` runs it, means a login shell.
+    if env::args().next().is_some_and(|name| name.starts_with('-')) {
+        options.login = true;
+    }
+    if options.login
+        && let Ok(exe) = env::current_exe()
+    {
+        // SAFETY: no other threads have been started yet.
+        unsafe { env::set_var("SHELL", exe) };
+    }
     let mut shell = Shell::new();
-    shell.run()
+    // At startup `$_` is the path the shell was invoked by.
+    if let Some(invoked_as) = env::args().next() {
+        shell.set_var("_", &invoked_as);
+    }
+    let mode = options.mode(std::io::stdin().is_terminal());
+    shell.interactive.set(mode == Mode::Interactive);
+    if mode == Mode::Interactive {
+        shell.follow_editor();
+    }
+    shell.load_startup_files(&options, mode);
+    if shell.exit_code.get().is_none() {
+        match mode {
+            Mode::Command => {
+                shell.run_line(options.command.as_deref().unwrap_or_default());
+            }
+            Mode::Script => shell.run_script(options.script.as_deref().unwrap_or_default(), options.script_args.clone()),
+            Mode::Interactive => {
+                if let Err(e) = shell.run() {
+                    shell.write_error(&format!("myshell: {}\n", e));
+                }
+            }
+            Mode::Stdin => shell.run_stdin(),
+        }
+    }
+    shell.run_logout(&options);
+    std::process::exit(shell.exit_status())
 }
@@ -0,0 +1,190 @@
+// Command-line options for the shell binary.
+
+use std::path::PathBuf;
+
+use crate::expand::{self, Variables};
+
+/// The rc file sourced at startup, relative to `HOME`.
+pub const RC_FILE: &str = ".myshellrc";
+
+/// Sourced by every login shell, before the user's profile.
+pub const SYSTEM_PROFILE: &str = "/etc/myshell_profile";
+
+/// The user's login profile and logout file, relative to `HOME`.
+pub const PROFILE_FILE: &str = ".myshell_profile";
+pub const LOGOUT_FILE: &str = ".myshell_logout";
+
+/// Set in the environment of commands run while the `ENV` file is sourced,
+/// so that a shell started from that file does not source it again.
+pub const ENV_GUARD: &str = "MYSHELL_SOURCING_ENV";
+
+/// Printed by `--help`.
+pub const USAGE: &str = "\
+usage: myshell [option ...] [FILE [ARG ...]]
+  -c COMMAND       run COMMAND, then exit with its status
+  FILE             run the script FILE with ARG ... as $1 ...
+  -i               run the interactive REPL even if stdin is not a terminal
+  -s               read commands from stdin without the REPL
+  -l, --login      act as a login shell
+  --norc           do not read ~/.myshellrc
+  --rcfile FILE    read FILE instead of ~/.myshellrc
+  --complete LINE POS
+                   print the completions for LINE with the cursor at byte
+                   POS, one per line, and exit
+  --help           show this help and exit
+  --version        show the version and exit
+
+-c wins over FILE, and FILE over -i; -i wins over -s. Without any of
+them the REPL runs when stdin is a terminal, and stdin is read otherwise.
+";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// `--norc`: source no rc file. Takes precedence over `--rcfile`.
+    pub norc: bool,
+    /// `--rcfile PATH`: the rc file to source instead of `~/.myshellrc`.
+    pub rcfile: Option<String>,
+    /// `-c COMMAND`: run the command instead of the REPL.
+    pub command: Option<String>,
+    /// A script to run instead of the REPL, and its positional parameters.
+    pub script: Option<String>,
+    pub script_args: Vec<String>,
+    /// `-i`: run the REPL even if stdin is not a terminal.
+    pub force_interactive: bool,
+    /// `-s`: read commands from stdin without the REPL, even from a terminal.
+    pub force_stdin: bool,
+    /// `--help`: print `USAGE` and exit.
+    pub help: bool,
+    /// `--version`: print the version and exit.
+    pub version: bool,
+    /// `--complete LINE POS`: print the completions at byte `POS` of `LINE`
+    /// and exit.
+    pub complete: Option<(String, usize)>,
+    /// `-l`/`--login`, or a program name starting with `-`: read the login
+    /// profiles at startup and the logout file at exit.
+    pub login: bool,
+}
+
+/// Where the shell takes its commands from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// `-c COMMAND`.
+    Command,
+    /// A script file named on the command line.
+    Script,
+    /// The REPL, with line editing and history.
+    Interactive,
+    /// Lines read from stdin, without a prompt.
+    Stdin,
+}
+
+/// A file the shell sources at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupFile {
+    /// `SYSTEM_PROFILE`.
+    SystemProfile,
+    /// `~/.myshell_profile`.
+    Profile,
+    /// `~/.myshellrc`, or the `--rcfile` file.
+    Rc,
+    /// The file named by `$ENV`.
+    Env,
+}
+
+/// The files a shell reads at startup, in order. Login shells read the
+/// profiles and interactive shells the rc file, so an interactive login
+/// shell reads all three; a non-interactive, non-login shell reads none.
+pub fn startup_files(login: bool, interactive: bool) -> Vec<StartupFile> {
+    let mut files = Vec::new();
+    if login {
+        files.extend([StartupFile::SystemProfile, StartupFile::Profile]);
+    }
+    if interactive {
+        files.push(StartupFile::Rc);
+    }
+    files
+}
+
+impl Options {
+    /// Parses the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--norc" => options.norc = true,
+                "--rcfile" => {
+                    let path = args.next().ok_or("--rcfile: option requires an argument")?;
+                    options.rcfile = Some(path);
+                }
+                "-c" => {
+                    let command = args.next().ok_or("-c: option requires an argument")?;
+                    options.command = Some(command);
+                }
+                "-i" => options.force_interactive = true,
+                "-s" => options.force_stdin = true,
+                "-l" | "--login" => options.login = true,
+                "--help" => options.help = true,
+                "--version" => options.version = true,
+                "--complete" => {
+                    let (Some(line), Some(pos)) = (args.next(), args.next()) else {
+                        return Err("--complete: option requires LINE and POS".to_string());
+                    };
+                    match pos.parse() {
+                        Ok(pos) if line.is_char_boundary(pos) => options.complete = Some((line, pos)),
+                        _ => return Err(format!("--complete: {}: invalid position", pos)),
+                    }
+                }
+                _ if !arg.starts_with('-') => {
+                    options.script = Some(arg);
+                    options.script_args = args.collect();
+                    break;
+                }
+                _ => return Err(format!("{}: invalid option", arg)),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Where commands come from, given whether stdin is a terminal. `-c`
+    /// wins over a script file, a script over `-i`, and `-i` over `-s`.
+    pub fn mode(&self, stdin_is_terminal: bool) -> Mode {
+        if self.command.is_some() {
+            Mode::Command
+        } else if self.script.is_some() {
+            Mode::Script
+        } else if self.force_interactive {
+            Mode::Interactive
+        } else if self.force_stdin || !stdin_is_terminal {
+            Mode::Stdin
+        } else {
+            Mode::Interactive
+        }
+    }
+
+    /// The files to source at startup in `mode`: those of `startup_files`,
+    /// then for a script or `-c` command the file named by `$ENV`.
+    pub fn startup_files(&self, mode: Mode) -> Vec<StartupFile> {
+        let mut files = startup_files(self.login, mode == Mode::Interactive);
+        if matches!(mode, Mode::Command | Mode::Script) {
+            files.push(StartupFile::Env);
+        }
+        files
+    }
+
+    /// The rc file to source, if any. A leading `~` in `--rcfile` is
+    /// expanded, since the path may have been quoted past the calling shell.
+    pub fn rc_path(&self, vars: &dyn Variables) -> Option<PathBuf> {
+        if self.norc {
+            return None;
+        }
+        let home = || expand::tilde("", vars);
+        match &self.rcfile {
+            Some(path) => match path.strip_prefix('~') {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(PathBuf::from(home()? + rest)),
+                _ => Some(PathBuf::from(path)),
+            },
+            None => Some(PathBuf::from(home()?).join(RC_FILE)),
+        }
+    }
+}
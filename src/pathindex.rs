@@ -0,0 +1,154 @@
+// An index of the executables on PATH. Each directory is read once and its
+// listing reused until the directory's modification time changes, so `type`
+// can answer any number of names from one snapshot instead of walking PATH
+// for each. A directory that cannot be read is found out once and then left
+// alone, rather than failing again on every lookup and every Tab, until
+// PATH is assigned or `hash -r` asks for it to be checked again.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+/// How the index sees the filesystem; tests substitute one that counts reads.
+pub trait DirReader {
+    /// The names of the executable files in `dir`.
+    fn executables(&self, dir: &Path) -> HashSet<String>;
+    /// When `dir` last changed, or `None` if that cannot be told, in which
+    /// case it is read again on every refresh.
+    fn modified(&self, dir: &Path) -> Option<SystemTime>;
+    /// Whether `dir` can be listed and its entries examined.
+    fn check(&self, dir: &Path) -> io::Result<()> {
+        std::fs::read_dir(dir)?;
+        // Listing needs read permission, but looking at an entry needs
+        // search permission too.
+        std::fs::metadata(dir.join(".")).map(drop)
+    }
+}
+
+pub struct FsReader;
+
+impl DirReader for FsReader {
+    fn executables(&self, dir: &Path) -> HashSet<String> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return HashSet::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| std::fs::metadata(entry.path()).is_ok_and(|metadata| is_executable(&metadata)))
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect()
+    }
+
+    fn modified(&self, dir: &Path) -> Option<SystemTime> {
+        std::fs::metadata(dir).and_then(|metadata| metadata.modified()).ok()
+    }
+}
+
+/// Whether a file with this metadata can be run.
+pub fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    return metadata.is_file() && metadata.permissions().mode() & 0o111 != 0;
+    #[cfg(not(unix))]
+    return metadata.is_file();
+}
+
+/// The directories named in a PATH value that exist, in order.
+pub fn split_path(value: &str) -> Vec<PathBuf> {
+    let splitter = if cfg!(windows) { ';' } else { ':' };
+    value.split(splitter).map(PathBuf::from).filter(|path| path.is_dir()).collect()
+}
+
+struct Listing {
+    modified: Option<SystemTime>,
+    names: HashSet<String>,
+}
+
+pub struct PathIndex {
+    dirs: Vec<PathBuf>,
+    reader: Box<dyn DirReader>,
+    listings: HashMap<PathBuf, Listing>,
+    /// Whether the directories have been checked since the index was made
+    /// or `recheck` last ran.
+    checked: bool,
+    unreadable: HashSet<PathBuf>,
+}
+
+impl PathIndex {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self::with_reader(dirs, Box::new(FsReader))
+    }
+
+    pub fn with_reader(dirs: Vec<PathBuf>, reader: Box<dyn DirReader>) -> Self {
+        PathIndex { dirs, reader, listings: HashMap::new(), checked: false, unreadable: HashSet::new() }
+    }
+
+    /// Replaces the directories, as when PATH is assigned, keeping the
+    /// listings of those still among them. All of them are checked again.
+    pub fn set_dirs(&mut self, dirs: Vec<PathBuf>) {
+        self.listings.retain(|dir, _| dirs.contains(dir));
+        self.dirs = dirs;
+        self.recheck();
+    }
+
+    /// Checks that each directory can be read, unless that has been done
+    /// already, in which case it returns `None`. Otherwise it returns the
+    /// directories that cannot be, with the reason, and skips them from now on.
+    pub fn check(&mut self) -> Option<Vec<(PathBuf, io::Error)>> {
+        if self.checked {
+            return None;
+        }
+        self.checked = true;
+        let mut failures = Vec::new();
+        for dir in &self.dirs {
+            if let Err(e) = self.reader.check(dir) {
+                self.unreadable.insert(dir.clone());
+                failures.push((dir.clone(), e));
+            }
+        }
+        Some(failures)
+    }
+
+    /// Forgets which directories could not be read, so the next `check`
+    /// tries them all again.
+    pub fn recheck(&mut self) {
+        self.checked = false;
+        self.unreadable.clear();
+    }
+
+    /// Whether `dir` was found unreadable by the last `check`.
+    pub fn is_unreadable(&self, dir: &Path) -> bool {
+        self.unreadable.contains(dir)
+    }
+
+    /// The directories, less those found unreadable.
+    pub fn readable_dirs(&self) -> Vec<PathBuf> {
+        self.dirs.iter().filter(|dir| !self.unreadable.contains(*dir)).cloned().collect()
+    }
+
+    /// Reads the directories that are new or changed since the last refresh.
+    pub fn refresh(&mut self) {
+        for dir in self.dirs.iter().filter(|dir| !self.unreadable.contains(*dir)) {
+            let modified = self.reader.modified(dir);
+            let stale = match self.listings.get(dir) {
+                Some(listing) => modified.is_none() || listing.modified != modified,
+                None => true,
+            };
+            if stale {
+                let names = self.reader.executables(dir);
+                self.listings.insert(dir.clone(), Listing { modified, names });
+            }
+        }
+    }
+
+    /// Where `name` would be found on PATH, as of the last refresh.
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        self.dirs
+            .iter()
+            .find(|dir| self.listings.get(*dir).is_some_and(|listing| listing.names.contains(name)))
+            .map(|dir| dir.join(name))
+    }
+}
@@ -0,0 +1,78 @@
+// Platform path conventions shared by `cd` and directory completion. On
+// Windows both `\` and `/` separate components, drive letters and UNC shares
+// are absolute roots, and file names compare case-insensitively.
+
+use std::path::{Path, PathBuf};
+
+/// Whether `c` separates path components as typed by the user.
+pub fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// Splits `word` after its last separator into the directory part (with the
+/// separator) and the partial name being typed.
+pub fn split_dir_prefix(word: &str) -> (&str, &str) {
+    match word.rfind(is_separator) {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    }
+}
+
+/// The separator to append after a completed directory: whichever style the
+/// user last typed in `word`, defaulting to the platform's own.
+pub fn preferred_separator(word: &str) -> char {
+    word.chars().rev().find(|&c| is_separator(c)).unwrap_or(std::path::MAIN_SEPARATOR)
+}
+
+/// Whether `target` names a location directly rather than something to look
+/// up along CDPATH: absolute paths, `.`/`..` and paths starting with them,
+/// and on Windows anything with a drive prefix such as `D:` or `D:dir`.
+pub fn is_explicit(target: &str) -> bool {
+    if Path::new(target).is_absolute() || target == "." || target == ".." {
+        return true;
+    }
+    let dotted = |rest: &str| rest.starts_with(is_separator);
+    if target.strip_prefix("..").is_some_and(dotted) || target.strip_prefix('.').is_some_and(dotted) {
+        return true;
+    }
+    let mut chars = target.chars();
+    cfg!(windows) && chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.next() == Some(':')
+}
+
+/// Whether the directory entry `name` completes the partial name `prefix`.
+pub fn name_has_prefix(name: &str, prefix: &str) -> bool {
+    if cfg!(windows) {
+        name.to_lowercase().starts_with(&prefix.to_lowercase())
+    } else {
+        name.starts_with(prefix)
+    }
+}
+
+/// Resolves `path` to the absolute directory it names, in the form a user
+/// would type. Trailing separators are dropped and `..` at the root stays
+/// there, so `dir/` is `dir` and `/..` is `/`. Windows' verbatim `\\?\`
+/// prefixes are removed.
+pub fn normalize_dir(path: &Path) -> Option<PathBuf> {
+    let dir = path.canonicalize().ok()?;
+    if !dir.is_dir() {
+        return None;
+    }
+    Some(strip_verbatim(dir))
+}
+
+#[cfg(windows)]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    let text = path.display().to_string();
+    if let Some(share) = text.strip_prefix(r"\\?\UNC\") {
+        PathBuf::from(format!(r"\\{}", share))
+    } else if let Some(local) = text.strip_prefix(r"\\?\") {
+        PathBuf::from(local)
+    } else {
+        path
+    }
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    path
+}
@@ -0,0 +1,82 @@
+// The pipes between the stages of a pipeline. External commands are handed
+// the writing end directly. What the shell itself writes, for a builtin or
+// function, goes through a thread instead, so output larger than the pipe's
+// buffer cannot block the shell before the next stage is reading.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Sender};
+
+/// Opens a pipe, returning its reading and writing ends.
+pub fn pipe() -> io::Result<(File, File)> {
+    let (reader, writer) = io::pipe()?;
+    Ok((into_file(reader), into_file(writer)))
+}
+
+#[cfg(unix)]
+fn into_file(end: impl Into<std::os::fd::OwnedFd>) -> File {
+    File::from(end.into())
+}
+
+#[cfg(windows)]
+fn into_file(end: impl Into<std::os::windows::io::OwnedHandle>) -> File {
+    File::from(end.into())
+}
+
+enum Message {
+    Bytes(Vec<u8>),
+    /// Answered once everything sent before it has been written.
+    Flush(Sender<()>),
+}
+
+/// The writing end of a pipe, shared by the commands of one stage.
+#[derive(Debug)]
+pub struct PipeWriter {
+    file: File,
+    sender: Sender<Message>,
+}
+
+impl PipeWriter {
+    pub fn new(file: File) -> io::Result<Self> {
+        let mut writing = file.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        // The thread ends, closing its copy of the pipe, once the writer is
+        // dropped or the reading end is gone.
+        std::thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    Message::Bytes(bytes) => {
+                        if writing.write_all(&bytes).is_err() {
+                            break;
+                        }
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Ok(PipeWriter { file, sender })
+    }
+
+    /// Queues `bytes` to be written after anything queued earlier.
+    pub fn write(&self, bytes: &[u8]) {
+        let _ = self.sender.send(Message::Bytes(bytes.to_vec()));
+    }
+
+    /// A copy of the writing end that bypasses the queue, for a copy of the
+    /// shell, which has no thread to drain it.
+    pub fn file_copy(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// The writing end, for a child process. Waits for queued output to be
+    /// written first, so the child's output follows it.
+    pub fn file(&self) -> &File {
+        let (done, flushed) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+        &self.file
+    }
+}
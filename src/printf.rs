@@ -0,0 +1,361 @@
+// `printf` formatting: backslash escapes and `%` conversions with flags,
+// width and precision. As in bash, the format is reused while arguments
+// remain, missing arguments read as empty or zero, and a format that
+// consumes no arguments is applied exactly once.
+
+/// The output, plus any arguments that were not valid numbers. The output
+/// still uses 0 for those, so the caller decides how to report them.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Formatted {
+    pub output: String,
+    pub errors: Vec<String>,
+}
+
+pub fn format_printf(format: &str, args: &[String]) -> Formatted {
+    let mut result = Formatted::default();
+    let mut next = 0;
+    loop {
+        let before = next;
+        if !format_once(format, args, &mut next, &mut result) {
+            // `\c` stops all output.
+            break;
+        }
+        if next >= args.len() || next == before {
+            break;
+        }
+    }
+    result
+}
+
+// One pass over the format. Returns false if `\c` ended the output.
+fn format_once(format: &str, args: &[String], next: &mut usize, result: &mut Formatted) -> bool {
+    let chars: Vec<char> = format.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let (text, used, stop) = escape(&chars[i + 1..], false);
+                result.output.push_str(&text);
+                if stop {
+                    return false;
+                }
+                i += 1 + used;
+            }
+            '%' if chars.get(i + 1) == Some(&'%') => {
+                result.output.push('%');
+                i += 2;
+            }
+            '%' => {
+                let Some((spec, used)) = Spec::parse(&chars[i + 1..], args, next) else {
+                    // An incomplete conversion is printed as written.
+                    result.output.extend(&chars[i..]);
+                    return true;
+                };
+                let arg = args.get(*next).map(String::as_str);
+                *next += 1;
+                if !spec.format(arg.unwrap_or(""), arg.is_none(), result) {
+                    return false;
+                }
+                i += 1 + used;
+            }
+            c => {
+                result.output.push(c);
+                i += 1;
+            }
+        }
+    }
+    true
+}
+
+// Decodes the escape after a backslash, returning the text, how many chars
+// it used and whether it was `\c`. In `%b` arguments, octal escapes are
+// written `\0NNN`; in the format, `\NNN`.
+fn escape(rest: &[char], in_argument: bool) -> (String, usize, bool) {
+    let Some(&c) = rest.first() else {
+        return ("\\".to_string(), 0, false);
+    };
+    let simple = match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        'a' => Some('\x07'),
+        'b' => Some('\x08'),
+        'f' => Some('\x0c'),
+        'v' => Some('\x0b'),
+        'e' | 'E' => Some('\x1b'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        _ => None,
+    };
+    if let Some(s) = simple {
+        return (s.to_string(), 1, false);
+    }
+    match c {
+        'c' => (String::new(), 1, true),
+        'x' => {
+            let digits: String = rest[1..].iter().take(2).take_while(|d| d.is_ascii_hexdigit()).collect();
+            match u32::from_str_radix(&digits, 16) {
+                Ok(code) => (char::from_u32(code).unwrap_or('?').to_string(), 1 + digits.len(), false),
+                Err(_) => ("\\x".to_string(), 1, false),
+            }
+        }
+        '0'..='7' => {
+            let skip = usize::from(in_argument && c == '0');
+            let digits: String = rest[skip..].iter().take(3).take_while(|d| ('0'..='7').contains(d)).collect();
+            let code = u32::from_str_radix(&digits, 8).unwrap_or(0);
+            (char::from_u32(code & 0xff).unwrap_or('?').to_string(), skip + digits.len(), false)
+        }
+        _ => (format!("\\{}", c), 1, false),
+    }
+}
+
+/// Decodes the escapes `echo -e` knows in `text`, which are those of a `%b`
+/// argument less `\"`, `\'` and `\E`, kept as written as in coreutils.
+/// Returns false as well if `\c` ended the output.
+pub fn echo_escapes(text: &str) -> (String, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || matches!(chars.get(i + 1), Some('"' | '\'' | 'E')) {
+            output.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let (decoded, used, stop) = escape(&chars[i + 1..], true);
+        output.push_str(&decoded);
+        if stop {
+            return (output, false);
+        }
+        i += 1 + used;
+    }
+    (output, true)
+}
+
+struct Spec {
+    left: bool,
+    zero: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+impl Spec {
+    // Parses the conversion after a `%`, returning it and how many chars it
+    // used. A `*` width or precision takes the next argument.
+    fn parse(chars: &[char], args: &[String], next: &mut usize) -> Option<(Spec, usize)> {
+        let mut spec = Spec {
+            left: false,
+            zero: false,
+            plus: false,
+            space: false,
+            alternate: false,
+            width: 0,
+            precision: None,
+            conversion: 's',
+        };
+        let mut i = 0;
+        while let Some(&c) = chars.get(i) {
+            match c {
+                '-' => spec.left = true,
+                '0' => spec.zero = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alternate = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let mut number = |i: &mut usize| -> usize {
+            if chars.get(*i) == Some(&'*') {
+                *i += 1;
+                let value = args.get(*next).and_then(|a| a.parse::<usize>().ok()).unwrap_or(0);
+                *next += 1;
+                return value;
+            }
+            let start = *i;
+            while chars.get(*i).is_some_and(char::is_ascii_digit) {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect::<String>().parse().unwrap_or(0)
+        };
+        spec.width = number(&mut i);
+        if chars.get(i) == Some(&'.') {
+            i += 1;
+            spec.precision = Some(number(&mut i));
+        }
+        spec.conversion = *chars.get(i)?;
+        if !"sbcdiouxXeEfFgG".contains(spec.conversion) {
+            return None;
+        }
+        Some((spec, i + 1))
+    }
+
+    // Formats one argument. Returns false if a `%b` argument held `\c`.
+    fn format(&self, arg: &str, missing: bool, result: &mut Formatted) -> bool {
+        let mut keep_going = true;
+        let body = match self.conversion {
+            's' => self.truncate(arg),
+            'b' => {
+                let chars: Vec<char> = arg.chars().collect();
+                let mut text = String::new();
+                let mut i = 0;
+                while i < chars.len() {
+                    if chars[i] == '\\' {
+                        let (decoded, used, stop) = escape(&chars[i + 1..], true);
+                        text.push_str(&decoded);
+                        if stop {
+                            keep_going = false;
+                            break;
+                        }
+                        i += 1 + used;
+                    } else {
+                        text.push(chars[i]);
+                        i += 1;
+                    }
+                }
+                self.truncate(&text)
+            }
+            'c' => arg.chars().next().map(String::from).unwrap_or_default(),
+            'd' | 'i' | 'o' | 'u' | 'x' | 'X' => {
+                let value = if missing { 0 } else { integer(arg, result) };
+                self.integer(value)
+            }
+            _ => {
+                let value = if missing { 0.0 } else { float(arg, result) };
+                self.float(value)
+            }
+        };
+        result.output.push_str(&self.pad(&body));
+        keep_going
+    }
+
+    fn truncate(&self, text: &str) -> String {
+        match self.precision {
+            Some(p) => text.chars().take(p).collect(),
+            None => text.to_string(),
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn integer(&self, value: i64) -> String {
+        let magnitude = value.unsigned_abs();
+        let (digits, prefix) = match self.conversion {
+            'o' => (format!("{:o}", value as u64), if self.alternate { "0" } else { "" }),
+            'x' => (format!("{:x}", value as u64), if self.alternate && value != 0 { "0x" } else { "" }),
+            'X' => (format!("{:X}", value as u64), if self.alternate && value != 0 { "0X" } else { "" }),
+            'u' => ((value as u64).to_string(), ""),
+            _ => (magnitude.to_string(), self.sign(value < 0)),
+        };
+        let digits = match self.precision {
+            Some(p) if digits.len() < p => format!("{}{}", "0".repeat(p - digits.len()), digits),
+            _ => digits,
+        };
+        self.zero_fill(prefix, &digits, self.precision.is_none())
+    }
+
+    fn float(&self, value: f64) -> String {
+        let precision = self.precision.unwrap_or(6);
+        let digits = match self.conversion {
+            'e' | 'E' => exponent(value.abs(), precision, self.conversion == 'E'),
+            'g' | 'G' => {
+                let precision = precision.max(1);
+                let exp = if value == 0.0 { 0 } else { value.abs().log10().floor() as i64 };
+                if exp < -4 || exp >= precision as i64 {
+                    exponent(value.abs(), precision - 1, self.conversion == 'G')
+                } else {
+                    let fixed = format!("{:.*}", (precision as i64 - 1 - exp).max(0) as usize, value.abs());
+                    if fixed.contains('.') && !self.alternate {
+                        fixed.trim_end_matches('0').trim_end_matches('.').to_string()
+                    } else {
+                        fixed
+                    }
+                }
+            }
+            _ => format!("{:.*}", precision, value.abs()),
+        };
+        self.zero_fill(self.sign(value.is_sign_negative() && value != 0.0), &digits, true)
+    }
+
+    // Joins sign or prefix and digits, zero-padding between them for `0`.
+    fn zero_fill(&self, prefix: &str, digits: &str, allowed: bool) -> String {
+        let len = prefix.len() + digits.len();
+        if self.zero && !self.left && allowed && len < self.width {
+            format!("{}{}{}", prefix, "0".repeat(self.width - len), digits)
+        } else {
+            format!("{}{}", prefix, digits)
+        }
+    }
+
+    fn pad(&self, body: &str) -> String {
+        let len = body.chars().count();
+        if len >= self.width {
+            body.to_string()
+        } else if self.left {
+            format!("{}{}", body, " ".repeat(self.width - len))
+        } else {
+            format!("{}{}", " ".repeat(self.width - len), body)
+        }
+    }
+}
+
+fn exponent(value: f64, precision: usize, upper: bool) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exp) = text.split_once('e').unwrap_or((&text, "0"));
+    let exp: i32 = exp.parse().unwrap_or(0);
+    let e = if upper { 'E' } else { 'e' };
+    format!("{}{}{}{:02}", mantissa, e, if exp < 0 { '-' } else { '+' }, exp.abs())
+}
+
+// Reads an integer argument: decimal, `0x` hex, `0` octal, or `'c` for a
+// character's code. Invalid text reads as 0 and is reported.
+fn integer(arg: &str, result: &mut Formatted) -> i64 {
+    let text = arg.trim();
+    if let Some(c) = text.strip_prefix(['\'', '"']) {
+        return c.chars().next().map_or(0, |c| c as i64);
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    match parsed {
+        Ok(value) => if negative { -value } else { value },
+        Err(_) => {
+            result.errors.push(arg.to_string());
+            0
+        }
+    }
+}
+
+fn float(arg: &str, result: &mut Formatted) -> f64 {
+    let text = arg.trim();
+    if let Some(c) = text.strip_prefix(['\'', '"']) {
+        return c.chars().next().map_or(0.0, |c| c as u32 as f64);
+    }
+    text.parse().unwrap_or_else(|_| {
+        result.errors.push(arg.to_string());
+        0.0
+    })
+}
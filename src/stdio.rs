@@ -0,0 +1,53 @@
+// The shell's own standard output and error. Once a write fails with EPIPE,
+// because whatever was reading the stream has gone away, the stream counts
+// as closed and later writes to it are dropped rather than failing again.
+// The shell checks after each command and, unless interactive, ends with the
+// status of a process killed by SIGPIPE. Nothing here panics, as `print!`
+// would.
+
+use std::io::{ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The status a non-interactive shell exits with once its stdout is closed:
+/// 128 plus SIGPIPE, as a shell killed by the signal would report.
+pub const BROKEN_PIPE_STATUS: i32 = 141;
+
+// Whether stdout and stderr have hit EPIPE.
+static CLOSED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+fn closed(fd: i32) -> &'static AtomicBool {
+    &CLOSED[if fd == 1 { 0 } else { 1 }]
+}
+
+/// Whether writes to `fd` (1 for stdout, otherwise stderr) have stopped
+/// because its reader went away.
+pub fn is_closed(fd: i32) -> bool {
+    closed(fd).load(Ordering::Relaxed)
+}
+
+/// Writes `bytes` to stdout (fd 1) or stderr and flushes. Errors other than
+/// EPIPE are ignored, as an unwritable terminal leaves nowhere to report them.
+pub fn write(fd: i32, bytes: &[u8]) {
+    if is_closed(fd) {
+        return;
+    }
+    let written = if fd == 1 {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes).and_then(|()| stdout.flush())
+    } else {
+        std::io::stderr().lock().write_all(bytes)
+    };
+    if written.is_err_and(|e| e.kind() == ErrorKind::BrokenPipe) {
+        closed(fd).store(true, Ordering::Relaxed);
+    }
+}
+
+/// `print!` that cannot panic.
+pub fn print(text: &str) {
+    write(1, text.as_bytes());
+}
+
+/// `eprint!` that cannot panic.
+pub fn eprint(text: &str) {
+    write(2, text.as_bytes());
+}
@@ -0,0 +1,752 @@
+// Shell grammar: splits input into commands, dropping comments, and parses
+// the compound constructs. Simple commands are kept as source text and parsed by
+// `CommandLine` when they run, so expansions see up-to-date variables. The
+// bodies of here-documents are read from the lines after the command that
+// opens them and kept with it, unexpanded.
+
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The input ended inside a construct; more lines may complete it.
+    Incomplete,
+    Unexpected(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => f.write_str("syntax error: unexpected end of file"),
+            ParseError::Unexpected(token) => write!(f, "syntax error near unexpected token `{}'", token),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// A simple command's source text, the line it starts on, and the bodies
+    /// of its here-documents in the order their `<<` appear.
+    Simple { text: String, line: usize, here_docs: Vec<HereDoc> },
+    /// `(( expr ))`, holding the text between the parentheses.
+    Arithmetic(String),
+    /// `[[ expr ]]`, holding the text between the brackets.
+    Conditional(String),
+    /// `select NAME in WORDS; do BODY; done`. Words are unexpanded.
+    Select { name: String, words: Vec<String>, body: Vec<Node> },
+    /// `case WORD in PATTERN|PATTERN) BODY;; ... esac`. Word and patterns are unexpanded.
+    Case { word: String, arms: Vec<CaseArm> },
+    /// `{ LIST; }`.
+    Group(Vec<Node>),
+    /// `NAME() BODY` or `function NAME BODY`, where the body is a compound command.
+    Function { name: String, body: Rc<Node> },
+    /// `! PIPELINE`, which inverts the pipeline's exit status.
+    Not(Box<Node>),
+    /// `A | B | ...`, two or more commands each reading the previous one's
+    /// output.
+    Pipeline(Vec<Stage>),
+    /// `A && B`, which runs B only if A succeeds.
+    And(Box<Node>, Box<Node>),
+    /// `A || B`, which runs B only if A fails.
+    Or(Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub command: Node,
+    /// Whether the stage is followed by `|&`, which pipes its stderr as well
+    /// as its stdout.
+    pub pipe_stderr: bool,
+}
+
+/// The body of a `<<WORD` here-document: the lines up to the one holding just
+/// WORD, each with its newline. With `<<-WORD`, leading tabs are already gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HereDoc {
+    pub body: String,
+    /// Whether the body is expanded when the command runs, which it is unless
+    /// any part of WORD was quoted.
+    pub expand: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseArm {
+    pub patterns: Vec<String>,
+    pub body: Vec<Node>,
+}
+
+/// Extends `line` with further lines from `next_line` for as long as it is
+/// incomplete: an open quote, backquote, `$(` or `${`, a construct such as
+/// `select ... do` without its `done`, or a here-document without its
+/// delimiter line. Stops early if input runs out.
+pub fn read_continuation(mut line: String, mut next_line: impl FnMut() -> Option<String>) -> String {
+    while parse(&line) == Err(ParseError::Incomplete) {
+        let Some(more) = next_line() else {
+            break;
+        };
+        line.push('\n');
+        line.push_str(&more);
+    }
+    line
+}
+
+/// Parses a complete script.
+pub fn parse(src: &str) -> Result<Vec<Node>, ParseError> {
+    parse_script(src).map_err(|(e, _)| e)
+}
+
+/// Parses a complete script, giving with an error the line it was found on.
+/// Lines count from 1; an input that ends too soon fails on its last line.
+pub fn parse_script(src: &str) -> Result<Vec<Node>, (ParseError, usize)> {
+    parse_script_from(src, 1)
+}
+
+/// Parses part of a script as `parse_script` does, counting its lines from
+/// `first_line`.
+pub fn parse_script_from(src: &str, first_line: usize) -> Result<Vec<Node>, (ParseError, usize)> {
+    let last_line = src.strip_suffix('\n').unwrap_or(src).matches('\n').count() + first_line;
+    let (tokens, here_docs) = tokenize(src, first_line).map_err(|e| (e, last_line))?;
+    let mut parser = Parser { src, tokens, here_docs, pos: 0 };
+    let error_line = |parser: &Parser| parser.peek().map_or(last_line, |token| token.line);
+    let nodes = parser.list(&[]).map_err(|e| (e, error_line(&parser)))?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(nodes),
+        Some(token) => Err((ParseError::Unexpected(parser.text(token).to_string()), token.line)),
+    }
+}
+
+/// Whether `src` opens a here-document, whose body must keep its lines.
+pub fn has_here_docs(src: &str) -> bool {
+    tokenize(src, 1).is_ok_and(|(_, here_docs)| !here_docs.is_empty())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Word,
+    Op(&'static str),
+    Newline,
+    Arith(String),
+    Cond(String),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    start: usize,
+    end: usize,
+    line: usize,
+}
+
+// Words after which the next word starts a new command.
+const COMMAND_PREFIXES: &[&str] = &["do", "then", "else", "elif", "!", "{"];
+
+// Words that close a construct and so cannot begin a command.
+const CLOSING_WORDS: &[&str] = &["do", "done", "esac", "}"];
+
+// A here-document with the index of the word holding its delimiter.
+type TokenDoc = (usize, HereDoc);
+
+// A here-document whose body is still to be read.
+struct PendingDoc {
+    // The word holding the delimiter.
+    token: usize,
+    delimiter: String,
+    strip_tabs: bool,
+    expand: bool,
+}
+
+// Scanning works on bytes: every character with syntactic meaning is ASCII,
+// and UTF-8 continuation bytes never collide with ASCII.
+fn tokenize(src: &str, first_line: usize) -> Result<(Vec<Token>, Vec<TokenDoc>), ParseError> {
+    let b = src.as_bytes();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut here_docs = Vec::new();
+    // Here-documents opened on the current line, and whether the last word
+    // was a `<<` or `<<-` waiting for its delimiter.
+    let mut pending: Vec<PendingDoc> = Vec::new();
+    let mut awaiting_delimiter = None;
+    // Whether the last word ended with an operator such as `>` or `2>&`,
+    // whose target must be the next word.
+    let mut awaiting_target = false;
+    let mut command_start = true;
+    // The line `i` is on, counted up to `counted`.
+    let mut line = first_line;
+    let mut counted = 0;
+    let mut i = 0;
+    while i < b.len() {
+        line += b[counted..i].iter().filter(|&&c| c == b'\n').count();
+        counted = i;
+        let start = i;
+        let op = match b[i] {
+            b' ' | b'\t' => {
+                i += 1;
+                continue;
+            }
+            b'\n' => {
+                if awaiting_delimiter.is_some() || awaiting_target {
+                    return Err(ParseError::Unexpected("newline".to_string()));
+                }
+                tokens.push(Token { kind: Kind::Newline, start, end: i + 1, line });
+                command_start = true;
+                i += 1;
+                for doc in pending.drain(..) {
+                    let (body, next) = read_here_doc(src, i, &doc)?;
+                    here_docs.push((doc.token, HereDoc { body, expand: doc.expand }));
+                    i = next;
+                }
+                continue;
+            }
+            // Each pass starts where a word could, so this `#` begins a
+            // comment, which runs to the end of the line. A `#` inside a
+            // word is scanned as part of it.
+            b'#' => {
+                i = b[i..].iter().position(|&c| c == b'\n').map_or(b.len(), |j| i + j);
+                continue;
+            }
+            b';' if b.get(i + 1) == Some(&b';') => Some(";;"),
+            b';' => Some(";"),
+            b'|' if b.get(i + 1) == Some(&b'|') => Some("||"),
+            b'|' if b.get(i + 1) == Some(&b'&') => Some("|&"),
+            b'&' if b.get(i + 1) == Some(&b'&') => Some("&&"),
+            b'|' => Some("|"),
+            b')' => Some(")"),
+            b'(' if !(command_start && b.get(i + 1) == Some(&b'(')) => Some("("),
+            _ => None,
+        };
+        if let Some(op) = op {
+            if awaiting_delimiter.is_some() || awaiting_target {
+                return Err(ParseError::Unexpected(op.to_string()));
+            }
+            i += op.len();
+            tokens.push(Token { kind: Kind::Op(op), start, end: i, line });
+            command_start = true;
+            continue;
+        }
+        if command_start && b[i..].starts_with(b"((") {
+            let close = find_double_paren_end(b, i + 2).ok_or(ParseError::Incomplete)?;
+            i = close + 2;
+            tokens.push(Token { kind: Kind::Arith(src[start + 2..close].to_string()), start, end: i, line });
+            command_start = false;
+            continue;
+        }
+        if command_start && b[i..].starts_with(b"[[") && b.get(i + 2).is_none_or(|c| c.is_ascii_whitespace()) {
+            let close = find_conditional_end(b, i + 2).ok_or(ParseError::Incomplete)?;
+            i = close + 2;
+            tokens.push(Token { kind: Kind::Cond(src[start + 2..close].to_string()), start, end: i, line });
+            command_start = false;
+            continue;
+        }
+        i = scan_word(b, i).ok_or(ParseError::Incomplete)?;
+        command_start = command_start && COMMAND_PREFIXES.contains(&&src[start..i]);
+        let word = tokens.len();
+        if let Some(strip_tabs) = awaiting_delimiter.take() {
+            pending.push(PendingDoc::new(word, &src[start..i], strip_tabs));
+        }
+        for (delimiter, strip_tabs) in here_doc_operators(&b[start..i]) {
+            if delimiter.is_empty() {
+                awaiting_delimiter = Some(strip_tabs);
+            } else {
+                pending.push(PendingDoc::new(word, &src[start + delimiter.start..start + delimiter.end], strip_tabs));
+            }
+        }
+        awaiting_target = awaits_target(&b[start..i]);
+        tokens.push(Token { kind: Kind::Word, start, end: i, line });
+    }
+    if awaiting_delimiter.is_some() || awaiting_target {
+        return Err(ParseError::Unexpected("newline".to_string()));
+    }
+    if !pending.is_empty() {
+        return Err(ParseError::Incomplete);
+    }
+    Ok((tokens, here_docs))
+}
+
+impl PendingDoc {
+    // Any quoting in the delimiter is removed, and stops the body being
+    // expanded.
+    fn new(token: usize, raw: &str, strip_tabs: bool) -> Self {
+        let mut delimiter = String::new();
+        let mut quote = None;
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(open), _) if c == open => quote = None,
+                (None, '\'' | '"') => quote = Some(c),
+                (None | Some('"'), '\\') => delimiter.extend(chars.next()),
+                _ => delimiter.push(c),
+            }
+        }
+        let expand = !raw.contains(['\'', '"', '\\']);
+        PendingDoc { token, delimiter, strip_tabs, expand }
+    }
+}
+
+// Whether `word` ends with an unquoted redirection operator, such as `>`,
+// `>>`, `2>&` or `<<<`, and so needs the next word as its target. A
+// trailing `<<` waits for a here-document delimiter instead.
+fn awaits_target(word: &[u8]) -> bool {
+    let mut operator_start = 0;
+    let mut i = 0;
+    while i < word.len() {
+        let next = skip_unit(word, i);
+        if !(next == i + 1 && matches!(word[i], b'<' | b'>' | b'&')) {
+            operator_start = next;
+        }
+        i = next;
+    }
+    let operator = &word[operator_start..];
+    operator.iter().any(|&c| c == b'<' || c == b'>') && operator != b"<<"
+}
+
+// The here-document operators in a word, as the range of the delimiter
+// written after each, which is empty if it is the next word, and whether the
+// operator is `<<-`. A delimiter runs to the next unquoted `<` or `>`.
+fn here_doc_operators(word: &[u8]) -> Vec<(Range<usize>, bool)> {
+    let mut operators = Vec::new();
+    let mut i = 0;
+    while i < word.len() {
+        if word[i..].starts_with(b"<<<") {
+            i += 3;
+        } else if word[i..].starts_with(b"<<") {
+            let strip_tabs = word.get(i + 2) == Some(&b'-');
+            i += if strip_tabs { 3 } else { 2 };
+            let start = i;
+            while i < word.len() && !matches!(word[i], b'<' | b'>') {
+                i = skip_unit(word, i);
+            }
+            operators.push((start..i, strip_tabs));
+        } else {
+            i = skip_unit(word, i);
+        }
+    }
+    operators
+}
+
+// Returns the index just past the character at `i` of a complete word, or
+// past the quoted string, escape or `$` group it starts.
+fn skip_unit(word: &[u8], i: usize) -> usize {
+    let next = match word[i] {
+        b'\\' => Some(i + 2),
+        b'\'' | b'`' => skip_quoted(word, i),
+        b'"' => skip_double_quotes(word, i + 1),
+        b'$' if matches!(word.get(i + 1), Some(b'(') | Some(b'{')) => skip_group(word, i + 1),
+        _ => Some(i + 1),
+    };
+    next.unwrap_or(word.len()).min(word.len())
+}
+
+// Reads a here-document's body from the lines starting at `i`, up to the line
+// holding just its delimiter, and returns it with the index past that line.
+fn read_here_doc(src: &str, mut i: usize, doc: &PendingDoc) -> Result<(String, usize), ParseError> {
+    let mut body = String::new();
+    while i < src.len() {
+        let end = src[i..].find('\n').map_or(src.len(), |j| i + j);
+        let mut line = &src[i..end];
+        if doc.strip_tabs {
+            line = line.trim_start_matches('\t');
+        }
+        i = (end + 1).min(src.len());
+        if line == doc.delimiter {
+            return Ok((body, i));
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+    Err(ParseError::Incomplete)
+}
+
+/// The byte ranges of the words and operators in `line`, split as the
+/// tokenizer splits them: a quoted string or `$(...)` stays inside its word,
+/// and one left open runs to the end of the line. Editing commands use this
+/// to work on whole arguments.
+pub fn word_spans(line: &str) -> Vec<Range<usize>> {
+    let b = line.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        i = match b[i] {
+            b' ' | b'\t' | b'\n' => {
+                i += 1;
+                continue;
+            }
+            _ if [&b";;"[..], b"|&", b"||", b"&&"].iter().any(|op| b[i..].starts_with(op)) => i + 2,
+            b';' | b'|' | b'(' | b')' => i + 1,
+            _ => scan_word(b, i).unwrap_or(b.len()).min(b.len()),
+        };
+        spans.push(start..i);
+    }
+    spans
+}
+
+// Returns the index just past the word starting at `i`. Quotes, backquotes
+// and `$(...)` or `${...}` are part of the word; `None` if one is left open.
+fn scan_word(b: &[u8], mut i: usize) -> Option<usize> {
+    while i < b.len() {
+        match b[i] {
+            b' ' | b'\t' | b'\n' | b';' | b'|' | b'(' | b')' => break,
+            b'&' if b.get(i + 1) == Some(&b'&') => break,
+            b'\\' => i += 2,
+            b'\'' | b'`' => i = skip_quoted(b, i)?,
+            b'"' => i = skip_double_quotes(b, i + 1)?,
+            b'$' if matches!(b.get(i + 1), Some(b'(') | Some(b'{')) => i = skip_group(b, i + 1)?,
+            _ => i += 1,
+        }
+    }
+    Some(i.min(b.len()))
+}
+
+// Returns the index just past the quote closing the one at `open`, which has
+// no escapes inside (a `'` or a backquote).
+fn skip_quoted(b: &[u8], open: usize) -> Option<usize> {
+    let quote = b[open];
+    b[open + 1..].iter().position(|&c| c == quote).map(|j| open + 1 + j + 1)
+}
+
+// Returns the index just past the `"` closing a string whose body starts at `i`.
+fn skip_double_quotes(b: &[u8], mut i: usize) -> Option<usize> {
+    while i < b.len() {
+        match b[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            b'`' => i = skip_quoted(b, i)?,
+            b'$' if matches!(b.get(i + 1), Some(b'(') | Some(b'{')) => i = skip_group(b, i + 1)?,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+// Returns the index just past the bracket matching the `(` or `{` at `open`.
+fn skip_group(b: &[u8], open: usize) -> Option<usize> {
+    let (opener, closer) = if b[open] == b'(' { (b'(', b')') } else { (b'{', b'}') };
+    let mut depth = 0;
+    let mut i = open;
+    while i < b.len() {
+        match b[i] {
+            b'\\' => i += 1,
+            b'\'' if opener == b'(' => i = skip_quoted(b, i)? - 1,
+            b'`' => i = skip_quoted(b, i)? - 1,
+            b'"' => i = skip_double_quotes(b, i + 1)? - 1,
+            c if c == opener => depth += 1,
+            c if c == closer => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Finds the `))` closing a `((` whose body starts at `i`.
+fn find_double_paren_end(b: &[u8], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    while i < b.len() {
+        match b[i] {
+            b'(' => depth += 1,
+            b')' if depth == 0 => return (b.get(i + 1) == Some(&b')')).then_some(i),
+            b')' => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// Finds the `]]` word closing a `[[` whose body starts at `i`.
+fn find_conditional_end(b: &[u8], mut i: usize) -> Option<usize> {
+    while i < b.len() {
+        match b[i] {
+            b'\\' => i += 1,
+            b'\'' => i += 1 + b[i + 1..].iter().position(|&c| c == b'\'')?,
+            b'"' => i = skip_double_quotes(b, i + 1)? - 1,
+            b']' if b[i - 1].is_ascii_whitespace()
+                && b.get(i + 1) == Some(&b']')
+                && b.get(i + 2).is_none_or(|c| c.is_ascii_whitespace() || matches!(c, b';' | b'|' | b'&' | b')')) =>
+            {
+                return Some(i);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    here_docs: Vec<TokenDoc>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn text(&self, token: &Token) -> &str {
+        match token.kind {
+            Kind::Newline => "newline",
+            _ => &self.src[token.start..token.end],
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        self.peek().filter(|t| t.kind == Kind::Word).map(|t| &self.src[t.start..t.end])
+    }
+
+    fn peek_op(&self, op: &str) -> bool {
+        self.peek().is_some_and(|t| matches!(t.kind, Kind::Op(o) if o == op))
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::Unexpected(self.text(token).to_string()),
+            None => ParseError::Incomplete,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().is_some_and(|t| t.kind == Kind::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    // Skips one `;` or any number of newlines between commands.
+    fn skip_separator(&mut self) {
+        if self.peek_op(";") {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.peek_word() != Some(word) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Parses commands until one of `terminators` (a closing word or `;;`)
+    // appears where a command could start, leaving it unconsumed. With no
+    // terminators the list runs to the end of input.
+    fn list(&mut self, terminators: &[&str]) -> Result<Vec<Node>, ParseError> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_newlines();
+            let Some(token) = self.peek() else {
+                return if terminators.is_empty() { Ok(nodes) } else { Err(ParseError::Incomplete) };
+            };
+            let text = self.text(token);
+            let at_terminator = match token.kind {
+                Kind::Word | Kind::Op(";;") => terminators.contains(&text),
+                _ => false,
+            };
+            if at_terminator {
+                return Ok(nodes);
+            }
+            nodes.push(self.and_or()?);
+            match self.peek().map(|t| &t.kind) {
+                None | Some(Kind::Newline) | Some(Kind::Op(";;")) => {}
+                Some(Kind::Op(";")) => self.pos += 1,
+                _ => return Err(self.unexpected()),
+            }
+            if self.peek_op(";;") && !terminators.contains(&";;") {
+                return Err(self.unexpected());
+            }
+        }
+    }
+
+    fn command(&mut self) -> Result<Node, ParseError> {
+        let token = self.peek().ok_or(ParseError::Incomplete)?.clone();
+        match &token.kind {
+            Kind::Arith(expr) => {
+                self.pos += 1;
+                Ok(Node::Arithmetic(expr.clone()))
+            }
+            Kind::Cond(expr) => {
+                self.pos += 1;
+                Ok(Node::Conditional(expr.clone()))
+            }
+            Kind::Word => match self.text(&token) {
+                "select" => self.select(),
+                "case" => self.case(),
+                "{" => self.group(),
+                "!" => {
+                    self.pos += 1;
+                    // A bare `!` is an error rather than a cue to keep reading.
+                    match self.peek().map(|t| &t.kind) {
+                        None => Err(ParseError::Unexpected("newline".to_string())),
+                        Some(Kind::Newline | Kind::Op(_)) => Err(self.unexpected()),
+                        _ => Ok(Node::Not(Box::new(self.pipeline()?))),
+                    }
+                }
+                "function" => {
+                    self.pos += 1;
+                    let name = self.peek_word().ok_or_else(|| self.unexpected())?.to_string();
+                    self.pos += 1;
+                    if self.peek_op("(") {
+                        self.expect_empty_parens()?;
+                    }
+                    self.function_body(name)
+                }
+                word if CLOSING_WORDS.contains(&word) => Err(self.unexpected()),
+                word if self.tokens.get(self.pos + 1).is_some_and(|t| t.kind == Kind::Op("(")) => {
+                    let name = word.to_string();
+                    self.pos += 1;
+                    self.expect_empty_parens()?;
+                    self.function_body(name)
+                }
+                _ => self.simple(),
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // Pipelines joined by `&&` and `||`, which bind equally tightly and are
+    // taken from the left. Like a pipe, either continues onto the next line.
+    fn and_or(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.pipeline()?;
+        loop {
+            let and = if self.peek_op("&&") {
+                true
+            } else if self.peek_op("||") {
+                false
+            } else {
+                return Ok(node);
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            let next = Box::new(self.pipeline()?);
+            node = if and { Node::And(Box::new(node), next) } else { Node::Or(Box::new(node), next) };
+        }
+    }
+
+    // A command, or several joined by `|` or `|&`. A pipe at the end of a
+    // line continues on the next.
+    fn pipeline(&mut self) -> Result<Node, ParseError> {
+        let mut stages = vec![Stage { command: self.command()?, pipe_stderr: false }];
+        loop {
+            let pipe_stderr = if self.peek_op("|") {
+                false
+            } else if self.peek_op("|&") {
+                true
+            } else {
+                break;
+            };
+            self.pos += 1;
+            stages.last_mut().unwrap().pipe_stderr = pipe_stderr;
+            self.skip_newlines();
+            stages.push(Stage { command: self.command()?, pipe_stderr: false });
+        }
+        if stages.len() == 1 {
+            return Ok(stages.pop().unwrap().command);
+        }
+        Ok(Node::Pipeline(stages))
+    }
+
+    fn group(&mut self) -> Result<Node, ParseError> {
+        self.pos += 1;
+        let body = self.list(&["}"])?;
+        self.expect_word("}")?;
+        Ok(Node::Group(body))
+    }
+
+    fn expect_empty_parens(&mut self) -> Result<(), ParseError> {
+        for op in ["(", ")"] {
+            if !self.peek_op(op) {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn function_body(&mut self, name: String) -> Result<Node, ParseError> {
+        self.skip_newlines();
+        match self.command()? {
+            Node::Simple { .. } | Node::Function { .. } | Node::Not(_) | Node::Pipeline(_) => Err(ParseError::Unexpected(name)),
+            body => Ok(Node::Function { name, body: Rc::new(body) }),
+        }
+    }
+
+    fn simple(&mut self) -> Result<Node, ParseError> {
+        let Token { start, line, .. } = self.tokens[self.pos];
+        let first = self.pos;
+        let mut end = start;
+        while let Some(token) = self.peek() {
+            if matches!(token.kind, Kind::Newline | Kind::Op(";" | ";;" | "|" | "|&" | "&&" | "||")) {
+                break;
+            }
+            end = token.end;
+            self.pos += 1;
+        }
+        let here_docs =
+            self.here_docs.iter().filter(|(token, _)| (first..self.pos).contains(token)).map(|(_, doc)| doc.clone()).collect();
+        Ok(Node::Simple { text: self.src[start..end].to_string(), line, here_docs })
+    }
+
+    fn select(&mut self) -> Result<Node, ParseError> {
+        self.pos += 1;
+        let name = self.peek_word().ok_or_else(|| self.unexpected())?.to_string();
+        self.pos += 1;
+        self.skip_newlines();
+        let mut words = Vec::new();
+        if self.peek_word() == Some("in") {
+            self.pos += 1;
+            while let Some(word) = self.peek_word() {
+                words.push(word.to_string());
+                self.pos += 1;
+            }
+        }
+        self.skip_separator();
+        self.expect_word("do")?;
+        let body = self.list(&["done"])?;
+        self.expect_word("done")?;
+        Ok(Node::Select { name, words, body })
+    }
+
+    fn case(&mut self) -> Result<Node, ParseError> {
+        self.pos += 1;
+        let word = self.peek_word().ok_or_else(|| self.unexpected())?.to_string();
+        self.pos += 1;
+        self.skip_newlines();
+        self.expect_word("in")?;
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_word() == Some("esac") {
+                self.pos += 1;
+                return Ok(Node::Case { word, arms });
+            }
+            if self.peek_op("(") {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                patterns.push(self.peek_word().ok_or_else(|| self.unexpected())?.to_string());
+                self.pos += 1;
+                if !self.peek_op("|") {
+                    break;
+                }
+                self.pos += 1;
+            }
+            if !self.peek_op(")") {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+            let body = self.list(&[";;", "esac"])?;
+            if self.peek_op(";;") {
+                self.pos += 1;
+            }
+            arms.push(CaseArm { patterns, body });
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Terminal-state guard. The attributes of the controlling terminal are saved
+// at startup and put back before every prompt, on exit, and when the shell is
+// killed by SIGTERM or SIGHUP, so a child that crashes after switching the
+// terminal to raw mode (or turning off echo) cannot leave the prompt unusable.
+// The shell switches modes itself only for `read -n` and `read -s`, through
+// `InputMode`, which puts the previous mode back when dropped.
+
+#[cfg(unix)]
+mod imp {
+    use std::sync::OnceLock;
+
+    // The attributes restored by the signal handler, which cannot reach the
+    // guard itself.
+    static SAVED: OnceLock<(i32, libc::termios)> = OnceLock::new();
+
+    pub struct TerminalGuard {
+        fd: i32,
+        saved: libc::termios,
+    }
+
+    impl TerminalGuard {
+        /// Saves the attributes of stdin, if it is a terminal.
+        pub fn capture() -> Option<Self> {
+            Self::capture_fd(libc::STDIN_FILENO)
+        }
+
+        pub fn capture_fd(fd: i32) -> Option<Self> {
+            // SAFETY: `termios` is plain data, and `tcgetattr` only writes
+            // through the pointer it is given.
+            unsafe {
+                if libc::isatty(fd) != 1 {
+                    return None;
+                }
+                let mut saved: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(fd, &mut saved) != 0 {
+                    return None;
+                }
+                Some(TerminalGuard { fd, saved })
+            }
+        }
+
+        /// Puts the saved attributes back.
+        pub fn restore(&self) {
+            // SAFETY: `saved` was filled in by `tcgetattr` for this descriptor.
+            unsafe {
+                libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.saved);
+            }
+        }
+
+        /// Also restores the attributes if the shell is terminated by SIGTERM
+        /// or SIGHUP. Only the first guard to call this is used.
+        pub fn restore_on_signal(&self) {
+            if SAVED.set((self.fd, self.saved)).is_err() {
+                return;
+            }
+            // SAFETY: the handler only calls async-signal-safe functions.
+            unsafe {
+                for signal in [libc::SIGTERM, libc::SIGHUP] {
+                    libc::signal(signal, handle_signal as *const () as libc::sighandler_t);
+                }
+            }
+        }
+    }
+
+    impl Drop for TerminalGuard {
+        fn drop(&mut self) {
+            self.restore();
+        }
+    }
+
+    /// The terminal on a descriptor with line input or echo turned off,
+    /// until this is dropped.
+    pub struct InputMode {
+        _previous: TerminalGuard,
+    }
+
+    impl InputMode {
+        /// Unless `canonical`, reads return each character as it is typed
+        /// instead of waiting for Enter; unless `echo`, typing is not shown.
+        /// `None` if `fd` is not a terminal or its mode cannot be changed.
+        pub fn set(fd: i32, canonical: bool, echo: bool) -> Option<Self> {
+            let previous = TerminalGuard::capture_fd(fd)?;
+            let mut attributes = previous.saved;
+            if !canonical {
+                attributes.c_lflag &= !libc::ICANON;
+                attributes.c_cc[libc::VMIN] = 1;
+                attributes.c_cc[libc::VTIME] = 0;
+            }
+            if !echo {
+                attributes.c_lflag &= !libc::ECHO;
+            }
+            // SAFETY: `attributes` is a copy of what `tcgetattr` filled in.
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &attributes) } != 0 {
+                return None;
+            }
+            Some(InputMode { _previous: previous })
+        }
+    }
+
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        // SAFETY: `tcsetattr`, `signal` and `raise` are async-signal-safe.
+        // The default disposition is reinstated so the shell still dies of
+        // the signal, with the usual exit status.
+        unsafe {
+            if let Some((fd, saved)) = SAVED.get() {
+                libc::tcsetattr(*fd, libc::TCSANOW, saved);
+            }
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    /// Terminal attributes are only managed on Unix.
+    pub struct TerminalGuard;
+
+    impl TerminalGuard {
+        pub fn capture() -> Option<Self> {
+            None
+        }
+
+        pub fn restore(&self) {}
+
+        pub fn restore_on_signal(&self) {}
+    }
+
+    pub struct InputMode;
+
+    impl InputMode {
+        pub fn set(_fd: i32, _canonical: bool, _echo: bool) -> Option<Self> {
+            None
+        }
+    }
+}
+
+pub use imp::{InputMode, TerminalGuard};
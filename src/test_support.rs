@@ -0,0 +1,126 @@
+// Fixtures shared by the tests: temporary directories that clean up after
+// themselves, executable builders, and `ShellSession`, which runs lines
+// through a `Shell` rooted in its own directory and captures what it prints.
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use tempfile::TempDir;
+
+use crate::paths;
+use crate::{CommandLine, Input, Output, Shell};
+
+/// A fresh directory, removed when the returned guard is dropped.
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new().prefix("shell_test_").tempdir().expect("failed to create temp dir")
+}
+
+/// Writes `contents` to `dir/name`, creating parent directories as needed.
+pub fn write_file(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).expect("failed to create parent dir");
+    }
+    fs::write(&path, contents).expect("failed to write file");
+    path
+}
+
+/// Writes an executable `dir/name` whose body is `script`.
+pub fn write_executable(dir: &Path, name: &str, script: &str) -> PathBuf {
+    let path = write_file(dir, name, script);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).expect("failed to set permissions");
+    }
+    path
+}
+
+/// What one line printed, and the status it left behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A shell whose working directory and `HOME` are a private temp dir, whose
+/// stdin is empty unless supplied and whose output is captured rather than
+/// printed. Nothing touches the
+/// process's cwd or environment, so sessions can run in parallel.
+pub struct ShellSession {
+    pub shell: Shell,
+    dir: TempDir,
+    stdout: Rc<RefCell<Vec<u8>>>,
+    stderr: Rc<RefCell<Vec<u8>>>,
+}
+
+impl ShellSession {
+    pub fn new() -> Self {
+        Self::with_shell(Shell::new())
+    }
+
+    pub fn with_shell(shell: Shell) -> Self {
+        let dir = temp_dir();
+        let root = paths::normalize_dir(dir.path()).expect("failed to resolve temp dir");
+        shell.set_cwd(root.clone());
+        shell.vars.borrow_mut().insert("HOME".to_string(), root.display().to_string());
+
+        let stdout = Rc::new(RefCell::new(Vec::new()));
+        let stderr = Rc::new(RefCell::new(Vec::new()));
+        *shell.stdout.borrow_mut() = Output::Buffer(stdout.clone());
+        *shell.stderr.borrow_mut() = Output::Buffer(stderr.clone());
+        *shell.stdin.borrow_mut() = Input::Buffer(Rc::default());
+        // Sessions stand in for the REPL, so errors do not end the shell.
+        shell.interactive.set(true);
+        ShellSession { shell, dir, stdout, stderr }
+    }
+
+    /// The session's root directory (its initial cwd and `HOME`).
+    pub fn root(&self) -> PathBuf {
+        paths::normalize_dir(self.dir.path()).expect("failed to resolve temp dir")
+    }
+
+    /// `rel` resolved against the session root.
+    pub fn path(&self, rel: &str) -> PathBuf {
+        self.root().join(rel)
+    }
+
+    pub fn write_file(&self, rel: &str, contents: &str) -> PathBuf {
+        write_file(&self.root(), rel, contents)
+    }
+
+    pub fn mkdir(&self, rel: &str) -> PathBuf {
+        let path = self.path(rel);
+        fs::create_dir_all(&path).expect("failed to create dir");
+        path
+    }
+
+    pub fn read_file(&self, rel: &str) -> String {
+        fs::read_to_string(self.path(rel)).expect("failed to read file")
+    }
+
+    /// Supplies `text` as the shell's stdin, replacing anything unread.
+    pub fn set_stdin(&self, text: &str) {
+        *self.shell.stdin.borrow_mut() = Input::Buffer(Rc::new(RefCell::new(text.as_bytes().to_vec())));
+    }
+
+    /// Runs one input line as the REPL would.
+    pub fn run(&self, line: &str) -> Outcome {
+        let status = self.shell.run_line(line);
+        self.take(status)
+    }
+
+    /// Runs an already-parsed command line.
+    pub fn execute(&self, cmd: CommandLine) -> Outcome {
+        let status = self.shell.execute(cmd);
+        self.take(status)
+    }
+
+    fn take(&self, status: i32) -> Outcome {
+        let drain = |buffer: &Rc<RefCell<Vec<u8>>>| String::from_utf8_lossy(&buffer.take()).into_owned();
+        Outcome { status, stdout: drain(&self.stdout), stderr: drain(&self.stderr) }
+    }
+}
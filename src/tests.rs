@@ -1,531 +1,3758 @@
-#[cfg(test)]
-mod tests {
-    use crate::{Shell, MyHelper, CommandLine, Argument};
-    use std::fs::File;
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    #[test]
-    fn test_completion_exact_match() {
-        let helper = MyHelper {
-            commands: vec!["echo".into(), "exit".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("echo", 4);
-        assert_eq!(start, 0);
-        assert_eq!(matches, vec!["echo "]);
-    }
-
-    #[test]
-    fn test_completion_partial_match() {
-        let helper = MyHelper {
-            commands: vec!["echo".into(), "exit".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("ec", 2);
-        assert_eq!(start, 0);
-        assert_eq!(matches, vec!["echo "]);
-    }
-
-    #[test]
-    fn test_completion_multiple_matches() {
-        let helper = MyHelper {
-            commands: vec!["echo".into(), "exit".into(), "echoloco".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("ec", 2);
-        assert_eq!(start, 0);
-        assert!(matches.contains(&"echo ".to_string()));
-        assert!(matches.contains(&"echoloco ".to_string()));
-        assert!(!matches.contains(&"exit ".to_string()));
-        assert_eq!(matches.len(), 2);
-    }
-
-    #[test]
-    fn test_completion_no_match() {
-        let helper = MyHelper {
-            commands: vec!["echo".into(), "exit".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("foo", 3);
-        assert_eq!(start, 0);
-        assert!(matches.is_empty());
-    }
-
-    #[test]
-    fn test_completion_second_argument() {
-        let helper = MyHelper {
-            commands: vec!["echo".into(), "exit".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("sudo ec", 7);
-        assert_eq!(start, 5);
-        assert_eq!(matches, vec!["echo "]);
-    }
-
-    #[test]
-    fn test_completion_executable_match() {
-        let (temp_dir, _exec_path) = setup_executable("my_custom_exec");
-        let helper = MyHelper {
-            commands: vec!["echo".into()],
-            path_dirs: vec![temp_dir.as_path().to_path_buf()],
-        };
-        let (start, matches) = helper.get_all_suggestions("my_c", 4);
-        assert_eq!(start, 0);
-        assert!(matches.contains(&"my_custom_exec ".to_string()));
-        assert_eq!(matches.len(), 1); 
-
-        let _ = std::fs::remove_dir_all(temp_dir);
-    }
-    
-    #[test]
-    fn test_completion_ech_partial() {
-        let helper = MyHelper {
-            commands: vec!["echo".into()],
-            path_dirs: vec![],
-        };
-        let (start, matches) = helper.get_all_suggestions("ech", 3);
-        assert_eq!(start, 0);
-        assert_eq!(matches, vec!["echo "]);
-    }
-
-    #[test]
-    fn test_parse_args_simple() {
-        let cmd = CommandLine::parse("prog hello world");
-        assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("world")]);
-    }
-
-    #[test]
-    fn test_parse_args_quoted() {
-        let cmd = CommandLine::parse("prog 'hello world'");
-        assert_eq!(cmd.args, vec![Argument::new("hello world")]);
-    }
-
-    #[test]
-    fn test_parse_args_mixed() {
-        let cmd = CommandLine::parse("echo 'hello world'");
-        assert_eq!(cmd.args, vec![Argument::new("hello world")]);
-    }
-
-    #[test]
-    fn test_parse_args_adjacent_quotes() {
-        let cmd = CommandLine::parse("prog 'hello''world'");
-        assert_eq!(cmd.args, vec![Argument::new("helloworld")]);
-    }
-
-    #[test]
-    fn test_parse_args_empty_and_spaces() {
-        let cmd = CommandLine::parse("prog    hello   world   ");
-        assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("world")]);
-    }
-    
-    #[test]
-    fn test_parse_args_inner_quotes() {
-        let cmd = CommandLine::parse("prog hello 'inner' world");
-        assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("inner"), Argument::new("world")]);
-    }
-
-    #[test]
-    fn test_parse_args_double_quotes() {
-        let cmd = CommandLine::parse("echo \"hello world\"");
-        assert_eq!(cmd.args, vec![Argument::new("hello world")]);
-    }
-
-    #[test]
-    fn test_parse_command_simple() {
-        let cmd_line = CommandLine::parse("ls -l");
-        assert_eq!(cmd_line.command, "ls");
-        assert_eq!(cmd_line.args, vec![Argument::new("-l")]);
-        assert!(cmd_line.redirection.is_none());
-    }
-    
-    #[test]
-    fn test_parse_command_with_quotes() {
-        let cmd_line = CommandLine::parse("echo 'hello world'");
-        assert_eq!(cmd_line.command, "echo");
-        assert_eq!(cmd_line.args, vec![Argument::new("hello world")]);
-        assert!(cmd_line.redirection.is_none());
-    }
-
-    #[test]
-    fn test_parse_command_redirect() {
-        let cmd_line = CommandLine::parse("echo hello > output.txt");
-        assert_eq!(cmd_line.command, "echo");
-        assert_eq!(cmd_line.args, vec![Argument::new("hello")]);
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "output.txt");
-        assert_eq!(r.mode_name(), "1>");
-    }
-    
-    #[test]
-    fn test_parse_command_redirect_explicit() {
-        let cmd_line = CommandLine::parse("cat file 1> out");
-        assert_eq!(cmd_line.command, "cat");
-        assert_eq!(cmd_line.args, vec![Argument::new("file")]);
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "out");
-        assert_eq!(r.mode_name(), "1>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_quoted_filename() {
-        let cmd_line = CommandLine::parse("ls > 'my file'");
-        assert_eq!(cmd_line.command, "ls");
-        assert!(cmd_line.args.is_empty());
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "my file");
-        assert_eq!(r.mode_name(), "1>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_stderr() {
-        let cmd_line = CommandLine::parse("ls 2> error.log");
-        assert_eq!(cmd_line.command, "ls");
-        assert!(cmd_line.args.is_empty());
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "error.log");
-        assert_eq!(r.mode_name(), "2>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_stderr_with_args() {
-        let cmd_line = CommandLine::parse("grep foo bar 2> error.log");
-        assert_eq!(cmd_line.command, "grep");
-        assert_eq!(cmd_line.args, vec![Argument::new("foo"), Argument::new("bar")]);
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "error.log");
-        assert_eq!(r.mode_name(), "2>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_append() {
-        let cmd_line = CommandLine::parse("ls >> out");
-        assert_eq!(cmd_line.command, "ls");
-        assert!(cmd_line.args.is_empty());
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "out");
-        assert_eq!(r.mode_name(), "1>>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_stdout_append_explicit() {
-        let cmd_line = CommandLine::parse("ls 1>> out");
-        assert_eq!(cmd_line.command, "ls");
-        assert!(cmd_line.args.is_empty());
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "out");
-        assert_eq!(r.mode_name(), "1>>");
-    }
-
-    #[test]
-    fn test_parse_command_redirect_stderr_append() {
-        let cmd_line = CommandLine::parse("ls 2>> out");
-        assert_eq!(cmd_line.command, "ls");
-        assert!(cmd_line.args.is_empty());
-        let r = cmd_line.redirection.as_ref().unwrap();
-        assert_eq!(r.target(), "out");
-        assert_eq!(r.mode_name(), "2>>");
-    }
-
-    // Helper to create a temp dir with an executable file
-    fn setup_executable(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
-        let mut dir = std::env::temp_dir();
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-        dir.push(format!("cc_shell_test_{}", timestamp));
-        std::fs::create_dir_all(&dir).expect("Failed to create temp dir");
-
-        let file_path = dir.join(name);
-        {
-            let _file = File::create(&file_path).expect("Failed to create executable file");
-            #[cfg(unix)]
-            {
-                let mut perms = _file.metadata().unwrap().permissions();
-                use std::os::unix::fs::PermissionsExt;
-                perms.set_mode(0o755);
-                std::fs::set_permissions(&file_path, perms).expect("Failed to set permissions");
-            }
+use crate::{sanitize_for_display, Shell, MyHelper, CommandLine, Argument};
+use crate::complete::{CompletionResult, Engine};
+use crate::test_support::{ShellSession, temp_dir, write_executable};
+use std::sync::{Arc, Mutex};
+
+// A completion engine offering the given shell names and the executables in
+// `path_dirs`.
+fn engine(builtins: &[&str], aliases: &[&str], functions: &[&str], path_dirs: Vec<std::path::PathBuf>) -> Engine {
+    let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+    let mut index = crate::commandindex::CommandIndex::new(names(builtins), path_dirs);
+    index.set_aliases(names(aliases));
+    index.set_functions(names(functions));
+    Engine { index: Arc::new(Mutex::new(index)), ..Default::default() }
+}
+
+// The line editor's completer over `engine`.
+fn completer(engine: Engine) -> MyHelper {
+    MyHelper { engine, theme: Default::default() }
+}
+
+// What each candidate replaces the word with.
+fn texts(result: &CompletionResult) -> Vec<&str> {
+    result.candidates.iter().map(|candidate| candidate.text.as_str()).collect()
+}
+
+#[test]
+fn test_completion_exact_match() {
+    let engine = engine(&["echo", "exit"], &[], &[], vec![]);
+    let result = engine.complete("echo", 4);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert_eq!(matches, vec!["echo "]);
+}
+
+#[test]
+fn test_completion_partial_match() {
+    let engine = engine(&["echo", "exit"], &[], &[], vec![]);
+    let result = engine.complete("ec", 2);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert_eq!(matches, vec!["echo "]);
+}
+
+#[test]
+fn test_completion_multiple_matches() {
+    let engine = engine(&["echo", "exit", "echoloco"], &[], &[], vec![]);
+    let result = engine.complete("ec", 2);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    // Neither is finished, since `echo` may go on to `echoloco`.
+    assert_eq!(matches, ["echo", "echoloco"]);
+}
+
+#[test]
+fn test_completion_no_match() {
+    let engine = engine(&["echo", "exit"], &[], &[], vec![]);
+    let result = engine.complete("foo", 3);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert!(matches.is_empty());
+}
+
+#[test]
+fn test_completion_second_argument() {
+    let engine = engine(&["echo", "exit"], &[], &[], vec![]);
+    let result = engine.complete("sudo ec", 7);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 5);
+    assert_eq!(matches, vec!["echo "]);
+}
+
+#[test]
+fn test_completion_executable_match() {
+    let temp_dir = temp_dir();
+    write_executable(temp_dir.path(), "my_custom_exec", "");
+    let engine = engine(&["echo"], &[], &[], vec![temp_dir.path().to_path_buf()]);
+    let result = engine.complete("my_c", 4);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert_eq!(matches, ["my_custom_exec "]);
+}
+
+#[test]
+fn test_completion_ech_partial() {
+    let engine = engine(&["echo"], &[], &[], vec![]);
+    let result = engine.complete("ech", 3);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert_eq!(matches, vec!["echo "]);
+}
+
+#[test]
+fn test_parse_args_simple() {
+    let cmd = CommandLine::parse("prog hello world");
+    assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("world")]);
+}
+
+#[test]
+fn test_parse_args_quoted() {
+    let cmd = CommandLine::parse("prog 'hello world'");
+    assert_eq!(cmd.args, vec![Argument::new("hello world")]);
+}
+
+#[test]
+fn test_parse_args_mixed() {
+    let cmd = CommandLine::parse("echo 'hello world'");
+    assert_eq!(cmd.args, vec![Argument::new("hello world")]);
+}
+
+#[test]
+fn test_parse_args_adjacent_quotes() {
+    let cmd = CommandLine::parse("prog 'hello''world'");
+    assert_eq!(cmd.args, vec![Argument::new("helloworld")]);
+}
+
+#[test]
+fn test_parse_args_empty_and_spaces() {
+    let cmd = CommandLine::parse("prog    hello   world   ");
+    assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("world")]);
+}
+
+#[test]
+fn test_parse_args_inner_quotes() {
+    let cmd = CommandLine::parse("prog hello 'inner' world");
+    assert_eq!(cmd.args, vec![Argument::new("hello"), Argument::new("inner"), Argument::new("world")]);
+}
+
+#[test]
+fn test_parse_args_double_quotes() {
+    let cmd = CommandLine::parse("echo \"hello world\"");
+    assert_eq!(cmd.args, vec![Argument::new("hello world")]);
+}
+
+#[test]
+fn test_parse_command_simple() {
+    let cmd_line = CommandLine::parse("ls -l");
+    assert_eq!(cmd_line.command, "ls");
+    assert_eq!(cmd_line.args, vec![Argument::new("-l")]);
+    assert!(cmd_line.redirection.is_none());
+}
+
+#[test]
+fn test_parse_command_with_quotes() {
+    let cmd_line = CommandLine::parse("echo 'hello world'");
+    assert_eq!(cmd_line.command, "echo");
+    assert_eq!(cmd_line.args, vec![Argument::new("hello world")]);
+    assert!(cmd_line.redirection.is_none());
+}
+
+#[test]
+fn test_parse_command_redirect() {
+    let cmd_line = CommandLine::parse("echo hello > output.txt");
+    assert_eq!(cmd_line.command, "echo");
+    assert_eq!(cmd_line.args, vec![Argument::new("hello")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "output.txt");
+    assert_eq!(r.mode_name(), "1>");
+}
+
+#[test]
+fn test_parse_command_redirect_explicit() {
+    let cmd_line = CommandLine::parse("cat file 1> out");
+    assert_eq!(cmd_line.command, "cat");
+    assert_eq!(cmd_line.args, vec![Argument::new("file")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "out");
+    assert_eq!(r.mode_name(), "1>");
+}
+
+#[test]
+fn test_parse_command_redirect_quoted_filename() {
+    let cmd_line = CommandLine::parse("ls > 'my file'");
+    assert_eq!(cmd_line.command, "ls");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "my file");
+    assert_eq!(r.mode_name(), "1>");
+}
+
+#[test]
+fn test_parse_command_redirect_stderr() {
+    let cmd_line = CommandLine::parse("ls 2> error.log");
+    assert_eq!(cmd_line.command, "ls");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "error.log");
+    assert_eq!(r.mode_name(), "2>");
+}
+
+#[test]
+fn test_parse_command_redirect_stderr_with_args() {
+    let cmd_line = CommandLine::parse("grep foo bar 2> error.log");
+    assert_eq!(cmd_line.command, "grep");
+    assert_eq!(cmd_line.args, vec![Argument::new("foo"), Argument::new("bar")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "error.log");
+    assert_eq!(r.mode_name(), "2>");
+}
+
+#[test]
+fn test_parse_command_redirect_append() {
+    let cmd_line = CommandLine::parse("ls >> out");
+    assert_eq!(cmd_line.command, "ls");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "out");
+    assert_eq!(r.mode_name(), "1>>");
+}
+
+#[test]
+fn test_parse_command_redirect_stdout_append_explicit() {
+    let cmd_line = CommandLine::parse("ls 1>> out");
+    assert_eq!(cmd_line.command, "ls");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "out");
+    assert_eq!(r.mode_name(), "1>>");
+}
+
+#[test]
+fn test_parse_command_redirect_stderr_append() {
+    let cmd_line = CommandLine::parse("ls 2>> out");
+    assert_eq!(cmd_line.command, "ls");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!(r.target(), "out");
+    assert_eq!(r.mode_name(), "2>>");
+}
+
+#[test]
+fn test_parse_command_redirect_without_spaces() {
+    let cmd_line = CommandLine::parse("echo a>>b");
+    assert_eq!(cmd_line.args, vec![Argument::new("a")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("b", "1>>"));
+
+    let cmd_line = CommandLine::parse("echo a 2>>err>out");
+    assert_eq!(cmd_line.args, vec![Argument::new("a")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("out", "1>"));
+}
+
+#[test]
+fn test_parse_command_quoted_operators_are_text() {
+    let cmd_line = CommandLine::parse("echo 'x>y' \"p>>q\" a'>'b 2\">\"c > 'out>file'");
+    assert_eq!(
+        cmd_line.args,
+        vec![Argument::new("x>y"), Argument::new("p>>q"), Argument::new("a>b"), Argument::new("2>c")]
+    );
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("out>file", "1>"));
+
+    let cmd_line = CommandLine::parse("cat < 'in<put' 2>> \"log>>\"");
+    assert_eq!(cmd_line.input, Some(crate::InputSource::File("in<put".to_string())));
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("log>>", "2>>"));
+}
+
+#[test]
+fn test_quoted_operators_run_as_arguments() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo \"a > b\" 'c >> d'").stdout, "a > b c >> d\n");
+
+    session.write_file("log.txt", "ok\nfd 2> redirected\n");
+    let outcome = session.run("grep '2>' log.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.status), ("fd 2> redirected\n", 0));
+
+    let outcome = session.run("echo 'x > y' > out.txt");
+    assert_eq!(outcome.stdout, "");
+    assert_eq!(session.read_file("out.txt"), "x > y\n");
+}
+
+#[test]
+fn test_parse_command_escaped_characters_are_text() {
+    let cmd_line = CommandLine::parse("echo a\\>b \\2>c \\$HOME a\\ b \"q\\\"\\$\\x\" '\\n' \\\\");
+    assert_eq!(
+        cmd_line.args,
+        vec![
+            Argument::new("a>b"),
+            Argument::new("2"),
+            Argument::new("$HOME"),
+            Argument::new("a b"),
+            Argument::new("q\"$\\x"),
+            Argument::new("\\n"),
+            Argument::new("\\"),
+        ]
+    );
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("c", "1>"));
+    assert!(CommandLine::parse("NAME\\=x").assignments.is_empty());
+}
+
+#[test]
+fn test_escaped_operators_run_as_text() {
+    let session = ShellSession::new();
+    let outcome = session.run("echo a \\> b");
+    assert_eq!((outcome.stdout.as_str(), outcome.status), ("a > b\n", 0));
+    assert!(!session.path("b").exists());
+    assert_eq!(session.run("echo \\| x").stdout, "| x\n");
+    assert_eq!(session.run("echo \\&\\& y").stdout, "&& y\n");
+    assert_eq!(session.run("echo one \\; echo two").stdout, "one ; echo two\n");
+}
+
+#[test]
+fn test_execute_adjacent_redirections() {
+    let session = ShellSession::new();
+    session.run("echo hi >first>second");
+    assert_eq!(session.read_file("first"), "");
+    assert_eq!(session.read_file("second"), "hi\n");
+    session.run("echo 'a>b' >'odd>name'");
+    assert_eq!(session.read_file("odd>name"), "a>b\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_failed_spawn_is_reported_on_stderr() {
+    let session = ShellSession::new();
+    write_executable(&session.root(), "garbled", "\x7fELF not really\n");
+    let outcome = session.run("x=$(./garbled); echo \"[$x] $?\"");
+    assert_eq!(outcome.stdout, "[] 126\n");
+    assert_eq!(outcome.stderr, "myshell: ./garbled: Exec format error\n");
+}
+
+#[test]
+fn test_running_a_directory_is_an_error() {
+    let bin = temp_dir();
+    std::fs::create_dir(bin.path().join("toolbox")).unwrap();
+    let session = ShellSession::with_shell(Shell::with_settings(vec![bin.path().to_path_buf()]));
+    session.mkdir("sub");
+    write_executable(&session.path("sub"), "run.sh", "#!/bin/sh\necho ran\n");
+
+    let outcome = session.run("./sub");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (126, ""));
+    assert_eq!(outcome.stderr, "myshell: ./sub: Is a directory\n");
+    let outcome = session.run("toolbox");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (126, "myshell: toolbox: Is a directory\n"));
+
+    // Explicit paths run from the working directory.
+    assert_eq!(session.run("sub/run.sh").stdout, "ran\n");
+}
+
+#[test]
+fn test_autocd_enters_a_directory_named_as_a_command() {
+    let session = ShellSession::new();
+    session.mkdir("sub/deeper");
+    session.run("set -o autocd");
+    let outcome = session.run("sub");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (0, ""));
+    assert_eq!(session.shell.cwd(), session.path("sub"));
+    session.run("./deeper");
+    assert_eq!(session.shell.cwd(), session.path("sub/deeper"));
+
+    // With arguments, or with the option off, it is an error again.
+    assert_eq!(session.run(".. extra").status, 126);
+    session.run("set +o autocd");
+    assert_eq!(session.run("..").status, 126);
+    assert_eq!(session.shell.cwd(), session.path("sub/deeper"));
+}
+
+#[test]
+fn test_find_executable_found() {
+    let dir = temp_dir();
+    let file_path = write_executable(dir.path(), "my_exec", "");
+
+    let shell = Shell::with_settings(vec![dir.path().to_path_buf()]);
+    let result = shell.find_executable_in_path("my_exec");
+
+    assert_eq!(result, Some(file_path));
+}
+
+#[test]
+fn test_find_executable_not_found() {
+    let dir = temp_dir();
+    write_executable(dir.path(), "other_exec", "");
+
+    let shell = Shell::with_settings(vec![dir.path().to_path_buf()]);
+    let result = shell.find_executable_in_path("non_existent");
+
+    assert_eq!(result, None);
+}
+
+#[test]
+fn test_execute_builtin_echo_redirect_stdout() {
+    let session = ShellSession::new();
+    let file_path = session.path("out.txt");
+
+    // echo hello > ...
+    let cmd = CommandLine {
+        command: "echo".to_string(),
+        args: vec![Argument::new("hello")],
+        redirection: Some(Box::new(crate::StdoutRedirect {
+            target: file_path.display().to_string()
+        })),
+        ..Default::default()
+    };
+    let outcome = session.execute(cmd);
+
+    assert_eq!(session.read_file("out.txt"), "hello\n");
+    assert_eq!(outcome.stdout, "");
+}
+
+#[test]
+fn test_execute_builtin_echo_redirect_append() {
+    let session = ShellSession::new();
+    let file_path = session.path("out.txt").display().to_string();
+
+    let cmd1 = CommandLine {
+        command: "echo".to_string(),
+        args: vec![Argument::new("hello")],
+        redirection: Some(Box::new(crate::StdoutRedirect { target: file_path.clone() })),
+        ..Default::default()
+    };
+    session.execute(cmd1);
+
+    let cmd2 = CommandLine {
+        command: "echo".to_string(),
+        args: vec![Argument::new("world")],
+        redirection: Some(Box::new(crate::StdoutAppendRedirect { target: file_path })),
+        ..Default::default()
+    };
+    session.execute(cmd2);
+
+    assert_eq!(session.read_file("out.txt"), "hello\nworld\n");
+}
+
+#[test]
+fn test_execute_external_redirect_stdout() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+        command: "sh".to_string(),
+        args: vec![Argument::new("-c"), Argument::new("echo external")],
+        redirection: Some(Box::new(crate::StdoutRedirect { target: session.path("out.txt").display().to_string() })),
+        ..Default::default()
+    };
+    session.execute(cmd);
+
+    assert!(session.read_file("out.txt").contains("external"));
+}
+
+#[test]
+fn test_execute_external_redirect_stderr() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+        command: "sh".to_string(),
+        args: vec![Argument::new("-c"), Argument::new("echo failure >&2")],
+        redirection: Some(Box::new(crate::StderrRedirect { target: session.path("err.txt").display().to_string() })),
+        ..Default::default()
+    };
+    session.execute(cmd);
+
+    assert!(session.read_file("err.txt").contains("failure"));
+}
+
+#[test]
+fn test_owl_scenario() {
+    let session = ShellSession::new();
+    for fruit in ["banana", "grape", "pear"] {
+        session.write_file(&format!("rat/{}", fruit), &format!("{}\n", fruit));
+    }
+    session.mkdir("owl");
+
+    // ls -1 /tmp/rat >> /tmp/owl/bee.md
+    let cmd = CommandLine {
+        command: "ls".to_string(),
+        args: vec![Argument::new("-1"), Argument::new(session.path("rat").display().to_string())],
+        redirection: Some(Box::new(crate::StdoutAppendRedirect { target: session.path("owl/bee.md").display().to_string() })),
+        ..Default::default()
+    };
+    session.execute(cmd);
+
+    let content = session.read_file("owl/bee.md");
+    assert!(content.contains("banana"));
+    assert!(content.contains("grape"));
+    assert!(content.contains("pear"));
+
+    // echo 'Hello Maria' 1>> /tmp/owl/fox.md
+    let cmd2 = CommandLine {
+        command: "echo".to_string(),
+        args: vec![Argument::new("Hello Maria")],
+        redirection: Some(Box::new(crate::StdoutAppendRedirect { target: session.path("owl/fox.md").display().to_string() })),
+        ..Default::default()
+    };
+    session.execute(cmd2);
+
+    assert_eq!(session.read_file("owl/fox.md").trim(), "Hello Maria");
+}
+
+#[test]
+fn test_execute_builtin_pwd_redirect_stdout() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+        command: "pwd".to_string(),
+        args: vec![],
+        redirection: Some(Box::new(crate::StdoutRedirect { target: session.path("pwd_out.txt").display().to_string() })),
+        ..Default::default()
+    };
+    session.execute(cmd);
+
+    assert_eq!(session.read_file("pwd_out.txt"), format!("{}\n", session.root().display()));
+}
+
+#[test]
+fn test_execute_builtin_type_builtin() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+         command: "type".to_string(),
+         args: vec![Argument::new("echo")],
+         redirection: Some(Box::new(crate::StdoutRedirect { target: session.path("type_out.txt").display().to_string() })),
+         ..Default::default()
+    };
+    session.execute(cmd);
+
+    assert_eq!(session.read_file("type_out.txt"), "echo is a shell builtin\n");
+}
+
+#[test]
+fn test_execute_builtin_type_not_found() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+         command: "type".to_string(),
+         args: vec![Argument::new("nonexistent")],
+         redirection: Some(Box::new(crate::StdoutRedirect { target: session.path("type_out.txt").display().to_string() })),
+         ..Default::default()
+    };
+    let outcome = session.execute(cmd);
+
+    assert_eq!(session.read_file("type_out.txt"), "nonexistent: not found\n");
+    assert_eq!(outcome.status, 1);
+}
+
+#[test]
+fn test_execute_builtin_cd_relative() {
+    let session = ShellSession::new();
+    let sub_dir = session.mkdir("raspberry/orange");
+
+    let cmd = CommandLine {
+        command: "cd".to_string(),
+        args: vec![Argument::new("./raspberry/orange")],
+        redirection: None,
+        ..Default::default()
+    };
+    session.execute(cmd);
+
+    assert_eq!(session.shell.cwd(), sub_dir);
+}
+
+#[test]
+fn test_execute_builtin_cd_absolute_error() {
+    let session = ShellSession::new();
+    let cmd = CommandLine {
+        command: "cd".to_string(),
+        args: vec![Argument::new("/non-existing-directory")],
+        redirection: None,
+        ..Default::default()
+    };
+    let outcome = session.execute(cmd);
+
+    assert_eq!(session.shell.cwd(), session.root());
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "cd: /non-existing-directory: No such file or directory\n");
+}
+
+#[test]
+fn test_session_captures_output_per_line() {
+    let session = ShellSession::new();
+    let outcome = session.run("echo one");
+    assert_eq!(outcome.stdout, "one\n");
+    assert_eq!(session.run("sh -c 'echo two'").stdout, "two\n");
+    let outcome = session.run("nonexistent_command_xyz");
+    assert_eq!(outcome.status, 127);
+    assert_eq!(outcome.stderr, "nonexistent_command_xyz: command not found\n");
+}
+
+#[test]
+fn test_session_relative_paths_follow_shell_cwd() {
+    let session = ShellSession::new();
+    session.mkdir("sub");
+    session.run("cd sub");
+    session.run("echo inside > note.txt");
+    assert_eq!(session.read_file("sub/note.txt"), "inside\n");
+    assert_eq!(session.run("ls").stdout, "note.txt\n");
+    assert_eq!(session.run("cd").status, 0);
+    assert_eq!(session.run("pwd").stdout, format!("{}\n", session.root().display()));
+}
+
+fn vars(pairs: &[(&str, &str)]) -> std::cell::RefCell<std::collections::HashMap<String, String>> {
+    std::cell::RefCell::new(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+}
+
+#[test]
+fn test_expand_plain_variables() {
+    let v = vars(&[("HOME", "/home/me"), ("X", "x")]);
+    let cmd = CommandLine::parse_with("echo $HOME \"$HOME/bin\" ${X}y $X$X $UNSET \"[$UNSET]\" '$HOME ${X}' \"'$X'\"", &v).unwrap();
+    assert_eq!(
+        cmd.args,
+        vec![
+            Argument::new("/home/me"),
+            Argument::new("/home/me/bin"),
+            Argument::new("xy"),
+            Argument::new("xx"),
+            Argument::new("[]"),
+            Argument::new("$HOME ${X}"),
+            Argument::new("'x'"),
+        ]
+    );
+}
+
+#[test]
+fn test_variables_come_from_the_environment() {
+    let session = ShellSession::new();
+    let path = std::env::var("PATH").unwrap();
+    assert_eq!(session.run("echo \"$PATH\"").stdout, format!("{}\n", path));
+    assert_eq!(session.run("echo a${NO_SUCH_VARIABLE_SET}b").stdout, "ab\n");
+}
+
+#[test]
+fn test_expand_substitute_first() {
+    let v = vars(&[("X", "foo foo")]);
+    let cmd = CommandLine::parse_with("echo \"${X/foo/bar}\"", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("bar foo")]);
+}
+
+#[test]
+fn test_expand_substitute_global() {
+    let v = vars(&[("X", "a.b.c")]);
+    let cmd = CommandLine::parse_with("echo ${X//./-}", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("a-b-c")]);
+}
+
+#[test]
+fn test_expand_substitute_glob_pattern_longest_match() {
+    let v = vars(&[("X", "hello world")]);
+    let cmd = CommandLine::parse_with("echo \"${X/o*o/0}\"", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("hell0rld")]);
+}
+
+#[test]
+fn test_expand_substitute_prefix_anchored() {
+    let v = vars(&[("X", "foofoo")]);
+    let cmd = CommandLine::parse_with("echo ${X/#foo/bar} ${X/#oo/bar}", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("barfoo"), Argument::new("foofoo")]);
+}
+
+#[test]
+fn test_expand_substitute_suffix_anchored() {
+    let v = vars(&[("X", "file.tar.gz")]);
+    let cmd = CommandLine::parse_with("echo ${X/%.gz/.xz} ${X/%.tar/.zip}", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("file.tar.xz"), Argument::new("file.tar.gz")]);
+}
+
+#[test]
+fn test_expand_substitute_empty_replacement_deletes() {
+    let v = vars(&[("X", "a-b-c")]);
+    let cmd = CommandLine::parse_with("echo ${X//-} ${X/-/}", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("abc"), Argument::new("ab-c")]);
+}
+
+#[test]
+fn test_expand_substitute_quoted_pattern_is_literal() {
+    let v = vars(&[("X", "a*b*c")]);
+    let cmd = CommandLine::parse_with("echo ${X//'*'/+}", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("a+b+c")]);
+}
+
+#[test]
+fn test_expand_defaults_and_alternatives() {
+    let v = vars(&[("X", "x"), ("E", "")]);
+    let cmd = CommandLine::parse_with("echo ${Y:-d} ${Y-d} [${E:-d}] [${E-d}] [${E:+alt}] [${Y:+alt}] [${X+alt}] ${Y:=new} $Y", &v).unwrap();
+    let expected = ["d", "d", "[d]", "[]", "[]", "[]", "[alt]", "new", "new"];
+    assert_eq!(cmd.args, expected.map(Argument::new));
+
+    let error = |line: &str| CommandLine::parse_with(line, &v).unwrap_err().to_string();
+    assert_eq!(error("echo ${Z:?msg}"), "Z: msg");
+    assert_eq!(error("echo ${E:?}"), "E: parameter null or not set");
+    assert_eq!(CommandLine::parse_with("echo \"[${E?}]\"", &v).unwrap().args, [Argument::new("[]")]);
+}
+
+#[test]
+fn test_expand_lengths_substrings_and_trimming() {
+    let v = vars(&[("X", "abcabc"), ("U", "é1")]);
+    let cmd = CommandLine::parse_with("echo ${#X} ${#U} ${X%c} ${X%%b*} ${X#*b} ${X##*b} ${X:1} ${X:1:2} ${X: -2} ${X:2:-1} [${X:10}] ${X:1+1:1}", &v).unwrap();
+    let expected = ["6", "2", "abcab", "a", "cabc", "c", "bcabc", "bc", "bc", "cab", "[]", "c"];
+    assert_eq!(cmd.args, expected.map(Argument::new));
+    assert_eq!(CommandLine::parse_with("echo ${X:1:-9}", &v).unwrap_err().to_string(), "-9: substring expression < 0");
+}
+
+#[test]
+fn test_unsupported_expansions_are_bad_substitutions() {
+    let session = ShellSession::new();
+    for word in ["${x!}", "${!x}", "${x:}", "${}", "${x^^}"] {
+        let outcome = session.run(&format!("x=abc; echo {}", word));
+        assert_eq!((outcome.status, outcome.stdout.as_str()), (1, ""), "{}", word);
+        assert_eq!(outcome.stderr, format!("{}: bad substitution\n", word));
+    }
+    let outcome = session.run("f() { echo ${#} ${#1} ${1:-none} ${3:-none}; }; f a b");
+    assert_eq!(outcome.stdout, "2 1 a none\n");
+}
+
+// Two CDPATH roots that both contain `shared`, plus a cwd with its own `local`.
+fn setup_cdpath() -> (tempfile::TempDir, std::path::PathBuf, String) {
+    let base = tempfile::tempdir().unwrap();
+    for dir in ["a/foo", "a/shared/inner", "b/bar", "b/shared", "cwd/local", "cwd/.hidden"] {
+        std::fs::create_dir_all(base.path().join(dir)).unwrap();
+    }
+    std::fs::write(base.path().join("a/not_a_dir"), "").unwrap();
+    let cwd = base.path().join("cwd");
+    let cdpath = format!("{}:{}", base.path().join("a").display(), base.path().join("b").display());
+    (base, cwd, cdpath)
+}
+
+#[test]
+fn test_cd_completion_reads_the_shells_cdpath() {
+    let (_base, _, cdpath) = setup_cdpath();
+    let session = ShellSession::new();
+    session.run(&format!("CDPATH='{}'", cdpath));
+    let engine = session.shell.completion_engine();
+    assert_eq!(texts(&engine.complete("cd sh", 5)), ["shared/"]);
+    assert_eq!(texts(&engine.complete("cd ba", 5)), ["bar/"]);
+}
+
+#[test]
+fn test_cd_completion_cdpath_entries() {
+    let (base, cwd, cdpath) = setup_cdpath();
+    let pairs = crate::complete::cd_completions("", &cwd, Some(&cdpath));
+    let replacements: Vec<&str> = pairs.iter().map(|p| p.text.as_str()).collect();
+    assert_eq!(replacements, vec!["foo/", "shared/", "bar/", "local/"]);
+
+    let shared = pairs.iter().find(|p| p.text == "shared/").unwrap();
+    assert_eq!(shared.display, format!("{}/", base.path().join("a/shared").display()));
+}
+
+#[test]
+fn test_cd_completion_overlapping_name_matches_cd_resolution() {
+    let (base, cwd, cdpath) = setup_cdpath();
+    let pairs = crate::complete::cd_completions("sh", &cwd, Some(&cdpath));
+    assert_eq!(pairs.len(), 1);
+    let resolved = crate::builtins::resolve_cd_target("shared", &cwd, Some(&cdpath)).unwrap();
+    assert_eq!(resolved, base.path().join("a/shared"));
+    assert_eq!(pairs[0].display, format!("{}/", resolved.display()));
+}
+
+#[test]
+fn test_cd_completion_nested_and_explicit_paths() {
+    let (_base, cwd, cdpath) = setup_cdpath();
+    let nested = crate::complete::cd_completions("shared/", &cwd, Some(&cdpath));
+    let replacements: Vec<&str> = nested.iter().map(|p| p.text.as_str()).collect();
+    assert_eq!(replacements, vec!["shared/inner/"]);
+
+    // `./` bypasses CDPATH, exactly like the builtin.
+    let explicit = crate::complete::cd_completions("./", &cwd, Some(&cdpath));
+    let replacements: Vec<&str> = explicit.iter().map(|p| p.text.as_str()).collect();
+    assert_eq!(replacements, vec!["./local/"]);
+    assert_eq!(crate::builtins::resolve_cd_target("./foo", &cwd, Some(&cdpath)), None);
+}
+
+#[test]
+fn test_cd_completion_without_cdpath() {
+    let (_base, cwd, _) = setup_cdpath();
+    let pairs = crate::complete::cd_completions("", &cwd, None);
+    let replacements: Vec<&str> = pairs.iter().map(|p| p.text.as_str()).collect();
+    assert_eq!(replacements, vec!["local/"]);
+}
+
+#[test]
+fn test_arithmetic_command_status() {
+    let shell = Shell::new();
+    assert_eq!(shell.run_line("(( 1 + 1 ))"), 0);
+    assert_eq!(shell.last_status(), 0);
+    assert_eq!(shell.run_line("(( 0 ))"), 1);
+    assert_eq!(shell.last_status(), 1);
+    assert_eq!(shell.run_line("(( 2 > 3 ))"), 1);
+}
+
+#[test]
+fn test_arithmetic_command_assigns_variables() {
+    let shell = Shell::new();
+    shell.run_line("(( n = 3 * 4 ))");
+    shell.run_line("((n++))");
+    assert_eq!(shell.vars.borrow().get("n").map(String::as_str), Some("13"));
+}
+
+#[test]
+fn test_let_then_echo() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out.txt");
+    let shell = Shell::new();
+    assert_eq!(shell.run_line("let x=5"), 0);
+    shell.run_line(&format!("echo $x > {}", out.display()));
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "5\n");
+
+    assert_eq!(shell.run_line("let \"x = x + 1\" y=x*2"), 0);
+    assert_eq!(shell.vars.borrow().get("y").map(String::as_str), Some("12"));
+    assert_eq!(shell.run_line("let y=0"), 1);
+}
+
+#[test]
+fn test_arithmetic_expansion() {
+    let v = vars(&[("a", "6"), ("b", "a * 2")]);
+    let cmd = CommandLine::parse_with("echo $((a + 1)) $(( b / 4 )) $(( (1 + 2) * -3 ))", &v).unwrap();
+    assert_eq!(cmd.args, vec![Argument::new("7"), Argument::new("3"), Argument::new("-9")]);
+}
+
+#[test]
+fn test_arithmetic_operators() {
+    let v = vars(&[]);
+    let eval = |expr: &str| crate::arith::evaluate(expr, &v);
+    assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+    assert_eq!(eval("7 % 3 + 0x10 + 010 + 2#101"), Ok(1 + 16 + 8 + 5));
+    assert_eq!(eval("1 < 2 && 3 > 4 || !0"), Ok(1));
+    assert_eq!(eval("5 > 3 ? 10 : 20"), Ok(10));
+    assert_eq!(eval("x = 4, x <<= 2, x"), Ok(16));
+    assert_eq!(eval("0 && (z = 1)"), Ok(0));
+    assert_eq!(v.borrow().get("z"), None);
+    assert!(eval("1 / 0").unwrap_err().message.contains("division by 0"));
+    assert!(eval("1 +").is_err());
+}
+
+#[test]
+fn test_conditional_glob_matching() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("[[ foo == f* ]]").status, 0);
+    assert_eq!(session.run("[[ foo == b* ]]").status, 1);
+    assert_eq!(session.run("[[ foo != b?? ]]").status, 0);
+    assert_eq!(session.run("[[ foo == [ef]oo ]]").status, 0);
+    // A quoted pattern matches literally.
+    assert_eq!(session.run("[[ foo == \"f*\" ]]").status, 1);
+    assert_eq!(session.run("[[ 'f*' == \"f*\" ]]").status, 0);
+    assert_eq!(session.run("[[ $HOME == /* ]]").status, 0);
+}
+
+#[test]
+fn test_conditional_logical_combinations() {
+    let session = ShellSession::new();
+    session.run("(( x = 5 ))");
+    assert_eq!(session.run("[[ -n $x && $x -gt 3 ]]").status, 0);
+    assert_eq!(session.run("[[ -z $x || $x -lt 3 ]]").status, 1);
+    assert_eq!(session.run("[[ ! -z $x ]]").status, 0);
+    assert_eq!(session.run("[[ -z $unset_var && ( a == b || c == c ) ]]").status, 0);
+    assert_eq!(session.run("[[ a < b ]]").status, 0);
+    assert_eq!(session.run("[[ a == ]]").status, 2);
+    let outcome = session.run("[[ ( a == a ]]");
+    assert_eq!(outcome.status, 2);
+    assert!(outcome.stderr.starts_with("[[: syntax error"));
+}
+
+#[test]
+fn test_conditional_file_tests() {
+    let session = ShellSession::new();
+    session.write_file("notes.txt", "hi");
+    session.write_file("empty", "");
+    session.mkdir("dir");
+    assert_eq!(session.run("[[ -f notes.txt && -s notes.txt ]]").status, 0);
+    assert_eq!(session.run("[[ -d dir && ! -f dir ]]").status, 0);
+    assert_eq!(session.run("[[ -s empty ]]").status, 1);
+    assert_eq!(session.run("[[ -e missing ]]").status, 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_conditional_permission_tests_ask_the_system() {
+    use std::os::unix::fs::PermissionsExt;
+    let session = ShellSession::new();
+    session.write_file("others_only", "");
+    std::fs::set_permissions(session.path("others_only"), std::fs::Permissions::from_mode(0o047)).unwrap();
+    // Bits for group and others do not grant the owner anything; root may
+    // read and write regardless, and execute since some `x` bit is set.
+    let root = unsafe { libc::geteuid() } == 0;
+    let status = |test: &str| session.run(&format!("[[ {} others_only ]]", test)).status;
+    assert_eq!(status("-r"), if root { 0 } else { 1 });
+    assert_eq!(status("-w"), if root { 0 } else { 1 });
+    assert_eq!(status("-x"), if root { 0 } else { 1 });
+}
+
+#[test]
+fn test_tilde_plus_expands_to_pwd() {
+    let session = ShellSession::new();
+    session.mkdir("sub");
+    session.run("cd sub");
+    let expected = format!("{} {}/x\n", session.path("sub").display(), session.path("sub").display());
+    assert_eq!(session.run("echo ~+ ~+/x").stdout, expected);
+    assert_eq!(session.run("echo '~+' \"~\" a~").stdout, "~+ ~ a~\n");
+}
+
+#[test]
+fn test_cd_tilde_minus_returns_to_previous_directory() {
+    let session = ShellSession::new();
+    session.mkdir("first");
+    session.mkdir("second");
+    session.run("cd first");
+    session.run("cd ../second");
+    assert_eq!(session.run("cd ~-").status, 0);
+    assert_eq!(session.shell.cwd(), session.path("first"));
+    assert_eq!(session.run("echo ~-").stdout, format!("{}\n", session.path("second").display()));
+}
+
+#[test]
+fn test_tilde_in_assignment_elements() {
+    let session = ShellSession::new();
+    let home = session.root().display().to_string();
+    session.run("P=~/bin:/usr/bin:~/.local/bin");
+    assert_eq!(
+        session.shell.vars.borrow().get("P").cloned(),
+        Some(format!("{home}/bin:/usr/bin:{home}/.local/bin"))
+    );
+    // Outside an assignment, only a leading tilde is expanded.
+    assert_eq!(session.run("echo x=~/a:~/b").stdout, "x=~/a:~/b\n");
+}
+
+#[test]
+fn test_prefix_assignment_applies_only_to_command() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("GREETING=hi sh -c 'echo $GREETING'").stdout, "hi\n");
+    assert_eq!(session.run("echo [$GREETING]").stdout, "[]\n");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_cd_drive_root() {
+    let session = ShellSession::new();
+    let drive = session.root().display().to_string()[..2].to_string();
+    assert_eq!(session.run(&format!("cd {}\\", drive)).status, 0);
+    assert_eq!(session.shell.cwd(), std::path::PathBuf::from(format!("{}\\", drive)));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_cd_mixed_separators() {
+    let session = ShellSession::new();
+    let target = session.mkdir("one/two/three");
+    assert_eq!(session.run("cd one/two\\three").status, 0);
+    assert_eq!(session.shell.cwd(), target);
+    assert!(!session.shell.cwd().display().to_string().starts_with(r"\\?\"));
+    assert_eq!(session.run("cd ..\\..").status, 0);
+    assert_eq!(session.shell.cwd(), session.path("one"));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_backslash_before_ordinary_character_is_literal() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo C:\\Users\\me").stdout, "C:\\Users\\me\n");
+    assert_eq!(session.run("echo a\\>b \\|").stdout, "a>b |\n");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_cd_completion_case_and_separator_style() {
+    let base = temp_dir();
+    std::fs::create_dir_all(base.path().join("Users").join("Me")).unwrap();
+    let replacements = |word: &str| -> Vec<String> {
+        crate::complete::cd_completions(word, base.path(), None).into_iter().map(|p| p.text).collect()
+    };
+    assert_eq!(replacements("us"), vec!["Users\\"]);
+    assert_eq!(replacements("Users\\m"), vec!["Users\\Me\\"]);
+    assert_eq!(replacements("Users/m"), vec!["Users/Me/"]);
+}
+
+#[test]
+fn test_select_binds_chosen_word() {
+    let session = ShellSession::new();
+    session.set_stdin("2\n");
+    let outcome = session.run("select fruit in apple banana cherry; do echo \"$fruit $REPLY\"; done");
+    assert_eq!(outcome.stdout, "banana 2\n");
+    assert_eq!(outcome.stderr, "1) apple\n2) banana\n3) cherry\n#? #? \n");
+}
+
+#[test]
+fn test_select_invalid_and_empty_replies() {
+    let session = ShellSession::new();
+    session.set_stdin("7\n\n1\n");
+    let outcome = session.run("select x in a b\ndo\n  echo \"[$x]\"\ndone");
+    assert_eq!(outcome.stdout, "[]\n[a]\n");
+    // The menu is shown at the start and again after the empty reply.
+    assert_eq!(outcome.stderr.matches("1) a").count(), 2);
+}
+
+#[test]
+fn test_select_uses_ps3_prompt() {
+    let session = ShellSession::new();
+    session.set_stdin("2\n");
+    let outcome = session.run("PS3='pick: '; select x in a b; do echo $x; done");
+    assert_eq!(outcome.stdout, "b\n");
+    assert_eq!(outcome.stderr, "1) a\n2) b\npick: pick: \n");
+}
+
+#[test]
+fn test_continuation_prompt_comes_from_ps2() {
+    let session = ShellSession::new();
+    let mut prompts = Vec::new();
+    let mut lines = ["b'"].into_iter();
+    let line = session.shell.read_continuation("echo 'a".to_string(), |prompt| {
+        prompts.push(prompt.to_string());
+        lines.next().map(String::from)
+    });
+    assert_eq!((line.as_str(), prompts), ("echo 'a\nb'", vec!["> ".to_string()]));
+
+    session.run("PS2='...> '");
+    let mut prompts = Vec::new();
+    let mut lines = ["  echo hi", "}"].into_iter();
+    session.shell.read_continuation("f() {".to_string(), |prompt| {
+        prompts.push(prompt.to_string());
+        lines.next().map(String::from)
+    });
+    assert_eq!(prompts, ["...> ", "...> "]);
+}
+
+#[test]
+fn test_select_break_ends_loop() {
+    let session = ShellSession::new();
+    session.set_stdin("1\n2\n1\n");
+    let outcome = session.run("select x in go stop; do case $x in stop) break;; esac; echo $x; done; echo after");
+    assert_eq!(outcome.stdout, "go\nafter\n");
+    // The third reply is left unread.
+    assert_eq!(session.shell.read_line().as_deref(), Some("1\n"));
+
+    let outcome = session.run("break");
+    assert_eq!(outcome.status, 0);
+    assert!(outcome.stderr.contains("only meaningful"));
+}
+
+#[test]
+fn test_parse_incomplete_and_unexpected() {
+    use crate::syntax::{parse, ParseError};
+    assert_eq!(parse("select x in a; do echo $x"), Err(ParseError::Incomplete));
+    assert_eq!(parse("done"), Err(ParseError::Unexpected("done".to_string())));
+    assert_eq!(parse("echo a;; echo b"), Err(ParseError::Unexpected(";;".to_string())));
+    let outcome = ShellSession::new().run("select x in a; do echo $x");
+    assert_eq!(outcome.status, 2);
+}
+
+#[test]
+fn test_commands_separated_by_semicolons_and_newlines() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo one; echo two\necho 'three; four'").stdout, "one\ntwo\nthree; four\n");
+    // Each command sees the state the one before left behind.
+    session.mkdir("sub");
+    let outcome = session.run("cd sub; pwd; false; echo $?; true; echo \"$? a;b\" c\\;d;");
+    assert_eq!(outcome.stdout, format!("{}\n1\n0 a;b c;d\n", session.path("sub").display()));
+    assert_eq!(outcome.status, 0);
+    // As in bash, a `;` with no command before it is a syntax error.
+    for line in ["; echo a", "echo a; ; echo b"] {
+        let outcome = session.run(line);
+        assert_eq!((outcome.status, outcome.stdout.as_str()), (2, ""), "{}", line);
+    }
+}
+
+#[test]
+fn test_case_exact_match() {
+    let session = ShellSession::new();
+    let script = "x=foo\ncase $x in\n  foo) echo matched foo;;\n  *) echo default;;\nesac";
+    assert_eq!(session.run(script).stdout, "matched foo\n");
+    // Until `esac` arrives the REPL keeps reading continuation lines.
+    assert_eq!(crate::syntax::parse("case $x in\n  foo) echo hi;;"), Err(crate::syntax::ParseError::Incomplete));
+}
+
+#[test]
+fn test_case_alternation() {
+    let session = ShellSession::new();
+    session.run("x=baz");
+    assert_eq!(session.run("case $x in foo) echo one;; bar|baz) echo two;; esac").stdout, "two\n");
+    assert_eq!(session.run("case quux in (foo | q*x) echo glob;; esac").stdout, "glob\n");
+}
+
+#[test]
+fn test_case_default_branch_and_status() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("case nothing in a) echo a;; *) echo default; (( 0 ));; esac").stdout, "default\n");
+    assert_eq!(session.run("case nothing in a) echo a;; esac").status, 0);
+    // Quoted pattern characters match literally.
+    assert_eq!(session.run("case 'a*' in \"a*\") echo literal;; esac").stdout, "literal\n");
+    assert_eq!(session.run("case abc in \"a*\") echo literal;; *) echo other;; esac").stdout, "other\n");
+}
+
+#[test]
+fn test_bang_inverts_exit_status() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("! true").status, 1);
+    assert_eq!(session.run("! false").status, 0);
+    assert_eq!(session.run("! sh -c 'exit 3'").status, 0);
+    assert_eq!(session.run("! ! false").status, 1);
+    assert_eq!(session.run("! { echo hi; false; }").stdout, "hi\n");
+    assert_eq!(session.shell.last_status.get(), 0);
+}
+
+#[test]
+fn test_bang_only_as_separate_word() {
+    use crate::syntax::{parse, ParseError};
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo ! done").stdout, "! done\n");
+    assert_eq!(session.run("!true").status, 127);
+    assert_eq!(parse("!"), Err(ParseError::Unexpected("newline".to_string())));
+    assert_eq!(parse("! ;"), Err(ParseError::Unexpected(";".to_string())));
+}
+
+#[test]
+fn test_parse_redirection_before_command() {
+    let cmd_line = CommandLine::parse("> out.txt echo hi");
+    assert_eq!(cmd_line.command, "echo");
+    assert_eq!(cmd_line.args, vec![Argument::new("hi")]);
+    assert_eq!(cmd_line.redirection.as_ref().unwrap().target(), "out.txt");
+
+    let cmd_line = CommandLine::parse("2>err.log grep pattern file");
+    assert_eq!(cmd_line.command, "grep");
+    assert_eq!(cmd_line.args, vec![Argument::new("pattern"), Argument::new("file")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("err.log", "2>"));
+}
+
+#[test]
+fn test_parse_redirection_mid_command() {
+    let cmd_line = CommandLine::parse("echo a >>log b");
+    assert_eq!(cmd_line.args, vec![Argument::new("a"), Argument::new("b")]);
+    assert_eq!(cmd_line.redirection.as_ref().unwrap().mode_name(), "1>>");
+
+    let cmd_line = CommandLine::parse("echo x2>f '2>' \"a > b\"");
+    assert_eq!(cmd_line.args, vec![Argument::new("x2"), Argument::new("2>"), Argument::new("a > b")]);
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("f", "1>"));
+}
+
+#[test]
+fn test_redirection_placements_execute() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("> first.txt echo leading").status, 0);
+    assert_eq!(session.read_file("first.txt"), "leading\n");
+    session.run("echo middle > second.txt more");
+    assert_eq!(session.read_file("second.txt"), "middle more\n");
+    session.run("2>err.log ls missing_file_xyz");
+    assert!(!session.read_file("err.log").is_empty());
+}
+
+#[test]
+fn test_bare_redirection_truncates_file() {
+    let session = ShellSession::new();
+    session.write_file("data.txt", "old contents\n");
+    let outcome = session.run("> data.txt");
+    assert_eq!(outcome.status, 0);
+    assert_eq!(session.read_file("data.txt"), "");
+    assert_eq!(session.run(">> created.txt").status, 0);
+    assert!(session.path("created.txt").exists());
+    assert_eq!(session.run("> missing_dir/file").status, 1);
+}
+
+#[test]
+fn test_function_definition_and_call() {
+    let session = ShellSession::new();
+    session.run("greet() { echo \"hello $1\"; echo \"$# args: $@\"; }");
+    assert_eq!(session.run("greet world again").stdout, "hello world\n2 args: world again\n");
+    session.run("function shout {\n  echo LOUD\n}");
+    assert_eq!(session.run("shout > loud.txt").stdout, "");
+    assert_eq!(session.read_file("loud.txt"), "LOUD\n");
+    // Positional parameters are restored after the call.
+    assert_eq!(session.run("echo [$1]").stdout, "[]\n");
+}
+
+#[test]
+fn test_command_not_found_handle_receives_command_and_args() {
+    let session = ShellSession::new();
+    session.run("command_not_found_handle() { echo \"missing: $1 ($2 $3)\"; (( 42 > 0 )); }");
+    let outcome = session.run("no_such_command_xyz alpha beta");
+    assert_eq!(outcome.stdout, "missing: no_such_command_xyz (alpha beta)\n");
+    assert_eq!(outcome.stderr, "");
+    assert_eq!(outcome.status, 0);
+}
+
+#[test]
+fn test_command_not_found_handle_does_not_recurse() {
+    let session = ShellSession::new();
+    session.run("command_not_found_handle() { another_missing_xyz; }");
+    let outcome = session.run("no_such_command_xyz");
+    assert_eq!(outcome.stderr, "another_missing_xyz: command not found\n");
+    assert_eq!(outcome.status, 127);
+}
+
+#[test]
+fn test_cached_patterns_agree_with_fresh_compilation() {
+    let patterns = ["*.rs", "f?o", "[a-c]*", "[!x]y*", "\\*lit", "[[:digit:]]*", "*"];
+    let texts = ["main.rs", "foo", "bar", "zyx", "ay", "*lit", "9lives", ""];
+    for pattern in patterns {
+        for text in texts {
+            let fresh = crate::glob::Pattern::new(pattern).matches(text);
+            assert_eq!(crate::glob::compile(pattern).matches(text), fresh, "{pattern} vs {text}");
+            // A second lookup is served from the cache and must agree too.
+            assert_eq!(crate::glob::compile(pattern).matches(text), fresh, "{pattern} vs {text} (cached)");
         }
-        
-        (dir, file_path)
-    }
-
-    #[test]
-    fn test_find_executable_found() {
-        let (dir, file_path) = setup_executable("my_exec");
-        
-        let shell = Shell::with_settings(vec![dir.clone()]);
-        let result = shell.find_executable_in_path("my_exec");
-        
-        assert_eq!(result, Some(file_path));
-        let _ = std::fs::remove_dir_all(dir);
-    }
-
-    #[test]
-    fn test_find_executable_not_found() {
-        let (dir, _) = setup_executable("other_exec");
-        
-        let shell = Shell::with_settings(vec![dir.clone()]);
-        let result = shell.find_executable_in_path("non_existent");
-        
-        assert_eq!(result, None);
-        let _ = std::fs::remove_dir_all(dir);
-    }
-
-    #[test]
-    fn test_execute_builtin_echo_redirect_stdout() {
-        let dir = std::env::temp_dir().join("shell_tests_stdout");
-        std::fs::create_dir_all(&dir).unwrap();
-        let file_path = dir.join("out.txt");
-        let file_path_str = file_path.to_str().unwrap();
-
-        if file_path.exists() {
-            std::fs::remove_file(&file_path).unwrap();
+    }
+    assert!(std::rc::Rc::ptr_eq(&crate::glob::compile("*.rs"), &crate::glob::compile("*.rs")));
+}
+
+#[test]
+fn test_pattern_cache_reuses_compilations_until_evicted() {
+    use crate::glob::{compile, CACHE_CAPACITY};
+    use std::rc::Rc;
+    let pattern = "[[:alpha:]]*_[0-9][0-9]*.{rs,toml}*[!~]";
+    let first = compile(pattern);
+    // Every later lookup is a hit, handing back the same compilation.
+    assert!((0..10_000).all(|_| Rc::ptr_eq(&compile(pattern), &first)));
+    // Using it keeps it cached while others come and go.
+    for n in 0..CACHE_CAPACITY * 2 {
+        compile(&format!("other{}*", n));
+        assert!(Rc::ptr_eq(&compile(pattern), &first));
+    }
+    // Once that many others are used after it, it is the one evicted.
+    for n in 0..CACHE_CAPACITY {
+        compile(&format!("newer{}*", n));
+    }
+    assert!(!Rc::ptr_eq(&compile(pattern), &first));
+}
+
+#[test]
+fn test_echo_escapes_match_coreutils() {
+    use crate::builtins::render_echo;
+
+    // Each input with the bytes coreutils `echo -e` prints for it.
+    let cases: &[(&str, &[u8])] = &[
+        (r"a\nb", b"a\nb\n"),
+        (r"\t|\v|\f|\r|\a|\b", b"\t|\x0b|\x0c|\r|\x07|\x08\n"),
+        (r"\0101", b"A\n"),
+        (r"\101", b"A\n"),
+        (r"\01234", b"S4\n"),
+        (r"\1234", b"S4\n"),
+        (r"\0", b"\0\n"),
+        (r"\x41", b"A\n"),
+        (r"\x4G", b"\x04G\n"),
+        (r"\xfg", b"\x0fg\n"),
+        (r"\x", b"\\x\n"),
+        (r"\e", b"\x1b\n"),
+        (r"\\", b"\\\n"),
+        (r"\", b"\\\n"),
+        (r#"\q|\8|\E|\'|\""#, b"\\q|\\8|\\E|\\'|\\\"\n"),
+        (r"a\cb", b"a"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(render_echo(&[input], true, true).as_bytes(), *expected, "echo -e {:?}", input);
+    }
+
+    // `\c` also drops later words; without -e nothing is decoded.
+    assert_eq!(render_echo(&[r"a\tb", "c"], true, true), "a\tb c\n");
+    assert_eq!(render_echo(&["a", r"b\c", "c"], true, true), "a b");
+    assert_eq!(render_echo(&[r"a\tb"], false, true), "a\\tb\n");
+}
+
+#[test]
+fn test_echo_options() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo -n hi").stdout, "hi");
+    assert_eq!(session.run(r"echo -e 'a\tb'").stdout, "a\tb\n");
+    assert_eq!(session.run(r"echo -ne 'a\n'").stdout, "a\n");
+    assert_eq!(session.run(r"echo -e -E 'a\tb'").stdout, "a\\tb\n");
+    assert_eq!(session.run("echo -x - -n").stdout, "-x - -n\n");
+}
+
+#[test]
+fn test_enable_disables_and_restores_builtin() {
+    let session = ShellSession::new();
+    // The builtin echo prints --version; /bin/echo understands it.
+    assert_eq!(session.run("echo --version").stdout, "--version\n");
+    assert_eq!(session.run("enable -n echo").status, 0);
+    assert_ne!(session.run("echo --version").stdout, "--version\n");
+    assert!(session.run("type echo").stdout.starts_with("echo is hashed (/"));
+    assert_eq!(session.run("enable -n").stdout, "enable -n echo\n");
+
+    assert_eq!(session.run("enable echo").status, 0);
+    assert_eq!(session.run("echo --version").stdout, "--version\n");
+    assert_eq!(session.run("type echo").stdout, "echo is a shell builtin\n");
+}
+
+#[test]
+fn test_enable_lists_builtins() {
+    let session = ShellSession::new();
+    session.run("enable -n pwd");
+    let all = session.run("enable -a").stdout;
+    assert!(all.contains("enable cd\n"));
+    assert!(all.contains("enable -n pwd\n"));
+    assert!(!session.run("enable").stdout.contains("pwd"));
+}
+
+#[test]
+fn test_enable_unknown_builtin_fails() {
+    let session = ShellSession::new();
+    let outcome = session.run("enable -n no_such_builtin");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "enable: no_such_builtin: not a shell builtin\n");
+}
+
+// A command that crashes after putting the terminal in raw mode leaves it
+// that way; the guard taken at startup puts the original attributes back.
+#[cfg(unix)]
+#[test]
+fn test_terminal_guard_restores_attributes() {
+    use crate::terminal::TerminalGuard;
+
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: openpty writes the two descriptors; the name and settings are optional.
+    let opened = unsafe {
+        libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null())
+    };
+    assert_eq!(opened, 0, "openpty failed");
+    let attributes = |fd| {
+        // SAFETY: termios is plain data filled in by tcgetattr.
+        unsafe {
+            let mut t: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(fd, &mut t), 0);
+            t
         }
+    };
+
+    let original = attributes(slave);
+    let guard = TerminalGuard::capture_fd(slave).expect("pty is a terminal");
+    let mut raw = original;
+    // SAFETY: `raw` is a valid termios for the pty.
+    unsafe {
+        libc::cfmakeraw(&mut raw);
+        libc::tcsetattr(slave, libc::TCSANOW, &raw);
+    }
+    assert_ne!(attributes(slave).c_lflag, original.c_lflag);
+
+    guard.restore();
+    let restored = attributes(slave);
+    assert_eq!(restored.c_lflag, original.c_lflag);
+    assert_eq!(restored.c_iflag, original.c_iflag);
+    assert_eq!(restored.c_oflag, original.c_oflag);
 
-        let shell = Shell::new();
-        // echo hello > ...
-        let cmd = CommandLine {
-            command: "echo".to_string(),
-            args: vec![Argument::new("hello")],
-            redirection: Some(Box::new(crate::StdoutRedirect { 
-                target: file_path_str.to_string() 
-            })),
-        };
-        shell.execute(cmd);
-
-        let content = std::fs::read_to_string(&file_path).unwrap();
-        assert_eq!(content, "hello\n");
-    }
-
-    #[test]
-    fn test_execute_builtin_echo_redirect_append() {
-        let dir = std::env::temp_dir().join("shell_tests_append");
-        std::fs::create_dir_all(&dir).unwrap();
-        let file_path = dir.join("out.txt");
-        let file_path_str = file_path.to_str().unwrap();
-
-        if file_path.exists() {
-             std::fs::remove_file(&file_path).unwrap();
+    drop(guard);
+    // SAFETY: both descriptors came from openpty and are closed once.
+    unsafe {
+        libc::close(slave);
+        libc::close(master);
+    }
+}
+
+// `read -n` and `read -s` take input a character at a time, unechoed, and
+// leave the terminal as it was.
+#[cfg(unix)]
+#[test]
+fn test_input_mode_reads_without_enter_and_restores() {
+    use crate::terminal::InputMode;
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: openpty writes the two descriptors; the name and settings are optional.
+    let opened = unsafe {
+        libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null())
+    };
+    assert_eq!(opened, 0, "openpty failed");
+    // SAFETY: the descriptors came from openpty, and each File closes its own.
+    let (mut master, mut slave) = unsafe { (std::fs::File::from_raw_fd(master), std::fs::File::from_raw_fd(slave)) };
+    let slave_fd = std::os::fd::AsRawFd::as_raw_fd(&slave);
+    let lflag = || {
+        // SAFETY: termios is plain data filled in by tcgetattr.
+        unsafe {
+            let mut t: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(slave_fd, &mut t), 0);
+            t.c_lflag
         }
-        
-        let shell = Shell::new();
-        let cmd1 = CommandLine {
-            command: "echo".to_string(),
-            args: vec![Argument::new("hello")],
-            redirection: Some(Box::new(crate::StdoutRedirect { target: file_path_str.to_string() })),
-        };
-        shell.execute(cmd1);
-
-        let cmd2 = CommandLine {
-            command: "echo".to_string(),
-            args: vec![Argument::new("world")],
-            redirection: Some(Box::new(crate::StdoutAppendRedirect { target: file_path_str.to_string() })),
-        };
-        shell.execute(cmd2);
-
-        let content = std::fs::read_to_string(&file_path).unwrap();
-        assert_eq!(content, "hello\nworld\n");
-    }
-
-    #[test]
-    fn test_execute_external_redirect_stdout() {
-         let dir = std::env::temp_dir().join("shell_tests_ext_stdout");
-         std::fs::create_dir_all(&dir).unwrap();
-         let file_path = dir.join("out.txt");
-         let file_path_str = file_path.to_str().unwrap();
-         
-         if file_path.exists() {
-            std::fs::remove_file(&file_path).unwrap();
-         }
-         
-         let shell = Shell::new();
-         let cmd = CommandLine {
-             command: "sh".to_string(),
-             args: vec![Argument::new("-c"), Argument::new("echo external")],
-             redirection: Some(Box::new(crate::StdoutRedirect { target: file_path_str.to_string() })),
-         };
-         shell.execute(cmd);
-         
-         let content = std::fs::read_to_string(&file_path).expect("File should exist");
-         assert!(content.contains("external"));
-    }
-
-    #[test]
-    fn test_execute_external_redirect_stderr() {
-         let dir = std::env::temp_dir().join("shell_tests_ext_stderr");
-         std::fs::create_dir_all(&dir).unwrap();
-         let file_path = dir.join("err.txt");
-         let file_path_str = file_path.to_str().unwrap();
-         
-         if file_path.exists() {
-            std::fs::remove_file(&file_path).unwrap();
-         }
-         
-         let shell = Shell::new();
-         let cmd = CommandLine {
-             command: "sh".to_string(),
-             args: vec![Argument::new("-c"), Argument::new("echo failure >&2")],
-             redirection: Some(Box::new(crate::StderrRedirect { target: file_path_str.to_string() })),
-         };
-         shell.execute(cmd);
-         
-         let content = std::fs::read_to_string(&file_path).expect("File should exist");
-         assert!(content.contains("failure"));
-    }
-
-    #[test]
-    fn test_owl_scenario() {
-         let rat_dir = std::env::temp_dir().join("rat_test");
-         std::fs::create_dir_all(&rat_dir).unwrap();
-         std::fs::write(rat_dir.join("banana"), "banana\n").unwrap();
-         std::fs::write(rat_dir.join("grape"), "grape\n").unwrap();
-         std::fs::write(rat_dir.join("pear"), "pear\n").unwrap();
-         
-         let owl_dir = std::env::temp_dir().join("owl_test");
-         std::fs::create_dir_all(&owl_dir).unwrap();
-         let bee_md = owl_dir.join("bee.md");
-         if bee_md.exists() { std::fs::remove_file(&bee_md).unwrap(); }
-         
-         let rat_dir_str = rat_dir.to_str().unwrap();
-         let bee_md_str = bee_md.to_str().unwrap();
-         
-         let shell = Shell::new();
-         // ls -1 /tmp/rat >> /tmp/owl/bee.md
-         let cmd = CommandLine {
-             command: "ls".to_string(),
-             args: vec![Argument::new("-1"), Argument::new(rat_dir_str)],
-             redirection: Some(Box::new(crate::StdoutAppendRedirect { target: bee_md_str.to_string() })),
-         };
-         shell.execute(cmd);
-         
-         let content = std::fs::read_to_string(&bee_md).expect("ls output file should exist");
-         assert!(content.contains("banana"));
-         assert!(content.contains("grape"));
-         assert!(content.contains("pear"));
-         
-         let fox_md = owl_dir.join("fox.md");
-         let fox_md_str = fox_md.to_str().unwrap();
-         if fox_md.exists() { std::fs::remove_file(&fox_md).unwrap(); }
-
-         // echo 'Hello Maria' 1>> /tmp/owl/fox.md
-         let cmd2 = CommandLine {
-             command: "echo".to_string(),
-             args: vec![Argument::new("Hello Maria")],
-             redirection: Some(Box::new(crate::StdoutAppendRedirect { target: fox_md_str.to_string() })),
-         };
-         shell.execute(cmd2);
-         
-         let fox_content = std::fs::read_to_string(&fox_md).expect("echo output file should exist");
-         assert_eq!(fox_content.trim(), "Hello Maria");
-    }
-
-    #[test]
-    fn test_execute_builtin_pwd_redirect_stdout() {
-        let dir = std::env::temp_dir().join("shell_tests_pwd");
-        std::fs::create_dir_all(&dir).unwrap();
-        let file_path = dir.join("pwd_out.txt");
-        let file_path_str = file_path.to_str().unwrap();
-
-        if file_path.exists() {
-            std::fs::remove_file(&file_path).unwrap();
+    };
+    let original = lflag();
+    assert_ne!(original & libc::ICANON, 0);
+
+    let mode = InputMode::set(slave_fd, false, false).expect("pty is a terminal");
+    assert_eq!(lflag() & (libc::ICANON | libc::ECHO), 0);
+    // Typed characters can be read before Enter is pressed.
+    master.write_all(b"xy").unwrap();
+    let mut poll = libc::pollfd { fd: slave_fd, events: libc::POLLIN, revents: 0 };
+    // SAFETY: `poll` reads and writes the one pollfd given.
+    assert_eq!(unsafe { libc::poll(&mut poll, 1, 2000) }, 1, "input waited for Enter");
+    let mut byte = [0];
+    slave.read_exact(&mut byte).unwrap();
+    assert_eq!(&byte, b"x");
+
+    drop(mode);
+    assert_eq!(lflag(), original);
+    assert!(InputMode::set(-1, false, false).is_none());
+}
+
+#[test]
+fn test_read_n_stops_after_a_count_of_characters() {
+    let session = ShellSession::new();
+    session.set_stdin("abcdef\n\u{e9}t\u{e9}\nx\ny z\n");
+    assert_eq!(session.run("read -n 3 first; echo \"[$first]\"").stdout, "[abc]\n");
+    assert_eq!(session.run("read -n3 second; echo \"[$second]\"").stdout, "[def]\n");
+    // The newline after `def` ends the next read at once.
+    assert_eq!(session.run("read -n 2; echo \"[$REPLY]\"").stdout, "[]\n");
+    assert_eq!(session.run("read -rn 2 accents; echo \"[$accents]\"").stdout, "[\u{e9}t]\n");
+    session.run("read -n 9 rest");
+    // A newline stops the read before the count.
+    assert_eq!(session.run("read -n 9 line; echo \"[$line]\"").stdout, "[x]\n");
+    // Off a terminal, -s reads a line as usual.
+    assert_eq!(session.run("read -s a b; echo \"[$a][$b]\"").stdout, "[y][z]\n");
+    assert_eq!(session.run("read -n 1 none").status, 1);
+    // Input that ends before the count is stored, but the read fails.
+    session.set_stdin("ab");
+    let outcome = session.run("read -n 5 short; echo \"$? [$short]\"");
+    assert_eq!(outcome.stdout, "1 [ab]\n");
+    session.set_stdin("ab");
+    assert_eq!(session.run("read -n 2 exact; echo \"$? [$exact]\"").stdout, "0 [ab]\n");
+
+    assert_eq!(session.run("read -n").stderr, "read: -n: option requires an argument\nread: usage: read [-rs] [-n nchars] [-t timeout] [name ...]\n");
+    assert_eq!(session.run("read -n many").stderr, "read: many: invalid number\n");
+    assert_eq!(session.run("read -q").status, 2);
+}
+
+// `read -t` gives up when a pipe stays open with nothing written to it.
+#[cfg(unix)]
+#[test]
+fn test_read_times_out_waiting_for_input() {
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::{BufReader, Write};
+    use std::os::fd::FromRawFd;
+    use std::rc::Rc;
+
+    let mut ends = [0; 2];
+    // SAFETY: `pipe` fills in the two descriptors.
+    assert_eq!(unsafe { libc::pipe(ends.as_mut_ptr()) }, 0);
+    // SAFETY: each File takes ownership of one end of the new pipe.
+    let (reader, mut writer) = unsafe { (File::from_raw_fd(ends[0]), File::from_raw_fd(ends[1])) };
+    let session = ShellSession::new();
+    *session.shell.stdin.borrow_mut() = crate::Input::File(Rc::new(RefCell::new(BufReader::new(reader))));
+
+    session.run("x=old");
+    let started = std::time::Instant::now();
+    let outcome = session.run("read -t 0.2 x");
+    assert!(outcome.status > 128, "{:?}", outcome);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(session.run("echo \"[$x]\"").stdout, "[]\n");
+
+    writer.write_all(b"on time\nab").unwrap();
+    assert_eq!(session.run("read -t 5 x y; echo \"[$x][$y]\"").stdout, "[on][time]\n");
+    // Input that stops partway through a line still times out.
+    assert_eq!(session.run("read -t 0.1 x").status, 142);
+    writer.write_all(b"cd").unwrap();
+    assert_eq!(session.run("read -t1 -n 1 x; echo \"[$x]\"").stdout, "[c]\n");
+    drop(writer);
+    assert_eq!(session.run("read -t 5 x; echo \"[$x]\"").stdout, "[d]\n");
+    assert_eq!(session.run("read -t 5 x").status, 1);
+
+    assert_eq!(session.run("read -t soon x").stderr, "read: soon: invalid timeout specification\n");
+    assert_eq!(session.run("read -t -1 x").status, 1);
+}
+
+#[test]
+fn test_alias_expands_command_word() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("alias greet='echo hello'").status, 0);
+    assert_eq!(session.run("greet world").stdout, "hello world\n");
+    assert_eq!(session.run("alias greet").stdout, "alias greet='echo hello'\n");
+    assert_eq!(session.run("\\greet").status, 127);
+}
+
+#[test]
+fn test_alias_referring_to_itself_runs_the_command() {
+    let session = ShellSession::new();
+    session.run("alias echo='echo [x]'");
+    assert_eq!(session.run("echo hi").stdout, "[x] hi\n");
+
+    session.run("alias loop_a=loop_b loop_b=loop_a");
+    let outcome = session.run("loop_a");
+    assert_eq!(outcome.status, 127);
+    assert_eq!(outcome.stderr, "loop_a: command not found\n");
+}
+
+#[test]
+fn test_alias_chains_into_another_alias_once() {
+    let session = ShellSession::new();
+    session.run("alias say='echo [said]' greet='say hello'");
+    assert_eq!(session.run("greet world").stdout, "[said] hello world\n");
+
+    session.run("alias echo='echo [x]' shout='echo loud'");
+    assert_eq!(session.run("shout").stdout, "[x] loud\n");
+}
+
+#[test]
+fn test_alias_ending_in_blank_expands_the_next_word() {
+    let session = ShellSession::new();
+    session.run("alias sudo='sudo ' ll='ls -l' ls='ls --color'");
+    assert_eq!(session.shell.expand_alias("sudo ll /tmp"), "sudo  ls --color -l /tmp");
+    assert_eq!(session.shell.expand_alias("sudo sudo"), "sudo  sudo");
+
+    session.run("alias say='echo ' quiet=echo target=world");
+    assert_eq!(session.run("say target").stdout, "world\n");
+    assert_eq!(session.run("quiet target").stdout, "target\n");
+    assert_eq!(session.run("say 'target'").stdout, "target\n");
+}
+
+#[test]
+fn test_unalias_removes_alias() {
+    let session = ShellSession::new();
+    session.run("alias a='echo a' b='echo b'");
+    assert_eq!(session.run("unalias a").status, 0);
+    assert_eq!(session.run("alias").stdout, "alias b='echo b'\n");
+    let outcome = session.run("unalias a");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "unalias: a: not found\n");
+}
+
+#[test]
+fn test_unalias_all_removes_every_alias() {
+    let session = ShellSession::new();
+    session.run("alias a='echo a' b='echo b' c=pwd");
+    assert_eq!(session.run("alias").stdout.lines().count(), 3);
+    assert_eq!(session.run("unalias -a b").status, 0);
+    let outcome = session.run("alias");
+    assert_eq!(outcome.status, 0);
+    assert_eq!(outcome.stdout, "");
+    assert_eq!(session.run("a").status, 127);
+}
+
+#[test]
+fn test_sanitize_for_display_renders_control_characters() {
+    assert_eq!(sanitize_for_display("plain name"), "plain name");
+    assert_eq!(sanitize_for_display("\x1b]0;pwned\x07"), "^[]0;pwned^G");
+    assert_eq!(sanitize_for_display("a\nb\tc\x7f"), "a^Jb^Ic^?");
+    assert_eq!(sanitize_for_display("\u{9b}31m"), "\\x9b31m");
+}
+
+#[test]
+fn test_diagnostics_sanitize_untrusted_names() {
+    let session = ShellSession::new();
+    let hostile = "'\x1b]0;pwned\x07\nx'";
+    let shown = "^[]0;pwned^G^Jx";
+
+    assert_eq!(session.run(&format!("{} arg", hostile)).stderr, format!("{}: command not found\n", shown));
+    assert_eq!(session.run(&format!("type {}", hostile)).stdout, format!("{}: not found\n", shown));
+    assert_eq!(session.run(&format!("cd {}", hostile)).stderr, format!("cd: {}: No such file or directory\n", shown));
+    assert_eq!(session.run(&format!("unalias {}", hostile)).stderr, format!("unalias: {}: not found\n", shown));
+    let arithmetic = session.run(&format!("let {}", hostile)).stderr;
+    assert!(arithmetic.starts_with("let: ") && arithmetic.contains(shown), "{:?}", arithmetic);
+    assert!(!arithmetic.contains('\x1b'));
+
+    let redirect = session.run(&format!("echo hi > missing/{}", hostile));
+    assert_eq!(redirect.stderr, format!("myshell: missing/{}: No such file or directory\n", shown));
+}
+
+#[test]
+fn test_completion_includes_aliases() {
+    let engine = engine(&["echo", "exit"], &["ll", "la"], &[], vec![]);
+    let result = engine.complete("l", 1);
+    let (start, matches) = (result.start, texts(&result));
+    assert_eq!(start, 0);
+    assert_eq!(matches, vec!["la ", "ll "]);
+    assert_eq!(texts(&engine.complete("ll", 2)), ["ll "]);
+}
+
+#[test]
+fn test_underscore_is_last_argument_of_previous_command() {
+    let session = ShellSession::new();
+    session.run("word=expanded");
+    session.run("echo first $word");
+    assert_eq!(session.run("echo $_").stdout, "expanded\n");
+    session.run("pwd");
+    assert_eq!(session.run("echo $_").stdout, "pwd\n");
+
+    session.run("sh -c true one two");
+    assert_eq!(session.run("echo $_").stdout, "two\n");
+}
+
+#[test]
+fn test_type_reports_functions_and_aliases() {
+    let session = ShellSession::new();
+    session.run("greet() { echo hi; }");
+    session.run("alias ll='ls -l'");
+    assert_eq!(session.run("type greet").stdout, "greet is a function\n");
+    assert_eq!(session.run("type ll").stdout, "ll is aliased to `ls -l'\n");
+    assert_eq!(session.run("type -t greet ll echo sh").stdout, "function\nalias\nbuiltin\nfile\n");
+    let outcome = session.run("type -t missing_command_xyz");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stdout, "");
+}
+
+#[test]
+fn test_type_json_describes_each_kind() {
+    let session = ShellSession::new();
+    session.run("greet() { echo hi; }");
+    session.run("alias ll='ls -l'");
+    let sh = session.shell.find_indexed_executable("sh").unwrap().display().to_string();
+
+    let outcome = session.run("type --json echo cd sh greet ll");
+    assert_eq!(outcome.status, 0);
+    assert_eq!(
+        outcome.stdout,
+        format!(
+            concat!(
+                r#"[{{"name":"echo","kind":"builtin"}},{{"name":"cd","kind":"builtin"}},"#,
+                r#"{{"name":"sh","kind":"file","path":"{}"}},{{"name":"greet","kind":"function"}},"#,
+                r#"{{"name":"ll","kind":"alias"}}]"#,
+                "\n"
+            ),
+            sh
+        )
+    );
+
+    let outcome = session.run(r#"type --json 'no"such\tool'"#);
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stdout, "[{\"name\":\"no\\\"such\\\\tool\",\"kind\":null}]\n");
+    assert_eq!(session.run("type --json").stdout, "[]\n");
+}
+
+#[test]
+fn test_completion_includes_functions() {
+    let engine = engine(&["echo"], &[], &["greet"], vec![]);
+    assert_eq!(texts(&engine.complete("gr", 2)), ["greet "]);
+}
+
+#[test]
+fn test_word_spans_follow_the_tokenizer() {
+    use crate::syntax::word_spans;
+    let words = |line: &str| word_spans(line).into_iter().map(|span| line[span].to_string()).collect::<Vec<_>>();
+    assert_eq!(words(r#"echo "two word arg" 'x y'z"#), [r#"echo"#, r#""two word arg""#, "'x y'z"]);
+    assert_eq!(words(r"cp a\ b c\\"), ["cp", r"a\ b", r"c\\"]);
+    assert_eq!(words("sort <in >'out file' 2>>err|&tee;ls"), ["sort", "<in", ">'out file'", "2>>err", "|&", "tee", ";", "ls"]);
+    assert_eq!(words("echo $(a b) \"open quote"), ["echo", "$(a b)", "\"open quote"]);
+}
+
+#[test]
+fn test_kill_argument_boundaries() {
+    use crate::{kill_argument_backward, kill_argument_forward};
+    let line = r#"grep "two word arg" > out\ file"#;
+    let at = |word: &str| line.find(word).unwrap();
+    // Forward from the start of a word, from inside one, and from blanks.
+    assert_eq!(kill_argument_forward(line, at("\"two"), 1), at(" > "));
+    assert_eq!(kill_argument_forward(line, at("word"), 1), at(" > "));
+    assert_eq!(kill_argument_forward(line, at(" \"two"), 1), at(" > "));
+    assert_eq!(kill_argument_forward(line, 0, 2), at(" > "));
+    assert_eq!(kill_argument_forward(line, at("out"), 1), line.len());
+    assert_eq!(kill_argument_forward(line, line.len(), 1), line.len());
+
+    assert_eq!(kill_argument_backward(line, line.len(), 1), at("out"));
+    assert_eq!(kill_argument_backward(line, at(" > "), 1), at("\"two"));
+    assert_eq!(kill_argument_backward(line, at("arg"), 1), at("\"two"));
+    assert_eq!(kill_argument_backward(line, line.len(), 3), at("\"two"));
+    assert_eq!(kill_argument_backward(line, 2, 1), 0);
+    assert_eq!(kill_argument_backward(line, 0, 1), 0);
+}
+
+#[test]
+fn test_inputrc_settings_parse() {
+    use crate::inputrc::EditSettings;
+    use rustyline::config::{BellStyle, EditMode};
+
+    let mut settings = EditSettings::default();
+    let warnings = settings.load(
+        "# editing\nset editing-mode vi\nset completion-ignore-case On\n\nset bell-style none\n\
+         set history-size 500\nset show-all-if-ambiguous on\nset colored-stats on\nset history-size lots\nbogus\n",
+    );
+    assert_eq!(settings.editing_mode, EditMode::Vi);
+    assert!(settings.completion_ignore_case);
+    assert_eq!(settings.bell_style, BellStyle::None);
+    assert_eq!(settings.history_size, 500);
+    assert!(settings.show_all_if_ambiguous);
+    assert_eq!(
+        warnings,
+        vec![
+            ".myshell_inputrc: line 8: colored-stats: unknown variable name",
+            ".myshell_inputrc: line 9: lots: invalid value for history-size",
+            ".myshell_inputrc: line 10: bogus: expected `set name value'",
+        ]
+    );
+}
+
+#[test]
+fn test_inputrc_loaded_from_home() {
+    use rustyline::config::BellStyle;
+
+    let session = ShellSession::new();
+    session.write_file(".myshell_inputrc", "set bell-style visible\nset nonsense on\n");
+    session.shell.load_inputrc();
+    assert_eq!(session.shell.edit_settings.borrow().bell_style, BellStyle::Visible);
+    assert_eq!(session.run("").stderr, ".myshell_inputrc: line 2: nonsense: unknown variable name\n");
+}
+
+#[test]
+fn test_editing_mode_follows_the_editor() {
+    use crate::inputrc::EditSettings;
+    use rustyline::config::EditMode;
+    for (editor, mode) in [
+        ("vi", EditMode::Vi),
+        ("/usr/bin/nvim", EditMode::Vi),
+        ("vim -u NONE", EditMode::Vi),
+        ("emacs -nw", EditMode::Emacs),
+        ("/home/david/bin/nano", EditMode::Emacs),
+        ("", EditMode::Emacs),
+    ] {
+        let mut settings = EditSettings::default();
+        settings.follow_editor(editor);
+        assert_eq!(settings.editing_mode, mode, "{}", editor);
+    }
+
+    // VISUAL comes before EDITOR, unless it is empty.
+    let session = ShellSession::new();
+    session.run("VISUAL=nano EDITOR=vim");
+    session.shell.follow_editor();
+    assert_eq!(session.shell.edit_settings.borrow().editing_mode, EditMode::Emacs);
+    session.run("VISUAL=");
+    session.shell.follow_editor();
+    assert!(session.run("set -o").stdout.contains("vi             \ton\n"));
+    // `set -o` still switches back afterwards.
+    session.run("set -o emacs");
+    assert_eq!(session.shell.edit_settings.borrow().editing_mode, EditMode::Emacs);
+}
+
+#[test]
+fn test_set_o_switches_editing_mode() {
+    use rustyline::config::{Builder, Config, EditMode};
+
+    let session = ShellSession::new();
+    assert_eq!(session.run("set -o").stdout, "autocd         \toff\ncomplete-options\toff\nemacs          \ton\nlithist        \toff\ntimes          \toff\nvi             \toff\n");
+    assert_eq!(session.run("set -o vi").status, 0);
+    assert_eq!(session.run("set +o").stdout, "set +o autocd\nset +o complete-options\nset +o emacs\nset +o lithist\nset +o times\nset -o vi\n");
+
+    // The editor picks the mode up before the next prompt.
+    let mut builder = Builder::new();
+    session.shell.edit_settings.borrow().apply_to(&mut builder).unwrap();
+    let config: Config = builder.build();
+    assert_eq!(config.edit_mode(), EditMode::Vi);
+
+    session.run("set +o vi");
+    assert_eq!(session.shell.edit_settings.borrow().editing_mode, EditMode::Emacs);
+    let outcome = session.run("set -o nosuch");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "set: nosuch: invalid option name\n");
+}
+
+#[test]
+fn test_bind_lists_and_sets_variables() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("bind 'set editing-mode vi' 'set completion-ignore-case on'").status, 0);
+    let listing = session.run("bind -v").stdout;
+    assert!(listing.contains("set editing-mode vi\n"), "{}", listing);
+    assert!(listing.contains("set completion-ignore-case on\n"), "{}", listing);
+    assert_eq!(session.run("bind 'set editing-mode ed'").stderr, "bind: ed: invalid value for editing-mode\n");
+}
+
+#[test]
+fn test_completion_ignore_case() {
+    use crate::inputrc::EditSettings;
+
+    let settings = EditSettings { completion_ignore_case: true, ..Default::default() };
+    let engine = Engine { settings: Arc::new(Mutex::new(settings)), ..engine(&["Echo", "exit"], &[], &[], vec![]) };
+    assert_eq!(texts(&engine.complete("ec", 2)), ["Echo "]);
+}
+
+#[test]
+fn test_path_assignment_changes_where_commands_are_found() {
+    let (first, second) = (temp_dir(), temp_dir());
+    write_executable(first.path(), "mytool", "#!/bin/sh\necho first\n");
+    let path = write_executable(second.path(), "mytool", "#!/bin/sh\necho second\n");
+    write_executable(second.path(), "othertool", "#!/bin/sh\n");
+    let session = ShellSession::new();
+
+    let outcome = session.run("mytool");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (127, "mytool: command not found\n"));
+    session.run(&format!("export PATH={}:$PATH", first.path().display()));
+    assert_eq!(session.run("mytool").stdout, "first\n");
+
+    // Earlier directories win, and what was hashed is forgotten.
+    session.run(&format!("PATH={}:$PATH", second.path().display()));
+    assert_eq!(session.run("type mytool").stdout, format!("mytool is {}\n", path.display()));
+    assert_eq!(session.run("mytool").stdout, "second\n");
+    assert_eq!(session.shell.complete("othert", 6).names(), ["othertool"]);
+}
+
+#[test]
+fn test_type_reports_hashed_commands() {
+    let bin = temp_dir();
+    let path = write_executable(bin.path(), "hello_tool", "#!/bin/sh\necho hello\n");
+    let mut shell = Shell::with_settings(vec![bin.path().to_path_buf()]);
+    shell.builtins.push(crate::Builtin::new(Box::new(crate::builtins::TypeCommand), "type", ""));
+    shell.builtins.push(crate::Builtin::new(Box::new(crate::builtins::HashCommand), "hash", ""));
+    let session = ShellSession::with_shell(shell);
+
+    assert_eq!(session.run("hash").stdout, "hash: hash table empty\n");
+    assert_eq!(session.run("type hello_tool").stdout, format!("hello_tool is {}\n", path.display()));
+    assert_eq!(session.run("hello_tool").stdout, "hello\n");
+    assert_eq!(session.run("hello_tool").stdout, "hello\n");
+    assert_eq!(session.run("type hello_tool").stdout, format!("hello_tool is hashed ({})\n", path.display()));
+    assert_eq!(session.run("hash").stdout, format!("hits\tcommand\n   2\t{}\n", path.display()));
+
+    session.run("hash -r");
+    assert_eq!(session.run("type hello_tool").stdout, format!("hello_tool is {}\n", path.display()));
+}
+
+// The session's HOME is its root, so approvals land in `.config/myshell`.
+fn direnv_session() -> ShellSession {
+    let session = ShellSession::new();
+    session.run("XDG_CONFIG_HOME=");
+    session.mkdir("proj/sub");
+    session.write_file("proj/.myshellenv", "GREETING=hello\nKEPT=inner\n");
+    session
+}
+
+#[test]
+fn test_direnv_requires_allow() {
+    let session = direnv_session();
+    let outcome = session.run("cd proj");
+    assert_eq!(outcome.status, 0);
+    assert!(outcome.stderr.contains(".myshellenv: not allowed; run `envallow' to load it"), "{}", outcome.stderr);
+    assert_eq!(session.run("echo \"[$GREETING]\"").stdout, "[]\n");
+
+    assert_eq!(session.run("envallow").status, 0);
+    assert_eq!(session.run("echo $GREETING").stdout, "hello\n");
+    let allow_list = session.read_file(".config/myshell/envallow");
+    assert!(allow_list.ends_with(&format!("\t{}\n", session.path("proj/.myshellenv").display())), "{}", allow_list);
+    let hash = crate::direnv::content_hash(b"GREETING=hello\nKEPT=inner\n");
+    assert!(allow_list.starts_with(&format!("{}\t", hash)), "{}", allow_list);
+}
+
+#[test]
+fn test_direnv_hashes_with_sha256() {
+    use crate::direnv::content_hash;
+    assert_eq!(content_hash(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+    // Approvals recorded with the old 64-bit hash must be given again.
+    let session = direnv_session();
+    session.mkdir(".config/myshell");
+    session.write_file(".config/myshell/envallow", &format!("0123456789abcdef\t{}\n", session.path("proj/.myshellenv").display()));
+    assert!(session.run("cd proj").stderr.contains("changed since it was allowed"));
+}
+
+#[test]
+fn test_direnv_unloads_on_leaving() {
+    let session = direnv_session();
+    session.run("KEPT=outer");
+    session.run("cd proj; envallow");
+    assert_eq!(session.run("echo $GREETING $KEPT").stdout, "hello inner\n");
+
+    // Subdirectories stay inside the environment.
+    session.run("cd sub");
+    assert_eq!(session.run("echo $GREETING").stdout, "hello\n");
+
+    let outcome = session.run("cd ../..");
+    assert_eq!(outcome.stderr, "");
+    assert_eq!(session.run("echo \"[$GREETING] $KEPT\"").stdout, "[] outer\n");
+
+    // Once allowed, re-entering loads it silently.
+    let outcome = session.run("cd proj");
+    assert_eq!(outcome.stderr, "");
+    assert_eq!(session.run("echo $GREETING").stdout, "hello\n");
+}
+
+#[test]
+fn test_direnv_detects_modification() {
+    let session = direnv_session();
+    session.run("cd proj; envallow; cd ..");
+    session.write_file("proj/.myshellenv", "GREETING=tampered\n");
+    let outcome = session.run("cd proj");
+    assert!(outcome.stderr.contains("changed since it was allowed"), "{}", outcome.stderr);
+    assert_eq!(session.run("echo \"[$GREETING]\"").stdout, "[]\n");
+
+    session.run("envallow");
+    assert_eq!(session.run("echo $GREETING").stdout, "tampered\n");
+}
+
+#[test]
+fn test_direnv_checks_the_contents_it_runs() {
+    use crate::direnv::{allow, approval, Approval};
+    let session = direnv_session();
+    let (list, file) = (session.path("allowed"), session.path("proj/.myshellenv"));
+    allow(&list, &file).unwrap();
+    assert_eq!(approval(&list, &file, b"GREETING=hello\nKEPT=inner\n"), Approval::Allowed);
+    // What was read is judged, whatever the file holds by now.
+    assert_eq!(approval(&list, &file, b"GREETING=swapped\n"), Approval::Changed);
+}
+
+#[test]
+fn test_direnv_deny_revokes() {
+    let session = direnv_session();
+    session.run("envallow proj/.myshellenv");
+    assert_eq!(session.run("cd proj").stderr, "");
+    assert_eq!(session.run("envallow -d").status, 0);
+    assert_eq!(session.run("echo \"[$GREETING]\"").stdout, "[]\n");
+    session.run("cd ..");
+    assert!(session.run("cd proj").stderr.contains("not allowed"));
+
+    let outcome = session.run("envallow missing/.myshellenv");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "envallow: missing/.myshellenv: No such file or directory\n");
+}
+
+#[test]
+fn test_source_runs_file() {
+    let session = ShellSession::new();
+    session.write_file("vars.sh", "A=1\necho sourced $A\n");
+    assert_eq!(session.run("source vars.sh").stdout, "sourced 1\n");
+    assert_eq!(session.run(". vars.sh; echo $A").stdout, "sourced 1\n1\n");
+    assert_eq!(session.run("source").status, 2);
+    assert_eq!(session.run("source nope.sh").status, 1);
+}
+
+#[test]
+fn test_source_arguments_are_positional_parameters_while_it_runs() {
+    let session = ShellSession::new();
+    session.write_file("args.sh", "echo \"$# [$1] [$2]\"\n");
+    session.run("f() { source args.sh one 'two words'; echo \"after $# [$1]\"; . args.sh; }");
+    assert_eq!(session.run("f outer").stdout, "2 [one] [two words]\nafter 1 [outer]\n1 [outer] []\n");
+    // The caller's parameters come back even when the file is missing.
+    session.run("g() { . missing.sh x y; echo \"$# [$1]\"; }");
+    assert_eq!(session.run("g kept").stdout, "1 [kept]\n");
+}
+
+fn history_session(lines: &[&str]) -> ShellSession {
+    let session = ShellSession::new();
+    for line in lines {
+        session.shell.history.borrow_mut().push(line);
+    }
+    session
+}
+
+fn history_lines(session: &ShellSession) -> Vec<String> {
+    session.shell.history.borrow().entries().iter().map(|entry| entry.line.clone()).collect()
+}
+
+#[test]
+fn test_history_lists_entries() {
+    let session = history_session(&["echo one", "  ", "echo two"]);
+    assert_eq!(session.run("history").stdout, "    1  echo one\n    2  echo two\n");
+}
+
+#[test]
+fn test_history_n_lists_the_last_entries() {
+    let session = history_session(&["echo one", "echo two", "echo three"]);
+    assert_eq!(session.run("history 2").stdout, "    2  echo two\n    3  echo three\n");
+    assert_eq!(session.run("history 9").stdout.lines().count(), 3);
+    assert_eq!(session.run("history 0").stdout, "");
+    session.run("history 1 > h.txt");
+    assert_eq!(session.read_file("h.txt"), "    3  echo three\n");
+
+    let outcome = session.run("history many");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (1, "history: many: numeric argument required\n"));
+    assert_eq!(session.run("history 1 2").status, 2);
+}
+
+// Feeds `lines` to the shell as the REPL does: continuation lines join the
+// first until the command is complete, then it runs and is recorded.
+fn type_lines(session: &ShellSession, lines: &[&str]) -> String {
+    let mut rest = lines.iter().map(|line| line.to_string());
+    let first = rest.next().unwrap();
+    let command = crate::syntax::read_continuation(first, || rest.next());
+    let stdout = session.run(&command).stdout;
+    session.shell.record_history(&command);
+    stdout
+}
+
+#[test]
+fn test_history_keeps_a_multi_line_command_as_one_entry() {
+    let session = ShellSession::new();
+    let lines = ["case b in", "  a|b) echo matched;;", "esac"];
+    assert_eq!(type_lines(&session, &lines), "matched\n");
+    type_lines(&session, &["greet() {", "  echo hi", "}"]);
+    type_lines(&session, &["echo 'a", "b'"]);
+    assert_eq!(history_lines(&session), ["case b in a|b) echo matched;; esac", "greet() { echo hi; }", "echo 'a\nb'"]);
+
+    // The joined entry runs as the construct did.
+    assert_eq!(session.run(&history_lines(&session)[0]).stdout, "matched\n");
+
+    session.run("set -o lithist");
+    type_lines(&session, &lines);
+    assert_eq!(history_lines(&session)[3], "case b in\n  a|b) echo matched;;\nesac");
+    assert_eq!(session.run("history").stdout.lines().count(), 7);
+}
+
+#[test]
+fn test_history_joins_lines_but_keeps_comments_from_swallowing_them() {
+    use crate::history::join_lines;
+    assert_eq!(join_lines("f() { # greets\n  echo hi # there\n}"), "f() { # greets\necho hi # there\n}");
+    assert_eq!(join_lines("for i in 1 2\ndo echo $i # each\ndone"), "for i in 1 2; do echo $i # each\ndone");
+    assert_eq!(join_lines("echo a#b '# c'\necho d"), "echo a#b '# c'; echo d");
+    let session = ShellSession::new();
+    type_lines(&session, &["g() { # greets", "  echo hi", "}"]);
+    session.run(&history_lines(&session)[0]);
+    assert_eq!(session.run("g").stdout, "hi\n");
+}
+
+#[test]
+fn test_history_file_round_trips_multi_line_entries() {
+    let session = history_session(&["case b in\n  b) echo $i;;\nesac", "echo a\\nb", "ls"]);
+    session.shell.save_history();
+    let text = session.read_file(".myshell_history");
+    assert_eq!(text.lines().count(), 8);
+    let stamps = text.lines().filter(|line| line.starts_with('#') && line[1..].parse::<u64>().is_ok()).count();
+    assert_eq!(stamps, 3);
+
+    let saved = session.shell.history.borrow().entries().to_vec();
+    session.shell.history.borrow_mut().delete("1-3").unwrap();
+    session.shell.load_history();
+    assert_eq!(session.shell.history.borrow().entries(), saved);
+}
+
+// Files written by bash 5.2 and by this shell with `HISTFILE_FORMAT=native`.
+const BASH_PLAIN: &str = include_str!("../tests/fixtures/bash_plain.history");
+const BASH_TIMESTAMPS: &str = include_str!("../tests/fixtures/bash_timestamps.history");
+const BASH_LITHIST: &str = include_str!("../tests/fixtures/bash_lithist.history");
+const NATIVE: &str = include_str!("../tests/fixtures/native.history");
+
+fn entry_lines(history: &crate::history::History) -> Vec<&str> {
+    history.entries().iter().map(|entry| entry.line.as_str()).collect()
+}
+
+#[test]
+fn test_history_file_formats_round_trip_their_fixtures() {
+    use crate::history::{FileFormat, History};
+    let bash = History::load(BASH_TIMESTAMPS.as_bytes());
+    assert_eq!(
+        entry_lines(&bash),
+        [
+            "HISTTIMEFORMAT=\"%s \"",
+            "ls -l",
+            "echo \"a b\" | tr a-z A-Z",
+            "for i in 1 2; do   echo $i; done",
+            "printf 'a\\\\nb\\\\n'",
+            "history -w bash_timestamps.history",
+        ]
+    );
+    assert_eq!(bash.to_bytes(FileFormat::Bash), BASH_TIMESTAMPS.as_bytes());
+    let plain = History::load(BASH_PLAIN.as_bytes());
+    assert_eq!(entry_lines(&History::load(&plain.to_bytes(FileFormat::Bash))), entry_lines(&plain));
+    assert_eq!(History::load(BASH_LITHIST.as_bytes()).to_bytes(FileFormat::Multiline), BASH_LITHIST.as_bytes());
+
+    let native = History::load(NATIVE.as_bytes());
+    assert_eq!(
+        entry_lines(&native)[2..],
+        ["case x in\n  x) echo \"#1 x\";;\nesac", "echo 'multi\nline'", "echo 'back\\slash\\n'"]
+    );
+    assert_eq!(native.to_bytes(FileFormat::Native), NATIVE.as_bytes());
+    // Flattened for bash, the case joins up but the quoted newline stays.
+    let flattened = History::load(&native.to_bytes(FileFormat::Bash));
+    assert_eq!(entry_lines(&flattened)[2..], ["case x in x) echo \"#1 x\";; esac", "echo 'multi\nline'", "echo 'back\\slash\\n'"]);
+}
+
+#[test]
+fn test_history_reads_files_of_mixed_formats_whole() {
+    use crate::history::History;
+    let parts = [NATIVE, BASH_PLAIN, BASH_LITHIST, BASH_TIMESTAMPS, NATIVE];
+    let mixed = History::load(parts.concat().as_bytes());
+    let expected: Vec<String> = parts.iter().flat_map(|part| History::load(part.as_bytes()).entries().to_vec()).map(|entry| entry.line).collect();
+    assert_eq!(entry_lines(&mixed), expected);
+    // A native stamp whose entry is missing loses nothing after it.
+    let broken = History::load(b"#5 native\n#6\nls\n");
+    assert_eq!(entry_lines(&broken), ["ls"]);
+    assert_eq!(broken.entries()[0].time, 6);
+}
+
+#[test]
+fn test_histfile_format_picks_how_history_is_saved() {
+    let formats = [
+        ("", "\nfor i in 1 2\ndo echo $i\ndone\n"),
+        ("bash", "\nfor i in 1 2; do echo $i; done\n"),
+        ("native", " native\nfor i in 1 2\\ndo echo $i\\ndone\n"),
+    ];
+    for (format, saved) in formats {
+        let session = history_session(&["for i in 1 2\ndo echo $i\ndone"]);
+        session.run(&format!("HISTFILE_FORMAT={}", format));
+        session.shell.save_history();
+        let text = session.read_file(".myshell_history");
+        assert!(text.ends_with(saved), "{}", text);
+    }
+    let session = history_session(&["for i in 1 2\ndo echo $i\ndone"]);
+    session.run("HISTFILE_FORMAT=native");
+    session.shell.save_history();
+    session.shell.load_history();
+    assert_eq!(history_lines(&session), ["for i in 1 2\ndo echo $i\ndone"]);
+
+    // A file that is not UTF-8 still loads, and saving keeps its bytes.
+    session.run("HISTFILE_FORMAT=");
+    std::fs::write(session.path(".myshell_history"), b"#7\necho caf\xe9\n#8\nls\n").unwrap();
+    session.shell.load_history();
+    assert_eq!(history_lines(&session), ["echo caf\u{fffd}", "ls"]);
+    session.shell.save_history();
+    assert_eq!(std::fs::read(session.path(".myshell_history")).unwrap(), b"#7\necho caf\xe9\n#8\nls\n");
+    session.run("HISTFILE_FORMAT=native");
+    session.shell.save_history();
+    session.shell.load_history();
+    assert_eq!(session.shell.history.borrow().entries()[0].raw.as_deref(), Some(&b"echo caf\xe9"[..]));
+}
+
+#[test]
+fn test_history_file_follows_histfile_and_its_sizes() {
+    let session = ShellSession::new();
+    session.run("HISTFILE=hist");
+    session.write_file("hist", "#1\none\n#2\ntwo\n#3\nthree\n");
+    session.run("HISTSIZE=1 HISTFILESIZE=10");
+    session.shell.load_history();
+    assert_eq!(history_lines(&session), ["three"]);
+
+    // Saving adds to the file, keeping what the session left out and what
+    // another session wrote since.
+    session.shell.record_history("four");
+    std::fs::write(session.path("hist"), "#1\none\n#2\ntwo\n#3\nthree\n#4\nother\n").unwrap();
+    session.shell.save_history();
+    let saved = crate::history::History::load(&std::fs::read(session.path("hist")).unwrap());
+    assert_eq!(entry_lines(&saved), ["one", "two", "three", "other", "four"]);
+    assert_eq!(history_lines(&session), ["four"]);
+    session.shell.save_history();
+    assert_eq!(entry_lines(&crate::history::History::load(&std::fs::read(session.path("hist")).unwrap())).len(), 5);
+
+    // HISTFILESIZE cuts the file; a negative size means no limit.
+    session.run("HISTSIZE=-1 HISTFILESIZE=2");
+    session.shell.record_history("five");
+    session.shell.save_history();
+    assert_eq!(session.read_file("hist").lines().filter(|line| !line.starts_with('#')).collect::<Vec<_>>(), ["four", "five"]);
+    session.shell.load_history();
+    session.shell.record_history("six");
+    assert_eq!(history_lines(&session), ["four", "five", "six"]);
+
+    // An empty HISTFILE saves nothing.
+    session.run("HISTFILE=");
+    assert_eq!(session.shell.history_file(), None);
+    assert!(!session.path(".myshell_history").exists());
+}
+
+#[test]
+fn test_history_shows_times_with_histtimeformat() {
+    let session = ShellSession::new();
+    session.write_file(".myshell_history", "#1700000000
+echo one
+#1700000000
+echo two
+");
+    session.shell.load_history();
+    assert_eq!(session.run("history").stdout, "    1  echo one\n    2  echo two\n");
+    session.run("HISTTIMEFORMAT='%Y-%m '");
+    assert_eq!(session.run("history").stdout, "    1  2023-11 echo one\n    2  2023-11 echo two\n");
+    assert_eq!(crate::history::format_time(1700000000, "%%s"), "%s");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_history_stamps_new_entries_with_the_current_time() {
+    let session = history_session(&["echo hi"]);
+    let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    session.run("HISTTIMEFORMAT='%s| '");
+    let listing = session.run("history").stdout;
+    let (time, command) = listing.trim_start().strip_prefix("1  ").unwrap().split_once("| ").unwrap();
+    assert_eq!(command, "echo hi\n");
+    assert!(time.parse::<u64>().unwrap().abs_diff(before) <= 5, "{}", listing);
+}
+
+#[test]
+fn test_history_porcelain_format() {
+    let session = ShellSession::new();
+    session.write_file(
+        ".myshell_history",
+        "#1700000000\necho one\n#1700000042\ncase x in\n  x) printf 'a\\tb';;\nesac\n#1700000100\necho\ttab\n",
+    );
+    session.shell.load_history();
+    session.run("HISTTIMEFORMAT='%F '");
+    let outcome = session.run("history --porcelain");
+    assert_eq!(outcome.status, 0);
+    assert_eq!(
+        outcome.stdout,
+        "1\t1700000000\techo one\n2\t1700000042\tcase x in\\n  x) printf 'a\\\\tb';;\\nesac\n3\t1700000100\techo\\ttab\n"
+    );
+    assert_eq!(session.run("history --porcelain extra").status, 2);
+}
+
+#[test]
+fn test_history_r_imports_a_bash_history_file() {
+    let session = history_session(&["echo first"]);
+    session.write_file(
+        "bash_history",
+        "untimed line\n#1700000000\nls -l\n#1700000100\ncase x in\n  x) echo x;;\nesac\n#1700000200\necho last\n",
+    );
+    assert_eq!(session.run("history -r bash_history").status, 0);
+    assert_eq!(history_lines(&session), ["echo first", "untimed line", "ls -l", "case x in\n  x) echo x;;\nesac", "echo last"]);
+    let times: Vec<u64> = session.shell.history.borrow().entries()[2..].iter().map(|entry| entry.time).collect();
+    assert_eq!(times, [1700000000, 1700000100, 1700000200]);
+    assert!(session.shell.history.borrow_mut().take_rewritten());
+
+    let missing = session.run("history -r nope");
+    assert_eq!(missing.status, 1);
+    assert!(missing.stderr.starts_with("history: "));
+}
+
+#[test]
+fn test_history_delete_single_and_negative() {
+    let session = history_session(&["a", "b", "c", "secret", "d"]);
+    assert_eq!(session.run("history -d 2").status, 0);
+    assert_eq!(history_lines(&session), ["a", "c", "secret", "d"]);
+    assert_eq!(session.run("history -d -2").status, 0);
+    assert_eq!(history_lines(&session), ["a", "c", "d"]);
+    assert_eq!(session.run("history -d -1").status, 0);
+    assert_eq!(history_lines(&session), ["a", "c"]);
+    assert!(session.shell.history.borrow_mut().take_rewritten());
+}
+
+#[test]
+fn test_history_delete_range() {
+    let session = history_session(&["1", "2", "3", "4", "5", "6"]);
+    assert_eq!(session.run("history -d 2-4").status, 0);
+    assert_eq!(history_lines(&session), ["1", "5", "6"]);
+    assert_eq!(session.run("history -d -2--1").status, 0);
+    assert_eq!(history_lines(&session), ["1"]);
+}
+
+#[test]
+fn test_history_delete_out_of_range() {
+    let session = history_session(&["a", "b"]);
+    for spec in ["0", "3", "-3", "2-1", "1-5", "x"] {
+        let outcome = session.run(&format!("history -d {}", spec));
+        assert_eq!(outcome.status, 1, "{}", spec);
+        assert_eq!(outcome.stderr, format!("history: {}: history position out of range\n", spec));
+    }
+    assert_eq!(history_lines(&session), ["a", "b"]);
+    assert!(!session.shell.history.borrow_mut().take_rewritten());
+}
+
+#[test]
+fn test_open_quote_continues_on_next_line() {
+    use crate::syntax::{parse, read_continuation, ParseError};
+
+    for open in ["echo \"a", "echo 'a", "echo `date", "echo $(pwd", "echo ${HOME", "echo \"$(pwd\""] {
+        assert_eq!(parse(open), Err(ParseError::Incomplete), "{}", open);
+    }
+
+    let mut rest = vec!["b\"".to_string()].into_iter();
+    let line = read_continuation("echo \"a".to_string(), || rest.next());
+    assert_eq!(line, "echo \"a\nb\"");
+    assert_eq!(ShellSession::new().run(&line).stdout, "a\nb\n");
+
+    let mut rest = vec!["two".to_string(), "three'".to_string(), "unused".to_string()].into_iter();
+    let line = read_continuation("echo 'one".to_string(), || rest.next());
+    assert_eq!(line, "echo 'one\ntwo\nthree'");
+    assert_eq!(rest.next().as_deref(), Some("unused"));
+
+    // Input that ends inside the quote is left for the parser to reject.
+    let line = read_continuation("echo \"a".to_string(), || None);
+    let outcome = ShellSession::new().run(&line);
+    assert_eq!(outcome.status, 2);
+    assert_eq!(outcome.stderr, "syntax error: unexpected end of file\n");
+}
+
+#[test]
+fn test_input_redirection_failure_skips_builtin() {
+    let session = ShellSession::new();
+    let outcome = session.run("echo hi < missing.txt");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stdout, "");
+    assert_eq!(outcome.stderr, "myshell: missing.txt: No such file or directory\n");
+
+    let outcome = session.run("x=1 read y < missing.txt");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(session.run("echo \"[$y]\"").stdout, "[]\n");
+}
+
+#[test]
+fn test_read_from_input_redirection() {
+    let session = ShellSession::new();
+    session.write_file("input.txt", "first line here\nsecond\n");
+    assert_eq!(session.run("read x < input.txt").status, 0);
+    assert_eq!(session.run("echo $x").stdout, "first line here\n");
+    session.run("read a b <input.txt");
+    assert_eq!(session.run("echo \"$a|$b\"").stdout, "first|line here\n");
+    session.run("read < input.txt");
+    assert_eq!(session.run("echo $REPLY").stdout, "first line here\n");
+
+    // The redirection only lasts for the one command.
+    session.set_stdin("from stdin\n");
+    session.run("read x");
+    assert_eq!(session.run("echo $x").stdout, "from stdin\n");
+    assert_eq!(session.run("read x").status, 1);
+}
+
+#[test]
+fn test_input_redirection_for_external_and_bare() {
+    let session = ShellSession::new();
+    session.write_file("input.txt", "piped through\n");
+    assert_eq!(session.run("cat < input.txt").stdout, "piped through\n");
+    assert_eq!(session.run("< input.txt").status, 0);
+    assert_eq!(session.run("< missing.txt").status, 1);
+    assert_eq!(session.run("> missing/out.txt").stderr, "myshell: missing/out.txt: No such file or directory\n");
+    // The error goes to the stderr the command started with.
+    let outcome = session.run("echo hi > missing/out.txt 2>/dev/null");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (1, ""));
+    assert_eq!(outcome.stderr, "myshell: missing/out.txt: No such file or directory\n");
+    assert_eq!(session.run("echo hi >&7").stderr, "myshell: 7: Bad file descriptor\n");
+}
+
+#[test]
+fn test_options_rc_path() {
+    use crate::options::Options;
+    use std::path::PathBuf;
+
+    let session = ShellSession::new();
+    let args = |list: &[&str]| Options::parse(list.iter().map(|a| a.to_string()));
+
+    let options = args(&[]).unwrap();
+    assert_eq!(options.rc_path(&session.shell), Some(session.path(".myshellrc")));
+    let options = args(&["--rcfile", "~/other_rc"]).unwrap();
+    assert_eq!(options.rc_path(&session.shell), Some(session.path("other_rc")));
+    let options = args(&["--rcfile", "/etc/some_rc"]).unwrap();
+    assert_eq!(options.rc_path(&session.shell), Some(PathBuf::from("/etc/some_rc")));
+    let options = args(&["--rcfile", "/etc/some_rc", "--norc"]).unwrap();
+    assert_eq!(options.rc_path(&session.shell), None);
+
+    let options = args(&["-c", "echo hi"]).unwrap();
+    assert_eq!(options.command.as_deref(), Some("echo hi"));
+    assert_eq!(options.startup_files(options.mode(true)), vec![crate::options::StartupFile::Env]);
+
+    assert_eq!(args(&["--rcfile"]), Err("--rcfile: option requires an argument".to_string()));
+    assert_eq!(args(&["-c"]), Err("-c: option requires an argument".to_string()));
+    assert_eq!(args(&["--bogus"]), Err("--bogus: invalid option".to_string()));
+
+    let options = args(&["--complete", "ec", "2"]).unwrap();
+    assert_eq!(options.complete, Some(("ec".to_string(), 2)));
+    assert_eq!(args(&["--complete", "ec"]), Err("--complete: option requires LINE and POS".to_string()));
+    assert_eq!(args(&["--complete", "ec", "3"]), Err("--complete: 3: invalid position".to_string()));
+    assert_eq!(args(&["--complete", "é", "1"]), Err("--complete: 1: invalid position".to_string()));
+}
+
+#[test]
+fn test_load_rc_reports_missing_rcfile_only() {
+    use crate::options::Options;
+
+    let session = ShellSession::new();
+    session.shell.load_rc(&Options::default());
+    assert_eq!(session.run("").stderr, "");
+
+    session.write_file("my_rc", "alias hi='echo hi from rc'\n");
+    session.shell.load_rc(&Options { rcfile: Some("my_rc".to_string()), ..Default::default() });
+    assert_eq!(session.run("hi").stdout, "hi from rc\n");
+
+    session.shell.load_rc(&Options { rcfile: Some("missing_rc".to_string()), ..Default::default() });
+    assert!(session.run("").stderr.starts_with("missing_rc: No such file"));
+}
+
+#[test]
+fn test_completion_order_builtins_then_executables() {
+    let bin = temp_dir();
+    for name in ["zz_tool", "Cat", "cat", "cd", "Ab", "echo"] {
+        write_executable(bin.path(), name, "#!/bin/sh\n");
+    }
+    let helper = completer(engine(&["echo", "cd"], &[], &["by_func"], vec![bin.path().to_path_buf()]));
+    let expected = ["by_func", "cd", "echo", "Ab", "Cat", "cat", "zz_tool"];
+    assert_eq!(helper.engine.complete("", 0).names(), expected);
+
+    // The double-Tab listing uses the same order.
+    let tab_handler = helper.tab_handler();
+    assert_eq!(tab_handler.listing("", 0).0, expected);
+}
+
+#[test]
+fn test_completion_follows_command_changes_at_once() {
+    let session = ShellSession::new();
+    let engine = session.shell.completion_engine();
+    let generation = || engine.index.lock().unwrap().generation();
+    let start = generation();
+
+    session.run("alias zz_short='echo hi'");
+    assert_eq!(texts(&engine.complete("zz_", 3)), ["zz_short "]);
+    session.run("zz_fn() { :; }");
+    assert_eq!(texts(&engine.complete("zz_", 3)), ["zz_fn ", "zz_short "]);
+    session.run("unalias zz_short");
+    assert_eq!(texts(&engine.complete("zz_", 3)), ["zz_fn "]);
+    assert_eq!(generation(), start + 3);
+
+    session.run("enable -n compgen");
+    assert!(engine.complete("compg", 5).candidates.is_empty());
+    session.run("enable compgen");
+    assert_eq!(texts(&engine.complete("compg", 5)), ["compgen "]);
+
+    // Commands that change nothing leave the generation alone.
+    let before = generation();
+    session.run("echo; alias");
+    assert_eq!(generation(), before);
+}
+
+#[test]
+fn test_kill_option_completes_signal_names() {
+    let engine = engine(&["echo", "kill"], &[], &[], vec![]);
+    let result = engine.complete("kill -t", 7);
+    assert_eq!(result.start, 5);
+    let shown: Vec<(&str, &str)> = result.candidates.iter().map(|c| (c.display.as_str(), c.text.as_str())).collect();
+    assert_eq!(
+        shown,
+        [("-TERM", "-TERM "), ("-TRAP", "-TRAP "), ("-TSTP", "-TSTP "), ("-TTIN", "-TTIN "), ("-TTOU", "-TTOU ")]
+    );
+    assert_eq!(engine.complete("kill -", 6).candidates.len(), 22);
+    assert!(engine.complete("kill -9", 7).candidates.is_empty());
+
+    // Only options of kill name signals.
+    assert!(engine.complete("kill 12", 7).candidates.is_empty());
+    assert!(engine.complete("echo -T", 7).candidates.is_empty());
+}
+
+#[test]
+fn test_completion_describes_builtins_but_replaces_bare_names() {
+    let bin = temp_dir();
+    write_executable(bin.path(), "cdrecord", "#!/bin/sh\n");
+    let helper = completer(engine(&["cd", "echo"], &["cdl"], &["cdf"], vec![bin.path().to_path_buf()]));
+    let result = helper.engine.complete("cd", 2);
+    assert_eq!(result.start, 0);
+    let shown: Vec<(&str, Option<&str>, &str)> = result.candidates.iter().map(|c| (c.display.as_str(), c.kind, c.text.as_str())).collect();
+    assert_eq!(
+        shown,
+        [
+            ("cd  (builtin)", Some("builtin"), "cd "),
+            ("cdf  (function)", Some("function"), "cdf "),
+            ("cdl  (alias)", Some("alias"), "cdl "),
+            ("cdrecord", None, "cdrecord ")
+        ]
+    );
+
+    let tab_handler = helper.tab_handler();
+    let (matches, listing) = tab_handler.listing("cd", 2);
+    assert_eq!(matches, ["cd", "cdf", "cdl", "cdrecord"]);
+    assert_eq!(listing, result.candidates.iter().map(|c| c.display.clone()).collect::<Vec<_>>());
+
+    helper.engine.settings.lock().unwrap().completion_descriptions = false;
+    let result = helper.engine.complete("cd", 2);
+    assert!(result.candidates.iter().all(|c| c.display == c.text.trim_end()));
+    assert_eq!(tab_handler.listing("cd", 2).1, matches);
+}
+
+#[test]
+fn test_completer_and_tab_handler_adapt_engine_candidates() {
+    use rustyline::completion::Completer;
+    let helper = completer(engine(&["echo", "exit", "kill"], &[], &[], vec![]));
+    let history = rustyline::history::MemHistory::new();
+    let (start, pairs) = helper.complete("kill -TE", 8, &rustyline::Context::new(&history)).unwrap();
+    assert_eq!(start, 5);
+    let shown: Vec<(&str, &str)> = pairs.iter().map(|p| (p.display.as_str(), p.replacement.as_str())).collect();
+    assert_eq!(shown, [("-TERM", "-TERM ")]);
+    let (_, pairs) = helper.complete("e", 1, &rustyline::Context::new(&history)).unwrap();
+    let shown: Vec<(&str, &str)> = pairs.iter().map(|p| (p.display.as_str(), p.replacement.as_str())).collect();
+    assert_eq!(shown, [("echo  (builtin)", "echo "), ("exit  (builtin)", "exit ")]);
+
+    let tab_handler = helper.tab_handler();
+    assert_eq!(tab_handler.listing("kill -TE", 8), (vec!["-TERM".to_string()], vec!["-TERM".to_string()]));
+}
+
+#[test]
+fn test_completer_uses_the_shells_directory_and_cdpath() {
+    use rustyline::completion::Completer;
+    let (base, _, cdpath) = setup_cdpath();
+    let session = ShellSession::new();
+    session.write_file("sub/inner/file", "");
+    // Set as a shell variable only, never exported.
+    session.run(&format!("cd sub; CDPATH='{}'", cdpath));
+    assert_ne!(std::env::current_dir().unwrap(), session.path("sub"));
+
+    let helper = session.shell.completer();
+    let history = rustyline::history::MemHistory::new();
+    let (start, pairs) = helper.complete("cd in", 5, &rustyline::Context::new(&history)).unwrap();
+    assert_eq!(start, 3);
+    assert_eq!(pairs.iter().map(|p| p.replacement.as_str()).collect::<Vec<_>>(), ["inner/"]);
+    let (_, pairs) = helper.complete("cd sh", 5, &rustyline::Context::new(&history)).unwrap();
+    assert_eq!(pairs.iter().map(|p| p.display.as_str()).collect::<Vec<_>>(), [format!("{}/", base.path().join("a/shared").display())]);
+}
+
+#[test]
+fn test_shell_complete_uses_its_own_directory() {
+    let session = ShellSession::new();
+    session.write_file("sub/inner/file", "");
+    session.write_file("sub/other.txt", "");
+    session.run("cd sub");
+    let result = session.shell.complete("cd i", 4);
+    assert_eq!(result.start, 3);
+    assert_eq!(texts(&result), ["inner/"]);
+    assert_eq!(result.candidates[0].display, format!("{}/", session.root().join("sub/inner").display()));
+    assert_eq!(session.shell.complete("ech", 3).candidates[0].kind, Some("builtin"));
+}
+
+#[test]
+fn test_printf_recycles_format_until_arguments_run_out() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("printf '%s\\n' a b c").stdout, "a\nb\nc\n");
+    assert_eq!(session.run("printf '%s=%s\\n' a 1 b").stdout, "a=1\nb=\n");
+    // A format with no conversions is printed once, however many arguments follow.
+    assert_eq!(session.run("printf 'x\\n' a b c").stdout, "x\n");
+    assert_eq!(session.run("printf 'only %%\\n' a").stdout, "only %\n");
+}
+
+#[test]
+fn test_printf_conversions() {
+    use crate::printf::format_printf;
+
+    let format = |fmt: &str, args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        format_printf(fmt, &args).output
+    };
+    assert_eq!(format("[%5s|%-5s|%.2s]", &["ab", "cd", "xyz"]), "[   ab|cd   |xy]");
+    assert_eq!(format("%d %05d %+d %x %X %o %#x", &["42", "-42", "7", "255", "255", "8", "255"]), "42 -0042 +7 ff FF 10 0xff");
+    assert_eq!(format("%.3f %e %g %g", &["3.14159", "1234.5", "0.0001", "100000"]), "3.142 1.234500e+03 0.0001 100000");
+    assert_eq!(format("%c%c %d", &["hello", "!", "'A"]), "h! 65");
+    assert_eq!(format("%*d|%-*s|", &["4", "7", "3", "a"]), "   7|a  |");
+    assert_eq!(format("%b|%s", &["a\\tb", "a\\tb"]), "a\tb|a\\tb");
+    assert_eq!(format("\\x41\\101\\e[0m", &[]), "AA\x1b[0m");
+    assert_eq!(format("one\\ctwo", &[]), "one");
+    assert_eq!(format("%d %s", &[]), "0 ");
+}
+
+#[test]
+fn test_printf_invalid_number() {
+    let session = ShellSession::new();
+    let outcome = session.run("printf '%d|%d\\n' 12 abc");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stdout, "12|0\n");
+    assert_eq!(outcome.stderr, "printf: abc: invalid number\n");
+    assert_eq!(session.run("printf").status, 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ulimit_lowers_open_files_soft_limit() {
+    let session = ShellSession::new();
+    let outcome = session.run("ulimit -n");
+    assert_eq!(outcome.status, 0);
+    let soft: u64 = outcome.stdout.trim().parse().expect("a numeric soft limit");
+    let lowered = soft - 1;
+
+    assert_eq!(session.run(&format!("ulimit -S -n {}", lowered)).status, 0);
+    assert_eq!(session.run("ulimit -n").stdout, format!("{}\n", lowered));
+    // Children inherit the new limit.
+    let child = session.run("sh -c 'ulimit -n'").stdout;
+    assert_eq!(session.run(&format!("ulimit -S -n {}", soft)).status, 0);
+    assert_eq!(child, format!("{}\n", lowered));
+    assert_eq!(session.run("ulimit -n").stdout, format!("{}\n", soft));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ulimit_listing_and_errors() {
+    let session = ShellSession::new();
+    let listing = session.run("ulimit -a").stdout;
+    assert!(listing.contains("open files                      (-n) "), "{}", listing);
+    assert!(listing.contains("file size               (blocks, -f) "), "{}", listing);
+    assert!(listing.contains("max user processes              (-u) "), "{}", listing);
+
+    let outcome = session.run("ulimit -n lots");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (1, "ulimit: lots: invalid number\n"));
+    assert_eq!(session.run("ulimit -z").status, 2);
+}
+
+#[test]
+fn test_arg_history_records_typed_lines_most_recent_first() {
+    let session = ShellSession::new();
+    session.shell.run_typed_line("echo alpha beta");
+    session.shell.run_typed_line("echo gamma; echo alpha");
+    // Lines from scripts and the rc file are not recorded.
+    session.run("echo delta");
+    let history = session.shell.arg_history.borrow();
+    assert_eq!(history.suggestions("echo", |_| true), vec!["alpha", "gamma", "beta"]);
+    assert_eq!(history.suggestions("echo", |arg| arg.starts_with('g')), vec!["gamma"]);
+}
+
+#[test]
+fn test_arg_history_skips_lines_starting_with_space() {
+    let session = ShellSession::new();
+    session.shell.run_typed_line(" echo secret");
+    session.shell.run_typed_line("echo public");
+    assert_eq!(session.shell.arg_history.borrow().suggestions("echo", |_| true), vec!["public"]);
+}
+
+#[test]
+fn test_arg_history_keeps_arguments_as_typed() {
+    let session = ShellSession::new();
+    session.run("TOKEN=secret");
+    session.shell.run_typed_line("LANG=C echo -H $TOKEN \"$HOME\" >out 2>&1 < /dev/null");
+    let history = session.shell.arg_history.borrow();
+    assert_eq!(history.suggestions("echo", |_| true), vec!["\"$HOME\"", "$TOKEN", "-H"]);
+    assert!(!history.to_text().contains("secret"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_typed_history_files_are_private() {
+    use std::os::unix::fs::PermissionsExt;
+    let session = history_session(&["echo hi"]);
+    session.shell.run_typed_line("echo hi");
+    session.shell.save_history();
+    session.shell.save_arg_history();
+    for name in [".myshell_history", ".myshell_args"] {
+        let mode = std::fs::metadata(session.path(name)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600, "{}", name);
+    }
+}
+
+#[test]
+fn test_arg_history_caps_entries_and_round_trips() {
+    use crate::arghistory::{ArgHistory, MAX_PER_COMMAND};
+    let mut history = ArgHistory::default();
+    let args: Vec<String> = (0..MAX_PER_COMMAND + 5).map(|n| format!("host{}", n)).collect();
+    history.record("ssh", args.iter().map(String::as_str));
+    history.record("ssh", ["host10"]);
+    let used = history.suggestions("ssh", |_| true);
+    assert_eq!(used.len(), MAX_PER_COMMAND);
+    assert_eq!(used[..2], ["host10", &format!("host{}", MAX_PER_COMMAND + 4)]);
+    assert!(!used.contains(&"host0".to_string()));
+    assert_eq!(ArgHistory::load(&history.to_text()), history);
+}
+
+#[test]
+fn test_completion_offers_used_arguments_unless_a_file_matches() {
+    use crate::arghistory::ArgHistory;
+    use crate::inputrc::EditSettings;
+    use crate::complete::used_argument_completions;
+    let session = ShellSession::new();
+    session.write_file("build.log", "");
+    let mut history = ArgHistory::default();
+    history.record("make", ["test", "build", "bench"]);
+    let settings = EditSettings::default();
+    let root = session.root();
+
+    let complete = |line: &str| used_argument_completions(line, line.len(), &root, &history, &settings);
+    assert_eq!(complete("make "), Some(vec!["bench".to_string(), "build".to_string(), "test".to_string()]));
+    assert_eq!(complete("make t"), Some(vec!["test".to_string()]));
+    // `bu` could be `build.log`, so file completion applies.
+    assert_eq!(complete("make bu"), None);
+    assert_eq!(complete("make x"), None);
+    assert_eq!(complete("ssh "), None);
+    assert_eq!(complete("mak"), None);
+}
+
+#[test]
+fn test_completion_looks_past_command_prefixes() {
+    use crate::complete::command_words;
+    for (line, words) in [
+        ("sudo -u www-data ", &[][..]),
+        ("sudo -u www-data git ", &["git"][..]),
+        ("env -u HOME FOO=bar ", &[]),
+        ("FOO=1 env -- BAR=2 make -j ", &["make", "-j"]),
+        ("command ", &[]),
+        ("builtin cd ", &["cd"]),
+        ("time -p ", &[]),
+        ("nohup nice -n 5 ", &[]),
+        ("xargs -I {} -0 kill ", &["kill"]),
+        ("ls sudo ", &["ls", "sudo"]),
+    ] {
+        assert_eq!(command_words(line, line.len()), words, "{}", line);
+    }
+
+    let session = ShellSession::new();
+    session.mkdir("src");
+    let engine = engine(&["echo", "cd"], &[], &[], vec![]);
+    engine.arg_history.lock().unwrap().record("sudo", ["ecology"]);
+    engine.arg_history.lock().unwrap().record("git", ["branch"]);
+    let complete = |line: &str| {
+        let result = engine.complete_in(line, line.len(), &session.root(), None);
+        result.names().iter().map(|name| name.to_string()).collect::<Vec<_>>()
+    };
+    // In command position, the arguments used with `sudo` itself are not offered.
+    for line in ["sudo -u www-data ec", "env FOO=bar ec", "command ec", "builtin ec", "time -p ec", "nohup ec", "nice -n 5 ec", "xargs -I {} ec"] {
+        assert_eq!(complete(line), ["echo"], "{}", line);
+    }
+    assert_eq!(complete("sudo -u root git br"), ["branch"]);
+    assert_eq!(complete("sudo cd s"), ["src/"]);
+    assert_eq!(complete("env -u HOME kill -TE"), ["-TERM"]);
+}
+
+#[test]
+fn test_brace_expansion_table() {
+    use crate::brace::expand_word;
+    let cases: &[(&str, &[&str])] = &[
+        ("{1..5}", &["1", "2", "3", "4", "5"]),
+        ("{1..10..2}", &["1", "3", "5", "7", "9"]),
+        ("{5..1}", &["5", "4", "3", "2", "1"]),
+        ("{10..1..3}", &["10", "7", "4", "1"]),
+        // The step's sign is ignored; the range runs from the first endpoint.
+        ("{1..7..-3}", &["1", "4", "7"]),
+        ("{7..1..3}", &["7", "4", "1"]),
+        ("{1..3..0}", &["1", "2", "3"]),
+        ("{-2..2}", &["-2", "-1", "0", "1", "2"]),
+        ("{2..-1}", &["2", "1", "0", "-1"]),
+        ("{3..3}", &["3"]),
+        ("{01..05}", &["01", "02", "03", "04", "05"]),
+        ("{1..010..3}", &["001", "004", "007", "010"]),
+        ("{-05..5..5}", &["-05", "000", "005"]),
+        ("{a..e}", &["a", "b", "c", "d", "e"]),
+        ("{e..a..2}", &["e", "c", "a"]),
+        ("{x..x}", &["x"]),
+        ("x{1..3}y", &["x1y", "x2y", "x3y"]),
+        ("{a,b}{1..2}", &["a1", "a2", "b1", "b2"]),
+        ("{a,{1..2}}", &["a", "1", "2"]),
+        // Not valid ranges, so left as written.
+        ("{1..}", &["{1..}"]),
+        ("{a..5}", &["{a..5}"]),
+        ("{ab..c}", &["{ab..c}"]),
+        ("{1..5..x}", &["{1..5..x}"]),
+        ("{1..2..3..4}", &["{1..2..3..4}"]),
+        ("{1}", &["{1}"]),
+        ("'{1..3}'", &["'{1..3}'"]),
+        ("\\{1..3}", &["\\{1..3}"]),
+        ("${x}", &["${x}"]),
+    ];
+    for (word, expected) in cases {
+        assert_eq!(expand_word(word), *expected, "expanding {}", word);
+    }
+}
+
+#[test]
+fn test_brace_expansion_in_commands() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo {1..10..2}").stdout, "1 3 5 7 9\n");
+    assert_eq!(session.run("echo \"{1..3}\" pre{a..c}").stdout, "{1..3} prea preb prec\n");
+    assert_eq!(session.run("echo {Y..b}").stdout, "Y Z [ \\ ] ^ _ ` a b\n");
+    session.run("x={1..3}");
+    assert_eq!(session.run("echo $x").stdout, "{1..3}\n");
+    session.run("echo hi > out{1..2}");
+    assert_eq!(session.read_file("out{1..2}"), "hi\n");
+}
+
+#[test]
+fn test_pipe_with_stderr_is_one_token() {
+    use crate::syntax::{parse, Node, Stage};
+    let simple = |text: &str| Node::Simple { text: text.to_string(), line: 1, here_docs: Vec::new() };
+    assert_eq!(
+        parse("true|&cat"),
+        Ok(vec![Node::Pipeline(vec![Stage { command: simple("true"), pipe_stderr: true }, Stage { command: simple("cat"), pipe_stderr: false }])])
+    );
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo 'a |& b' \"|&\"").stdout, "a |& b |&\n");
+    let outcome = session.run("sh -c 'echo out; echo err >&2' |& sort -r");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("out\nerr\n", ""));
+}
+
+#[test]
+fn test_parse_and_or_lists() {
+    use crate::syntax::{parse, Node, ParseError};
+    // `&&` and `||` group from the left, and pipelines bind tighter.
+    let nodes = parse("a | b && c || d").unwrap();
+    let [Node::Or(first, last)] = &nodes[..] else {
+        panic!("{:?}", nodes);
+    };
+    let Node::And(pipeline, middle) = &**first else {
+        panic!("{:?}", first);
+    };
+    assert!(matches!(&**pipeline, Node::Pipeline(stages) if stages.len() == 2));
+    assert!(matches!(&**middle, Node::Simple { text, .. } if text == "c"));
+    assert!(matches!(&**last, Node::Simple { text, .. } if text == "d"));
+    assert!(matches!(&parse("a&&b").unwrap()[..], [Node::And(..)]));
+    assert!(matches!(&parse("echo 'a&&b' a\\|\\|b").unwrap()[..], [Node::Simple { .. }]));
+    assert_eq!(parse("a &&"), Err(ParseError::Incomplete));
+    assert_eq!(parse("|| b"), Err(ParseError::Unexpected("||".to_string())));
+    assert_eq!(parse("a && || b"), Err(ParseError::Unexpected("||".to_string())));
+}
+
+#[test]
+fn test_and_or_lists_short_circuit() {
+    let session = ShellSession::new();
+    let outcome = session.run("sh -c 'exit 3' && echo ran || echo \"failed $?\"");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (0, "failed 3\n"));
+    let outcome = session.run("cd /nope && echo moved || echo stayed");
+    assert_eq!(outcome.stdout, "stayed\n");
+    assert!(outcome.stderr.contains("/nope"));
+    assert_eq!(session.run("true && echo b || echo c").stdout, "b\n");
+    // `a || b && c` runs c after either a or b succeeds.
+    assert_eq!(session.run("true || echo b && echo c").stdout, "c\n");
+    let outcome = session.run("false && echo b");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (1, ""));
+    assert_eq!(session.run("echo one | tr o 0 && echo two\necho three").stdout, "0ne\ntwo\nthree\n");
+    assert_eq!(session.run("false ||\necho next").stdout, "next\n");
+    // `exit` on the left ends the shell before the right side runs.
+    assert_eq!(session.run("exit 4 || echo after").stdout, "");
+    assert_eq!(session.shell.exit_code.get(), Some(4));
+}
+
+#[test]
+fn test_and_or_bind_tighter_than_semicolons() {
+    use crate::syntax::{parse, Node};
+    // A single `&` is not an operator, and a single `|` is a pipe.
+    assert!(matches!(&parse("echo a & b").unwrap()[..], [Node::Simple { text, .. }] if text == "echo a & b"));
+    assert!(matches!(&parse("a | b || c").unwrap()[..], [Node::Or(first, _)] if matches!(**first, Node::Pipeline(_))));
+    assert!(matches!(&parse("a && b; c || d").unwrap()[..], [Node::And(..), Node::Or(..)]));
+
+    let session = ShellSession::new();
+    let outcome = session.run("mkdir foo && cd foo && pwd; false && echo skipped; echo next");
+    assert_eq!(outcome.stdout, format!("{}\nnext\n", session.path("foo").display()));
+    let outcome = session.run("cd /nope || echo failed; echo \"$?\"");
+    assert_eq!(outcome.stdout, "failed\n0\n");
+}
+
+#[test]
+fn test_comments_run_to_the_end_of_the_line() {
+    use crate::syntax::parse;
+    assert_eq!(parse("# only a comment"), Ok(vec![]));
+    // A quote inside a comment does not ask for another line.
+    assert_eq!(parse("  # don't"), Ok(vec![]));
+
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo hello # this is a comment").stdout, "hello\n");
+    assert_eq!(session.run("# nothing to run").status, 0);
+    let outcome = session.run("echo foo#bar \"#double\" '#single' \\#escaped $# #gone");
+    assert_eq!(outcome.stdout, "foo#bar #double #single #escaped 0\n");
+    session.run("echo saved > out.txt # not part of the name");
+    assert_eq!(session.read_file("out.txt"), "saved\n");
+    assert_eq!(session.run("echo a;# b\n# c\necho d # e").stdout, "a\nd\n");
+    assert_eq!(session.run("f() { # greets\n  echo hi # there\n}\nf").stdout, "hi\n");
+}
+
+#[test]
+fn test_parse_quoted_semicolons_stay_in_the_command() {
+    use crate::syntax::{parse, Node};
+    let nodes = parse("echo 'a;b' \"c;d\" e\\;f; echo g").unwrap();
+    let texts: Vec<&str> = nodes
+        .iter()
+        .map(|node| match node {
+            Node::Simple { text, .. } => text.as_str(),
+            other => panic!("{:?}", other),
+        })
+        .collect();
+    assert_eq!(texts, ["echo 'a;b' \"c;d\" e\\;f", "echo g"]);
+}
+
+#[test]
+fn test_exit_ends_a_command_list() {
+    let session = ShellSession::new();
+    let outcome = session.run("echo a; exit 3; echo b");
+    assert_eq!(outcome.stdout, "a\n");
+    assert_eq!(session.shell.exit_code.get(), Some(3));
+}
+
+#[test]
+fn test_parse_pipelines() {
+    use crate::syntax::{parse, Node, ParseError};
+    let nodes = parse("! ls | grep foo | wc -l; echo a|b").unwrap();
+    let [Node::Not(pipeline), Node::Pipeline(second)] = &nodes[..] else {
+        panic!("{:?}", nodes);
+    };
+    let Node::Pipeline(stages) = &**pipeline else {
+        panic!("{:?}", pipeline);
+    };
+    assert_eq!(stages.len(), 3);
+    assert_eq!(second.len(), 2);
+    // A pipe at the end of a line continues on the next.
+    assert_eq!(parse("echo a |"), Err(ParseError::Incomplete));
+    assert!(matches!(&parse("echo a |\n  cat").unwrap()[..], [Node::Pipeline(_)]));
+    assert_eq!(parse("| cat"), Err(ParseError::Unexpected("|".to_string())));
+    assert_eq!(parse("echo a | | cat"), Err(ParseError::Unexpected("|".to_string())));
+}
+
+#[test]
+fn test_pipeline_connects_stdout_to_stdin() {
+    let session = ShellSession::new();
+    session.write_file("list.txt", "foo\nbar\nfood\n");
+    assert_eq!(session.run("cat list.txt | grep foo | wc -l").stdout.trim(), "2");
+    assert_eq!(session.run("echo hi | cat").stdout, "hi\n");
+    assert_eq!(session.run("echo 'a | b' | cat").stdout, "a | b\n");
+    assert_eq!(session.run("yes | head -2").stdout, "y\ny\n");
+
+    // The status is the last stage's; an earlier failure only shows on stderr.
+    let outcome = session.run("ls /nonexistent | wc -l");
+    assert_eq!((outcome.status, outcome.stdout.trim()), (0, "0"));
+    assert!(!outcome.stderr.is_empty());
+    assert_eq!(session.run("true | false").status, 1);
+    assert_eq!(session.run("! true | false").status, 0);
+}
+
+#[test]
+fn test_pipeline_stages_run_by_the_shell() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo x | wc -c").stdout.trim(), "2");
+    assert_eq!(session.run("type echo | cat").stdout, "echo is a shell builtin\n");
+    assert_eq!(session.run("{ echo one; echo two; } | tail -1").stdout, "two\n");
+    session.run("f() { echo fn; sh -c 'echo child'; echo after; }");
+    assert_eq!(session.run("f | cat").stdout, "fn\nchild\nafter\n");
+    // Output much larger than a pipe's buffer cannot block the shell.
+    assert_eq!(session.run("printf '%s\\n' {1..30000} | tail -1").stdout, "30000\n");
+    session.run("printf 'one\\ntwo\\n' | read first");
+    assert_eq!(session.run("echo $first").stdout, "one\n");
+    // Later commands read the shell's own input again.
+    session.set_stdin("typed\n");
+    session.run("echo piped | read v");
+    assert_eq!(session.run("read w; echo $v $w").stdout, "piped typed\n");
+}
+
+#[test]
+fn test_builtins_at_either_end_of_a_pipeline() {
+    let session = ShellSession::new();
+    session.shell.record_history("echo first");
+    session.shell.record_history("echo second");
+    assert_eq!(session.run("history | head -1").stdout, "    1  echo first\n");
+    // A builtin that reads no input ignores what is piped to it.
+    assert_eq!(session.run("ls | type cat").stdout.lines().next().unwrap_or(""), format!("cat is {}", session.shell.find_executable_in_path("cat").unwrap().display()));
+    assert_eq!(session.run("echo lost | echo mid | cat").stdout, "mid\n");
+    assert_eq!(session.run("sh -c 'echo piped' | read line; echo $line | tr a-z A-Z").stdout, "PIPED\n");
+}
+
+#[test]
+fn test_set_o_times_reports_each_command() {
+    fn is_timing_line(line: &str) -> bool {
+        line.strip_prefix("real\t")
+            .and_then(|t| t.strip_suffix('s'))
+            .and_then(|t| t.split_once('m'))
+            .is_some_and(|(min, sec)| min.parse::<u64>().is_ok() && sec.parse::<f64>().is_ok() && sec.len() >= 5)
+    }
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo quiet").stderr, "");
+
+    session.run("set -o times");
+    let outcome = session.run("echo hi; sh -c 'exit 3'");
+    assert_eq!(outcome.stdout, "hi\n");
+    assert_eq!(outcome.status, 3);
+    let lines: Vec<&str> = outcome.stderr.lines().collect();
+    assert_eq!(lines.len(), 2, "{:?}", outcome.stderr);
+    assert!(lines.iter().all(|line| is_timing_line(line)), "{:?}", outcome.stderr);
+    session.run("set +o times");
+    assert_eq!(session.run("echo quiet").stderr, "");
+
+    session.run("SHELL_TIME_ALL=1");
+    assert!(is_timing_line(session.run("echo hi").stderr.trim_end()));
+}
+
+#[test]
+fn test_format_elapsed() {
+    use std::time::Duration;
+    assert_eq!(crate::format_elapsed(Duration::from_millis(1250)), "0m1.250s");
+    assert_eq!(crate::format_elapsed(Duration::from_millis(61_005)), "1m1.005s");
+}
+
+#[test]
+fn test_sourced_parse_error_names_file_and_line() {
+    let session = ShellSession::new();
+    session.write_file("bad.sh", "echo a\necho b\necho c ;; echo d\necho e\n");
+    let outcome = session.run("source bad.sh");
+    // The lines before the error run; the rest of the file does not.
+    assert_eq!(outcome.stdout, "a\nb\n");
+    assert_eq!(outcome.stderr, "bad.sh: line 3: syntax error near unexpected token `;;'\n");
+
+    session.write_file("open.sh", "echo a\necho 'b\n\n");
+    let outcome = session.run(". open.sh");
+    assert_eq!(outcome.stdout, "a\n");
+    assert_eq!(outcome.stderr, "open.sh: line 3: syntax error: unexpected end of file\n");
+
+    // Interactive input has no location.
+    assert_eq!(session.run("echo ;;").stderr, "syntax error near unexpected token `;;'\n");
+}
+
+#[test]
+fn test_lineno_and_expansion_errors_in_sourced_file() {
+    let session = ShellSession::new();
+    session.write_file("inner.sh", "\necho inner $LINENO\n");
+    session.write_file(
+        "script.sh",
+        "echo one\necho $LINENO\ncase x in\n  x) echo $LINENO;;\nesac\necho $((1/0))\nsource inner.sh\necho 'a\nb' $LINENO\n",
+    );
+    let outcome = session.run("source script.sh");
+    assert_eq!(outcome.stdout, "one\n2\n4\ninner 2\na\nb 8\n");
+    assert!(outcome.stderr.starts_with("script.sh: line 6: "), "{}", outcome.stderr);
+    assert_eq!(session.run("echo $LINENO").stdout, "1\n");
+}
+
+#[test]
+fn test_cd_prints_only_for_cdpath_and_dash() {
+    let session = ShellSession::new();
+    let root = session.root();
+    session.mkdir("first/sub");
+    session.mkdir("lib/proj");
+    for line in ["cd first", "cd sub", "cd ..", &format!("cd {}", root.display()), "cd ./lib", "cd"] {
+        assert_eq!(session.run(line).stdout, "", "{}", line);
+    }
+
+    session.run(&format!("cd {}", root.join("first").display()));
+    session.run("cd sub");
+    let outcome = session.run("cd -");
+    assert_eq!(outcome.stdout, format!("{}\n", root.join("first").display()));
+    assert_eq!(session.shell.cwd(), root.join("first"));
+    assert_eq!(session.run("cd -").stdout, format!("{}\n", root.join("first/sub").display()));
+
+    session.run(&format!("CDPATH={}", root.join("lib").display()));
+    assert_eq!(session.run("cd proj").stdout, format!("{}\n", root.join("lib/proj").display()));
+    // A match in the current directory, or an explicit path, is silent.
+    session.run(&format!("cd {}", root.display()));
+    assert_eq!(session.run("cd first").stdout, "");
+    assert_eq!(session.run("cd ../lib/proj").stdout, "");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cd_trailing_slash_and_dot_dot_at_root() {
+    let session = ShellSession::new();
+    session.mkdir("subdir");
+    let outcome = session.run("cd subdir/");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (0, ""));
+    assert_eq!(session.shell.cwd(), session.root().join("subdir"));
+    assert_eq!(session.run("echo $PWD").stdout, format!("{}\n", session.root().join("subdir").display()));
+    session.run("cd ..//");
+    assert_eq!(session.shell.cwd(), session.root());
+
+    assert_eq!(session.run("cd /").status, 0);
+    assert_eq!(session.run("cd ..").status, 0);
+    assert_eq!(session.run("pwd").stdout, "/\n");
+    assert_eq!(session.run("cd /../..").status, 0);
+    assert_eq!(session.shell.cwd(), std::path::Path::new("/"));
+}
+
+#[test]
+fn test_cd_dash_without_oldpwd() {
+    let session = ShellSession::new();
+    session.shell.vars.borrow_mut().remove("OLDPWD");
+    let outcome = session.run("cd -");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (1, "cd: OLDPWD not set\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_type_reads_each_path_dir_once() {
+    use crate::pathindex::{DirReader, FsReader, PathIndex};
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use std::time::SystemTime;
+
+    struct Counting(Rc<Cell<usize>>);
+    impl DirReader for Counting {
+        fn executables(&self, dir: &Path) -> HashSet<String> {
+            self.0.set(self.0.get() + 1);
+            FsReader.executables(dir)
+        }
+        fn modified(&self, dir: &Path) -> Option<SystemTime> {
+            FsReader.modified(dir)
         }
+    }
+
+    let bin = temp_dir();
+    for n in 0..1000 {
+        write_executable(bin.path(), &format!("tool{:04}", n), "#!/bin/sh\n");
+    }
+    let reads = Rc::new(Cell::new(0));
+    let session = ShellSession::new();
+    *session.shell.path_index.borrow_mut() = PathIndex::with_reader(vec![bin.path().to_path_buf()], Box::new(Counting(reads.clone())));
+
+    let names: Vec<String> = (0..50).map(|n| format!("tool{:04}", n * 20)).collect();
+    let outcome = session.run(&format!("type {} tool0000", names.join(" ")));
+    assert_eq!(outcome.status, 0);
+    assert_eq!(outcome.stdout.lines().count(), 51);
+    assert_eq!(outcome.stdout.lines().next().unwrap(), format!("tool0000 is {}", bin.path().join("tool0000").display()));
+    assert_eq!(reads.get(), 1);
+
+    // An unchanged directory is not read again; a changed one is.
+    session.run("type -t tool0001");
+    assert_eq!(reads.get(), 1);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write_executable(bin.path(), "newtool", "#!/bin/sh\n");
+    assert_eq!(session.run("type -t newtool").stdout, "file\n");
+    assert_eq!(reads.get(), 2);
+}
+
+#[test]
+fn test_redirect_target_uses_word_quoting() {
+    let session = ShellSession::new();
+    session.run("echo one > \"my file\".log");
+    assert_eq!(session.read_file("my file.log"), "one\n");
+    session.run("echo two >'a b'\"c d\"e.txt");
+    assert_eq!(session.read_file("a bc de.txt"), "two\n");
+    session.run("echo three >> pre\"'mid'\"post");
+    assert_eq!(session.read_file("pre'mid'post"), "three\n");
+    // An expanded target is not split into words.
+    session.run("name='x y'; sh -c 'echo four >&2' 2>$name.err");
+    assert_eq!(session.read_file("x y.err"), "four\n");
+    // A quote is only removed when it is matched.
+    session.run("echo five > \"it's\"");
+    assert_eq!(session.read_file("it's"), "five\n");
+}
+
 
-        let shell = Shell::new();
-        let cmd = CommandLine {
-            command: "pwd".to_string(),
-            args: vec![],
-            redirection: Some(Box::new(crate::StdoutRedirect { target: file_path_str.to_string() })),
-        };
-        shell.execute(cmd);
+#[test]
+fn test_redirections_apply_left_to_right() {
+    let session = ShellSession::new();
+    // stdout goes to the file first, so stderr follows it there.
+    let outcome = session.run("sh -c 'echo o; echo e >&2' > both.txt 2>&1");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", ""));
+    assert_eq!(session.read_file("both.txt"), "o\ne\n");
 
-        let content = std::fs::read_to_string(&file_path).unwrap();
-        let expected = std::env::current_dir().unwrap().to_string_lossy().to_string() + "\n";
-        assert_eq!(content, expected);
+    // stderr copies stdout before stdout moves, so only stdout is in the file.
+    let outcome = session.run("sh -c 'echo o; echo e >&2' 2>&1 > out.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("e\n", ""));
+    assert_eq!(session.read_file("out.txt"), "o\n");
+
+    // A later redirection of the same descriptor wins.
+    let outcome = session.run("sh -c 'echo e >&2' 2>&1 2> err.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", ""));
+    assert_eq!(session.read_file("err.txt"), "e\n");
+}
+
+#[test]
+fn test_builtin_errors_follow_duplicated_stderr() {
+    let session = ShellSession::new();
+    let outcome = session.run("cd /nope > both.txt 2>&1");
+    assert_eq!((outcome.status, outcome.stdout.as_str(), outcome.stderr.as_str()), (1, "", ""));
+    assert_eq!(session.read_file("both.txt"), "cd: /nope: No such file or directory\n");
+
+    let outcome = session.run("cd /nope 2>&1 > out.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("cd: /nope: No such file or directory\n", ""));
+    assert_eq!(session.read_file("out.txt"), "");
+}
+
+#[test]
+fn test_stdout_duplicated_onto_stderr() {
+    let session = ShellSession::new();
+    let outcome = session.run("sh -c 'echo o; echo e >&2' 2> err.txt 1>&2");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", ""));
+    assert_eq!(session.read_file("err.txt"), "o\ne\n");
+
+    let outcome = session.run("echo builtin 1>&2 2> err.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", "builtin\n"));
+    assert_eq!(session.read_file("err.txt"), "");
+}
+
+#[test]
+fn test_builtin_output_follows_fd_duplication_in_order() {
+    let session = ShellSession::new();
+    for (line, stdout, stderr) in [
+        ("echo hi 1>&2", "", "hi\n"),
+        ("pwd >&2 >/dev/null", "", ""),
+        ("printf '%s\n' x 2>/dev/null 1>&2", "", ""),
+        // Each duplication copies where the other descriptor points now.
+        ("echo swapped 2>&1 1>&2", "swapped\n", ""),
+        ("cd /nope 2>&1 >/dev/null", "cd: /nope: No such file or directory\n", ""),
+        ("cd /nope >/dev/null 2>&1", "", ""),
+    ] {
+        let outcome = session.run(line);
+        assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), (stdout, stderr), "{}", line);
+    }
+}
+
+#[test]
+fn test_redirect_both_streams() {
+    let session = ShellSession::new();
+    session.run("sh -c 'echo o; echo e >&2' &> all.txt");
+    assert_eq!(session.read_file("all.txt"), "o\ne\n");
+    session.run("sh -c 'echo o2; echo e2 >&2' &>> all.txt");
+    assert_eq!(session.read_file("all.txt"), "o\ne\no2\ne2\n");
+
+    let outcome = session.run("f() { echo out; echo err >&2; }; f &>>all.txt");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", ""));
+    assert_eq!(session.read_file("all.txt"), "o\ne\no2\ne2\nout\nerr\n");
+
+    let outcome = session.run("echo moved >&2");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", "moved\n"));
+}
+
+#[test]
+fn test_redirect_descriptors_past_stderr() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo a 99>&1").stdout, "a\n");
+    let outcome = session.run("echo moved 12> twelve.txt >&12");
+    assert_eq!((outcome.stdout.as_str(), outcome.stderr.as_str()), ("", ""));
+    assert_eq!(session.read_file("twelve.txt"), "moved\n");
+    session.run("sh -c 'echo three >&3' 3> three.txt");
+    session.run("sh -c 'echo again >&3' 3>> three.txt");
+    assert_eq!(session.read_file("three.txt"), "three\nagain\n");
+}
+
+#[test]
+fn test_dash_closes_a_descriptor() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo hi 3>&-").stdout, "hi\n");
+    assert_eq!(session.run("echo gone >&-").stdout, "");
+    assert_eq!(session.run("sh -c 'echo x >&3 || echo closed' 3> three.txt 3>&-").stdout, "closed\n");
+    assert_eq!(session.read_file("three.txt"), "");
+    assert!(!session.path("-").exists());
+}
+
+#[test]
+fn test_redirection_without_target_is_a_syntax_error() {
+    let session = ShellSession::new();
+    for line in ["echo a 2>&", "echo a >", "echo a <<<", "echo a &>"] {
+        let outcome = session.run(line);
+        assert_eq!((outcome.status, outcome.stderr.as_str()), (2, "syntax error near unexpected token `newline'\n"), "{}", line);
     }
+    assert_eq!(session.run("echo a >; echo b").stderr, "syntax error near unexpected token `;'\n");
+    assert_eq!(session.run("echo '>' \\> a\">\"").stdout, "> > a>\n");
+}
+
+#[test]
+fn test_parse_redirection_list() {
+    let cmd_line = CommandLine::parse("cmd > out 2>&1 &>>log");
+    assert_eq!(cmd_line.command, "cmd");
+    assert!(cmd_line.args.is_empty());
+    let r = cmd_line.redirection.as_ref().unwrap();
+    assert_eq!((r.target(), r.mode_name()), ("log", "&>>"));
+}
+
+#[test]
+fn test_parse_input_descriptors() {
+    let cmd_line = CommandLine::parse("cmd 3< in.txt <<< \"a b\" 4<<<word");
+    assert_eq!(cmd_line.input, Some(crate::InputSource::HereString("a b".to_string())));
+    assert_eq!(
+        cmd_line.fd_inputs,
+        vec![(3, crate::InputSource::File("in.txt".to_string())), (4, crate::InputSource::HereString("word".to_string()))]
+    );
+    assert!(cmd_line.args.is_empty());
+
+    let cmd_line = CommandLine::parse("echo x3<f '3'<g");
+    assert_eq!(cmd_line.args, vec![Argument::new("x3"), Argument::new("3")]);
+    assert_eq!(cmd_line.input, Some(crate::InputSource::File("g".to_string())));
+}
+
+#[test]
+fn test_here_string_on_stdin() {
+    let session = ShellSession::new();
+    session.run("NAME=world");
+    assert_eq!(session.run("cat <<< \"hello $NAME\"").stdout, "hello world\n");
+    session.run("read -r line <<< 'one two'");
+    assert_eq!(session.run("echo \"$line\"").stdout, "one two\n");
+}
+
+#[test]
+fn test_here_document_on_stdin() {
+    let session = ShellSession::new();
+    session.run("NAME=world");
+    let outcome = session.run("cat <<EOF\nhello $NAME\n  'quoted' \\$NAME \"$((1 + 2))\"\nEOF\necho after");
+    assert_eq!(outcome.stdout, "hello world\n  'quoted' $NAME \"3\"\nafter\n");
+    // A quoted delimiter keeps the body as written.
+    assert_eq!(session.run("cat <<'EOF'\n$NAME \\$x\nEOF").stdout, "$NAME \\$x\n");
+    assert_eq!(session.run("cat << \"E\"OF\n$NAME\nEOF").stdout, "$NAME\n");
+    // `<<-` strips leading tabs, from the delimiter line too.
+    assert_eq!(session.run("cat <<-END\n\tone\n\t\ttwo\n  three\n\tEND").stdout, "one\ntwo\n  three\n");
+    assert_eq!(session.run("read -r line <<EOF\nfirst\nsecond\nEOF\necho \"$line\"").stdout, "first\n");
+}
+
+#[test]
+fn test_here_documents_follow_their_command_line() {
+    let session = ShellSession::new();
+    // Bodies are read in order after the line holding the commands.
+    let outcome = session.run("cat <<A | tr a-z A-Z; cat <<B\nfirst\nA\nsecond\nB");
+    assert_eq!(outcome.stdout, "FIRST\nsecond\n");
+    // A function keeps its here-document, expanded each time it runs.
+    session.run("greet() {\n  cat <<EOF\nhi $1\nEOF\n}");
+    assert_eq!(session.run("greet you; greet me").stdout, "hi you\nhi me\n");
+    session.run("cat <<EOF > out.txt\nsaved\nEOF");
+    assert_eq!(session.read_file("out.txt"), "saved\n");
+}
+
+#[test]
+fn test_parse_here_documents() {
+    use crate::syntax::{parse, read_continuation, HereDoc, Node, ParseError};
+    let nodes = parse("cat <<'X' <<-Y; echo\nsome $text\nX\n\tmore\n\tY\necho done").unwrap();
+    let [Node::Simple { text, here_docs, .. }, Node::Simple { .. }, Node::Simple { line, .. }] = &nodes[..] else {
+        panic!("{:?}", nodes);
+    };
+    assert_eq!(text, "cat <<'X' <<-Y");
+    assert_eq!(
+        here_docs,
+        &[HereDoc { body: "some $text\n".to_string(), expand: false }, HereDoc { body: "more\n".to_string(), expand: true }]
+    );
+    assert_eq!(*line, 6);
+    // Without its delimiter line the command is incomplete, so more lines
+    // are read; `<<<` is a here-string and needs none.
+    assert_eq!(parse("cat <<EOF"), Err(ParseError::Incomplete));
+    assert_eq!(parse("cat <<EOF\nbody\nEOFX"), Err(ParseError::Incomplete));
+    assert!(parse("cat <<< EOF").is_ok());
+    assert_eq!(parse("cat <<"), Err(ParseError::Unexpected("newline".to_string())));
+    let mut rest = ["body", "EOF", "echo unread"].map(String::from).into_iter();
+    assert_eq!(read_continuation("cat <<EOF".to_string(), || rest.next()), "cat <<EOF\nbody\nEOF");
+}
+
+#[test]
+fn test_history_keeps_here_documents_on_their_lines() {
+    use crate::history::join_lines;
+    assert_eq!(join_lines("cat <<EOF\none\nEOF"), "cat <<EOF\none\nEOF");
+    assert_eq!(join_lines("echo a\necho <<< b"), "echo a; echo <<< b");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_input_on_numbered_descriptor() {
+    let session = ShellSession::new();
+    let outcome = session.run("sh -c 'read line <&3; echo \"got $line\"' 3<<< fed");
+    assert_eq!((outcome.status, outcome.stdout.as_str()), (0, "got fed\n"));
+
+    session.write_file("in.txt", "from file\n");
+    assert_eq!(session.run("sh -c 'cat <&5' 5< in.txt").stdout, "from file\n");
+    assert_eq!(session.run("sh -c 'cat <&5' 5< missing.txt").status, 1);
+}
+
+#[test]
+fn test_compgen_matches_completion_engine() {
+    let session = ShellSession::new();
+    session.run("ecfn() { echo; }");
+    session.run("alias ecal=echo");
+    let compgen = session.run("compgen -c ec");
+    assert_eq!(compgen.status, 0);
+
+    let engine: String = session.shell.complete("ec", 2).names().iter().map(|name| format!("{}\n", name)).collect();
+    assert_eq!(compgen.stdout, engine);
+    assert!(compgen.stdout.starts_with("ecal\necfn\necho\n"));
+}
+
+#[test]
+fn test_compgen_builtins_files_and_dirs() {
+    let session = ShellSession::new();
+    let builtins = session.run("compgen -b").stdout;
+    assert!(builtins.lines().any(|name| name == "compgen"));
+    assert_eq!(session.run("compgen -b ul").stdout, "ulimit\n");
+
+    session.mkdir("src/bin");
+    session.write_file("src/main.rs", "");
+    session.write_file("setup.sh", "");
+    session.write_file(".secret", "");
+    assert_eq!(session.run("compgen -f s").stdout, "setup.sh\nsrc\n");
+    assert_eq!(session.run("compgen -f src/").stdout, "src/bin\nsrc/main.rs\n");
+    assert_eq!(session.run("compgen -d s").stdout, "src\n");
+    assert_eq!(session.run("compgen -f .s").stdout, ".secret\n");
+
+    assert_eq!(session.run("compgen -d zzz").status, 1);
+    let outcome = session.run("compgen -x");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (2, "compgen: -x: invalid option\ncompgen: usage: compgen [-bcdf] [word]\n"));
+}
 
-    #[test]
-    fn test_execute_builtin_type_builtin() {
-        let dir = std::env::temp_dir().join("shell_tests_type");
-        std::fs::create_dir_all(&dir).unwrap();
-        let file_path = dir.join("type_out.txt");
-        let file_path_str = file_path.to_str().unwrap();
+#[test]
+fn test_startup_files_by_login_and_interactive() {
+    use crate::options::{startup_files, Options, StartupFile::*};
 
-        if file_path.exists() {
-            std::fs::remove_file(&file_path).unwrap();
+    assert_eq!(startup_files(true, true), vec![SystemProfile, Profile, Rc]);
+    assert_eq!(startup_files(true, false), vec![SystemProfile, Profile]);
+    assert_eq!(startup_files(false, true), vec![Rc]);
+    assert_eq!(startup_files(false, false), vec![]);
+
+    let options = Options::parse(["--login".to_string(), "-c".to_string(), "true".to_string()]).unwrap();
+    assert_eq!(options.startup_files(options.mode(true)), vec![SystemProfile, Profile, Env]);
+    let options = Options::parse(["-l".to_string()]).unwrap();
+    assert_eq!(options.startup_files(options.mode(true)), vec![SystemProfile, Profile, Rc]);
+    assert_eq!(options.startup_files(options.mode(false)), vec![SystemProfile, Profile]);
+}
+
+#[test]
+fn test_mode_precedence() {
+    use crate::options::{Mode, Options};
+
+    let mode = |list: &[&str], terminal: bool| Options::parse(list.iter().map(|a| a.to_string())).unwrap().mode(terminal);
+    assert_eq!(mode(&[], true), Mode::Interactive);
+    assert_eq!(mode(&[], false), Mode::Stdin);
+    assert_eq!(mode(&["-i"], false), Mode::Interactive);
+    assert_eq!(mode(&["-s"], true), Mode::Stdin);
+    assert_eq!(mode(&["-s", "-i"], false), Mode::Interactive);
+    assert_eq!(mode(&["-i", "script.sh"], true), Mode::Script);
+    assert_eq!(mode(&["-c", "true", "-i"], true), Mode::Command);
+    assert_eq!(mode(&["-i", "-c", "true", "script.sh"], false), Mode::Command);
+
+    let options = Options::parse(["script.sh", "-x", "arg"].map(String::from)).unwrap();
+    assert_eq!((options.script.as_deref(), options.script_args), (Some("script.sh"), vec!["-x".to_string(), "arg".to_string()]));
+}
+
+#[test]
+fn test_command_substitution() {
+    use crate::expand::Variables;
+
+    let session = ShellSession::new();
+    assert_eq!(session.run("echo \"[$(echo a; echo b)]\"").stdout, "[a\nb]\n");
+    assert_eq!(session.run("echo $(echo \"x )\" $(echo nested))").stdout, "x ) nested\n");
+    assert_eq!(session.run("echo $(printf 'trail\\n\\n\\n')end").stdout, "trailend\n");
+    assert_eq!(session.run("x=$(sh -c 'exit 3')").status, 3);
+    assert_eq!(session.run("x=$(true)").status, 0);
+    // `exit` ends only the substitution.
+    assert_eq!(session.run("echo $(echo in; exit 4; echo not) after").stdout, "in after\n");
+    assert_eq!(session.run("echo still here").stdout, "still here\n");
+
+    let outcome = session.run("f() { echo $(f); }; f");
+    assert!(outcome.stderr.contains("command substitution: nested too deeply"));
+    assert_eq!(session.shell.var("x"), Some(String::new()));
+}
+
+#[test]
+fn test_command_substitution_runs_in_a_subshell() {
+    let session = ShellSession::new();
+    session.mkdir("sub");
+    let outcome = session.run("x=1; y=$(x=2; echo $x; cd sub; export NEW=1); echo \"$x $y\"; pwd");
+    assert_eq!(outcome.stdout, format!("1 2\n{}\n", session.root().display()));
+    assert_eq!(session.run("echo \"[$NEW]\"; sh -c 'echo \"[$NEW]\"'").stdout, "[]\n[]\n");
+    session.run("y=$(alias ll='ls -l'; f() { echo f; }; set -o autocd; unset HOME)");
+    assert_ne!(session.run("alias ll").status, 0);
+    assert_ne!(session.run("f").status, 0);
+    assert!(!session.shell.autocd.get());
+    assert_eq!(session.run("echo \"$HOME\"").stdout, format!("{}\n", session.root().display()));
+    // Inside, the parent's definitions and arguments are all visible.
+    session.run("g() { echo \"g:$1\"; }; alias hi='echo hi'");
+    let outcome = session.run("h() { echo $(g \"$1\"; hi; echo $#); }; h arg");
+    assert_eq!(outcome.stdout, "g:arg hi 1\n");
+}
+
+#[test]
+fn test_command_substitution_captures_large_output() {
+    use crate::expand::Variables;
+
+    let session = ShellSession::new();
+    let outcome = session.run("x=$(sh -c 'i=0; while [ $i -lt 40960 ]; do printf %0255d $i; echo; i=$((i+1)); done')");
+    assert_eq!(outcome.status, 0);
+    let x = session.shell.var("x").unwrap();
+    assert_eq!(x.len(), 40960 * 256 - 1);
+    assert!(x.lines().enumerate().all(|(i, line)| line == format!("{:0255}", i)));
+}
+
+#[test]
+fn test_command_substitution_truncates_past_limit() {
+    use crate::expand::Variables;
+
+    let session = ShellSession::new();
+    session.run("MYSHELL_SUBST_MAX=1000");
+    // Far more than a pipe holds, so the child only finishes if the shell
+    // keeps draining past the limit.
+    let outcome = session.run("x=$(sh -c 'yes 0123456789 | head -c 1000000')");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "command substitution: output truncated to 1000 bytes\n");
+    let x = session.shell.var("x").unwrap();
+    assert_eq!(x.len(), 1000);
+    assert!(x.starts_with("0123456789\n0123456789\n"));
+}
+
+#[test]
+fn test_theme_selection() {
+    use crate::theme::Theme;
+    assert_eq!(Theme::select(None, None, true), Theme::default());
+    assert_eq!(Theme::select(Some("default"), Some("1"), true), Theme::default());
+    assert_eq!(Theme::select(Some("default"), None, false), Theme::default());
+    let palette = Theme::select(Some("default"), Some(""), true);
+    assert_eq!(palette.error, "31");
+    assert_eq!(palette.prompt, "1");
+    let custom = Theme::parse("error=1;35:prompt=:hint=bold:nonsense=4");
+    assert_eq!(custom.error, "1;35");
+    assert_eq!(custom.prompt, "");
+    assert_eq!(custom.hint, "2");
+    assert_eq!(Theme::paint_line(&custom.error, "oops\n"), "\x1b[1;35moops\x1b[0m\n");
+    assert_eq!(Theme::paint_line("", "oops\n"), "oops\n");
+}
+
+#[test]
+fn test_highlighter_follows_the_theme() {
+    use rustyline::highlight::Highlighter;
+    let helper = completer(engine(&["echo"], &[], &[], vec![]));
+    assert_eq!(helper.highlight("echo hi", 7), "echo hi");
+    assert_eq!(helper.highlight_prompt("$ ", true), "$ ");
+    assert_eq!(helper.highlight_hint("llo"), "llo");
+    *helper.theme.lock().unwrap() = crate::theme::Theme::parse("builtin=1;36:prompt=32");
+    assert_eq!(helper.highlight(" echo hi", 8), " \x1b[1;36mecho\x1b[0m hi");
+    assert_eq!(helper.highlight("nosuch hi", 9), "nosuch hi");
+    assert_eq!(helper.highlight_prompt("$ ", true), "\x1b[32m$ \x1b[0m");
+    assert_eq!(helper.highlight_hint("llo"), "\x1b[2mllo\x1b[0m");
+    let theme = helper.theme.lock().unwrap();
+    assert_eq!(crate::highlight_description("echo  (builtin)", &theme), "echo  \x1b[2m(builtin)\x1b[0m");
+}
+
+#[test]
+fn test_errors_are_uncolored_off_a_terminal() {
+    let session = ShellSession::new();
+    session.run("MYSHELL_THEME=default");
+    let outcome = session.run("nosuchcommand");
+    assert_eq!(outcome.stderr, "nosuchcommand: command not found\n");
+    session.run("NO_COLOR=1");
+    assert!(!session.run("nosuchcommand").stderr.contains('\x1b'));
+}
+
+#[test]
+fn test_scrape_long_options() {
+    let help = "Usage: tool [--verbose] [--color=WHEN]\n  -q, --quiet     say less\n      --dry-run, --no-op\n  ------\n  see also a--b and --Upper and --help.\n";
+    assert_eq!(crate::helpopts::scrape(help), vec!["--color", "--dry-run", "--help", "--no-op", "--quiet", "--verbose"]);
+    assert!(crate::helpopts::scrape("no options here -- at all").is_empty());
+}
+
+#[test]
+fn test_option_cache_asks_each_executable_once() {
+    use crate::helpopts::{HelpReader, OptionCache};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    struct Counting(Arc<AtomicUsize>, Arc<Mutex<SystemTime>>);
+    impl HelpReader for Counting {
+        fn help(&self, _path: &Path) -> Option<String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some("--all --any".to_string())
+        }
+        fn modified(&self, _path: &Path) -> Option<SystemTime> {
+            Some(*self.1.lock().unwrap())
         }
+    }
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let modified = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+    let mut cache = OptionCache::with_reader(Box::new(Counting(runs.clone(), modified.clone())));
+    assert_eq!(cache.options(Path::new("/bin/tool")), ["--all", "--any"]);
+    cache.options(Path::new("/bin/tool"));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    cache.options(Path::new("/bin/other"));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    *modified.lock().unwrap() += Duration::from_secs(1);
+    cache.options(Path::new("/bin/tool"));
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+}
 
-        let shell = Shell::new();
-        let cmd = CommandLine {
-             command: "type".to_string(),
-             args: vec![Argument::new("echo")],
-             redirection: Some(Box::new(crate::StdoutRedirect { target: file_path_str.to_string() })),
-        };
-        shell.execute(cmd);
+#[cfg(unix)]
+#[test]
+fn test_run_help_gives_up_on_a_slow_command() {
+    use std::time::{Duration, Instant};
+    let bin = temp_dir();
+    let slow = write_executable(bin.path(), "slow", "#!/bin/sh\nexec sleep 5\n");
+    let started = Instant::now();
+    assert_eq!(crate::helpopts::run_help(&slow, Duration::from_millis(100), 1024), None);
+    assert!(started.elapsed() < Duration::from_secs(2));
 
-        let content = std::fs::read_to_string(&file_path).unwrap();
-        assert_eq!(content, "echo is a shell builtin\n");
+    let chatty = write_executable(bin.path(), "chatty", "#!/bin/sh\necho \"$1 --one\"\n");
+    assert_eq!(crate::helpopts::run_help(&chatty, Duration::from_secs(5), 8).as_deref(), Some("--help -"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_complete_long_options_from_help() {
+    let bin = temp_dir();
+    write_executable(bin.path(), "tool", "#!/bin/sh\n[ \"$1\" = --help ] && echo 'tool [--force] [--format=FMT] [--help]'\n");
+    let engine = engine(&["echo"], &[], &[], vec![bin.path().to_path_buf()]);
+    let complete = |line: &str| {
+        let result = engine.complete_in(line, line.len(), bin.path(), None);
+        (result.start, result.names().iter().map(|name| name.to_string()).collect::<Vec<_>>())
+    };
+    // Off, `--fo` is left to the general rules, and names no command.
+    assert_eq!(complete("tool --fo"), (5, vec![]));
+
+    engine.settings.lock().unwrap().complete_options = true;
+    assert_eq!(complete("tool --fo"), (5, vec!["--force".to_string(), "--format".to_string()]));
+    assert_eq!(complete("tool x -").1.len(), 3);
+    assert_eq!(complete("./tool --he"), (7, vec!["--help".to_string()]));
+    assert_eq!(complete("tool fo").1, Vec::<String>::new());
+    assert_eq!(complete("tool --zzz").1, Vec::<String>::new());
+    assert_eq!(complete("echo --").1, Vec::<String>::new());
+}
+
+#[test]
+fn test_every_registered_builtin_dispatches() {
+    let shell = Shell::new();
+    for builtin in &shell.builtins {
+        let name = builtin.command.name();
+        let found = shell.builtin(name).unwrap_or_else(|| panic!("{} does not dispatch", name));
+        assert!(std::ptr::addr_eq(found, builtin.command.as_ref()), "{} dispatches elsewhere", name);
+        assert!(builtin.synopsis == name || builtin.synopsis.starts_with(&format!("{} ", name)), "{}: {}", name, builtin.synopsis);
+        assert!(builtin.description.ends_with('.'), "{}: {}", name, builtin.description);
     }
+    let index = shell.command_index.lock().unwrap();
+    assert_eq!(index.builtins().len(), shell.builtins.len());
+}
+
+#[test]
+fn test_help_describes_builtins() {
+    let session = ShellSession::new();
+    let all = session.run("help").stdout;
+    assert!(all.lines().any(|line| line == "cd [dir]"));
+    assert_eq!(all.lines().count(), session.shell.builtins.len());
+    assert_eq!(session.run("help pw").stdout, "pwd: pwd\n    Print the current working directory.\n");
+    assert_eq!(session.run("help e").stdout.lines().filter(|line| !line.starts_with(' ')).count(), 5);
+
+    let outcome = session.run("help pw nosuch");
+    assert_eq!(outcome.status, 1);
+    assert_eq!(outcome.stderr, "help: no help topics match `nosuch'\n");
+}
+
+#[test]
+fn test_last_status_expands() {
+    let session = ShellSession::new();
+    assert_eq!(session.run("false; echo $?").stdout, "1\n");
+    assert_eq!(session.run("echo $?").stdout, "0\n");
+    assert_eq!(session.run("cd /nonexistent/dir; echo \"[$?]\" ${?}").stdout, "[1] 1\n");
+    assert_eq!(session.run("sh -c 'exit 7'; echo $(( $? + 1 ))").stdout, "8\n");
+    assert_eq!(session.run("nosuchcommand; echo $? '$?'").stdout, "127 $?\n");
+}
+
+#[test]
+fn test_declare_p_prints_reusable_definitions() {
+    let session = ShellSession::new();
+    session.run("zz_scalar=42");
+    assert_eq!(session.run("declare -p zz_scalar").stdout, "declare -- zz_scalar=\"42\"\n");
 
-    #[test]
-    fn test_execute_builtin_type_not_found() {
-        let out_dir = std::env::temp_dir().join("shell_tests_type_not");
-        std::fs::create_dir_all(&out_dir).unwrap();
-        let out_file = out_dir.join("type_out.txt");
-        let out_file_str = out_file.to_str().unwrap();
+    session.run(r#"zz_spaced='a b "c" $d \e'"#);
+    let printed = session.run("declare -p zz_spaced").stdout;
+    assert_eq!(printed, "declare -- zz_spaced=\"a b \\\"c\\\" \\$d \\\\e\"\n");
+    session.write_file("saved.sh", &printed);
+    session.run("zz_spaced=changed");
+    session.run("source saved.sh");
+    assert_eq!(session.run("echo \"$zz_spaced\"").stdout, "a b \"c\" $d \\e\n");
 
-        if out_file.exists() {
-            std::fs::remove_file(&out_file).unwrap();
+    let all = session.run("declare -p").stdout;
+    assert!(all.lines().any(|line| line == "declare -- zz_scalar=\"42\""));
+    let outcome = session.run("declare -p zz_nosuch");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (1, "declare: zz_nosuch: not found\n"));
+}
+
+#[test]
+fn test_declare_x_exports_to_commands_and_export_p() {
+    let session = ShellSession::new();
+    session.run("zz_plain=1; declare -x zz_exported=\"two words\"");
+    assert_eq!(session.run("declare -p zz_exported").stdout, "declare -x zz_exported=\"two words\"\n");
+    let exported = session.run("export -p").stdout;
+    assert!(exported.lines().any(|line| line == "declare -x zz_exported=\"two words\""));
+    assert!(!exported.contains("zz_plain"));
+    assert_eq!(session.run("sh -c 'echo \"$zz_exported|$zz_plain\"'").stdout, "two words|\n");
+
+    // Exporting a set variable keeps its value.
+    session.run("declare -x zz_plain");
+    assert_eq!(session.run("declare -px zz_plain").stdout, "declare -x zz_plain=\"1\"\n");
+    assert_eq!(session.run("declare -z").status, 2);
+    assert_eq!(session.run("declare 1x=2").stderr, "declare: `1x=2': not a valid identifier\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unreadable_path_dir_warns_once() {
+    use crate::pathindex::{DirReader, FsReader, PathIndex};
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+    use std::time::SystemTime;
+
+    // Reads the filesystem, except that `blocked` fails its check.
+    struct Blocking {
+        blocked: PathBuf,
+        checks: Rc<Cell<usize>>,
+    }
+    impl DirReader for Blocking {
+        fn executables(&self, dir: &Path) -> HashSet<String> {
+            FsReader.executables(dir)
+        }
+        fn modified(&self, dir: &Path) -> Option<SystemTime> {
+            FsReader.modified(dir)
+        }
+        fn check(&self, dir: &Path) -> std::io::Result<()> {
+            self.checks.set(self.checks.get() + 1);
+            if dir == self.blocked {
+                return Err(std::io::Error::from_raw_os_error(13));
+            }
+            Ok(())
         }
+    }
+
+    let (blocked, bin) = (temp_dir(), temp_dir());
+    write_executable(blocked.path(), "zz_hidden", "#!/bin/sh\n");
+    write_executable(bin.path(), "zz_tool", "#!/bin/sh\necho ran\n");
+    let dirs = vec![blocked.path().to_path_buf(), bin.path().to_path_buf()];
+    let session = ShellSession::new();
+    let checks = Rc::new(Cell::new(0));
+    *session.shell.path_index.borrow_mut() =
+        PathIndex::with_reader(dirs, Box::new(Blocking { blocked: blocked.path().to_path_buf(), checks: checks.clone() }));
+
+    let warning = format!("myshell: warning: cannot read PATH directory {}: Permission denied\n", blocked.path().display());
+    let outcome = session.run("zz_tool; zz_hidden");
+    assert_eq!(outcome.stdout, "ran\n");
+    assert_eq!(outcome.stderr, format!("{}zz_hidden: command not found\n", warning));
+    assert_eq!(checks.get(), 2);
+
+    // Completion and later lookups skip the directory without asking again.
+    for _ in 0..3 {
+        assert_eq!(session.shell.complete("zz_", 3).names(), ["zz_tool"]);
+    }
+    let outcome = session.run("type zz_hidden; zz_tool");
+    assert_eq!(outcome.stdout, "zz_hidden: not found\nran\n");
+    assert_eq!(checks.get(), 2);
+
+    // `hash -r` tries again.
+    assert_eq!(session.run("hash -r").stderr, warning);
+    assert_eq!(checks.get(), 4);
+
+    // So does assigning PATH, which completion sees at once.
+    let generation = session.shell.command_index.lock().unwrap().generation();
+    session.run(&format!("PATH={}:{}", bin.path().display(), blocked.path().display()));
+    assert_eq!(session.run("zz_tool").stderr, warning);
+    assert_eq!(checks.get(), 6);
+    assert!(session.shell.command_index.lock().unwrap().generation() > generation);
+    assert_eq!(session.shell.complete("zz_", 3).names(), ["zz_tool"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_mode_000_directory_fails_the_check() {
+    use crate::pathindex::{DirReader, FsReader};
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = temp_dir();
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o000)).unwrap();
+    let listable = std::fs::read_dir(dir.path()).is_ok();
+    let result = FsReader.check(dir.path());
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    // Permissions do not bind root.
+    if !listable {
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+    }
+    assert!(FsReader.check(dir.path()).is_ok());
+}
+
+#[test]
+fn test_export_passes_variables_to_commands() {
+    let session = ShellSession::new();
+    session.run("export ZZ_FOO=bar");
+    assert_eq!(session.run("sh -c 'echo $ZZ_FOO'").stdout, "bar\n");
+
+    session.run("ZZ_LATER=shell");
+    assert_eq!(session.run("sh -c 'echo [$ZZ_LATER]'").stdout, "[]\n");
+    session.run("export ZZ_LATER");
+    assert_eq!(session.run("sh -c 'echo [$ZZ_LATER]'").stdout, "[shell]\n");
+
+    session.run("export > exported.txt");
+    let listed: Vec<String> = session.read_file("exported.txt").lines().map(str::to_string).collect();
+    let mut sorted = listed.clone();
+    sorted.sort();
+    assert_eq!(listed, sorted);
+    assert!(listed.contains(&"ZZ_FOO=bar".to_string()));
+    assert!(listed.contains(&"ZZ_LATER=shell".to_string()));
+
+    let outcome = session.run("export ZZ_OK=1 9bad=2");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (1, "export: `9bad=2': not a valid identifier\n"));
+    assert_eq!(session.run("echo $ZZ_OK").stdout, "1\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_redirected_fd_is_restored_after_the_closure_and_a_panic() {
+    use std::io::Write;
+    use std::os::fd::AsRawFd;
+
+    // The descriptor of `original` is swapped, so writes through it show
+    // where the descriptor points.
+    let dir = temp_dir();
+    let mut original = std::fs::File::create(dir.path().join("original")).unwrap();
+    let target = std::fs::File::create(dir.path().join("target")).unwrap();
+    let fd = original.as_raw_fd();
+
+    let value = crate::fd::with_redirected(fd, &target, || {
+        original.write_all(b"inside\n").unwrap();
+        7
+    })
+    .unwrap();
+    assert_eq!(value, 7);
+    original.write_all(b"after\n").unwrap();
+
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        crate::fd::with_redirected(fd, &target, || {
+            original.write_all(b"before the panic\n").unwrap();
+            panic!("inside the closure");
+        })
+        .unwrap()
+    }));
+    assert!(panicked.is_err());
+    original.write_all(b"after the panic\n").unwrap();
+
+    let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+    assert_eq!(read("target"), "inside\nbefore the panic\n");
+    assert_eq!(read("original"), "after\nafter the panic\n");
+}
+
+#[test]
+fn test_unset_removes_variables() {
+    let session = ShellSession::new();
+    session.run("export ZZ_A=1; ZZ_B=2; ZZ_C=3");
+    let outcome = session.run("unset ZZ_A ZZ_B ZZ_NEVER_SET");
+    assert_eq!((outcome.status, outcome.stderr.as_str()), (0, ""));
+    assert_eq!(session.run("echo \"[$ZZ_A][$ZZ_B][$ZZ_C]\"").stdout, "[][][3]\n");
+    assert!(session.shell.vars.borrow().get("ZZ_A").is_none());
+    assert!(!session.run("export").stdout.contains("ZZ_A"));
+    assert_eq!(session.run("sh -c 'echo \"[$ZZ_A]\"'").stdout, "[]\n");
 
-        let shell = Shell::new();
-        let cmd = CommandLine {
-             command: "type".to_string(),
-             args: vec![Argument::new("nonexistent")],
-             redirection: Some(Box::new(crate::StdoutRedirect { target: out_file_str.to_string() })),
-        };
-        shell.execute(cmd);
-
-        let content = std::fs::read_to_string(&out_file).unwrap();
-        assert_eq!(content, "nonexistent: not found\n");
-
-        std::fs::remove_dir_all(out_dir).unwrap();
-    }
-
-    #[test]
-    fn test_execute_builtin_cd_relative() {
-        let temp_base = std::env::temp_dir().join("test_cd_relative");
-        std::fs::create_dir_all(&temp_base).unwrap();
-        let sub_dir = temp_base.join("raspberry").join("orange");
-        std::fs::create_dir_all(&sub_dir).unwrap();
-
-        let original_cwd = std::env::current_dir().unwrap();
-        std::env::set_current_dir(&temp_base).unwrap();
-
-        let shell = Shell::new();
-        let cmd = CommandLine {
-            command: "cd".to_string(),
-            args: vec![Argument::new("./raspberry/orange")],
-            redirection: None,
-        };
-        shell.execute(cmd);
-
-        let new_cwd = std::env::current_dir().unwrap();
-        assert_eq!(new_cwd, sub_dir);
-
-        std::env::set_current_dir(&original_cwd).unwrap();
-        std::fs::remove_dir_all(&temp_base).unwrap();
-    }
-
-    #[test]
-    fn test_execute_builtin_cd_absolute_error() {
-        let original_cwd = std::env::current_dir().unwrap();
-        let shell = Shell::new();
-        let cmd = CommandLine {
-            command: "cd".to_string(),
-            args: vec![Argument::new("/non-existing-directory")],
-            redirection: None,
-        };
-        shell.execute(cmd);
-        let new_cwd = std::env::current_dir().unwrap();
-        assert_eq!(original_cwd, new_cwd); 
-    }
-}
\ No newline at end of file
+    // A variable inherited from the environment is not passed on either.
+    let inherited = std::env::vars().map(|(name, _)| name).find(|name| crate::expand::is_name(name) && name != "PATH").unwrap();
+    let passed = |session: &ShellSession| session.run("env").stdout.lines().any(|line| line.starts_with(&format!("{}=", inherited)));
+    assert!(passed(&session));
+    session.run(&format!("unset {}", inherited));
+    assert!(!passed(&session));
+}
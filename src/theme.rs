@@ -0,0 +1,81 @@
+// The colors the shell itself uses: the prompt, the command word as it is
+// typed, completion descriptions and error messages. Each color is an SGR
+// parameter string such as `1;31`, and empty means uncolored.
+//
+// Colors are off unless `MYSHELL_THEME` is set, typically in the rc file.
+// `MYSHELL_THEME=default` gives the built-in palette; a list of `key=value`
+// pairs separated by `:`, such as `error=1;31:prompt=32`, changes those keys
+// from the palette. `NO_COLOR`, or output that is not a terminal, turns every
+// color off whatever the theme says.
+
+/// The built-in palette, as `MYSHELL_THEME` would spell it.
+pub const DEFAULT_THEME: &str = "error=31:builtin=36:executable=32:hint=2:prompt=1";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Theme {
+    pub error: String,
+    /// A command word naming a builtin, function or alias.
+    pub builtin: String,
+    /// A command word naming an executable on PATH.
+    pub executable: String,
+    /// Hints and the kinds shown beside completion candidates.
+    pub hint: String,
+    pub prompt: String,
+}
+
+impl Theme {
+    /// Reads a `MYSHELL_THEME` value. Unknown keys and values that are not
+    /// SGR parameters are ignored.
+    pub fn parse(spec: &str) -> Self {
+        let mut theme = Theme::default();
+        theme.apply(DEFAULT_THEME);
+        if spec != "default" {
+            theme.apply(spec);
+        }
+        theme
+    }
+
+    fn apply(&mut self, spec: &str) {
+        for (key, value) in spec.split(':').filter_map(|entry| entry.split_once('=')) {
+            if !value.chars().all(|c| c.is_ascii_digit() || c == ';') {
+                continue;
+            }
+            let color = match key {
+                "error" => &mut self.error,
+                "builtin" => &mut self.builtin,
+                "executable" => &mut self.executable,
+                "hint" => &mut self.hint,
+                "prompt" => &mut self.prompt,
+                _ => continue,
+            };
+            *color = value.to_string();
+        }
+    }
+
+    /// The theme for output that goes to a terminal if `terminal`, given
+    /// the values of `MYSHELL_THEME` and `NO_COLOR`.
+    pub fn select(spec: Option<&str>, no_color: Option<&str>, terminal: bool) -> Self {
+        match spec {
+            Some(spec) if terminal && no_color.is_none_or(str::is_empty) => Theme::parse(spec),
+            _ => Theme::default(),
+        }
+    }
+
+    /// `text` in `color`, or unchanged if the color is empty.
+    pub fn paint(color: &str, text: &str) -> String {
+        if color.is_empty() {
+            text.to_string()
+        } else {
+            format!("\x1b[{}m{}\x1b[0m", color, text)
+        }
+    }
+
+    /// `text` in `color`, leaving a final newline outside the color so the
+    /// terminal's next line starts uncolored.
+    pub fn paint_line(color: &str, text: &str) -> String {
+        match text.strip_suffix('\n') {
+            Some(line) => Self::paint(color, line) + "\n",
+            None => Self::paint(color, text),
+        }
+    }
+}
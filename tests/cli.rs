@@ -0,0 +1,353 @@
+// Runs the shell binary itself, feeding commands on stdin, to cover startup
+// behaviour that unit tests cannot reach.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn run_shell(home: &Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .args(args)
+        .env("HOME", home)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start the shell");
+    // A shell that exits without reading, as with `--help`, closes the pipe.
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn rcfile_is_sourced_instead_of_default() {
+    let home = tempfile::tempdir().unwrap();
+    fs::write(home.path().join(".myshellrc"), "FROM_DEFAULT=yes\n").unwrap();
+    let rc = home.path().join("custom_rc");
+    fs::write(&rc, "FROM_CUSTOM=yes\n").unwrap();
+
+    let output = run_shell(home.path(), &["-i", "--rcfile", rc.to_str().unwrap()], "echo \"[$FROM_DEFAULT] [$FROM_CUSTOM]\"\nexit\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "[] [yes]\n");
+
+    let output = run_shell(home.path(), &["-i"], "echo \"[$FROM_DEFAULT] [$FROM_CUSTOM]\"\nexit\n");
+    assert_eq!(stdout(&output), "[yes] []\n");
+}
+
+#[test]
+fn norc_wins_over_rcfile() {
+    let home = tempfile::tempdir().unwrap();
+    let rc = home.path().join("custom_rc");
+    fs::write(&rc, "FROM_CUSTOM=yes\n").unwrap();
+
+    let output = run_shell(home.path(), &["-i", "--rcfile", rc.to_str().unwrap(), "--norc"], "echo \"[$FROM_CUSTOM]\"\nexit\n");
+    assert_eq!(stdout(&output), "[]\n");
+}
+
+#[test]
+fn invalid_option_exits_with_status_2() {
+    let home = tempfile::tempdir().unwrap();
+    let output = run_shell(home.path(), &["--no-such-option"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "--no-such-option: invalid option\n");
+}
+
+#[test]
+fn help_prints_usage_and_exits() {
+    let home = tempfile::tempdir().unwrap();
+    // The REPL would run `echo` from stdin; --help must exit first.
+    let output = run_shell(home.path(), &["--help"], "echo ran\n");
+    assert_eq!(output.status.code(), Some(0));
+    let usage = stdout(&output);
+    assert!(usage.starts_with("usage: myshell"));
+    assert!(usage.contains("-c COMMAND"));
+    assert!(!usage.contains("ran"));
+
+    let output = run_shell(home.path(), &["--version"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), format!("myshell {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn command_option_runs_without_rc() {
+    let home = tempfile::tempdir().unwrap();
+    fs::write(home.path().join(".myshellrc"), "FROM_RC=yes\n").unwrap();
+    let output = run_shell(home.path(), &["-c", "echo \"[$FROM_RC]\"; sh -c 'exit 4'"], "echo stdin\n");
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(stdout(&output), "[]\n");
+}
+
+// Startup files that append their name to `$HOME/order`, to show which ran
+// and in what sequence.
+fn write_marker_files(home: &Path) {
+    for (file, name) in [(".myshell_profile", "profile"), (".myshellrc", "rc"), (".myshell_logout", "logout")] {
+        fs::write(home.join(file), format!("echo {} >> \"$HOME/order\"\n", name)).unwrap();
+    }
+}
+
+fn order(home: &Path) -> String {
+    fs::read_to_string(home.join("order")).unwrap_or_default()
+}
+
+#[test]
+fn login_shell_reads_profile_and_logout() {
+    let home = tempfile::tempdir().unwrap();
+    write_marker_files(home.path());
+    let output = run_shell(home.path(), &["-l", "-i"], "echo \"$SHELL\"\nexit 3\n");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(order(home.path()), "profile\nrc\nlogout\n");
+    assert_eq!(stdout(&output).trim_end(), env!("CARGO_BIN_EXE_codecrafters-shell"));
+
+    let home = tempfile::tempdir().unwrap();
+    write_marker_files(home.path());
+    let output = run_shell(home.path(), &["--login", "-c", "true"], "");
+    assert!(output.status.success());
+    assert_eq!(order(home.path()), "profile\nlogout\n");
+}
+
+#[test]
+fn non_login_shell_skips_profile_and_logout() {
+    let home = tempfile::tempdir().unwrap();
+    write_marker_files(home.path());
+    run_shell(home.path(), &["-i"], "exit\n");
+    assert_eq!(order(home.path()), "rc\n");
+
+    let home = tempfile::tempdir().unwrap();
+    write_marker_files(home.path());
+    run_shell(home.path(), &["-c", "true"], "");
+    assert_eq!(order(home.path()), "");
+}
+
+#[test]
+fn piped_stdin_runs_without_rc_unless_interactive() {
+    let home = tempfile::tempdir().unwrap();
+    fs::write(home.path().join(".myshellrc"), "FROM_RC=yes\n").unwrap();
+    let script = "echo \"[$FROM_RC]\"\nif true; then\n  echo multi\nfi\nsh -c 'exit 5'\n";
+    let output = run_shell(home.path(), &[], script);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(stdout(&output), "[]\nmulti\n");
+
+    let output = run_shell(home.path(), &["-i"], "echo \"[$FROM_RC]\"\nexit\n");
+    assert_eq!(stdout(&output), "[yes]\n");
+}
+
+// With no PATH to search, every line must run in the shell itself.
+#[test]
+fn no_op_script_runs_without_starting_processes() {
+    let home = tempfile::tempdir().unwrap();
+    let script = home.path().join("no-ops.sh");
+    fs::write(&script, "PATH=\n".to_string() + &":\ntrue\n".repeat(5000) + "false\n").unwrap();
+    let output = run_shell(home.path(), &[script.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+// A benchmark, run with `cargo test -- --ignored`. A line of `:` or `true`
+// should cost far less than one that starts a process. Each script's best of
+// a few runs is compared per line, so a busy machine slows both alike.
+#[test]
+#[ignore]
+fn bench_no_op_lines_against_process_starts() {
+    let home = tempfile::tempdir().unwrap();
+    let best_per_line = |name: &str, line: &str, count: u32| {
+        let script = home.path().join(name);
+        fs::write(&script, line.repeat(count as usize)).unwrap();
+        let best = (0..5)
+            .map(|_| {
+                let started = std::time::Instant::now();
+                run_shell(home.path(), &[script.to_str().unwrap()], "");
+                started.elapsed()
+            })
+            .min()
+            .unwrap();
+        best / count
+    };
+    let no_op = best_per_line("no-ops.sh", ":\ntrue\n", 5000) / 2;
+    let process = best_per_line("processes.sh", "/bin/true\n", 200);
+    eprintln!("per line: no-op {:?}, process {:?}", no_op, process);
+    assert!(no_op * 3 < process, "no-op {:?} vs process {:?}", no_op, process);
+}
+
+#[test]
+fn script_file_gets_positional_parameters() {
+    let home = tempfile::tempdir().unwrap();
+    let script = home.path().join("script.sh");
+    fs::write(&script, "echo \"$# $1 $2\"\nsh -c 'exit 6'\n").unwrap();
+    // A script wins over -i and ignores stdin.
+    let output = run_shell(home.path(), &["-i", script.to_str().unwrap(), "a", "b c"], "echo stdin\n");
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(stdout(&output), "2 a b c\n");
+
+    let output = run_shell(home.path(), &["missing.sh"], "");
+    assert_eq!(output.status.code(), Some(127));
+}
+
+#[test]
+fn syntax_error_ends_only_a_non_interactive_shell() {
+    let home = tempfile::tempdir().unwrap();
+    fs::write(home.path().join("bad.sh"), "echo ;;\n").unwrap();
+
+    let output = run_shell(home.path(), &["-c", "cd; echo a; . ./bad.sh; echo b"], "");
+    assert_eq!((stdout(&output).as_str(), output.status.code()), ("a\n", Some(2)));
+
+    let script = home.path().join("script.sh");
+    fs::write(&script, "echo 1\necho 2\necho ;;\necho 4\n").unwrap();
+    let output = run_shell(home.path(), &[script.to_str().unwrap()], "");
+    assert_eq!((stdout(&output).as_str(), output.status.code()), ("1\n2\n", Some(2)));
+
+    let output = run_shell(home.path(), &["-s"], "echo a\necho ;;\necho b\n");
+    assert_eq!((stdout(&output).as_str(), output.status.code()), ("a\n", Some(2)));
+
+    let output = run_shell(home.path(), &["-i"], "echo a\necho ;;\necho b\nexit\n");
+    assert_eq!(stdout(&output), "a\nb\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("syntax error near unexpected token `;;'"));
+}
+
+#[test]
+fn closed_stdout_ends_the_shell_quietly() {
+    let home = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        // The reading end is gone before the shell writes anything, as with
+        // `| head -0`.
+        let (reader, writer) = std::io::pipe().unwrap();
+        drop(reader);
+        Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+            .args(args)
+            .env("HOME", home.path())
+            .stdin(Stdio::null())
+            .stdout(writer)
+            .stderr(Stdio::piped())
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["-c", "echo hi; echo ran > ran.txt"]);
+    assert_eq!(output.status.code(), Some(141));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert!(!home.path().join("ran.txt").exists());
+
+    let output = run(&["--help"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn complete_prints_candidates_and_exits() {
+    let home = tempfile::tempdir().unwrap();
+    fs::create_dir(home.path().join("docs")).unwrap();
+    fs::write(home.path().join("notes.txt"), "").unwrap();
+    let complete = |line: &str, pos: &str| {
+        Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+            .args(["--complete", line, pos])
+            .env("HOME", home.path())
+            .env("PATH", "")
+            .current_dir(home.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = complete("ec", "2");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "echo\n");
+    let output = complete("e", "1");
+    assert_eq!(stdout(&output), "echo\nenable\nenvallow\nexit\nexport\n");
+    // Only the text before the cursor is completed.
+    let output = complete("cd d nowhere", "4");
+    assert_eq!(stdout(&output), "docs/\n");
+
+    let output = complete("zzz", "3");
+    assert_eq!((stdout(&output).as_str(), output.status.code()), ("", Some(1)));
+}
+
+#[test]
+fn pipeline_stages_share_the_real_stdout() {
+    let home = tempfile::tempdir().unwrap();
+    let output = run_shell(home.path(), &[], "echo foo | cat\nls /nonexistent | wc -l\n");
+    assert_eq!(stdout(&output), "foo\n0\n");
+    assert!(!output.stderr.is_empty());
+    assert!(output.status.success());
+
+    let output = run_shell(home.path(), &[], "echo foo | false\necho status $?\n");
+    assert_eq!(stdout(&output), "status 1\n");
+}
+
+#[test]
+fn compound_and_function_stages_run_alongside_their_reader() {
+    let home = tempfile::tempdir().unwrap();
+    // Far more than a pipe holds, so the writer must not run to the end
+    // before the reader starts.
+    let script = "{ seq 1 200000; } | wc -l\nf() { seq 1 200000; }; f | wc -l\n{ echo a; seq 1 2; echo b; } | cat\n";
+    let output = run_shell(home.path(), &["-c", script], "");
+    assert_eq!(stdout(&output).split_whitespace().collect::<Vec<_>>(), ["200000", "200000", "a", "1", "2", "b"]);
+
+    // What a stage changes stays in it, and one that never ends stops
+    // once its reader has gone.
+    let output = run_shell(home.path(), &["-c", "x=1; { x=2; } | cat; echo $x; { yes; } | head -1"], "");
+    assert_eq!(stdout(&output), "1\ny\n");
+}
+
+#[test]
+fn redirected_builtin_gives_back_the_real_stdout() {
+    let home = tempfile::tempdir().unwrap();
+    let output = run_shell(home.path(), &[], "echo into > ~/out.txt\npwd >> ~/out.txt\necho after\n");
+    assert_eq!(stdout(&output), "after\n");
+    let written = fs::read_to_string(home.path().join("out.txt")).unwrap();
+    assert_eq!(written.lines().next(), Some("into"));
+    assert_eq!(written.lines().count(), 2);
+}
+
+#[test]
+fn interactive_shell_without_a_terminal_reads_lines_plainly() {
+    let home = tempfile::tempdir().unwrap();
+    let output = run_shell(home.path(), &["-i"], "echo one\nread word\ntyped\necho \"got $word\"\nsh -c 'exit 3'\n");
+    assert_eq!(stdout(&output), "one\ngot typed\n");
+    assert_eq!(output.status.code(), Some(3));
+    let history = fs::read_to_string(home.path().join(".myshell_history")).unwrap();
+    let lines: Vec<&str> = history.lines().filter(|line| !line.starts_with('#')).collect();
+    assert_eq!(lines, ["echo one", "read word", "echo \"got $word\"", "sh -c 'exit 3'"]);
+}
+
+fn run_shell_with_env(home: &Path, env_file: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .args(args)
+        .env("HOME", home)
+        .env("ENV", env_file)
+        .env_remove("MYSHELL_SOURCING_ENV")
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to start the shell")
+}
+
+#[test]
+fn non_interactive_shell_sources_env_file() {
+    let home = tempfile::tempdir().unwrap();
+    fs::write(home.path().join("env.sh"), "echo env >> \"$HOME/order\"\ngreet() { echo \"hello $1\"; }\n").unwrap();
+    let output = run_shell_with_env(home.path(), "$HOME/env.sh", &["-c", "greet c"]);
+    assert_eq!(stdout(&output), "hello c\n");
+
+    let script = home.path().join("script.sh");
+    fs::write(&script, "greet \"$1\"\n").unwrap();
+    let output = run_shell_with_env(home.path(), "$HOME/env.sh", &[script.to_str().unwrap(), "script"]);
+    assert_eq!(stdout(&output), "hello script\n");
+    assert_eq!(order(home.path()), "env\nenv\n");
+
+    // Commands read from stdin do not source it.
+    run_shell_with_env(home.path(), "$HOME/env.sh", &["-s"]);
+    assert_eq!(order(home.path()), "env\nenv\n");
+}
+
+#[test]
+fn shell_started_from_env_file_does_not_source_it_again() {
+    let home = tempfile::tempdir().unwrap();
+    let shell = env!("CARGO_BIN_EXE_codecrafters-shell");
+    fs::write(home.path().join("env.sh"), format!("echo env >> \"$HOME/order\"\n{} -c 'echo nested'\n", shell)).unwrap();
+    let output = run_shell_with_env(home.path(), &home.path().join("env.sh").display().to_string(), &["-c", "echo main; sh -c 'echo $MYSHELL_SOURCING_ENV'"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "nested\nmain\n\n");
+    assert_eq!(order(home.path()), "env\n");
+}